@@ -14,7 +14,7 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
-use parity_db::{Key, Value};
+use parity_db::{CommitBuilder, Key, Value};
 
 pub trait Db: Send + Sync + 'static {
 	type Options;
@@ -23,4 +23,8 @@ pub trait Db: Send + Sync + 'static {
 	fn with_options(options: &Self::Options) -> Self;
 	fn get(&self, key: &Key) -> Option<Value>;
 	fn commit<I: IntoIterator<Item=(Key, Option<Value>)>>(&self, tx: I);
+	fn commit_builder(&self, builder: &mut CommitBuilder);
+	fn bulk_insert<I: IntoIterator<Item=(Key, Value)>>(&self, tx: I) {
+		self.commit(tx.into_iter().map(|(k, v)| (k, Some(v))))
+	}
 }
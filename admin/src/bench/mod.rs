@@ -22,17 +22,18 @@ use super::*;
 mod db;
 mod sizes;
 
-pub use parity_db::{Key, Value, Db};
+pub use parity_db::{CommitBuilder, Key, Value, Db};
 pub use db::Db as BenchDb;
 
-use std::{sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, }, thread};
-use rand::{SeedableRng, RngCore};
+use std::{sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex}, thread, time::Duration};
+use rand::{SeedableRng, RngCore, Rng};
 
 static COMMITS: AtomicUsize = AtomicUsize::new(0);
 //static QUERIES: AtomicUsize = AtomicUsize::new(0);
 
 const COMMIT_SIZE: usize = 100;
 
+// Last completed commit, followed by the seed of the run.
 const KEY_RESTART: Key = [1u8; 32];
 
 // Out of `COMMIT_SIZE` values `COMMIT_PRUNE_SIZE` will be deleted in a later commit.
@@ -60,6 +61,14 @@ impl BenchDb for BenchAdapter {
 	fn commit<I: IntoIterator<Item=(Key, Option<Value>)>>(&self, tx: I) {
 		self.0.commit(tx.into_iter().map(|(k, v)| (0, k, v))).unwrap()
 	}
+
+	fn commit_builder(&self, builder: &mut CommitBuilder) {
+		self.0.commit_builder(builder).unwrap()
+	}
+
+	fn bulk_insert<I: IntoIterator<Item=(Key, Value)>>(&self, tx: I) {
+		self.0.bulk_insert(0, tx).unwrap();
+	}
 }
 
 /// Stress tests (warning erase db first).
@@ -76,11 +85,17 @@ pub struct Stress {
 	#[structopt(long)]
 	pub writers: Option<usize>,
 
+	/// Fraction of operations that are reads of recently written keys, interleaved with
+	/// inserts on the writing threads. Replaces the reading threads. Must be in [0, 1).
+	#[structopt(long)]
+	pub rw_ratio: Option<f64>,
+
 	/// Total number of inserted commits.
 	#[structopt(long)]
 	pub commits: Option<usize>,
 
-	/// Random seed used for key generation.
+	/// Random seed used for key and value generation. Stored in the database, and must
+	/// match the stored one with `--append` [default: 0, or the stored one].
 	#[structopt(long)]
 	pub seed: Option<u64>,
 
@@ -95,6 +110,31 @@ pub struct Stress {
 	/// Do not check after writing.
 	#[structopt(long)]
 	pub no_check: bool,
+
+	/// Insert all values with a single bulk insert, bypassing the log.
+	/// Implies `--archive` and a single writer.
+	#[structopt(long)]
+	pub bulk: bool,
+
+	/// Write commits to the tables directly, without the write-ahead log.
+	#[structopt(long)]
+	pub disable_wal: bool,
+
+	/// Enable the value cache of all columns, with this size in bytes.
+	#[structopt(long)]
+	pub cache_size: Option<u64>,
+
+	/// Key hashing salt, as a 32 byte hex string. Random by default.
+	#[structopt(long)]
+	pub salt: Option<String>,
+
+	/// Size the index and value tables for all inserted values up front.
+	#[structopt(long)]
+	pub size_hint: bool,
+
+	/// Read value tables through memory maps.
+	#[structopt(long)]
+	pub mmap_value_tables: bool,
 }
 
 #[derive(Clone)]
@@ -102,44 +142,60 @@ pub struct Args { // TODO remove (rendundant with Stress)
 	pub readers: usize,
 	pub commits: usize,
 	pub writers: usize,
+	pub rw_ratio: Option<f64>,
 	pub seed: Option<u64>,
 	pub archive: bool,
 	pub append: bool,
 	pub no_check: bool,
+	pub bulk: bool,
 }
 
 impl Stress {
 	pub(super) fn get_args(&self) -> Args {
 		Args {
-			readers: self.readers.unwrap_or(4),
-			writers: self.writers.unwrap_or(1),
+			readers: if self.rw_ratio.is_some() { 0 } else { self.readers.unwrap_or(4) },
+			writers: if self.bulk { 1 } else { self.writers.unwrap_or(1) },
+			rw_ratio: self.rw_ratio,
 			commits: self.commits.unwrap_or(100_000),
 			seed: self.seed.clone(),
 			append: self.append,
-			archive: self.archive,
+			archive: self.archive || self.bulk,
 			no_check: self.no_check,
+			bulk: self.bulk,
+		}
+	}
+
+	pub(super) fn size_hint(&self) -> Option<parity_db::SizeHint> {
+		if !self.size_hint {
+			return None;
 		}
+		// Writers insert the same keys.
+		Some(parity_db::SizeHint {
+			entries: (self.get_args().commits * COMMIT_SIZE) as u64,
+			histogram: sizes::KUSAMA_STATE_DISTRIBUTION.iter().map(|(size, count)| (*size, *count as u64)).collect(),
+		})
 	}
 }
 
 struct SizePool {
 	distribution: std::collections::BTreeMap<u32, u32>,
 	total: u32,
+	seed: u64,
 }
 
 impl SizePool {
-	fn from_histogram(h: &[(u32, u32)]) -> SizePool {
+	fn from_histogram(h: &[(u32, u32)], seed: u64) -> SizePool {
 		let mut distribution = std::collections::BTreeMap::default();
 		let mut total = 0;
 		for (size, count) in h {
 			total += count;
 			distribution.insert(total, *size);
 		}
-		SizePool { distribution, total }
+		SizePool { distribution, total, seed }
 	}
 
 	fn value(&self, seed: u64) -> Vec<u8> {
-		let mut rng = rand::rngs::SmallRng::seed_from_u64(seed);
+		let mut rng = rand::rngs::SmallRng::seed_from_u64(seed ^ self.seed);
 		let sr = (rng.next_u64() % self.total as u64) as u32;
 		let mut range = self.distribution.range((std::ops::Bound::Included(sr), std::ops::Bound::Unbounded));
 		let size = *range.next().unwrap().1 as usize;
@@ -149,8 +205,14 @@ impl SizePool {
 		v
 	}
 
+	fn restart_value(&self, commit: usize) -> Vec<u8> {
+		let mut value = (commit as u64).to_be_bytes().to_vec();
+		value.extend_from_slice(&self.seed.to_be_bytes());
+		value
+	}
+
 	fn key(&self, seed: u64) -> Key {
-		let mut rng = rand::rngs::SmallRng::seed_from_u64(seed);
+		let mut rng = rand::rngs::SmallRng::seed_from_u64(seed ^ self.seed);
 		let mut key = Key::default();
 		rng.fill_bytes(&mut key);
 		key
@@ -170,31 +232,107 @@ fn informant(shutdown: Arc<AtomicBool>, total: usize, start: usize) {
 	}
 }
 
-fn writer<D: BenchDb>(db: Arc<D>, args: Arc<Args>, pool: Arc<SizePool>, shutdown: Arc<AtomicBool>, start_commit: usize) {
+#[derive(Default)]
+struct Latencies {
+	reads: Mutex<Vec<Duration>>,
+	writes: Mutex<Vec<Duration>>,
+}
+
+fn print_latencies(name: &str, samples: &mut Vec<Duration>) {
+	if samples.is_empty() {
+		return;
+	}
+	samples.sort();
+	let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+	println!(
+		"{} latency ({} samples): p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+		name,
+		samples.len(),
+		percentile(50),
+		percentile(90),
+		percentile(99),
+		percentile(100),
+	);
+}
+
+fn writer<D: BenchDb>(
+	db: Arc<D>,
+	args: Arc<Args>,
+	pool: Arc<SizePool>,
+	latencies: Arc<Latencies>,
+	shutdown: Arc<AtomicBool>,
+	start_commit: usize,
+	index: usize,
+) {
 	// Note that multiple worker will run on same range concurrently.
 	let mut key = start_commit as u64 * COMMIT_SIZE as u64;
 	let commit_size = COMMIT_SIZE;
-	let mut commit = Vec::with_capacity(commit_size);
+	let mut commit = CommitBuilder::with_capacity(commit_size + COMMIT_PRUNE_SIZE + 1);
+	let mut rng = rand::rngs::SmallRng::seed_from_u64(pool.seed ^ index as u64);
+	let mut reads = Vec::new();
+	let mut writes = Vec::new();
 
 	for n in start_commit .. start_commit + args.commits {
 		if shutdown.load(Ordering::Relaxed) { break; }
+		// Reads target keys of the last `COMMIT_PRUNE_WINDOW` commits, most of which are
+		// still in the commit overlay or the log. Some of them may have been pruned.
+		let read_start = n.saturating_sub(COMMIT_PRUNE_WINDOW) as u64 * COMMIT_SIZE as u64;
+		let read_end = n as u64 * COMMIT_SIZE as u64;
 		for _ in 0 .. commit_size {
-			commit.push((pool.key(key), Some(pool.value(key))));
+			if let Some(ratio) = args.rw_ratio {
+				while read_start < read_end && rng.gen_bool(ratio) {
+					let k = pool.key(rng.gen_range(read_start, read_end));
+					let start = std::time::Instant::now();
+					db.get(&k);
+					reads.push(start.elapsed());
+				}
+			}
+			commit.insert(0, &pool.key(key), pool.value(key));
 			key += 1;
 		}
 		if !args.archive && n >= COMMIT_PRUNE_WINDOW {
 			let prune_start = (n - COMMIT_PRUNE_WINDOW) * COMMIT_SIZE;
 			for p in prune_start .. prune_start + COMMIT_PRUNE_SIZE {
-				commit.push((pool.key(p as u64), None));
+				commit.remove(0, &pool.key(p as u64));
 			}
 		}
-		commit.push((KEY_RESTART, Some((n as u64).to_be_bytes().to_vec())));
+		commit.insert(0, &KEY_RESTART, pool.restart_value(n));
 
-		db.commit(commit.drain(..));
+		let start = std::time::Instant::now();
+		db.commit_builder(&mut commit);
+		writes.push(start.elapsed());
 		COMMITS.fetch_add(1, Ordering::Release);
-		commit.clear();
 	}
-	commit.clear();
+	latencies.reads.lock().unwrap().extend(reads);
+	latencies.writes.lock().unwrap().extend(writes);
+}
+
+fn bulk_writer<D: BenchDb>(db: Arc<D>, args: Arc<Args>, pool: Arc<SizePool>, shutdown: Arc<AtomicBool>, start_commit: usize) {
+	// Commits are only counted for progress reporting, values are inserted in one go.
+	let values = (start_commit .. start_commit + args.commits)
+		.take_while(|_| !shutdown.load(Ordering::Relaxed))
+		.flat_map(|n| {
+			let mut commit: Vec<_> = (0 .. COMMIT_SIZE).map(|i| {
+				let key = (n * COMMIT_SIZE + i) as u64;
+				(pool.key(key), pool.value(key))
+			}).collect();
+			commit.push((KEY_RESTART, pool.restart_value(n)));
+			COMMITS.fetch_add(1, Ordering::Release);
+			commit
+		});
+	db.bulk_insert(values);
+}
+
+// Close the database and print bytes written to storage by this process, where supported.
+fn report_written<D: BenchDb>(db: Arc<D>) {
+	drop(db);
+	let written = std::fs::read_to_string("/proc/self/io").ok().and_then(|io| io.lines()
+		.find_map(|l| l.strip_prefix("write_bytes:"))
+		.and_then(|v| v.trim().parse::<u64>().ok())
+	);
+	if let Some(bytes) = written {
+		println!("Written {} bytes", bytes);
+	}
 }
 
 fn reader<D: BenchDb>(_db: Arc<D>, shutdown: Arc<AtomicBool>) {
@@ -204,22 +342,43 @@ fn reader<D: BenchDb>(_db: Arc<D>, shutdown: Arc<AtomicBool>) {
 	}
 }
 
-pub fn run_internal<D: BenchDb>(args: Args, db: D) {
+pub fn run_internal<D: BenchDb>(args: Args, db: D) -> Result<(), String> {
+	if let Some(ratio) = args.rw_ratio {
+		if !(0.0 .. 1.0).contains(&ratio) {
+			return Err(format!("Read/write ratio {} must be in [0, 1)", ratio));
+		}
+		if args.bulk {
+			return Err("Read/write ratio is not supported with bulk insert".into());
+		}
+	}
 	let args = Arc::new(args);
+	let latencies = Arc::new(Latencies::default());
 	let shutdown = Arc::new(AtomicBool::new(false));
-	let pool = Arc::new(SizePool::from_histogram(&sizes::KUSAMA_STATE_DISTRIBUTION));
 	let db = Arc::new(db) as Arc<D>;
-	let start = std::time::Instant::now();
-
-	let mut threads = Vec::new();
 
-	let start_commit = if let Some(start) = db.get(&KEY_RESTART) {
+	let (start_commit, stored_seed) = if let Some(start) = db.get(&KEY_RESTART) {
 		let mut buf = [0u8; 8];
 		buf.copy_from_slice(&start[0..8]);
-		u64::from_be_bytes(buf) as usize + 1
+		let commit = u64::from_be_bytes(buf) as usize + 1;
+		// Databases written before the seed was stored used seed 0.
+		let seed = start.get(8..16).map_or(0, |s| {
+			buf.copy_from_slice(s);
+			u64::from_be_bytes(buf)
+		});
+		(commit, Some(seed))
 	} else {
-		0
+		(0, None)
+	};
+	let seed = match (stored_seed, args.seed) {
+		(Some(stored), Some(seed)) if stored != seed => {
+			return Err(format!("Seed {} does not match seed {} of the existing database", seed, stored));
+		},
+		(stored, seed) => stored.or(seed).unwrap_or(0),
 	};
+	let pool = Arc::new(SizePool::from_histogram(&sizes::KUSAMA_STATE_DISTRIBUTION, seed));
+	let start = std::time::Instant::now();
+
+	let mut threads = Vec::new();
 
 	COMMITS.store(start_commit as usize, Ordering::SeqCst);
 
@@ -247,11 +406,16 @@ pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 		let shutdown = shutdown.clone();
 		let pool = pool.clone();
 		let args = args.clone();
+		let latencies = latencies.clone();
 
 		threads.push(
 			thread::Builder::new()
 			.name(format!("writer {}", i))
-			.spawn(move || writer(db, args, pool, shutdown, start_commit))
+			.spawn(move || if args.bulk {
+				bulk_writer(db, args, pool, shutdown, start_commit)
+			} else {
+				writer(db, args, pool, latencies, shutdown, start_commit, i)
+			})
 			.unwrap()
 		);
 	}
@@ -270,14 +434,18 @@ pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 	let elapsed = start.elapsed().as_secs_f64();
 
 	println!(
-		"Completed {} commits in {} seconds. {} cps",
+		"Completed {} commits in {} seconds with seed {}. {} cps",
 		commits,
 		elapsed,
+		seed,
 		commits as f64  / elapsed
 	);
+	print_latencies("Read", &mut latencies.reads.lock().unwrap());
+	print_latencies("Write", &mut latencies.writes.lock().unwrap());
 
 	if args.no_check {
-		return;
+		report_written(db);
+		return Ok(());
 	}
 
 	// Verify content
@@ -323,4 +491,6 @@ pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 		elapsed,
 		queries as f64  / elapsed
 	);
+	report_written(db);
+	Ok(())
 }
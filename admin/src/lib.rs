@@ -41,28 +41,62 @@ pub fn run() -> Result<(), String> {
 	let nb_column = cli.shared().columns.unwrap_or(1);
 	let mut metadata_path = db_path.clone();
 	metadata_path.push("metadata");
-	let mut options = if let Some(metadata) = parity_db::Options::load_metadata(&metadata_path)
-		.map_err(|e| format!("Error resolving metas: {:?}", e))? {
-		let mut options = parity_db::Options::with_columns(db_path.as_path(), 0);
-		options.columns = metadata.columns;
-		options.salt = metadata.salt;
+	let mut options = if let Some(file) = &cli.shared().options_file {
+		let mut options = parity_db::Options::load_from(file)
+			.map_err(|e| format!("Error loading options file: {:?}", e))?;
+		if let Some(path) = &cli.shared().base_path {
+			options.path = path.clone();
+		}
 		options
+	} else if metadata_path.exists() {
+		parity_db::Options::from_existing(&db_path)
+			.map_err(|e| format!("Error resolving metas: {:?}", e))?
 	} else {
 		parity_db::Options::with_columns(db_path.as_path(), nb_column)
 	};
-	options.sync_wal = !cli.shared().no_sync;
-	options.sync_data = !cli.shared().no_sync;
-	options.stats = cli.shared().with_stats;
+	// Settings from the options file are only overridden by flags that are given.
+	let from_file = cli.shared().options_file.is_some();
+	if cli.shared().no_sync || !from_file {
+		options.sync_wal = if cli.shared().no_sync {
+			parity_db::WalSync::Never
+		} else {
+			parity_db::WalSync::EveryCommit
+		};
+		options.sync_data = !cli.shared().no_sync;
+	}
+	if cli.shared().with_stats || !from_file {
+		options.stats = cli.shared().with_stats;
+	}
+	options.validate_on_replay |= cli.shared().validate_on_replay;
+	options.repair_on_replay |= cli.shared().repair_on_replay;
+	if let Some(threads) = cli.shared().background_threads {
+		options.background_threads = threads;
+	}
+	if let Some(threads) = cli.shared().enact_threads {
+		options.enact_threads = threads;
+	}
 	println!("Options {:?}, {:?}", cli, options);
 	match cli.subcommand {
 		SubCommand::Stats(stat) => {
+			// Stored statistics are read regardless of `--with-stats`.
+			options.stats = true;
 			let db = parity_db::Db::open_read_only(&options)
 				.map_err(|e| format!("Invalid db: {:?}", e))?;
 			if stat.clear {
 				db.clear_stats(stat.column.clone());
 			} else {
 				let mut out = std::io::stdout();
-				db.collect_stats(&mut out, stat.column.clone());
+				db.collect_stats(&mut out, stat.column.clone())
+					.map_err(|e| format!("Error collecting stats: {:?}", e))?;
+				let columns = stat.column.map_or(0 .. db.num_columns(), |c| c .. c + 1);
+				for col in columns {
+					let hot = db.hot_index_chunks(col, 10)
+						.map_err(|e| format!("Error reading index: {:?}", e))?;
+					println!("Column {} most loaded index chunks:", col);
+					for (chunk_index, occupancy) in hot {
+						println!("    {}: {}", chunk_index, occupancy);
+					}
+				}
 			}
 		},
 		SubCommand::Migrate(args) => {
@@ -80,7 +114,7 @@ pub fn run() -> Result<(), String> {
 
 			let mut dest_options = Options::with_columns(&args.dest_path, dest_columns.len() as u8);
 			dest_options.columns = dest_columns;
-			dest_options.sync_wal = false;
+			dest_options.sync_wal = parity_db::WalSync::Never;
 			dest_options.sync_data = false;
 
 			parity_db::migrate(&db_path, dest_options, args.overwrite, &args.force_columns)
@@ -91,11 +125,19 @@ pub fn run() -> Result<(), String> {
 			}
 		},
 		SubCommand::Check(check) => {
+			if check.logs {
+				let report = parity_db::Db::validate_logs(&options)
+					.map_err(|e| format!("Log check error: {:?}", e))?;
+				println!("{:?}", report);
+				if !check.index_value {
+					return Ok(());
+				}
+			}
 			let db = parity_db::Db::open_read_only(&options)
 				.map_err(|e| format!("Invalid db: {:?}", e))?;
 			if !check.index_value {
 				// Note that we should use enum parameter instead.
-				return Err("Requires one of the following check flag: --index-value".to_string());
+				return Err("Requires one of the following check flag: --index-value, --logs".to_string());
 			}
 			let check_param = parity_db::CheckOptions::new(
 				check.column,
@@ -107,6 +149,20 @@ pub fn run() -> Result<(), String> {
 			db.check_from_index(check_param)
 				.map_err(|e| format!("Check error: {:?}", e))?;
 		},
+		SubCommand::Get(get) => {
+			let key = from_hex(&get.key).ok_or_else(|| "Key is not a hex string".to_string())?;
+			let db = parity_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			match db.get_with_meta(get.column, &key).map_err(|e| format!("Query error: {:?}", e))? {
+				Some((value, meta)) => {
+					println!("{:?}", meta);
+					let max = get.display_value_max.unwrap_or(u64::MAX) as usize;
+					let shown = &value[..value.len().min(max)];
+					println!("value ({} bytes): {}", value.len(), shown.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+				},
+				None => println!("Not found"),
+			}
+		},
 		SubCommand::Flush(_flush) => {
 			let _db = parity_db::Db::open(&options)
 				.map_err(|e| format!("Invalid db: {:?}", e))?;
@@ -114,6 +170,22 @@ pub fn run() -> Result<(), String> {
 		SubCommand::Stress(bench) => {
 
 			let args = bench.get_args();
+			if let Some(size) = bench.cache_size {
+				for column in options.columns.iter_mut() {
+					column.cache_size_bytes = size;
+				}
+			}
+			if let Some(salt) = &bench.salt {
+				let salt = from_hex(salt)
+					.and_then(|s| std::convert::TryInto::try_into(s).ok())
+					.ok_or_else(|| "Salt must be 32 bytes in hex".to_string())?;
+				options.salt = Some(salt);
+			}
+			options.disable_wal = bench.disable_wal;
+			options.mmap_value_tables |= bench.mmap_value_tables;
+			if let Some(hint) = bench.size_hint() {
+				options.size_hints.insert(0, hint);
+			}
 			// avoid deleting folders by mistake.
 			options.path.push("test_db_stress");
 			if options.path.exists() && !args.append {
@@ -124,7 +196,7 @@ pub fn run() -> Result<(), String> {
 			use crate::bench::BenchDb;
 			let db = bench::BenchAdapter::with_options(&options);
 
-			crate::bench::run_internal(args, db);
+			crate::bench::run_internal(args, db)?;
 		},
 	}
 	Ok(())
@@ -137,6 +209,12 @@ pub struct Shared {
 	#[structopt(long, short = "d", value_name = "PATH", parse(from_os_str))]
 	pub base_path: Option<PathBuf>,
 
+	/// Read database options from a file written by `Options::write_to`, such as the
+	/// `options` file of a database created with `persist_options`. The database path
+	/// defaults to the directory of the file.
+	#[structopt(long, value_name = "FILE", parse(from_os_str))]
+	pub options_file: Option<PathBuf>,
+
 	/// Do not sync file on each flush.
 	#[structopt(long)]
 	pub no_sync: bool,
@@ -145,6 +223,22 @@ pub struct Shared {
 	#[structopt(long)]
 	pub with_stats: bool,
 
+	/// Check all pending log records on open before replaying them.
+	#[structopt(long)]
+	pub validate_on_replay: bool,
+
+	/// Truncate a torn record at the end of the logs on open.
+	#[structopt(long)]
+	pub repair_on_replay: bool,
+
+	/// Number of background threads, 1 to 4.
+	#[structopt(long)]
+	pub background_threads: Option<usize>,
+
+	/// Maximum number of threads that enact changes to different columns of a log record.
+	#[structopt(long)]
+	pub enact_threads: Option<usize>,
+
 	/// Indicate the number of column, when using
 	/// a new or temporary db, defaults to one.
 	#[structopt(long)]
@@ -187,6 +281,8 @@ pub enum SubCommand {
 	Flush(Flush),
 	/// Check db content.
 	Check(Check),
+	/// Show a value and how it is stored.
+	Get(Get),
 	/// Stress tests.
 	Stress(bench::Stress),
 }
@@ -206,6 +302,9 @@ impl Cli {
 			SubCommand::Check(check) => {
 				&check.shared
 			},
+			SubCommand::Get(get) => {
+				&get.shared
+			},
 			SubCommand::Stress(bench) => {
 				&bench.shared
 			},
@@ -257,6 +356,33 @@ pub struct Migrate {
 	pub dest_meta: PathBuf,
 }
 
+/// Show a value and how it is stored.
+#[derive(Debug, StructOpt)]
+pub struct Get {
+	#[structopt(flatten)]
+	pub shared: Shared,
+
+	/// Column to query.
+	#[structopt(long)]
+	pub column: u8,
+
+	/// Key as a hex string.
+	#[structopt(long)]
+	pub key: String,
+
+	/// Max length for value to display.
+	#[structopt(long)]
+	pub display_value_max: Option<u64>,
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+	let s = s.strip_prefix("0x").unwrap_or(s);
+	if s.len() % 2 != 0 {
+		return None;
+	}
+	(0 .. s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i .. i + 2)?, 16).ok()).collect()
+}
+
 /// Run db until all logs are flushed.
 #[derive(Debug, StructOpt)]
 pub struct Flush {
@@ -279,6 +405,10 @@ pub struct Check {
 	#[structopt(long)]
 	pub index_value: bool,
 
+	/// Check pending log files without replaying them.
+	#[structopt(long)]
+	pub logs: bool,
+
 	/// Start range for operation.
 	/// Index start chunk in db.
 	#[structopt(long)]
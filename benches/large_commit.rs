@@ -0,0 +1,47 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+// Latency of bulk commits, from queueing to the record being written to the log. Records
+// of 4 MiB and more are checksummed on multiple threads.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use parity_db::{Db, Options};
+
+const VALUE_SIZE: usize = 64 * 1024;
+
+fn large_commit(c: &mut Criterion) {
+	let mut group = c.benchmark_group("large_commit");
+	group.sample_size(10);
+	for mib in [1usize, 16, 64] {
+		let tmp = tempfile::tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let values = mib * 1024 * 1024 / VALUE_SIZE;
+		group.throughput(Throughput::Bytes((mib * 1024 * 1024) as u64));
+		group.bench_with_input(BenchmarkId::from_parameter(format!("{}MiB", mib)), &values, |b, &values| {
+			b.iter(|| {
+				// Same keys every time, so that the tables don't grow.
+				let commit = (0..values as u64).map(|i| (0, i.to_be_bytes(), Some(vec![i as u8; VALUE_SIZE])));
+				db.commit(commit).unwrap();
+				db.flush().unwrap();
+			});
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(benches, large_commit);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+// Commits of many small values, with keys hashed by the database, and with keys that were
+// hashed by the caller beforehand.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use parity_db::{Db, Options};
+
+const KEYS: u64 = 10_000;
+
+fn commits(c: &mut Criterion) {
+	let tmp = tempfile::tempdir().unwrap();
+	let options = Options::with_columns(tmp.path(), 1);
+	let db = Db::open_or_create(&options).unwrap();
+	let keys: Vec<_> = (0..KEYS).map(|i| i.to_be_bytes()).collect();
+	let hashed: Vec<_> = keys.iter().map(|k| db.hash_key(0, k).unwrap()).collect();
+
+	let mut group = c.benchmark_group("raw_commit");
+	group.throughput(Throughput::Elements(KEYS));
+	group.bench_function("commit", |b| b.iter_batched(
+		|| keys.iter().map(|k| (0, *k, Some(k.to_vec()))).collect::<Vec<_>>(),
+		|commit| db.commit(commit).unwrap(),
+		BatchSize::SmallInput,
+	));
+	group.bench_function("raw_commit", |b| b.iter_batched(
+		|| keys.iter().zip(&hashed).map(|(k, h)| (0, *h, *k, Some(k.to_vec()))).collect::<Vec<_>>(),
+		|commit| db.raw_commit(commit).unwrap(),
+		BatchSize::SmallInput,
+	));
+	group.finish();
+}
+
+criterion_group!(benches, commits);
+criterion_main!(benches);
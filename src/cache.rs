@@ -0,0 +1,163 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! LRU cache of column values, by value table address.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
+use crate::{
+	index::Address,
+	table::{Key, Value},
+};
+
+// Estimated memory used by a cached value, in addition to the value itself.
+const ENTRY_OVERHEAD: usize = 128;
+
+struct Entry {
+	key: Key,
+	value: Arc<Value>,
+	tick: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+	entries: HashMap<u64, Entry>,
+	// Addresses by last use.
+	order: BTreeMap<u64, u64>,
+	tick: u64,
+	bytes: usize,
+	// Incremented on every invalidation.
+	epoch: u64,
+}
+
+impl Lru {
+	fn remove(&mut self, address: u64) -> bool {
+		match self.entries.remove(&address) {
+			Some(entry) => {
+				self.order.remove(&entry.tick);
+				self.bytes -= entry.value.len() + ENTRY_OVERHEAD;
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+pub struct ValueCache {
+	budget: usize,
+	lru: Mutex<Lru>,
+	hits: AtomicU64,
+	misses: AtomicU64,
+	evictions: AtomicU64,
+}
+
+impl ValueCache {
+	pub fn new(budget: usize) -> ValueCache {
+		ValueCache {
+			budget,
+			lru: Mutex::new(Default::default()),
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+			evictions: AtomicU64::new(0),
+		}
+	}
+
+	/// Size of the cached values, including the per-entry overhead.
+	pub fn bytes(&self) -> usize {
+		self.lru.lock().bytes
+	}
+
+	/// Take before reading a value from the tables, and pass to `insert`.
+	pub fn epoch(&self) -> u64 {
+		self.lru.lock().epoch
+	}
+
+	pub fn get(&self, address: Address, key: &Key) -> Option<Arc<Value>> {
+		let mut lru = self.lru.lock();
+		let lru = &mut *lru;
+		let entry = lru.entries.get_mut(&address.as_u64()).filter(|e| e.key == *key)?;
+		lru.order.remove(&entry.tick);
+		lru.tick += 1;
+		entry.tick = lru.tick;
+		lru.order.insert(entry.tick, address.as_u64());
+		self.hits.fetch_add(1, Ordering::Relaxed);
+		Some(entry.value.clone())
+	}
+
+	/// Cache a value read from the tables. Nothing is cached if there were any invalidations
+	/// since `epoch` was taken, as the value may already be stale.
+	pub fn insert(&self, address: Address, key: &Key, value: Arc<Value>, epoch: u64) {
+		self.misses.fetch_add(1, Ordering::Relaxed);
+		let size = value.len() + ENTRY_OVERHEAD;
+		if size > self.budget {
+			return;
+		}
+		let mut lru = self.lru.lock();
+		if lru.epoch != epoch {
+			return;
+		}
+		lru.remove(address.as_u64());
+		lru.tick += 1;
+		let tick = lru.tick;
+		lru.entries.insert(address.as_u64(), Entry { key: *key, value, tick });
+		lru.order.insert(tick, address.as_u64());
+		lru.bytes += size;
+		while lru.bytes > self.budget {
+			let (_, oldest) = lru.order.pop_first().expect("Cache size is accounted for its entries");
+			lru.remove(oldest);
+			self.evictions.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// Drop values at the given addresses. Called once the entries are overwritten or freed.
+	pub fn invalidate(&self, addresses: impl Iterator<Item = Address>) {
+		let mut lru = self.lru.lock();
+		lru.epoch += 1;
+		for address in addresses {
+			lru.remove(address.as_u64());
+		}
+	}
+
+	pub fn clear(&self) {
+		let mut lru = self.lru.lock();
+		let epoch = lru.epoch + 1;
+		*lru = Lru { epoch, ..Default::default() };
+	}
+
+	pub fn write_stats(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+		let (entries, bytes) = {
+			let lru = self.lru.lock();
+			(lru.entries.len(), lru.bytes)
+		};
+		writeln!(writer,
+			"Cache: {} values, {} of {} bytes, hits: {}, misses: {}, evictions: {}",
+			entries,
+			bytes,
+			self.budget,
+			self.hits.load(Ordering::Relaxed),
+			self.misses.load(Ordering::Relaxed),
+			self.evictions.load(Ordering::Relaxed),
+		)
+	}
+
+	pub fn clear_stats(&self) {
+		self.hits.store(0, Ordering::Relaxed);
+		self.misses.store(0, Ordering::Relaxed);
+		self.evictions.store(0, Ordering::Relaxed);
+	}
+}
@@ -15,26 +15,141 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use parking_lot::RwLock;
 use crate::{
 	error::{Error, Result},
-	table::{TableId as ValueTableId, ValueTable, Key, Value},
-	log::{Log, LogOverlays, LogReader, LogWriter, LogAction},
+	table::{TableId as ValueTableId, ValueTable, Key, Value, KEY_LEN},
+	log::{Log, LogOverlays, LogRead, LogReader, LogWriter, LogAction, LogChange},
 	display::hex,
-	index::{IndexTable, TableId as IndexTableId, PlanOutcome, Address},
-	options::{Options, ColumnOptions, Metadata},
-	stats::ColumnStats,
+	index::{IndexTable, TableId as IndexTableId, PlanOutcome, Address, Entry as IndexEntry},
+	options::{Options, ColumnOptions, Metadata, SizeHint},
+	stats::{ColumnStats, ColumnCounters, ColumnDiskUsage, ColumnVerifyReport, FreeStats, IoCounters, ValueMeta},
 	db::check::CheckDisplay,
+	cache::ValueCache,
 };
-use crate::compress::Compress;
+use crate::compress::{Compress, CompressionType};
 
 const START_BITS: u8 = 16;
+// Largest index created for a size hint, 2^32 chunks.
+const MAX_HINT_INDEX_BITS: u8 = 32;
 const MAX_REBALANCE_BATCH: usize = 8192;
+// Chunk is considered hot when it exceeds the mean occupancy by this factor.
+const SKEW_FACTOR: f64 = 4.0;
+const SKEW_SAMPLE_CHUNKS: u64 = 64;
 
 pub type ColId = u8;
+
+/// Column number, followed by the column name if it has one.
+pub fn column_label(col: ColId, name: Option<&str>) -> String {
+	match name {
+		Some(name) => format!("{} ({})", col, name),
+		None => col.to_string(),
+	}
+}
 pub type Salt = [u8; 32];
 
+/// Hash function that maps the keys of a column that is not `uniform` to index keys.
+/// See `ColumnOptions::key_hash`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyHash {
+	/// Salted blake2b. Keys can't be chosen to collide without knowing the salt.
+	Blake2,
+	/// Four salted xxHash64 hashes of the key, one per 8 bytes of the index key. Several
+	/// times faster than blake2b, but not designed to resist keys chosen to collide.
+	XxHash,
+}
+
+impl KeyHash {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			KeyHash::Blake2 => "blake2",
+			KeyHash::XxHash => "xxhash",
+		}
+	}
+
+	pub(crate) fn from_str(s: &str) -> Option<Self> {
+		match s {
+			"blake2" => Some(KeyHash::Blake2),
+			"xxhash" => Some(KeyHash::XxHash),
+			_ => None,
+		}
+	}
+}
+
+/// How a column takes keys. See `ColumnOptions::key_mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMode {
+	/// Keys are passed to `Db::get`, `Db::commit` and the other key based methods. Keys of
+	/// `uniform` columns are used as the index key as is.
+	Typed,
+	/// Natural keys of any length, such as 8-byte numbers or 20-byte addresses, passed to
+	/// `Db::get_raw` and `Db::commit_raw`, and hashed into the index key with the salt.
+	Raw,
+}
+
+impl KeyMode {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			KeyMode::Typed => "typed",
+			KeyMode::Raw => "raw",
+		}
+	}
+
+	pub(crate) fn from_str(s: &str) -> Option<Self> {
+		match s {
+			"typed" => Some(KeyMode::Typed),
+			"raw" => Some(KeyMode::Raw),
+			_ => None,
+		}
+	}
+}
+
+/// Key as stored in the index of a column, for the database `salt` and the `uniform` option
+/// of the column. See `Db::salt`. Uniform columns use the first 32 bytes of the key as is,
+/// other columns use the salted blake2b hash of it. Keys are stored in this form, so it does
+/// not change between versions of the database format. Columns with a `key_len` shorter
+/// than 32 bytes keep that many leading bytes of the result, and set the rest to zero.
+/// Use `Db::hash_key` for columns with a different `ColumnOptions::key_hash`.
+pub fn hash_key(key: &[u8], salt: Option<&Salt>, uniform: bool) -> Result<Key> {
+	if uniform && key.len() < 32 {
+		return Err(Error::InvalidInput(format!("Key of {} bytes is too short for uniform column, expected at least 32", key.len())));
+	}
+	Ok(hash(key, salt, uniform, KeyHash::Blake2))
+}
+
+fn hash(key: &[u8], salt: Option<&Salt>, uniform: bool, key_hash: KeyHash) -> Key {
+	let mut k = Key::default();
+	if uniform {
+		k.copy_from_slice(&key[0..32]);
+	} else {
+		match key_hash {
+			KeyHash::Blake2 => {
+				let salt: &[u8] = salt.map_or(&[], |s| &s[..]);
+				k.copy_from_slice(blake2_rfc::blake2b::blake2b(32, salt, key).as_bytes());
+			},
+			KeyHash::XxHash => {
+				use std::{convert::TryInto, hash::Hasher};
+				for (i, part) in k.chunks_mut(8).enumerate() {
+					// Seeds differ even if the salt repeats.
+					let seed = salt.map_or(0, |s| u64::from_le_bytes(s[i * 8 .. (i + 1) * 8].try_into().unwrap()));
+					let mut hasher = twox_hash::XxHash64::with_seed(seed.wrapping_add(i as u64));
+					hasher.write(key);
+					part.copy_from_slice(&hasher.finish().to_le_bytes());
+				}
+			},
+		}
+	}
+	k
+}
+
+/// Bytes `offset .. offset + len` of the value, clamped to its size.
+pub fn slice_value(value: &[u8], offset: usize, len: usize) -> &[u8] {
+	let start = offset.min(value.len());
+	&value[start .. start + len.min(value.len() - start)]
+}
+
 struct Tables {
 	index: IndexTable,
 	value: Vec<ValueTable>,
@@ -43,6 +158,16 @@ struct Tables {
 struct Reindex {
 	queue: VecDeque<IndexTable>,
 	progress: AtomicU64,
+	// Tables replaced by `write_clear_plan`. Logged changes are enacted to them until the
+	// record that drops them is enacted, but they are not searched.
+	replaced: Vec<IndexTable>,
+}
+
+impl Reindex {
+	// Queued or replaced table, to enact changes to.
+	fn table(&self, id: IndexTableId) -> Option<&IndexTable> {
+		self.queue.iter().chain(self.replaced.iter()).find(|t| t.id == id)
+	}
 }
 
 pub struct Column {
@@ -51,12 +176,19 @@ pub struct Column {
 	path: std::path::PathBuf,
 	preimage: bool,
 	uniform_keys: bool,
-	collect_stats: bool,
+	key_hash: KeyHash,
+	key_mode: KeyMode,
+	key_len: usize,
 	ref_counted: bool,
 	salt: Option<Salt>,
-	stats: ColumnStats,
+	// Statistics are not collected if `None`.
+	stats: Option<ColumnStats>,
+	counters: Option<IoCounters>,
 	compression: Compress,
 	db_version: u32,
+	skew_reported: AtomicBool,
+	cache: Option<ValueCache>,
+	name: Option<String>,
 }
 
 pub struct IterState {
@@ -66,30 +198,80 @@ pub struct IterState {
 	pub value: Vec<u8>,
 }
 
+/// Index entry that points to a value to be moved by compaction.
+pub struct MoveCandidate {
+	table: IndexTableId,
+	chunk: u64,
+	sub_index: usize,
+	entry: IndexEntry,
+}
+
 enum IterStateOrCorrupted {
 	Item(IterState),
 	Corrupted(crate::index::Entry, Option<Error>),
 }
 
 impl Column {
+	/// Largest value, as stored, that fits a single entry. The last table holds larger values in linked parts.
+	pub fn max_inline_size(&self) -> u32 {
+		let tables = self.tables.read();
+		tables.value[.. tables.value.len() - 1].iter().map(|t| t.value_size() as u32).max().unwrap_or(0)
+	}
+
+	pub fn cache_bytes(&self) -> usize {
+		self.cache.as_ref().map_or(0, |cache| cache.bytes())
+	}
+
 	pub fn get(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<Value>> {
+		if self.cache.is_some() {
+			return Ok(self.get_shared(key, log)?.map(|v| Arc::try_unwrap(v).unwrap_or_else(|v| (*v).clone())));
+		}
 		let tables = self.tables.read();
 		if let Some((tier, value)) = self.get_in_index(key, &tables.index, &*tables, log)? {
-			if self.collect_stats {
-				self.stats.query_hit(tier);
+			if let Some(stats) = &self.stats {
+				stats.query_hit(tier);
 			}
 			return Ok(Some(value));
 		}
 		for r in &self.reindex.read().queue {
 			if let Some((tier, value)) = self.get_in_index(key, &r, &*tables, log)? {
-				if self.collect_stats {
-					self.stats.query_hit(tier);
+				if let Some(stats) = &self.stats {
+					stats.query_hit(tier);
 				}
 				return Ok(Some(value));
 			}
 		}
-		if self.collect_stats {
-			self.stats.query_miss();
+		if let Some(stats) = &self.stats {
+			stats.query_miss();
+		}
+		Ok(None)
+	}
+
+	/// Same as `get`, but values are shared with the cache.
+	pub fn get_shared(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<Arc<Value>>> {
+		let cache = match &self.cache {
+			Some(cache) => cache,
+			None => return Ok(self.get(key, log)?.map(Arc::new)),
+		};
+		let tables = self.tables.read();
+		// Taken before the index is read, so that values freed in the meantime are not cached.
+		let epoch = cache.epoch();
+		if let Some((tier, value)) = self.get_in_index_cached(key, &tables.index, &tables, log, cache, epoch)? {
+			if let Some(stats) = &self.stats {
+				stats.query_hit(tier);
+			}
+			return Ok(Some(value));
+		}
+		for r in &self.reindex.read().queue {
+			if let Some((tier, value)) = self.get_in_index_cached(key, r, &tables, log, cache, epoch)? {
+				if let Some(stats) = &self.stats {
+					stats.query_hit(tier);
+				}
+				return Ok(Some(value));
+			}
+		}
+		if let Some(stats) = &self.stats {
+			stats.query_miss();
 		}
 		Ok(None)
 	}
@@ -98,6 +280,35 @@ impl Column {
 		self.get(key, log).map(|v| v.map(|v| v.len() as u32))
 	}
 
+	/// Bytes `offset .. offset + len` of the value, clamped to its size. Values of compressed
+	/// columns are decompressed in full and then sliced, others are read partially.
+	pub fn get_range(&self, key: &Key, log: &RwLock<LogOverlays>, offset: usize, len: usize) -> Result<Option<Value>> {
+		if CompressionType::from(&self.compression) != CompressionType::NoCompression {
+			return Ok(self.get(key, log)?.map(|v| slice_value(&v, offset, len).to_vec()));
+		}
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			let (mut entry, mut sub_index) = index.get(key, 0, log);
+			while !entry.is_empty() {
+				let address = entry.address(index.id.index_bits());
+				let table = &tables.value[address.size_tier() as usize];
+				match table.get_range(key, address.offset(), log, offset, len)? {
+					Some((value, false)) => return Ok(Some(value)),
+					// Stored compressed before compression was disabled.
+					Some((_, true)) => return Ok(table.get(key, address.offset(), log)?
+						.map(|(v, _)| slice_value(&self.decompress(&v), offset, len).to_vec())),
+					None => {
+						let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+						entry = next_entry;
+						sub_index = next_index;
+					}
+				}
+			}
+		}
+		Ok(None)
+	}
+
 	fn get_in_index(&self, key: &Key, index: &IndexTable, tables: &Tables, log: &RwLock<LogOverlays>) -> Result<Option<(u8, Value)>> {
 		let (mut entry, mut sub_index) = index.get(key, 0, log);
 		while !entry.is_empty() {
@@ -121,6 +332,119 @@ impl Column {
 		Ok(None)
 	}
 
+	fn get_in_index_cached(
+		&self,
+		key: &Key,
+		index: &IndexTable,
+		tables: &Tables,
+		log: &RwLock<LogOverlays>,
+		cache: &ValueCache,
+		epoch: u64,
+	) -> Result<Option<(u8, Arc<Value>)>> {
+		let (mut entry, mut sub_index) = index.get(key, 0, log);
+		while !entry.is_empty() {
+			let address = entry.address(index.id.index_bits());
+			let size_tier = address.size_tier();
+			if let Some(value) = cache.get(address, key) {
+				return Ok(Some((size_tier, value)));
+			}
+			match tables.value[size_tier as usize].get(key, address.offset(), log)? {
+				Some((value, compressed)) => {
+					let value = Arc::new(if compressed {
+						self.decompress(&value)
+					} else {
+						value
+					});
+					cache.insert(address, key, value.clone(), epoch);
+					return Ok(Some((size_tier, value)));
+				}
+				None =>  {
+					let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+					entry = next_entry;
+					sub_index = next_index;
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	pub fn has_cache(&self) -> bool {
+		self.cache.is_some()
+	}
+
+	/// Drop cached values of value table entries that are overwritten or freed.
+	pub fn invalidate_cached(&self, addresses: impl Iterator<Item = Address>) {
+		if let Some(cache) = &self.cache {
+			cache.invalidate(addresses);
+		}
+	}
+
+	pub fn clear_cache(&self) {
+		if let Some(cache) = &self.cache {
+			cache.clear();
+		}
+	}
+
+	/// Check if the key has a value. Only reads value entry headers.
+	pub fn has_key(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<bool> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			if self.has_key_in_index(key, index, &tables, log)? {
+				return Ok(true);
+			}
+		}
+		Ok(false)
+	}
+
+	// Same as `get_in_index`, but only reads value entry headers.
+	fn has_key_in_index(&self, key: &Key, index: &IndexTable, tables: &Tables, log: &RwLock<LogOverlays>) -> Result<bool> {
+		let (mut entry, mut sub_index) = index.get(key, 0, log);
+		while !entry.is_empty() {
+			let address = entry.address(index.id.index_bits());
+			if tables.value[address.size_tier() as usize].has_key_at(address.offset(), key, log)? {
+				return Ok(true);
+			}
+			let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+			entry = next_entry;
+			sub_index = next_index;
+		}
+		Ok(false)
+	}
+
+	/// Value with the details of how it is stored.
+	pub fn get_with_meta(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<(Value, ValueMeta)>> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			let (mut entry, mut sub_index) = index.get(key, 0, log);
+			while !entry.is_empty() {
+				let address = entry.address(index.id.index_bits());
+				let table = &tables.value[address.size_tier() as usize];
+				if let Some((value, rc, compressed, parts)) = table.get_with_parts(key, address.offset(), log)? {
+					let meta = ValueMeta {
+						size_tier: address.size_tier(),
+						entry_size: table.entry_size,
+						stored_size: value.len() as u32,
+						compressed,
+						parts,
+						ref_count: if self.ref_counted { Some(rc) } else { None },
+					};
+					let value = if compressed {
+						self.decompress(&value)
+					} else {
+						value
+					};
+					return Ok(Some((value, meta)));
+				}
+				let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+				entry = next_entry;
+				sub_index = next_index;
+			}
+		}
+		Ok(None)
+	}
+
 	/// Compress if needed and return the target tier to use.
 	fn compress(&self, key: &Key, value: &[u8], tables: &Tables) -> (Option<Vec<u8>>, usize) {
 		Self::compress_internal(&self.compression, key, value, tables)
@@ -153,17 +477,19 @@ impl Column {
 		self.compression.decompress(buf)
 	}
 
-	pub fn open(col: ColId, options: &Options, metadata: &Metadata) -> Result<Column> {
-		let (index, reindexing, stats) = Self::open_index(&options.path, col)?;
-		let collect_stats = options.stats;
-		let path = &options.path;
-		let arc_path = std::sync::Arc::new(path.clone());
+	pub fn open(col: ColId, options: &Options, metadata: &Metadata, mmap: bool) -> Result<Column> {
+		let chunk_entries = metadata.columns[col as usize].index_chunk_entries;
+		let path = options.column_path(col);
+		let (index, reindexing) = Self::open_index(path, col, chunk_entries)?;
+		let stats = options.stats.then(|| index.load_stats());
+		let arc_path = std::sync::Arc::new(path.to_path_buf());
 		let options = &metadata.columns[col as usize];
 		let db_version = metadata.version;
 		let tables = Tables {
 			index,
 			value: (0.. options.sizes.len() + 1)
-				.map(|i| Self::open_table(arc_path.clone(), col, i as u8, &options, db_version)).collect::<Result<_>>()?
+				.map(|i| Self::open_table(arc_path.clone(), col, i as u8, &options, db_version, mmap))
+				.collect::<Result<_>>()?
 		};
 
 		Ok(Column {
@@ -171,31 +497,81 @@ impl Column {
 			reindex: RwLock::new(Reindex {
 				queue: reindexing,
 				progress: AtomicU64::new(0),
+				replaced: Vec::new(),
 			}),
 			path: path.into(),
 			preimage: options.preimage,
 			uniform_keys: options.uniform,
+			key_hash: options.key_hash,
+			key_mode: options.key_mode,
+			key_len: options.key_len,
 			ref_counted: options.ref_counted,
-			collect_stats,
 			salt: metadata.salt.clone(),
+			counters: stats.is_some().then(Default::default),
 			stats,
 			compression: Compress::new(options.compression, options.compression_treshold),
 			db_version,
+			skew_reported: AtomicBool::new(false),
+			cache: (options.cache_size_bytes > 0).then(|| ValueCache::new(options.cache_size_bytes as usize)),
+			name: options.name.clone(),
 		})
 	}
 
+	/// Hash user supplied key. Uniform columns require keys of at least 32 bytes.
+	/// Fails for columns that take raw keys.
+	pub fn hash_key(&self, key: &[u8]) -> Result<Key> {
+		self.check_key_mode(KeyMode::Typed)?;
+		self.derive_key(key)
+	}
+
+	/// Hash a raw key of any length. Fails for columns that take typed keys.
+	pub fn hash_raw_key(&self, key: &[u8]) -> Result<Key> {
+		self.check_key_mode(KeyMode::Raw)?;
+		Ok(self.hash(key))
+	}
+
+	fn check_key_mode(&self, mode: KeyMode) -> Result<()> {
+		if self.key_mode != mode {
+			return Err(Error::InvalidInput(format!(
+				"Column {} takes {} keys, not {}",
+				self.tables.read().index.id.col(),
+				self.key_mode.as_str(),
+				mode.as_str(),
+			)));
+		}
+		Ok(())
+	}
+
+	/// Index key for `key` in the key mode of the column.
+	pub fn derive_key(&self, key: &[u8]) -> Result<Key> {
+		if self.uniform_keys && key.len() < self.key_len {
+			return Err(Error::InvalidInput(format!(
+				"Key of {} bytes is too short for uniform column {}, expected at least {}",
+				key.len(),
+				self.tables.read().index.id.col(),
+				self.key_len,
+			)));
+		}
+		Ok(self.hash(key))
+	}
+
 	pub fn hash(&self, key: &[u8]) -> Key {
-		let mut k = Key::default();
-		if self.uniform_keys {
-			k.copy_from_slice(&key[0..32]);
-		} else if let Some(salt) = &self.salt {
-			k.copy_from_slice(blake2_rfc::blake2b::blake2b(32, &salt[..], &key).as_bytes());
+		let mut k = if self.uniform_keys && key.len() < KEY_LEN {
+			let mut k = Key::default();
+			k[.. key.len()].copy_from_slice(key);
+			k
 		} else {
-			k.copy_from_slice(blake2_rfc::blake2b::blake2b(32, &[], &key).as_bytes());
-		}
+			hash(key, self.salt.as_ref(), self.uniform_keys, self.key_hash)
+		};
+		self.cut_key(&mut k);
 		k
 	}
 
+	// Keys shorter than `KEY_LEN` are padded with zeros.
+	fn cut_key(&self, key: &mut Key) {
+		key[self.key_len ..].fill(0);
+	}
+
 	pub fn flush(&self) -> Result<()> {
 		let tables = self.tables.read();
 		tables.index.flush()?;
@@ -205,15 +581,13 @@ impl Column {
 		Ok(())
 	}
 
-	fn open_index(path: &std::path::Path, col: ColId) -> Result<(IndexTable, VecDeque<IndexTable>, ColumnStats)> {
+	fn open_index(path: &std::path::Path, col: ColId, chunk_entries: usize) -> Result<(IndexTable, VecDeque<IndexTable>)> {
 		let mut reindexing = VecDeque::new();
 		let mut top = None;
-		let mut stats = ColumnStats::empty();
 		for bits in (START_BITS .. 65).rev() {
 			let id = IndexTableId::new(col, bits);
-			if let Some(table) = IndexTable::open_existing(path, id)? {
+			if let Some(table) = IndexTable::open_existing(path, id, chunk_entries)? {
 				if top.is_none() {
-					stats = table.load_stats();
 					top = Some(table);
 				} else {
 					reindexing.push_front(table);
@@ -222,9 +596,9 @@ impl Column {
 		}
 		let table = match top {
 			Some(table) => table,
-			None => IndexTable::create_new(path, IndexTableId::new(col, START_BITS)),
+			None => IndexTable::create_new(path, IndexTableId::new(col, START_BITS), chunk_entries),
 		};
-		Ok((table, reindexing, stats))
+		Ok((table, reindexing))
 	}
 
 	fn open_table(
@@ -233,10 +607,11 @@ impl Column {
 		tier: u8,
 		options: &ColumnOptions,
 		db_version: u32,
+		mmap: bool,
 	) -> Result<ValueTable> {
 		let id = ValueTableId::new(col, tier);
 		let entry_size = options.sizes.get(tier as usize).cloned();
-		ValueTable::open(path, id, entry_size, options, db_version)
+		ValueTable::open(path, id, entry_size, options, db_version, mmap)
 	}
 
 	fn trigger_reindex(
@@ -256,7 +631,7 @@ impl Column {
 			tables.index.id.col(),
 			tables.index.id.index_bits() + 1
 		);
-		let new_table = IndexTable::create_new(path, new_index_id);
+		let new_table = IndexTable::create_new(path, new_index_id, tables.index.chunk_entries());
 		let old_table = std::mem::replace(&mut tables.index, new_table);
 		reindex.queue.push_back(old_table);
 	}
@@ -342,7 +717,7 @@ impl Column {
 					.map(|cval| (cval.as_slice(), true))
 					.unwrap_or((val.as_slice(), false));
 
-				if self.collect_stats {
+				if let Some(stats) = &self.stats {
 					let (cur_size, compressed) = tables.value[existing_tier].size(&key, existing_address.offset(), log)?
 						.unwrap_or((0, false));
 					if compressed {
@@ -351,9 +726,9 @@ impl Column {
 							.expect("Same query as size").0;
 						let uncompressed = self.decompress(compressed.as_slice());
 
-						self.stats.replace_val(cur_size, uncompressed.len() as u32, val.len() as u32, cval.len() as u32);
+						stats.replace_val(cur_size, uncompressed.len() as u32, val.len() as u32, cval.len() as u32);
 					} else {
-						self.stats.replace_val(cur_size, cur_size, val.len() as u32, cval.len() as u32);
+						stats.replace_val(cur_size, cur_size, val.len() as u32, cval.len() as u32);
 					}
 				}
 				if existing_tier == target_tier {
@@ -386,8 +761,8 @@ impl Column {
 						return Ok(PlanOutcome::NeedReindex);
 					}
 					_ => {
-						if self.collect_stats {
-							self.stats.insert_val(val.len() as u32, cval.len() as u32);
+						if let Some(stats) = &self.stats {
+							stats.insert_val(val.len() as u32, cval.len() as u32);
 						}
 						return Ok(PlanOutcome::Written);
 					}
@@ -397,7 +772,7 @@ impl Column {
 			if let Some((table, sub_index, existing_tier, existing_address)) = existing {
 				// Deletion
 				let existing_tier = existing_tier as usize;
-				let cur_size = if self.collect_stats {
+				let cur_size = if self.stats.is_some() {
 					let (cur_size, compressed) = tables.value[existing_tier].size(&key, existing_address.offset(), log)?
 						.unwrap_or((0, false));
 					Some(if compressed {
@@ -423,29 +798,30 @@ impl Column {
 					true
 				};
 				if remove {
-					if let Some((cur_size, compressed_size)) = cur_size {
-						self.stats.remove_val(cur_size, compressed_size);
+					if let (Some(stats), Some((cur_size, compressed_size))) = (&self.stats, cur_size) {
+						stats.remove_val(cur_size, compressed_size);
 					}
 					table.write_remove_plan(key, sub_index, log)?;
 				}
 				return Ok(PlanOutcome::Written);
 			}
 			log::trace!(target: "parity-db", "{}: Deletion missed {}", tables.index.id, hex(key));
-			if self.collect_stats {
-				self.stats.remove_miss();
+			if let Some(stats) = &self.stats {
+				stats.remove_miss();
 			}
 		}
 		Ok(PlanOutcome::Skipped)
 	}
 
-	pub fn enact_plan(&self, action: LogAction, log: &mut LogReader) -> Result<()> {
+	pub fn enact_plan(&self, action: LogAction, log: &mut impl LogRead) -> Result<()> {
 		let tables = self.tables.read();
 		let reindex = self.reindex.read();
 		match action {
 			LogAction::InsertIndex(record) => {
 				if tables.index.id == record.table {
 					tables.index.enact_plan(record.index, log)?;
-				} else if let Some(table) = reindex.queue.iter().find(|r|r.id == record.table) {
+					self.check_skew(&tables.index, record.index);
+				} else if let Some(table) = reindex.table(record.table) {
 					table.enact_plan(record.index, log)?;
 				}
 				else {
@@ -465,6 +841,66 @@ impl Column {
 		Ok(())
 	}
 
+	/// Read the contents of an insert action from the log, to be passed to `enact_plan` later.
+	pub fn read_plan(&self, action: &LogAction, log: &mut LogReader) -> Result<Vec<u8>> {
+		match action {
+			// Chunk updates are the same size for all index tables.
+			LogAction::InsertIndex(_) => IndexTable::read_plan(log),
+			LogAction::InsertValue(record) => {
+				self.tables.read().value[record.table.size_tier() as usize].read_plan(record.index, log)
+			},
+			_ => panic!("Unexpected log action"),
+		}
+	}
+
+	/// Whether an unevenly loaded index chunk has been detected.
+	pub fn skew_reported(&self) -> bool {
+		self.skew_reported.load(Ordering::Relaxed)
+	}
+
+	fn check_skew(&self, index: &IndexTable, chunk_index: u64) {
+		let occupancy = index.chunk_occupancy(chunk_index);
+		// Chunks that are at least half full are checked for skew.
+		if occupancy < index.chunk_entries() / 2 || self.skew_reported.load(Ordering::Relaxed) {
+			return;
+		}
+		let mean = index.sampled_occupancy(SKEW_SAMPLE_CHUNKS);
+		if occupancy as f64 > mean * SKEW_FACTOR {
+			self.skew_reported.store(true, Ordering::Relaxed);
+			log::warn!(
+				target: "parity-db",
+				"{}: Index chunk {} holds {} entries, sampled mean is {:.2}. Keys are unevenly distributed{}",
+				index.id,
+				chunk_index,
+				occupancy,
+				mean,
+				if self.uniform_keys { ", consider disabling uniform keys to enable hashing" } else { "" },
+			);
+		}
+	}
+
+	/// Read a log action into the overlay, without modifying the tables.
+	pub fn load_plan(&self, action: LogAction, log: &mut LogReader, writer: &mut LogWriter) -> Result<()> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		match action {
+			LogAction::InsertIndex(record) => {
+				if tables.index.id == record.table {
+					tables.index.load_plan(record.index, log, writer)?;
+				} else if let Some(table) = reindex.table(record.table) {
+					table.load_plan(record.index, log, writer)?;
+				} else {
+					return Err(Error::Corruption("Missing table".into()));
+				}
+			},
+			LogAction::InsertValue(record) => {
+				tables.value[record.table.size_tier() as usize].load_plan(record.index, log, writer)?;
+			}
+			_ => panic!("Unexpected log action"),
+		}
+		Ok(())
+	}
+
 	pub fn validate_plan(&self, action: LogAction, log: &mut LogReader) -> Result<()> {
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
@@ -500,12 +936,49 @@ impl Column {
 		for t in tables.value.iter() {
 			t.complete_plan(log)?;
 		}
-		if self.collect_stats {
-			self.stats.commit()
+		if let Some(stats) = &self.stats {
+			stats.commit()
 		}
 		Ok(())
 	}
 
+	/// Apply a change to the tables directly, bypassing the log. Changes to other columns
+	/// are skipped.
+	pub fn apply_unlogged(&self, change: &LogChange) -> Result<()> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let col = tables.index.id.col();
+		for (id, index, mask, chunk) in change.index_changes().filter(|(id, ..)| id.col() == col) {
+			if tables.index.id == id {
+				tables.index.write_chunk(index, mask, chunk)?;
+				self.check_skew(&tables.index, index);
+			} else if let Some(table) = reindex.table(id) {
+				table.write_chunk(index, mask, chunk)?;
+			} else {
+				return Err(Error::Corruption("Missing table".into()));
+			}
+		}
+		for (id, index, data) in change.value_changes().filter(|(id, ..)| id.col() == col) {
+			tables.value[id.size_tier() as usize].write_entry(index, data)?;
+		}
+		Ok(())
+	}
+
+	/// Reopen table files, picking up tables created by another process.
+	pub fn refresh_tables(&self, options: &Options, metadata: &Metadata) -> Result<()> {
+		let col = self.tables.read().index.id.col();
+		let column_options = &metadata.columns[col as usize];
+		let path = options.column_path(col);
+		let (index, reindexing) = Self::open_index(path, col, column_options.index_chunk_entries)?;
+		let arc_path = std::sync::Arc::new(path.to_path_buf());
+		let value = (0 .. column_options.sizes.len() + 1)
+			.map(|i| Self::open_table(arc_path.clone(), col, i as u8, column_options, metadata.version, false))
+			.collect::<Result<_>>()?;
+		*self.tables.write() = Tables { index, value };
+		self.reindex.write().queue = reindexing;
+		Ok(())
+	}
+
 	pub fn refresh_metadata(&self) -> Result<()> {
 		let tables = self.tables.read();
 		for t in tables.value.iter() {
@@ -514,16 +987,383 @@ impl Column {
 		Ok(())
 	}
 
+	/// Write statistics summary. Does nothing if statistics are not collected.
 	pub fn write_stats(&self, writer: &mut impl std::io::Write) {
+		let (stats, counters) = match (&self.stats, &self.counters) {
+			(Some(stats), Some(counters)) => (stats, counters),
+			_ => return,
+		};
 		let tables = self.tables.read();
-		tables.index.write_stats(&self.stats);
-		self.stats.write_summary(writer, tables.index.id.col());
+		tables.index.write_stats(stats);
+		stats.write_summary(writer, &column_label(tables.index.id.col(), self.name.as_deref()));
+		let counters = counters.snapshot(tables.value.iter().map(|t| t.freed_bytes()).sum());
+		let _ = writeln!(writer, "Gets: {}, hits: {}, misses: {}", counters.gets, counters.hits, counters.misses);
+		let _ = writeln!(writer, "Committed keys: {}, committed bytes: {}, freed bytes: {}",
+			counters.committed_keys, counters.committed_bytes, counters.freed_bytes);
+		if let Some(cache) = &self.cache {
+			let _ = cache.write_stats(writer);
+		}
+		let _ = self.write_index_skew(&tables.index, writer);
+	}
+
+	fn write_index_skew(&self, index: &IndexTable, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+		writeln!(writer, "Index {} sampled mean chunk occupancy: {:.2}", index.id, index.sampled_occupancy(SKEW_SAMPLE_CHUNKS))?;
+		writeln!(writer, "Index skew detected: {}", self.skew_reported())?;
+		writeln!(writer)
+	}
+
+	/// Most loaded chunks of the index, as `(chunk index, occupied entries)`. Scans the whole index.
+	pub fn hot_index_chunks(&self, count: usize) -> Vec<(u64, usize)> {
+		self.tables.read().index.hot_chunks(count)
+	}
+
+	/// `None` if statistics are not collected.
+	pub fn counters(&self) -> Option<&IoCounters> {
+		self.counters.as_ref()
+	}
+
+	pub fn read_counters(&self) -> Option<ColumnCounters> {
+		let counters = self.counters.as_ref()?;
+		let freed_bytes = self.tables.read().value.iter().map(|t| t.freed_bytes()).sum();
+		Some(counters.snapshot(freed_bytes))
+	}
+
+	pub fn reset_counters(&self) {
+		if let Some(counters) = &self.counters {
+			counters.reset();
+		}
+		for t in self.tables.read().value.iter() {
+			t.reset_freed_bytes();
+		}
 	}
 
 	pub fn clear_stats(&self) {
 		let tables = self.tables.read();
 		let empty_stats = ColumnStats::empty();
 		tables.index.write_stats(&empty_stats);
+		if let Some(cache) = &self.cache {
+			cache.clear_stats();
+		}
+	}
+
+	pub fn disk_usage(&self) -> Result<ColumnDiskUsage> {
+		let tables = self.tables.read();
+		let mut usage = ColumnDiskUsage {
+			index: tables.index.disk_size()?,
+			tables: Vec::new(),
+		};
+		for index in self.reindex.read().queue.iter() {
+			usage.index += index.disk_size()?;
+		}
+		for table in tables.value.iter() {
+			if let Some(t) = table.disk_usage()? {
+				usage.tables.push(t);
+			}
+		}
+		Ok(usage)
+	}
+
+	pub fn free_space(&self, log: &Log) -> Result<FreeStats> {
+		let tables = self.tables.read();
+		let reader = log.begin_unlogged();
+		let mut stats = FreeStats::default();
+		for table in tables.value.iter() {
+			if let Some(t) = table.free_stats(&reader)? {
+				stats.tables.push(t);
+			}
+		}
+		Ok(stats)
+	}
+
+	/// Check that every index entry points to a value stored for its key, and that every
+	/// value in use is referenced by the index. Nothing is modified.
+	pub fn verify(&self, log: &Log, col: ColId) -> Result<ColumnVerifyReport> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let reader = log.begin_unlogged();
+		let mut report = ColumnVerifyReport { col, ..Default::default() };
+		let mut referenced: Vec<Vec<bool>> = tables.value.iter().map(|t| vec![false; t.filled() as usize]).collect();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			for chunk in 0 .. index.id.total_chunks() {
+				for entry in index.entries(chunk, &reader).iter() {
+					if entry.is_empty() {
+						continue;
+					}
+					report.index_entries += 1;
+					let address = entry.address(index.id.index_bits());
+					let tier = address.size_tier() as usize;
+					let value = match tables.value.get(tier) {
+						Some(value) if address.offset() != 0 && address.offset() < value.filled() => value,
+						_ => {
+							log::warn!(target: "parity-db", "{}: Entry in chunk {} points past the end of the value table", index.id, chunk);
+							report.dangling += 1;
+							continue;
+						},
+					};
+					let prefix = index.recover_key_prefix(chunk, *entry);
+					match value.verify_slot(address.offset(), &prefix, IndexEntry::key_prefix_bits(), &reader) {
+						Ok(Some(slots)) => {
+							for slot in slots {
+								referenced[tier][slot as usize] = true;
+							}
+						},
+						Ok(None) => {
+							log::warn!(target: "parity-db", "{}: Entry in chunk {} points to removed slot {}", index.id, chunk, address);
+							report.dangling += 1;
+						},
+						Err(Error::Corruption(e)) => {
+							log::warn!(target: "parity-db", "{}", e);
+							referenced[tier][address.offset() as usize] = true;
+							report.mismatched += 1;
+						},
+						Err(e) => return Err(e),
+					}
+				}
+			}
+		}
+		for (value, referenced) in tables.value.iter().zip(referenced) {
+			for (slot, referenced) in referenced.into_iter().enumerate().skip(1) {
+				if !referenced && value.is_live_slot(slot as u64, &reader)? {
+					log::warn!(target: "parity-db", "{}: Slot {} is not referenced", value.id, slot);
+					report.orphaned += 1;
+				}
+			}
+		}
+		Ok(report)
+	}
+
+	/// Index entries for the values stored in the value tables, to rebuild a damaged index.
+	/// Values only keep the part of the key past the first 6 bytes, so `key_of` has to tell
+	/// the key of each value, which is checked against the stored part. Returns the entries
+	/// and the number of values that were skipped, because `key_of` did not know them or the
+	/// key did not match.
+	pub fn recover_index_entries(
+		&self,
+		log: &Log,
+		mut key_of: impl FnMut(Value) -> Option<Key>,
+	) -> Result<(Vec<(Key, Address)>, u64)> {
+		let tables = self.tables.read();
+		let reader = log.begin_unlogged();
+		let mut entries = Vec::new();
+		let mut skipped = 0;
+		for (tier, table) in tables.value.iter().enumerate() {
+			// Slots that hold parts of split values, other than the first.
+			let mut parts = std::collections::HashSet::new();
+			if table.is_multipart() {
+				for slot in 1 .. table.filled() {
+					if table.is_live_slot(slot, &reader)? {
+						if let Some(next) = table.read_next_part(slot, &reader)? {
+							parts.insert(next);
+						}
+					}
+				}
+			}
+			for slot in 1 .. table.filled() {
+				if parts.contains(&slot) {
+					continue;
+				}
+				let (value, _, pk, compressed) = match table.get_with_meta(slot, &reader)? {
+					Some(value) => value,
+					None => continue,
+				};
+				let value = if compressed { self.decompress(&value) } else { value };
+				match key_of(value) {
+					Some(key) if key[KEY_LEN - pk.len() ..] == pk[..] => {
+						entries.push((key, Address::new(slot, tier as u8)));
+					},
+					_ => {
+						log::warn!(target: "parity-db", "{}: Skipping value at {}, key is unknown", table.id, slot);
+						skipped += 1;
+					},
+				}
+			}
+			log::info!(target: "parity-db", "{}: Recovered {} values", table.id, entries.len());
+		}
+		Ok((entries, skipped))
+	}
+
+	/// Replace the index, and any tables queued for reindexing, with an empty index sized
+	/// for `entries` values. Value tables are left as they are.
+	pub fn reset_index(&self, entries: u64) -> Result<()> {
+		let mut tables = self.tables.write();
+		let mut reindex = self.reindex.write();
+		let id = tables.index.id;
+		let chunk_entries = tables.index.chunk_entries();
+		let new_id = IndexTableId::new(id.col(), Self::index_bits_for(entries, chunk_entries));
+		let old = std::mem::replace(&mut tables.index, IndexTable::create_new(&self.path, new_id, chunk_entries));
+		for table in std::iter::once(old).chain(reindex.queue.drain(..)) {
+			match table.drop_file() {
+				Err(e) if e.io_error().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => {},
+				result => result?,
+			}
+		}
+		reindex.progress.store(0, Ordering::Relaxed);
+		log::info!(target: "parity-db", "Replaced index {} with {}", id, new_id);
+		Ok(())
+	}
+
+	/// No values have been written to the column.
+	pub fn is_empty(&self) -> bool {
+		self.tables.read().value.iter().all(|t| t.filled() <= 1)
+	}
+
+	/// Size the index and grow value tables to fit the expected values, if the column has
+	/// none yet. Returns `false` if the hint is ignored.
+	pub fn preallocate(&self, hint: &SizeHint) -> Result<bool> {
+		if !self.is_empty() {
+			return Ok(false);
+		}
+		let mut tables = self.tables.write();
+		// Index is only created on first insert, so it can be replaced while there is no file.
+		if tables.index.id.index_bits() == START_BITS && !tables.index.is_allocated() {
+			let bits = Self::index_bits_for(hint.entries, tables.index.chunk_entries());
+			if bits > START_BITS {
+				log::debug!(target: "parity-db", "{}: Sizing index for {} entries", tables.index.id, hint.entries);
+				let id = IndexTableId::new(tables.index.id.col(), bits);
+				tables.index = IndexTable::create_new(self.path.as_path(), id, tables.index.chunk_entries());
+			}
+		}
+		let total: u64 = hint.histogram.iter().map(|(_, count)| *count).sum();
+		if total == 0 {
+			return Ok(true);
+		}
+		let mut entries = vec![0u64; tables.value.len()];
+		for (size, count) in hint.histogram.iter() {
+			let values = (hint.entries as u128 * *count as u128 / total as u128) as u64;
+			let size = *size as u64;
+			match tables.value.iter().position(|t| size <= t.value_size() as u64) {
+				Some(tier) => entries[tier] += values,
+				None => {
+					// Large values are split into parts.
+					let blob = tables.value.len() - 1;
+					let part = tables.value[blob].value_size() as u64;
+					entries[blob] = entries[blob].saturating_add(values.saturating_mul(size.div_ceil(part)));
+				},
+			}
+		}
+		for (table, entries) in tables.value.iter().zip(entries) {
+			if entries > 0 {
+				table.preallocate(entries)?;
+			}
+		}
+		Ok(true)
+	}
+
+	// Smallest index that is at most half full with `entries`, so that chunks rarely overflow.
+	fn index_bits_for(entries: u64, chunk_entries: usize) -> u8 {
+		let chunks = (entries.saturating_mul(2) / chunk_entries as u64).max(1);
+		let bits = 64 - (chunks - 1).leading_zeros() as u8;
+		bits.clamp(START_BITS, MAX_HINT_INDEX_BITS)
+	}
+
+	/// Start compacting a value table. Live entries at or above the returned slot index
+	/// are to be moved to the removed slots below it. Returns `None` if there are no removed slots.
+	pub fn write_compaction_start_plan(&self, tier: usize, log: &mut LogWriter) -> Result<Option<u64>> {
+		let tables = self.tables.read();
+		let table = &tables.value[tier];
+		let mut free = table.free_slots(log)?;
+		if free.is_empty() {
+			return Ok(None);
+		}
+		free.sort_unstable();
+		let cut = table.filled() - free.len() as u64;
+		free.retain(|s| *s < cut);
+		table.write_free_list_plan(&free, log);
+		log::debug!(target: "parity-db", "{}: Compacting {} entries into {}", table.id, table.filled(), cut);
+		Ok(Some(cut))
+	}
+
+	/// Find index entries that point to values with any part at or above `cut`.
+	pub fn compaction_candidates(&self, log: &Log, tier: usize, cut: u64) -> Result<Vec<MoveCandidate>> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let value = &tables.value[tier];
+		let reader = log.begin_unlogged();
+		let mut candidates = Vec::new();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			for chunk in 0 .. index.id.total_chunks() {
+				for (sub_index, entry) in index.entries(chunk, log.overlays()).iter().enumerate() {
+					if entry.is_empty() {
+						continue;
+					}
+					let address = entry.address(index.id.index_bits());
+					if address.size_tier() as usize != tier {
+						continue;
+					}
+					let moved = address.offset() >= cut || (value.is_multipart() &&
+						value.chain_slots(address.offset(), &reader)?.iter().any(|s| *s >= cut));
+					if moved {
+						candidates.push(MoveCandidate { table: index.id, chunk, sub_index, entry: *entry });
+					}
+				}
+			}
+		}
+		Ok(candidates)
+	}
+
+	/// Move the value to slots below `cut`, unless the index entry has changed since
+	/// it was found. Returns `false` if the entry was skipped.
+	pub fn write_compaction_move_plan(&self, candidate: &MoveCandidate, cut: u64, log: &mut LogWriter) -> Result<bool> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let index = match std::iter::once(&tables.index).chain(reindex.queue.iter()).find(|t| t.id == candidate.table) {
+			Some(index) => index,
+			None => return Ok(false),
+		};
+		if index.entry_at(candidate.chunk, candidate.sub_index, log) != candidate.entry {
+			return Ok(false);
+		}
+		let address = candidate.entry.address(index.id.index_bits());
+		let value = &tables.value[address.size_tier() as usize];
+		let pk = match value.partial_key_at(address.offset(), log)? {
+			Some(pk) => pk,
+			None => return Ok(false),
+		};
+		let mut key = index.recover_key_prefix(candidate.chunk, candidate.entry);
+		key[6..].copy_from_slice(&pk);
+		// Stale entries in older index tables are left alone.
+		match Self::search_all_indexes(&key, &tables, &reindex, log)? {
+			Some((found, sub_index, _, found_address))
+				if found.id == candidate.table && sub_index == candidate.sub_index && found_address == address => {},
+			_ => return Ok(false),
+		}
+		let offset = value.write_move_plan(address.offset(), cut, log)?;
+		index.write_address_plan(candidate.chunk, candidate.sub_index, Address::new(offset, address.size_tier()), log);
+		Ok(true)
+	}
+
+	/// Complete compaction of a value table, dropping the slots past the last live entry.
+	/// Returns the new number of slots.
+	pub fn write_compaction_end_plan(&self, tier: usize, cut: u64, log: &mut LogWriter) -> Result<u64> {
+		let tables = self.tables.read();
+		let table = &tables.value[tier];
+		let filled = table.filled();
+		let mut end = cut.min(filled);
+		// Values may have been added past the cut while compacting.
+		for index in (end .. filled).rev() {
+			if table.is_live_slot(index, log)? {
+				end = index + 1;
+				break;
+			}
+		}
+		let mut free = table.free_slots(log)?;
+		free.retain(|s| *s < end);
+		// Slots freed by moves past the cut are not in the list.
+		let listed: std::collections::HashSet<u64> = free.iter().cloned().collect();
+		for index in cut.min(end) .. end {
+			if !listed.contains(&index) && !table.is_live_slot(index, log)? {
+				free.push(index);
+			}
+		}
+		free.sort_unstable();
+		table.write_free_list_plan(&free, log);
+		table.set_filled(end);
+		Ok(end)
+	}
+
+	/// Truncate a compacted value table file. Returns the number of bytes removed.
+	pub fn shrink_value_table(&self, tier: usize) -> Result<u64> {
+		self.tables.read().value[tier].shrink()
 	}
 
 	pub fn iter_while(&self, log: &Log, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
@@ -534,6 +1374,172 @@ impl Column {
 		self.iter_while_inner(log, action, 0, true)
 	}
 
+	/// Iterate over the index, starting at the given chunk. Corrupted entries are skipped.
+	pub fn iter_from(&self, log: &Log, start_chunk: u64, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
+		let action = |state | match state {
+			IterStateOrCorrupted::Item(item) => Ok(f(item)),
+			IterStateOrCorrupted::Corrupted( .. ) => Ok(true),
+		};
+		self.iter_while_inner(log, action, start_chunk, false)
+	}
+
+	// Value table tier and offset the index entry points to.
+	fn entry_address(&self, entry: &crate::index::Entry, index_bits: u8) -> (u8, u64) {
+		if self.db_version >= 4 {
+			let address = entry.address(index_bits);
+			(address.size_tier(), address.offset())
+		} else {
+			let addr_bits = index_bits + 10;
+			let address = Address::from_u64(entry.as_u64() & ((1u64 << addr_bits) - 1));
+			let size_tier = (address.as_u64() & 0x0f) as u8;
+			let offset = address.as_u64() >> 4;
+			(size_tier, offset)
+		}
+	}
+
+	/// Index tables of the column, in lookup order: the current index first, followed by
+	/// tables that are being reindexed.
+	pub fn index_tables(&self) -> Vec<IndexTableId> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		std::iter::once(tables.index.id).chain(reindex.queue.iter().map(|t| t.id)).collect()
+	}
+
+	/// Keys, reference counts and values of a single index chunk, including logged changes. Keys that are also
+	/// indexed in any of the `newer` tables are skipped, as these take precedence on lookup.
+	/// Returns nothing if the table no longer exists.
+	pub fn chunk_entries(
+		&self,
+		log: &Log,
+		table: IndexTableId,
+		chunk: u64,
+		newer: &[IndexTableId],
+	) -> Result<Vec<(Key, u32, Value)>> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let find = |id: IndexTableId| if tables.index.id == id {
+			Some(&tables.index)
+		} else {
+			reindex.queue.iter().find(|t| t.id == id)
+		};
+		let source = match find(table) {
+			Some(source) => source,
+			None => return Ok(Vec::new()),
+		};
+		let overlays = log.overlays();
+		let mut result = Vec::new();
+		for entry in source.entries(chunk, overlays).iter() {
+			if entry.is_empty() {
+				continue;
+			}
+			let (size_tier, offset) = self.entry_address(entry, source.id.index_bits());
+			let (value, rc, pk, compressed) = match tables.value[size_tier as usize].get_with_meta(offset, overlays)? {
+				Some(v) => v,
+				None => {
+					// Removed after the index chunk was read.
+					log::debug!(target: "parity-db", "{}: Missing value for chunk {}", source.id, chunk);
+					continue;
+				},
+			};
+			let mut key = source.recover_key_prefix(chunk, *entry);
+			key[6..].copy_from_slice(&pk);
+			let mut shadowed = false;
+			for id in newer {
+				if let Some(index) = find(*id) {
+					if self.has_key_in_index(&key, index, &tables, overlays)? {
+						shadowed = true;
+						break;
+					}
+				}
+			}
+			if shadowed {
+				continue;
+			}
+			let value = if compressed {
+				self.decompress(&value)
+			} else {
+				value
+			};
+			result.push((key, rc, value));
+		}
+		Ok(result)
+	}
+
+	/// Same as `chunk_entries`, but only returns keys. Only value entry headers are read.
+	pub fn chunk_keys(
+		&self,
+		log: &Log,
+		table: IndexTableId,
+		chunk: u64,
+		newer: &[IndexTableId],
+	) -> Result<Vec<Key>> {
+		self.chunk_keys_with(log, table, chunk, newer, |key, _, _, _| Ok(Some(key)))
+	}
+
+	/// Same as `chunk_keys`, along with the size of each value as stored.
+	pub fn chunk_sizes(
+		&self,
+		log: &Log,
+		table: IndexTableId,
+		chunk: u64,
+		newer: &[IndexTableId],
+	) -> Result<Vec<(Key, u32)>> {
+		self.chunk_keys_with(log, table, chunk, newer, |key, value, offset, overlays| {
+			Ok(value.size(&key, offset, overlays)?.map(|(size, _)| (key, size)))
+		})
+	}
+
+	// Calls `f` with each key of the chunk, its value table and offset.
+	fn chunk_keys_with<T>(
+		&self,
+		log: &Log,
+		table: IndexTableId,
+		chunk: u64,
+		newer: &[IndexTableId],
+		mut f: impl FnMut(Key, &ValueTable, u64, &RwLock<LogOverlays>) -> Result<Option<T>>,
+	) -> Result<Vec<T>> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let find = |id: IndexTableId| if tables.index.id == id {
+			Some(&tables.index)
+		} else {
+			reindex.queue.iter().find(|t| t.id == id)
+		};
+		let source = match find(table) {
+			Some(source) => source,
+			None => return Ok(Vec::new()),
+		};
+		let overlays = log.overlays();
+		let mut result = Vec::new();
+		'entries: for entry in source.entries(chunk, overlays).iter() {
+			if entry.is_empty() {
+				continue;
+			}
+			let (size_tier, offset) = self.entry_address(entry, source.id.index_bits());
+			let pk = match tables.value[size_tier as usize].partial_key_at(offset, overlays)? {
+				Some(pk) => pk,
+				None => {
+					// Removed after the index chunk was read.
+					log::debug!(target: "parity-db", "{}: Missing value for chunk {}", source.id, chunk);
+					continue;
+				},
+			};
+			let mut key = source.recover_key_prefix(chunk, *entry);
+			key[6..].copy_from_slice(&pk);
+			for id in newer {
+				if let Some(index) = find(*id) {
+					if self.has_key_in_index(&key, index, &tables, overlays)? {
+						continue 'entries;
+					}
+				}
+			}
+			if let Some(item) = f(key, &tables.value[size_tier as usize], offset, overlays)? {
+				result.push(item);
+			}
+		}
+		Ok(result)
+	}
+
 	fn iter_while_inner(
 		&self,
 		log: &Log,
@@ -570,16 +1576,7 @@ impl Column {
 				if entry.is_empty() {
 					continue;
 				}
-				let (size_tier, offset) = if self.db_version >= 4 {
-					let address = entry.address(source.id.index_bits());
-					(address.size_tier(), address.offset())
-				} else {
-					let addr_bits = source.id.index_bits() + 10;
-					let address = Address::from_u64(entry.as_u64() & ((1u64 << addr_bits) - 1));
-					let size_tier = (address.as_u64() & 0x0f) as u8;
-					let offset = address.as_u64() >> 4;
-					(size_tier, offset)
-				};
+				let (size_tier, offset) = self.entry_address(entry, source.id.index_bits());
 
 				if skip_preimage_indexes && self.preimage && size_tier as usize != tables.value.len() - 1 {
 					continue;
@@ -628,7 +1625,7 @@ impl Column {
 		let step = 1000;
 		let start_time = std::time::Instant::now();
 		log::info!(target: "parity-db", "Starting full index iteration at {:?}", start_time);
-		log::info!(target: "parity-db", "for {} chunks of column {}", self.tables.read().index.id.total_chunks(), col);
+		log::info!(target: "parity-db", "for {} chunks of column {}", self.tables.read().index.id.total_chunks(), column_label(col, self.name.as_deref()));
 		self.iter_while_inner(log, |state| match state {
 			IterStateOrCorrupted::Item(IterState { chunk_index, key, rc, value }) => {
 				if Some(chunk_index) == end_chunk {
@@ -707,15 +1704,76 @@ impl Column {
 	pub fn drop_index(&self, id: IndexTableId) -> Result<()> {
 		log::debug!(target: "parity-db", "Dropping {}", id);
 		let mut reindex = self.reindex.write();
-		if reindex.queue.front_mut().map_or(false, |index| index.id == id) {
-			let table = reindex.queue.pop_front();
+		let table = if reindex.queue.front_mut().map_or(false, |index| index.id == id) {
 			reindex.progress.store(0, Ordering::Relaxed);
-			table.unwrap().drop_file()?;
+			reindex.queue.pop_front()
+		} else if let Some(pos) = reindex.replaced.iter().position(|t| t.id == id) {
+			Some(reindex.replaced.remove(pos))
+		} else if let Some(pos) = reindex.queue.iter().position(|t| t.id == id) {
+			// Replayed clear of a column that was being reindexed.
+			reindex.queue.remove(pos)
 		} else {
-			log::warn!(target: "parity-db", "Dropping invalid index {}", id);
-			return Ok(());
+			None
+		};
+		match table {
+			// Tables that were never written have no file.
+			Some(table) => match table.drop_file() {
+				Err(e) if e.io_error().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => {},
+				result => result?,
+			},
+			None => {
+				log::warn!(target: "parity-db", "Dropping invalid index {}", id);
+				return Ok(());
+			},
 		}
 		log::debug!(target: "parity-db", "Dropped {}", id);
 		Ok(())
 	}
+
+	/// Replace the index with the empty table `id`, unless it is in place already. The old
+	/// index is dropped by a following `drop_index`.
+	pub fn replace_index(&self, id: IndexTableId) -> Result<()> {
+		let mut tables = self.tables.write();
+		if tables.index.id == id {
+			return Ok(());
+		}
+		let mut reindex = self.reindex.write();
+		let chunk_entries = tables.index.chunk_entries();
+		let old = std::mem::replace(&mut tables.index, IndexTable::create_new(&self.path, id, chunk_entries));
+		log::debug!(target: "parity-db", "Replaced index {} with {}", old.id, id);
+		reindex.replaced.push(old);
+		Ok(())
+	}
+
+	/// Remove all values: the index is replaced with an empty table, and all value table
+	/// slots are freed, in the same record. Reads find no values once this returns. The
+	/// replaced index tables are dropped when the record is enacted.
+	pub fn write_clear_plan(&self, log: &mut LogWriter) -> Result<()> {
+		let mut tables = self.tables.write();
+		let mut reindex = self.reindex.write();
+		let id = tables.index.id;
+		// The new table must not share a file or overlay entries with tables still in use.
+		let bits = (START_BITS .. 65)
+			.find(|bits| std::iter::once(&tables.index)
+				.chain(reindex.queue.iter())
+				.chain(reindex.replaced.iter())
+				.all(|t| t.id.index_bits() != *bits))
+			.ok_or_else(|| Error::Corruption("No free index table id".into()))?;
+		let new_id = IndexTableId::new(id.col(), bits);
+		log.replace_index(new_id);
+		let chunk_entries = tables.index.chunk_entries();
+		let old = std::mem::replace(&mut tables.index, IndexTable::create_new(&self.path, new_id, chunk_entries));
+		let queue: Vec<IndexTable> = reindex.queue.drain(..).collect();
+		for table in std::iter::once(old).chain(queue) {
+			log.drop_table(table.id);
+			reindex.replaced.push(table);
+		}
+		reindex.progress.store(0, Ordering::Relaxed);
+		for t in tables.value.iter() {
+			t.write_clear_plan();
+		}
+		self.clear_cache();
+		log::info!(target: "parity-db", "Cleared column, replaced index {} with {}", id, new_id);
+		Ok(())
+	}
 }
@@ -30,30 +30,257 @@
 /// Each background worker is signalled with a conditional variable once
 /// there is some work to be done.
 
-use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::sync::{Arc, mpsc::Sender, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use std::io::Write;
 use std::convert::TryInto;
-use std::collections::{HashMap, VecDeque};
-use parking_lot::{RwLock, Mutex, Condvar};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+use parking_lot::{RwLock, Mutex, MutexGuard, Condvar};
 use fs2::FileExt;
 use crate::{
-	table::Key,
+	table::{Key, TableId as ValueTableId},
 	error::{Error, Result},
-	column::{ColId, Column, IterState},
-	log::{Log, LogAction},
-	index::PlanOutcome,
-	options::{Metadata, Options},
+	column::{self, ColId, IterState, Salt},
+	log::{Log, LogAction, LogChange, LogReader, LogWriter, ReplayReport},
+	index::{Address, PlanOutcome, TableId as IndexTableId},
+	options::{ColumnOptions, Metadata, Options, WalSync},
+	display::hex,
+	trash::{self, JournalKey, Trash, TrashEntry},
+	export::{self, ExportOptions, ExportSummary, ImportOptions, ImportSummary, OnConflict},
+	ttl::{self, Expiry},
+	pool::ThreadPool,
+	stats::{ColumnCounters, CommitQueueDepth, DiskUsage, FreeStats, MemoryUsage, PendingBytes, RepairSummary, ValueMeta, VerifyReport, file_disk_size},
 };
 
 // These are in memory, so we use usize
-const MAX_COMMIT_QUEUE_BYTES: usize = 16 * 1024 * 1024;
 // These are disk-backed, so we use u64
 const MAX_LOG_QUEUE_BYTES: i64 = 128 * 1024 * 1024;
 const MIN_LOG_SIZE: u64 = 64 * 1024 * 1024;
+// Index chunks read per table to estimate the size of a key prefix.
+const PREFIX_SAMPLE_CHUNKS: u64 = 4096;
+// Background commits are logged once normal commits in the queue are below this size.
+const BACKGROUND_ADMIT_BYTES: usize = 64 * 1024;
+// Writers blocked by `Options::max_uncommitted_bytes` recheck the pending size this often.
+const UNCOMMITTED_WAIT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 const KEEP_LOGS: usize = 16;
+// Expired values are looked up this often, a batch of index chunks at a time.
+const EXPIRY_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const EXPIRY_SCAN_CHUNKS: u64 = 1024;
+const EXPIRY_BATCH: usize = 1024;
+// Bulk insert syncs the tables and saves progress after this many bytes of values.
+const BULK_SYNC_BYTES: usize = 64 * 1024 * 1024;
+// Imported entries are committed in batches of about this many bytes.
+const IMPORT_BATCH_BYTES: usize = 16 * 1024 * 1024;
+// Number of values moved in a single compaction record.
+const COMPACTION_BATCH: usize = 8192;
+// Index entries written per record when the index is rebuilt.
+const REPAIR_BATCH: usize = 8192;
+// Expired values are purged from the trash this often.
+const TRASH_EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Changes for `Db::commit_builder`. Meant to be reused: committing empties the
+/// builder but keeps its memory allocated.
+#[derive(Debug, Default)]
+pub struct CommitBuilder {
+	// Keys of all changes, back to back.
+	keys: Vec<u8>,
+	changes: Vec<(ColId, std::ops::Range<usize>, Option<Value>)>,
+}
+
+impl CommitBuilder {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	pub fn with_capacity(changes: usize) -> Self {
+		CommitBuilder {
+			keys: Vec::with_capacity(changes * std::mem::size_of::<Key>()),
+			changes: Vec::with_capacity(changes),
+		}
+	}
+
+	pub fn insert(&mut self, col: impl Into<ColId>, key: &[u8], value: Value) {
+		self.push(col, key, Some(value))
+	}
+
+	pub fn remove(&mut self, col: impl Into<ColId>, key: &[u8]) {
+		self.push(col, key, None)
+	}
+
+	/// Add a change, same as an item passed to `Db::commit`.
+	pub fn push(&mut self, col: impl Into<ColId>, key: &[u8], value: Option<Value>) {
+		let start = self.keys.len();
+		self.keys.extend_from_slice(key);
+		self.changes.push((col.into(), start .. self.keys.len(), value));
+	}
+
+	pub fn len(&self) -> usize {
+		self.changes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.changes.is_empty()
+	}
+
+	pub fn clear(&mut self) {
+		self.keys.clear();
+		self.changes.clear();
+	}
+}
+
+/// Progress of `Db::backup_to`.
+#[derive(Debug, Default, Clone)]
+pub struct BackupProgress {
+	pub files_copied: usize,
+	pub files_total: usize,
+	pub bytes_copied: u64,
+	pub bytes_total: u64,
+}
+
+/// Handle to a database column, returned by `Db::column` and `Db::column_at`.
+/// Accepted anywhere a column index is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Column(ColId);
+
+impl Column {
+	pub fn index(&self) -> ColId {
+		self.0
+	}
+}
+
+impl From<Column> for ColId {
+	fn from(col: Column) -> ColId {
+		col.0
+	}
+}
+
+/// Priority of a commit in the commit queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+	#[default]
+	Normal,
+	/// Logged once few normal commits are queued, or after
+	/// `Options::max_background_commit_delay`. Queued background commits are
+	/// merged and logged together.
+	Background,
+}
+
+/// Result of `Db::cas`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasOutcome {
+	/// The value matched and the change is committed.
+	Applied,
+	/// The value did not match. Holds the current value.
+	Mismatch(Option<Value>),
+}
+
+impl CasOutcome {
+	pub fn is_applied(&self) -> bool {
+		matches!(self, CasOutcome::Applied)
+	}
+}
+
+/// Change made by an enacted commit, as passed to commit hooks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitChange {
+	pub col: ColId,
+	/// Key as stored, i.e. hashed according to the column options.
+	pub key: Key,
+	/// Size of the inserted value as stored. `None` for removals.
+	pub size: Option<u32>,
+	/// Inserted value as stored. Only set for hooks registered with `values`.
+	pub value: Option<Value>,
+}
+
+/// Changes of an enacted commit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitSummary {
+	pub changes: Vec<CommitChange>,
+}
+
+impl CommitSummary {
+	fn without_values(&self) -> CommitSummary {
+		CommitSummary {
+			changes: self.changes.iter().map(|c| CommitChange { value: None, ..c.clone() }).collect(),
+		}
+	}
+}
+
+/// Called with the log record id and the changes of each enacted commit.
+pub type CommitHook = Box<dyn Fn(u64, &CommitSummary) + Send + Sync>;
+
+struct RegisteredHook {
+	id: u64,
+	values: bool,
+	hook: CommitHook,
+}
+
+/// Change to a single key of an enacted commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+	/// Value inserted, as stored. Adds a reference in reference counted columns.
+	Set(Value),
+	/// Value removed. Removes a reference in reference counted columns.
+	Remove,
+}
+
+/// Enacted commit, as sent to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnactedRecord {
+	pub record_id: u64,
+	/// Records that were not sent to this subscriber before this one, because it was not
+	/// draining the channel. Always 0 with `Backpressure::Block`.
+	pub dropped: u64,
+	/// Changed keys, as stored.
+	pub changes: Vec<(ColId, Key, Operation)>,
+}
+
+/// What to do when a subscriber channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+	/// Skip the record, and count it in `EnactedRecord::dropped` of the next one sent.
+	Drop,
+	/// Wait for the subscriber. This stops enactment, and eventually commits.
+	Block,
+}
+
+struct Subscriber {
+	sender: std::sync::mpsc::SyncSender<EnactedRecord>,
+	backpressure: Backpressure,
+	// Records dropped since the last one sent.
+	dropped: u64,
+}
+
+impl Subscriber {
+	// Returns `false` once the receiver is gone.
+	fn send(&mut self, record: &EnactedRecord) -> bool {
+		let record = EnactedRecord { dropped: self.dropped, ..record.clone() };
+		match self.backpressure {
+			Backpressure::Block => self.sender.send(record).is_ok(),
+			Backpressure::Drop => match self.sender.try_send(record) {
+				Ok(()) => {
+					self.dropped = 0;
+					true
+				},
+				Err(std::sync::mpsc::TrySendError::Full(_)) => {
+					self.dropped += 1;
+					true
+				},
+				Err(std::sync::mpsc::TrySendError::Disconnected(_)) => false,
+			},
+		}
+	}
+}
 
 /// Value is just a vector of bytes. Value sizes up to 4Gb are allowed.
+/// Values that don't fit a single value table entry are split into linked parts.
 pub type Value = Vec<u8>;
 
+/// Values of a page and the cursor of the next page, see `Db::iter_from`.
+pub type IterPage = (Vec<(Key, Value)>, Option<IterCursor>);
+
+const MAX_VALUE_SIZE: usize = u32::MAX as usize;
+
 
 // Commit data passed to `commit`
 #[derive(Default)]
@@ -66,23 +293,175 @@ struct Commit {
 	bytes: usize,
 	// Operations.
 	changeset: Vec<(ColId, Key, Option<Value>)>,
+	// Column cleared before the changes are applied.
+	clear: Option<ColId>,
+	// Notified once the commit is written to the log.
+	completion: Option<Completion>,
+}
+
+enum Completion {
+	Channel(Sender<Result<()>>),
+	// Sent once the log record is synced to disk, see `DbInner::complete_synced`.
+	Synced(Sender<Result<()>>),
+	Future(CommitSlot),
 }
 
-// Pending commits. This may not grow beyond `MAX_COMMIT_QUEUE_BYTES` bytes.
+impl Completion {
+	fn complete(self, result: Result<u64>) {
+		match self {
+			// Receiver may be gone, which is fine.
+			Completion::Channel(sender) | Completion::Synced(sender) => { let _ = sender.send(result.map(|_| ())); },
+			Completion::Future(slot) => slot.complete(result),
+		}
+	}
+}
+
+#[derive(Default)]
+struct CommitState {
+	result: Option<Result<u64>>,
+	completed: bool,
+	waker: Option<std::task::Waker>,
+}
+
+// Completing side of a `CommitFuture`. Resolves the future with an error if dropped
+// before the commit is logged.
+struct CommitSlot(Arc<Mutex<CommitState>>);
+
+impl CommitSlot {
+	fn complete(&self, result: Result<u64>) {
+		let mut state = self.0.lock();
+		if state.completed {
+			return;
+		}
+		state.result = Some(result);
+		state.completed = true;
+		if let Some(waker) = state.waker.take() {
+			waker.wake();
+		}
+	}
+}
+
+impl Drop for CommitSlot {
+	fn drop(&mut self) {
+		self.complete(Err(Error::InvalidInput("Database is shutting down".into())));
+	}
+}
+
+// Commit that is not queued, because there is no room in the commit queue.
+struct WaitingCommit {
+	commit: Vec<(ColId, Key, Option<Value>)>,
+	completion: Option<Completion>,
+}
+
+/// Returned by `Db::commit_async`. Resolves to the log record id once the commit
+/// is written to the log.
+pub struct CommitFuture {
+	state: Arc<Mutex<CommitState>>,
+	// Commit that waits for room in the commit queue, and the database to queue it to.
+	waiting: Option<(Arc<DbInner>, WaitingCommit)>,
+}
+
+impl CommitFuture {
+	fn new() -> (CommitFuture, CommitSlot) {
+		let state = Arc::new(Mutex::new(CommitState::default()));
+		(CommitFuture { state: state.clone(), waiting: None }, CommitSlot(state))
+	}
+
+	fn ready(result: Result<u64>) -> CommitFuture {
+		let (future, slot) = Self::new();
+		slot.complete(result);
+		future
+	}
+
+	// Queue the waiting commit if there is room for it, or have `waker` woken once there may be.
+	fn try_queue(&mut self, waker: &std::task::Waker) {
+		if let Some((db, waiting)) = self.waiting.take() {
+			let queued = db.queue_with_trash(
+				waiting.commit,
+				waiting.completion,
+				Priority::Normal,
+				|commit| db.try_lock_queue(commit, waker),
+			);
+			match queued {
+				Ok(None) => {},
+				Ok(Some(waiting)) => self.waiting = Some((db, waiting)),
+				// The completion is dropped with the rejected commit.
+				Err(e) => self.state.lock().result = Some(Err(e)),
+			}
+		}
+	}
+}
+
+// Waker of the first attempt to queue a commit, made before the future is polled.
+struct NoopWaker;
+
+impl std::task::Wake for NoopWaker {
+	fn wake(self: Arc<Self>) {}
+}
+
+impl std::future::Future for CommitFuture {
+	type Output = Result<u64>;
+
+	fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context) -> std::task::Poll<Result<u64>> {
+		self.try_queue(cx.waker());
+		let mut state = self.state.lock();
+		match state.result.take() {
+			Some(result) => std::task::Poll::Ready(result),
+			None if state.completed => std::task::Poll::Ready(Err(Error::InvalidInput("Commit result was already taken".into()))),
+			None => {
+				state.waker = Some(cx.waker().clone());
+				std::task::Poll::Pending
+			},
+		}
+	}
+}
+
+// Pending commits. New commits wait while it is above `Options::commit_queue_bytes` bytes.
 #[derive(Default)]
 struct CommitQueue {
 	// Log record.
 	record_id: u64,
-	// Total size of all commits in the queue.
+	// Total size of keys and values of all commits in the queue, and of the commit that is
+	// being written to the log.
 	bytes: usize,
+	// Largest `bytes` since the database was opened.
+	high_water_bytes: usize,
 	// FIFO queue.
 	commits: VecDeque<Commit>,
+	// Background priority commits, with the time they were queued.
+	background: VecDeque<(Instant, Commit)>,
+	// Size of the background commits, included in `bytes`.
+	background_bytes: usize,
+	// Wakers of `CommitFuture`s that wait for room in the queue.
+	waiting: Vec<std::task::Waker>,
+}
+
+impl CommitQueue {
+	fn wake_waiting(&mut self) {
+		for waker in self.waiting.drain(..) {
+			waker.wake();
+		}
+	}
+}
+
+// Log backlog above the `Options::backlog_warning` limits.
+#[derive(Default)]
+struct Backlog {
+	// When the backlog went above the limits, `None` while it is below.
+	since: Option<Instant>,
+	// When the last warning was logged.
+	warned: Option<Instant>,
+	// Warnings skipped since the last one was logged.
+	suppressed: u64,
 }
 
 #[derive(Default)]
 struct IdentityKeyHash(u64);
 type IdentityBuildHasher = std::hash::BuildHasherDefault<IdentityKeyHash>;
 
+// Actions of a log record that change a column, with their contents.
+type ColumnActions = Vec<(LogAction, Vec<u8>)>;
+
 impl std::hash::Hasher for IdentityKeyHash {
 	fn write(&mut self, bytes: &[u8]) {
 		self.0 = u64::from_le_bytes((&bytes[0..8]).try_into().unwrap())
@@ -100,54 +479,213 @@ impl std::hash::Hasher for IdentityKeyHash {
 	fn finish(&self) -> u64 { self.0 }
 }
 
+// Process that holds the database lock, as recorded in the lock file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LockOwner {
+	pid: u32,
+	// Unix time in seconds when the lock was taken. Zero if unknown.
+	since: u64,
+}
+
+impl std::fmt::Display for LockOwner {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "process {}", self.pid)?;
+		if self.since != 0 {
+			write!(f, " since {}", self.since)?;
+		}
+		Ok(())
+	}
+}
+
+// Record current process ID and lock time in the lock file.
+fn write_lock_owner(file: &mut std::fs::File) -> Result<()> {
+	use std::io::{Seek, Write};
+	file.set_len(0)?;
+	file.seek(std::io::SeekFrom::Start(0))?;
+	write!(file, "{} {}", std::process::id(), crate::trash::now())?;
+	Ok(())
+}
+
+fn read_lock_owner(file: &mut std::fs::File) -> Option<LockOwner> {
+	use std::io::{Read, Seek};
+	let mut owner = String::new();
+	file.seek(std::io::SeekFrom::Start(0)).ok()?;
+	file.read_to_string(&mut owner).ok()?;
+	let mut parts = owner.split_whitespace();
+	let pid = parts.next()?.parse().ok()?;
+	// Older lock files only have the process ID.
+	let since = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+	Some(LockOwner { pid, since })
+}
+
+// Extend lock error with the lock holder, if known.
+fn lock_error(owner: Option<LockOwner>, e: std::io::Error) -> Error {
+	match owner {
+		Some(owner) => Error::Locked(std::io::Error::new(e.kind(), format!("{}, held by {}", e, owner))),
+		None => Error::Locked(e),
+	}
+}
+
+// Files the database creates in its directory, other than the lock.
+fn is_log_file(name: &str) -> bool {
+	name.strip_prefix("log").is_some_and(|n| n.parse::<u32>().is_ok())
+}
+
+fn is_database_file(name: &str) -> bool {
+	// Column numbers take 2 digits, or 3 from column 100 on. Returns the rest of the name.
+	let column = |prefix| name.strip_prefix(prefix).and_then(|n| {
+		let digits = n.bytes().take_while(u8::is_ascii_digit).count();
+		if (2..=3).contains(&digits) { Some(&n[digits..]) } else { None }
+	});
+	let column_file = |prefix| column(prefix).is_some_and(|n| n.len() > 1 && n.starts_with('_'));
+	name == "metadata"
+		|| name == crate::options::OPTIONS_FILE
+		|| name == "stats.txt"
+		|| name == ttl::CLOCK_FILE
+		|| column_file("index_")
+		|| column_file("table_")
+		|| is_log_file(name)
+		|| column("bulk_") == Some("")
+}
+
+fn open_lock_file(path: &std::path::Path) -> Result<std::fs::File> {
+	Ok(std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)?)
+}
+
+// Exclusive database lock. The owner is cleared on release, so an owner
+// left in an unlocked file means the previous process did not exit cleanly.
+struct DbLock(std::fs::File);
+
+impl Drop for DbLock {
+	fn drop(&mut self) {
+		let _ = self.0.set_len(0);
+	}
+}
+
+// Lock the database. A lock file that is not locked is reclaimed right away. The lock
+// is released by the system when its holder exits, so a lock that is held belongs to a
+// live open file, even if the recorded owner process is gone, and is never taken over.
+fn lock_database(path: &std::path::Path) -> Result<DbLock> {
+	let mut file = open_lock_file(path)?;
+	if let Err(e) = file.try_lock_exclusive() {
+		return Err(lock_error(read_lock_owner(&mut file), e));
+	}
+	if let Some(owner) = read_lock_owner(&mut file) {
+		log::warn!(
+			target: "parity-db",
+			"Reclaiming stale lock {} left by {}",
+			path.display(),
+			owner,
+		);
+	}
+	write_lock_owner(&mut file)?;
+	Ok(DbLock(file))
+}
+
 struct DbInner {
-	columns: Vec<Column>,
+	columns: Vec<Arc<column::Column>>,
 	options: Options,
 	metadata: Metadata,
 	shutdown: AtomicBool,
 	log: Log,
 	commit_queue: Mutex<CommitQueue>,
 	commit_queue_full_cv: Condvar,
-	log_worker_cv: Condvar,
-	log_work: Mutex<bool>,
-	commit_worker_cv: Condvar,
-	commit_work: Mutex<bool>,
+	// Wake up signals of the background threads. See `Options::background_threads`.
+	worker_signals: Vec<WorkSignal>,
 	// Overlay of most recent values int the commit queue. ColumnId -> (Key -> (RecordId, Value)).
 	commit_overlay: RwLock<Vec<HashMap<Key, (u64, Option<Value>), IdentityBuildHasher>>>,
 	log_cv: Condvar,
 	log_queue_bytes: Mutex<i64>, // This may underflow occasionally, but is bound for 0 eventually
-	flush_worker_cv: Condvar,
-	flush_work: Mutex<bool>,
-	cleanup_worker_cv: Condvar,
-	cleanup_work: Mutex<bool>,
+	// Set by writers waiting for uncommitted bytes to drain. The log is flushed regardless of its size.
+	force_flush: AtomicBool,
+	// Set once the log overlay is above `Options::overlay_high_water_bytes`, until it is
+	// below the low water mark again.
+	overlay_stalled: AtomicBool,
+	// See `Options::backlog_warning`.
+	backlog: Mutex<Backlog>,
+	// Set by `Db::pause_background`. Flush, commit and cleanup workers do nothing meanwhile.
+	paused: AtomicBool,
 	last_enacted: AtomicU64,
+	// Held while a log record is enacted, by the commit worker or `Db::process_logs`.
+	enact_lock: Mutex<()>,
 	next_reindex: AtomicU64,
 	bg_err: Mutex<Option<Arc<Error>>>,
-	_lock_file: std::fs::File,
+	// Held by workers while they modify log or table files. Taken exclusively for backup.
+	file_gate: RwLock<()>,
+	// Per column trash journal, loaded on first use.
+	trash: Vec<Mutex<Option<Trash>>>,
+	// Reserved column that keeps the trash, after the user columns.
+	trash_col: Option<ColId>,
+	ttl_clock: ttl::Clock,
+	// Per column background expiry state.
+	expiry: Vec<Mutex<Expiry>>,
+	// Per column flag, set while a bulk insert is writing to the column tables.
+	bulk: Vec<AtomicBool>,
+	// Per column number of iterators and compactions. Reindexing is paused while there are any.
+	reindex_pauses: Vec<AtomicUsize>,
+	// Threads that enact the columns of a record concurrently. See `Options::enact_threads`.
+	enact_pool: Option<ThreadPool>,
+	// Serializes planning of log records, so that records are logged in the order of their ids.
+	plan_lock: Mutex<()>,
+	commit_hooks: RwLock<Vec<Arc<RegisteredHook>>>,
+	next_hook_id: AtomicU64,
+	// Summaries of logged commits for the hooks, by record id.
+	hooked_commits: Mutex<VecDeque<(u64, CommitSummary)>>,
+	// Logged commits with a `Completion::Synced`, by record id.
+	synced_waiters: Mutex<Vec<(u64, Completion)>>,
+	subscribers: Mutex<Vec<Subscriber>>,
+	// Opened with `Db::open_secondary`. Files are never modified.
+	secondary: bool,
+	_lock_file: Option<DbLock>,
 }
 
 impl DbInner {
-	fn open(options: &Options, create: bool) -> Result<DbInner> {
+	fn open(options: &Options, create: bool, secondary: bool) -> Result<DbInner> {
 		if create {
-			std::fs::create_dir_all(&options.path)?
+			std::fs::create_dir_all(&options.path)?;
+			std::fs::create_dir_all(options.log_dir())?;
+		};
+		let lock_file = if secondary {
+			None
+		} else {
+			let mut lock_path: std::path::PathBuf = options.path.clone();
+			lock_path.push("lock");
+			Some(lock_database(&lock_path)?)
 		};
-		let mut lock_path: std::path::PathBuf = options.path.clone();
-		lock_path.push("lock");
-		let lock_file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(lock_path.as_path())?;
-		lock_file.try_lock_exclusive().map_err(|e| Error::Locked(e))?;
 
-		let metadata = options.load_and_validate_metadata(create)?;
+		options.check_column_paths(!secondary)?;
+		let mut metadata = options.load_and_validate_metadata(create, secondary)?;
+		let trash_col = metadata.columns.iter().any(|c| c.trash.is_some()).then_some(metadata.columns.len() as ColId);
+		if trash_col.is_some() {
+			metadata.columns.push(trash::column_options());
+		}
 		let mut columns = Vec::with_capacity(metadata.columns.len());
 		let mut commit_overlay = Vec::with_capacity(metadata.columns.len());
-		let log = Log::open(&options)?;
+		let log = if secondary { Log::open_secondary(options) } else { Log::open(options)? };
 		let last_enacted = log.replay_record_id().unwrap_or(2) - 1;
+		// Files of a secondary instance may be truncated by the primary, which would fault a map.
+		let mmap = options.mmap_value_tables && !secondary;
 		for c in 0 .. metadata.columns.len() {
-			columns.push(Column::open(c as ColId, &options, &metadata)?);
+			columns.push(Arc::new(column::Column::open(c as ColId, &options, &metadata, mmap)?));
 			commit_overlay.push(
 				HashMap::with_hasher(std::hash::BuildHasherDefault::<IdentityKeyHash>::default())
 			);
 		}
 		log::debug!(target: "parity-db", "Opened db {:?}, metadata={:?}", options, metadata);
+		let trash = (0 .. columns.len()).map(|_| Mutex::new(None)).collect();
+		let expiry = (0 .. columns.len()).map(|_| Mutex::new(Default::default())).collect();
+		let bulk = (0 .. columns.len()).map(|_| AtomicBool::new(false)).collect();
+		let reindex_pauses = (0 .. columns.len()).map(|_| AtomicUsize::new(0)).collect();
+		let ttl_clock = ttl::Clock::open(
+			(!secondary && metadata.columns.iter().any(|c| c.ttl.is_some()))
+				.then(|| options.path.join(ttl::CLOCK_FILE))
+		)?;
+		let enact_threads = std::cmp::min(options.enact_threads, columns.len());
+		let enact_pool = if enact_threads > 1 && !secondary {
+			Some(ThreadPool::new("parity-db-enact", enact_threads)?)
+		} else {
+			None
+		};
 		Ok(DbInner {
 			columns,
 			options: options.clone(),
@@ -156,62 +694,139 @@ impl DbInner {
 			log,
 			commit_queue: Mutex::new(Default::default()),
 			commit_queue_full_cv: Condvar::new(),
-			log_worker_cv: Condvar::new(),
-			log_work: Mutex::new(false),
-			commit_worker_cv: Condvar::new(),
-			commit_work: Mutex::new(false),
+			worker_signals: (0..options.background_threads).map(|_| Default::default()).collect(),
 			commit_overlay: RwLock::new(commit_overlay),
 			log_queue_bytes: Mutex::new(0),
 			log_cv: Condvar::new(),
-			flush_worker_cv: Condvar::new(),
-			flush_work: Mutex::new(false),
-			cleanup_worker_cv: Condvar::new(),
-			cleanup_work: Mutex::new(false),
+			force_flush: AtomicBool::new(false),
+			overlay_stalled: AtomicBool::new(false),
+			backlog: Mutex::new(Default::default()),
+			paused: AtomicBool::new(false),
 			next_reindex: AtomicU64::new(1),
 			last_enacted: AtomicU64::new(last_enacted),
+			enact_lock: Mutex::new(()),
 			bg_err: Mutex::new(None),
+			file_gate: RwLock::new(()),
+			trash,
+			trash_col,
+			ttl_clock,
+			expiry,
+			bulk,
+			reindex_pauses,
+			enact_pool,
+			plan_lock: Mutex::new(()),
+			commit_hooks: RwLock::new(Vec::new()),
+			next_hook_id: AtomicU64::new(1),
+			hooked_commits: Mutex::new(VecDeque::new()),
+			synced_waiters: Mutex::new(Vec::new()),
+			subscribers: Mutex::new(Vec::new()),
+			secondary,
 			_lock_file: lock_file,
 		})
 	}
 
-	fn signal_log_worker(&self) {
-		let mut work = self.log_work.lock();
+	fn signal_worker(&self, worker: Worker) {
+		let signal = &self.worker_signals[worker.thread(self.options.background_threads)];
+		let mut work = signal.work.lock();
 		*work = true;
-		self.log_worker_cv.notify_one();
+		signal.cv.notify_one();
+	}
+
+	fn signal_log_worker(&self) {
+		self.signal_worker(Worker::Log)
 	}
 
 	fn signal_commit_worker(&self) {
-		let mut work = self.commit_work.lock();
-		*work = true;
-		self.commit_worker_cv.notify_one();
+		self.signal_worker(Worker::Commit)
 	}
 
 	fn signal_flush_worker(&self) {
-		let mut work = self.flush_work.lock();
-		*work = true;
-		self.flush_worker_cv.notify_one();
+		self.signal_worker(Worker::Flush)
 	}
 
 	fn signal_cleanup_worker(&self) {
-		let mut work = self.cleanup_work.lock();
-		*work = true;
-		self.cleanup_worker_cv.notify_one();
+		self.signal_worker(Worker::Cleanup)
+	}
+
+	// Whether the worker runs on a thread with other workers, and must not block waiting for them.
+	fn shares_thread(&self, worker: Worker) -> bool {
+		let threads = self.options.background_threads;
+		let thread = worker.thread(threads);
+		Worker::ALL.iter().any(|w| *w != worker && w.thread(threads) == thread)
 	}
 
 	fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
-		let key = self.columns[col as usize].hash(key);
+		let key = self.columns[col as usize].hash_key(key)?;
+		self.get_hashed(col, &key)
+	}
+
+	fn get_raw(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		let key = self.columns[col as usize].hash_raw_key(key)?;
+		self.get_hashed(col, &key)
+	}
+
+	fn count_get<T>(&self, col: ColId, value: &Option<T>) {
+		if let Some(counters) = self.columns[col as usize].counters() {
+			counters.get(value);
+		}
+	}
+
+	fn get_hashed(&self, col: ColId, key: &Key) -> Result<Option<Value>> {
+		match self.get_stored(col, key)? {
+			Some(value) => self.decode_stored(col, value),
+			None => Ok(None),
+		}
+	}
+
+	// Value as seen by the user, or `None` if it has expired.
+	fn decode_stored(&self, col: ColId, value: Value) -> Result<Option<Value>> {
+		match self.metadata.columns[col as usize].ttl {
+			Some(ttl) => ttl::decode_value(value, ttl, self.ttl_clock.now()),
+			None => Ok(Some(value)),
+		}
+	}
+
+	fn get_with_meta(&self, col: ColId, key: &Key) -> Result<Option<(Value, ValueMeta)>> {
+		match self.columns[col as usize].get_with_meta(key, self.log.overlays())? {
+			Some((value, meta)) => Ok(self.decode_stored(col, value)?.map(|value| (value, meta))),
+			None => Ok(None),
+		}
+	}
+
+	fn is_queued(&self, col: ColId, key: &Key) -> bool {
+		self.commit_overlay.read()[col as usize].contains_key(key)
+	}
+
+	// Get the value as stored in the column.
+	fn get_stored(&self, col: ColId, key: &Key) -> Result<Option<Value>> {
 		let overlay = self.commit_overlay.read();
 		// Check commit overlay first
-		if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key).map(|(_, v)| v.clone())) {
+		if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(key).map(|(_, v)| v.clone())) {
 			return Ok(v);
 		}
 		// Go into tables and log overlay.
 		let log = self.log.overlays();
-		self.columns[col as usize].get(&key, log)
+		self.columns[col as usize].get(key, log)
+	}
+
+	fn get_shared(&self, col: ColId, key: &[u8]) -> Result<Option<Arc<Value>>> {
+		let key = self.columns[col as usize].hash_key(key)?;
+		if self.metadata.columns[col as usize].ttl.is_some() {
+			return Ok(self.get_hashed(col, &key)?.map(Arc::new));
+		}
+		let overlay = self.commit_overlay.read();
+		if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key).map(|(_, v)| v.clone())) {
+			return Ok(v.map(Arc::new));
+		}
+		let log = self.log.overlays();
+		self.columns[col as usize].get_shared(&key, log)
 	}
 
 	fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
-		let key = self.columns[col as usize].hash(key);
+		let key = self.columns[col as usize].hash_key(key)?;
+		if self.metadata.columns[col as usize].ttl.is_some() {
+			return Ok(self.get_hashed(col, &key)?.map(|v| v.len() as u32));
+		}
 		let overlay = self.commit_overlay.read();
 		// Check commit overlay first
 		if let Some(l) = overlay.get(col as usize).and_then(
@@ -224,6 +839,40 @@ impl DbInner {
 		self.columns[col as usize].get_size(&key, log)
 	}
 
+	fn exists_many<I, K>(&self, col: ColId, keys: I) -> Result<Vec<bool>>
+	where
+		I: IntoIterator<Item=K>,
+		K: AsRef<[u8]>,
+	{
+		let column = &self.columns[col as usize];
+		let keys = keys.into_iter().map(|k| column.hash_key(k.as_ref())).collect::<Result<Vec<_>>>()?;
+		if self.metadata.columns[col as usize].ttl.is_some() {
+			// Expiry is only known from the value.
+			return keys.iter().map(|k| Ok(self.get_hashed(col, k)?.is_some())).collect();
+		}
+		let overlay = self.commit_overlay.read();
+		let log = self.log.overlays();
+		keys.iter().map(|key| match overlay[col as usize].get(key) {
+			Some((_, value)) => Ok(value.is_some()),
+			None => column.has_key(key, log),
+		}).collect()
+	}
+
+	fn get_range(&self, col: ColId, key: &[u8], offset: usize, len: usize) -> Result<Option<Value>> {
+		let key = self.columns[col as usize].hash_key(key)?;
+		if self.metadata.columns[col as usize].ttl.is_some() {
+			return Ok(self.get_hashed(col, &key)?.map(|v| column::slice_value(&v, offset, len).to_vec()));
+		}
+		let overlay = self.commit_overlay.read();
+		if let Some(v) = overlay.get(col as usize).and_then(
+			|o| o.get(&key).map(|(_, v)| v.as_ref().map(|v| column::slice_value(v, offset, len).to_vec()))
+		) {
+			return Ok(v);
+		}
+		let log = self.log.overlays();
+		self.columns[col as usize].get_range(&key, log, offset, len)
+	}
+
 	// Commit simply adds the the data to the queue and to the overlay and
 	// exits as early as possible.
 	fn commit<I, K>(&self, tx: I) -> Result<()>
@@ -231,220 +880,1350 @@ impl DbInner {
 		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
 		K: AsRef<[u8]>,
 	{
-		let commit: Vec<_> = tx.into_iter().map(
-			|(c, k, v)| (c, self.columns[c as usize].hash(k.as_ref()), v)
-		).collect();
+		let commit = self.hash_changeset(tx)?;
+		self.commit_hashed(self.stamp_ttl(commit))
+	}
 
-		self.commit_raw(commit)
+	fn commit_raw<I, K>(&self, tx: I) -> Result<()>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let commit = self.hash_changeset_with(tx, column::Column::hash_raw_key)?;
+		self.commit_hashed(self.stamp_ttl(commit))
 	}
 
-	fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
-		{
-			let mut queue = self.commit_queue.lock();
-			if queue.bytes > MAX_COMMIT_QUEUE_BYTES {
-				log::debug!(target: "parity-db", "Waiting, qb={}", queue.bytes);
-				self.commit_queue_full_cv.wait(&mut queue);
+	fn commit_builder(&self, builder: &mut CommitBuilder) -> Result<()> {
+		let mut commit = Vec::with_capacity(builder.len());
+		let mut result = Ok(());
+		for (c, range, value) in builder.changes.drain(..) {
+			match self.user_columns().get(c as usize) {
+				Some(column) => match column.hash_key(&builder.keys[range]) {
+					Ok(key) => commit.push((c, key, value)),
+					Err(e) => {
+						result = Err(e);
+						break;
+					},
+				},
+				None => {
+					result = Err(Error::InvalidInput(format!("Invalid column {}", c)));
+					break;
+				},
 			}
-			{
-				let bg_err = self.bg_err.lock();
-				if let Some(err) = &*bg_err {
-					return Err(Error::Background(err.clone()));
+		}
+		builder.clear();
+		result?;
+		self.commit_hashed(self.stamp_ttl(commit))
+	}
+
+	// Prefix inserted values with the insertion time, for columns that have a TTL.
+	fn stamp_ttl(&self, mut commit: Vec<(ColId, Key, Option<Value>)>) -> Vec<(ColId, Key, Option<Value>)> {
+		let now = self.ttl_clock.now();
+		for (c, _, value) in commit.iter_mut() {
+			if self.metadata.columns[*c as usize].ttl.is_some() {
+				if let Some(value) = value.as_mut() {
+					*value = ttl::encode_value(now, value);
 				}
 			}
+		}
+		commit
+	}
 
-			let mut overlay = self.commit_overlay.write();
+	fn hash_changeset<I, K>(&self, tx: I) -> Result<Vec<(ColId, Key, Option<Value>)>>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.hash_changeset_with(tx, column::Column::hash_key)
+	}
+
+	fn hash_changeset_with<I, K>(
+		&self,
+		tx: I,
+		hash: fn(&column::Column, &[u8]) -> Result<Key>,
+	) -> Result<Vec<(ColId, Key, Option<Value>)>>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		tx.into_iter().map(|(c, k, v)| match self.user_columns().get(c as usize) {
+			Some(column) => Ok((c, hash(column, k.as_ref())?, v)),
+			None => Err(Error::InvalidInput(format!("Invalid column {}", c))),
+		}).collect()
+	}
 
-			queue.record_id += 1;
-			let record_id = queue.record_id + 1;
+	// Columns of the user, without the reserved trash column.
+	fn user_columns(&self) -> &[Arc<column::Column>] {
+		&self.columns[.. self.options.columns.len()]
+	}
 
-			let mut bytes = 0;
-			for (c, k, v) in &commit {
-				bytes += k.len();
-				bytes += v.as_ref().map_or(0, |v|v.len());
-				// Don't add removed ref-counted values to overlay.
-				if !self.metadata.columns[*c as usize].ref_counted || v.is_some() {
-					overlay[*c as usize].insert(*k, (record_id, v.clone()));
-				}
+	// Column for the raw key API. Reserved columns are not accessible.
+	fn user_column(&self, col: ColId) -> Result<&column::Column> {
+		self.user_columns().get(col as usize)
+			.map(|c| c.as_ref())
+			.ok_or_else(|| Error::InvalidInput("Invalid column".into()))
+	}
+
+	fn raw_commit<I, K>(&self, tx: I) -> Result<()>
+	where
+		I: IntoIterator<Item=(ColId, Key, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let verify = cfg!(debug_assertions) || self.options.verify_raw_keys;
+		let mut commit = Vec::new();
+		for (c, hash, k, v) in tx {
+			let column = self.user_column(c)?;
+			if verify && column.derive_key(k.as_ref())? != hash {
+				return Err(Error::InvalidInput(format!(
+					"Pre-hashed key {} does not match key derivation for column {}",
+					hex(&hash),
+					c,
+				)));
 			}
+			commit.push((c, hash, v));
+		}
+		self.commit_hashed(self.stamp_ttl(commit))
+	}
 
-			let commit = Commit {
-				id: record_id,
-				changeset: commit,
-				bytes,
-			};
+	fn commit_with_completion<I, K>(&self, tx: I, completion: Sender<Result<()>>) -> Result<()>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let commit = self.hash_changeset(tx)?;
+		let completion = match self.options.sync_wal {
+			WalSync::EveryCommit => Completion::Synced(completion),
+			_ => Completion::Channel(completion),
+		};
+		self.commit_with_trash(self.stamp_ttl(commit), Some(completion), Priority::Normal, None)
+	}
 
-			log::debug!(
-				target: "parity-db",
-				"Queued commit {}, {} bytes",
-				commit.id,
-				bytes,
-			);
-			queue.commits.push_back(commit);
-			queue.bytes += bytes;
-			self.signal_log_worker();
+	fn commit_async<I, K>(self: &Arc<Self>, tx: I) -> CommitFuture
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let commit = match self.hash_changeset(tx) {
+			Ok(commit) => commit,
+			Err(e) => return CommitFuture::ready(Err(e)),
+		};
+		let (mut future, slot) = CommitFuture::new();
+		let waiting = WaitingCommit { commit: self.stamp_ttl(commit), completion: Some(Completion::Future(slot)) };
+		future.waiting = Some((self.clone(), waiting));
+		future.try_queue(&Arc::new(NoopWaker).into());
+		future
+	}
+
+	// Same as `commit`, with index keys as returned by `Column::derive_key`.
+	#[cfg(feature = "kvdb")]
+	fn commit_index_keys(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
+		if let Some((c, _, _)) = commit.iter().find(|(c, _, _)| *c as usize >= self.options.columns.len()) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", c)));
 		}
-		Ok(())
+		self.commit_hashed(self.stamp_ttl(commit))
 	}
 
-	fn process_commits(&self) -> Result<bool> {
-		{
-			// Wait if the queue is too big.
-			let mut queue = self.log_queue_bytes.lock();
-			if !self.shutdown.load(Ordering::Relaxed) && *queue > MAX_LOG_QUEUE_BYTES {
-				log::debug!(target: "parity-db", "Waiting, log_bytes={}", queue);
-				self.log_cv.wait(&mut queue);
+	fn commit_hashed(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
+		self.commit_with_trash(commit, None, Priority::Normal, None)
+	}
+
+	fn commit_with_priority<I, K>(&self, tx: I, priority: Priority) -> Result<()>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let commit = self.hash_changeset(tx)?;
+		self.commit_with_trash(self.stamp_ttl(commit), None, priority, None)
+	}
+
+	fn commit_until<I, K>(&self, tx: I, deadline: Instant) -> Result<()>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let commit = self.hash_changeset(tx)?;
+		self.commit_with_trash(self.stamp_ttl(commit), None, Priority::Normal, Some(deadline))
+	}
+
+	// Key in the trash column, derived from `tag`, the column and `data`.
+	fn trash_column_key(&self, tag: &[u8], col: ColId, data: &[u8]) -> (ColId, Key) {
+		let trash_col = self.trash_col.expect("Trash column is opened when trash is enabled");
+		let key = self.columns[trash_col as usize].hash(&[tag, &[col], data].concat());
+		(trash_col, key)
+	}
+
+	// Where the removed value of `key` is kept.
+	fn trash_key(&self, col: ColId, key: &Key) -> (ColId, Key) {
+		self.trash_column_key(b"trash", col, key)
+	}
+
+	fn journal_key(&self, col: ColId, key: JournalKey) -> (ColId, Key) {
+		match key {
+			JournalKey::Head => self.trash_column_key(b"trash_journal", col, &[]),
+			JournalKey::Chunk(index) => self.trash_column_key(b"trash_journal", col, &index.to_le_bytes()),
+		}
+	}
+
+	// Trash journal changes of the column, made since the last call.
+	fn journal_changes(&self, col: ColId, trash: &mut Trash) -> Vec<(ColId, Key, Option<Value>)> {
+		trash.journal_changes().into_iter().map(|(key, value)| {
+			let (trash_col, key) = self.journal_key(col, key);
+			(trash_col, key, value)
+		}).collect()
+	}
+
+	// Load trash journal for the column, if not loaded yet.
+	fn load_trash<'a>(&self, col: ColId, trash: &'a mut Option<Trash>) -> Result<&'a mut Trash> {
+		if trash.is_none() {
+			let options = self.metadata.columns[col as usize].trash
+				.ok_or_else(|| Error::InvalidInput(format!("Trash is not enabled for column {}", col)))?;
+			let (trash_col, head_key) = self.journal_key(col, JournalKey::Head);
+			let head = self.get_hashed(trash_col, &head_key)?;
+			*trash = Some(Trash::load(options, head.as_deref(), |index| {
+				let (trash_col, key) = self.journal_key(col, JournalKey::Chunk(index));
+				self.get_hashed(trash_col, &key)
+			})?);
+		}
+		Ok(trash.as_mut().unwrap())
+	}
+
+	// Move removed values to the trash, for columns that have it enabled. Trash that is over
+	// the size bound is purged by the cleanup worker.
+	// Fails with `Error::QueueFull` if the commit queue is still full at `deadline`.
+	fn commit_with_trash(
+		&self,
+		commit: Vec<(ColId, Key, Option<Value>)>,
+		completion: Option<Completion>,
+		priority: Priority,
+		deadline: Option<Instant>,
+	) -> Result<()> {
+		self.queue_with_trash(commit, completion, priority, |commit| self.lock_queue(commit, deadline).map(Some))?;
+		Ok(())
+	}
+
+	// Same as `commit_with_trash`, with the commit queue locked by `lock`. If `lock` finds no
+	// room for the commit, nothing is queued and the commit is returned.
+	fn queue_with_trash<'a>(
+		&'a self,
+		mut commit: Vec<(ColId, Key, Option<Value>)>,
+		completion: Option<Completion>,
+		priority: Priority,
+		lock: impl FnOnce(&[(ColId, Key, Option<Value>)]) -> Result<Option<MutexGuard<'a, CommitQueue>>>,
+	) -> Result<Option<WaitingCommit>> {
+		// Only removals change the trash.
+		let mut trash_columns: Vec<ColId> = commit.iter()
+			.filter(|(_, _, value)| value.is_none())
+			.map(|(c, _, _)| *c)
+			.filter(|c| self.metadata.columns[*c as usize].trash.is_some())
+			.collect();
+		if trash_columns.is_empty() {
+			return match lock(&commit)? {
+				Some(mut queue) => {
+					self.push_commit(&mut queue, commit, completion, priority)?;
+					Ok(None)
+				},
+				None => Ok(Some(WaitingCommit { commit, completion })),
+			};
+		}
+		trash_columns.sort();
+		trash_columns.dedup();
+
+		let now = trash::now();
+		let mut guards: Vec<_> = trash_columns.iter().map(|c| self.trash[*c as usize].lock()).collect();
+		// Trash is copied once a value is moved to it, and replaced once the commit is queued.
+		let mut updated: Vec<Option<Trash>> = vec![None; guards.len()];
+		let mut trash_changes = Vec::new();
+		for (c, key, value) in commit.iter() {
+			if value.is_some() {
+				continue;
+			}
+			if let Ok(i) = trash_columns.binary_search(c) {
+				if let Some(old) = self.get_hashed(*c, key)? {
+					let (trash_col, trash_key) = self.trash_key(*c, key);
+					trash_changes.push((trash_col, trash_key, Some(trash::encode_value(now, &old))));
+					let trash = match &mut updated[i] {
+						Some(trash) => trash,
+						None => updated[i].insert(self.load_trash(*c, &mut guards[i])?.clone()),
+					};
+					trash.push(TrashEntry { removed_at: now, key: *key, size: old.len() as u64 });
+				}
 			}
 		}
-		let commit = {
-			let mut queue = self.commit_queue.lock();
-			if let Some(commit) = queue.commits.pop_front() {
-				queue.bytes -= commit.bytes;
-				log::debug!(
-					target: "parity-db",
-					"Removed {}. Still queued commits {} bytes",
-					commit.bytes,
-					queue.bytes,
-				);
-				if queue.bytes <= MAX_COMMIT_QUEUE_BYTES && (queue.bytes + commit.bytes) > MAX_COMMIT_QUEUE_BYTES {
-					// Past the waiting threshold.
-					log::debug!(
-						target: "parity-db",
-						"Waking up commit queue worker",
-					);
-					self.commit_queue_full_cv.notify_one();
+		for (c, trash) in trash_columns.iter().zip(updated.iter_mut()) {
+			if let Some(trash) = trash {
+				trash_changes.extend(self.journal_changes(*c, trash));
+			}
+		}
+		let len = commit.len();
+		commit.extend(trash_changes);
+		match lock(&commit)? {
+			Some(mut queue) => self.push_commit(&mut queue, commit, completion, priority)?,
+			None => {
+				// Trash changes are made again once there is room.
+				commit.truncate(len);
+				return Ok(Some(WaitingCommit { commit, completion }));
+			},
+		}
+		let full = updated.iter().flatten().any(|trash| trash.is_full());
+		for (guard, trash) in guards.iter_mut().zip(updated) {
+			if let Some(trash) = trash {
+				**guard = Some(trash);
+			}
+		}
+		// The cleanup worker skips trash that is locked.
+		drop(guards);
+		if full {
+			self.signal_worker(Worker::Cleanup);
+		}
+		Ok(None)
+	}
+
+	// Wait for all queued commits to be logged and sync the logs.
+	fn flush(&self) -> Result<()> {
+		let (completion, done) = std::sync::mpsc::channel();
+		Self::admit_background(&mut self.commit_queue.lock());
+		// Empty commit is processed after everything queued before it, without writing a record.
+		self.enqueue_commit(Vec::new(), Some(Completion::Channel(completion)), Priority::Normal, None)?;
+		done.recv().map_err(|_| Error::InvalidInput("Database is shutting down".into()))??;
+		self.log.sync_all()
+	}
+
+	// Database files outside of the database directory: logs in `Options::wal_path` and
+	// files of columns with their own `ColumnOptions::path`.
+	fn external_files(&self) -> Result<Vec<std::fs::DirEntry>> {
+		let mut files = Vec::new();
+		if self.options.log_dir() != self.options.path {
+			for entry in std::fs::read_dir(self.options.log_dir())? {
+				let entry = entry?;
+				if entry.file_type()?.is_file() && entry.file_name().to_str().is_some_and(is_log_file) {
+					files.push(entry);
 				}
-				Some(commit)
-			} else {
-				None
 			}
+		}
+		for (col, options) in self.options.columns.iter().enumerate() {
+			let path = match &options.path {
+				Some(path) => path,
+				None => continue,
+			};
+			for entry in std::fs::read_dir(path)? {
+				let entry = entry?;
+				let name = entry.file_name();
+				let name = name.to_string_lossy();
+				if entry.file_type()?.is_file()
+					&& (crate::index::TableId::is_file_name(col as ColId, &name)
+						|| crate::table::TableId::is_file_name(col as ColId, &name))
+				{
+					files.push(entry);
+				}
+			}
+		}
+		Ok(files)
+	}
+
+	// Copy database files while background workers are paused.
+	fn backup(&self, dest: &std::path::Path, mut progress: impl FnMut(&BackupProgress)) -> Result<u64> {
+		std::fs::create_dir_all(dest)?;
+		let _gate = self.file_gate.write();
+		let record_id = self.log.last_record_id();
+		self.log.sync_appending(true)?;
+		let mut files = Vec::new();
+		let mut state = BackupProgress::default();
+		for entry in std::fs::read_dir(&self.options.path)? {
+			let entry = entry?;
+			if !entry.file_type()?.is_file() || entry.file_name() == "lock" {
+				continue;
+			}
+			state.bytes_total += entry.metadata()?.len();
+			files.push(entry);
+		}
+		for entry in self.external_files()? {
+			state.bytes_total += entry.metadata()?.len();
+			files.push(entry);
+		}
+		state.files_total = files.len();
+		progress(&state);
+		for entry in files {
+			// Copy is a cheap clone on file systems that support it.
+			state.bytes_copied += std::fs::copy(entry.path(), dest.join(entry.file_name()))?;
+			state.files_copied += 1;
+			progress(&state);
+		}
+		log::info!(target: "parity-db", "Backup to {} complete at record {}", dest.display(), record_id);
+		Ok(record_id)
+	}
+
+	fn undelete(&self, col: ColId, key: &[u8]) -> Result<bool> {
+		let key = self.columns[col as usize].hash_key(key)?;
+		let mut guard = self.trash[col as usize].lock();
+		let mut trash = self.load_trash(col, &mut guard)?.clone();
+		let entry = match trash.take(&key) {
+			Some(entry) => entry,
+			None => return Ok(false),
 		};
+		let (trash_col, trash_key) = self.trash_key(col, &key);
+		let mut changes = Vec::new();
+		let mut restored = false;
+		// Expired values that are not purged yet are not restored.
+		if !trash.is_expired(&entry, trash::now()) {
+			if let Some(value) = self.get_hashed(trash_col, &trash_key)? {
+				changes.push((col, key, Some(trash::decode_value(value)?)));
+				restored = true;
+			}
+		}
+		changes.push((trash_col, trash_key, None));
+		changes.extend(self.journal_changes(col, &mut trash));
+		self.enqueue_commit(changes, None, Priority::Normal, None)?;
+		*guard = Some(trash);
+		Ok(restored)
+	}
 
-		if let Some(commit) = commit {
-			let mut reindex = false;
-			let mut writer = self.log.begin_record();
-			log::debug!(
-				target: "parity-db",
-				"Processing commit {}, record {}, {} bytes",
-				commit.id,
-				writer.record_id(),
-				commit.bytes,
-			);
-			let mut ops: u64 = 0;
-			for (c, key, value) in commit.changeset.iter() {
-				match self.columns[*c as usize].write_plan(key, value, &mut writer)? {
-					// Reindex has triggered another reindex.
-					PlanOutcome::NeedReindex => {
-						reindex = true;
-					},
-					_ => {},
+	// Purge expired values from the trash. The cleanup worker skips columns with trash that
+	// is being changed, and does not wait for room in the commit queue.
+	fn purge_trash(&self, wait: bool) -> Result<()> {
+		let now = trash::now();
+		for c in 0 .. self.options.columns.len() {
+			if self.metadata.columns[c].trash.is_none() {
+				continue;
+			}
+			let col = c as ColId;
+			let mut guard = if wait {
+				self.trash[c].lock()
+			} else {
+				match self.trash[c].try_lock() {
+					Some(guard) => guard,
+					None => continue,
 				}
-				ops += 1;
+			};
+			let mut trash = self.load_trash(col, &mut guard)?.clone();
+			let mut changes: Vec<_> = trash.expire(now).into_iter()
+				.map(|expired| {
+					let (trash_col, key) = self.trash_key(col, &expired.key);
+					(trash_col, key, None)
+				})
+				.collect();
+			if changes.is_empty() {
+				continue;
 			}
-			// Collect final changes to value tables
-			for c in self.columns.iter() {
-				c.complete_plan(&mut writer)?;
+			log::debug!(target: "parity-db", "Column {}: purging {} values from the trash", col, changes.len());
+			changes.extend(self.journal_changes(col, &mut trash));
+			if wait {
+				self.enqueue_commit(changes, None, Priority::Normal, None)?;
+			} else {
+				self.push_commit(&mut self.commit_queue.lock(), changes, None, Priority::Normal)?;
 			}
-			let record_id = writer.record_id();
-			let l = writer.drain();
+			*guard = Some(trash);
+		}
+		Ok(())
+	}
 
-			let bytes = {
-				let bytes = self.log.end_record(l)?;
-				let mut logged_bytes = self.log_queue_bytes.lock();
-				*logged_bytes += bytes as i64;
-				self.signal_flush_worker();
-				bytes
-			};
+	fn enqueue_commit(
+		&self,
+		commit: Vec<(ColId, Key, Option<Value>)>,
+		completion: Option<Completion>,
+		priority: Priority,
+		deadline: Option<Instant>,
+	) -> Result<()> {
+		let mut queue = self.lock_queue(&commit, deadline)?;
+		self.push_commit(&mut queue, commit, completion, priority)
+	}
 
-			{
-				// Cleanup the commit overlay.
-				let mut overlay = self.commit_overlay.write();
-				for (c, key, _) in commit.changeset.iter() {
-					let overlay = &mut overlay[*c as usize];
-					if let std::collections::hash_map::Entry::Occupied(e) = overlay.entry(*key) {
-						if e.get().0 == commit.id {
-							e.remove_entry();
-						}
+	// Size of the commit, checked against the limits.
+	fn check_commit(&self, commit: &[(ColId, Key, Option<Value>)]) -> Result<u64> {
+		if self.secondary {
+			return Err(Error::InvalidInput("Secondary instance is read only".into()));
+		}
+		if let Some((c, _, _)) = commit.iter().find(|(_, _, v)| v.as_ref().is_some_and(|v| v.len() > MAX_VALUE_SIZE)) {
+			return Err(Error::InvalidInput(format!("Value in column {} exceeds maximum size of {} bytes", c, MAX_VALUE_SIZE)));
+		}
+		let bytes: u64 = commit.iter().map(|(_, k, v)| (k.len() + v.as_ref().map_or(0, |v| v.len())) as u64).sum();
+		if let Some(limit) = self.options.max_uncommitted_bytes {
+			if bytes > limit {
+				return Err(Error::InvalidInput(format!("Commit of {} bytes exceeds the limit of {} uncommitted bytes", bytes, limit)));
+			}
+		}
+		Ok(bytes)
+	}
+
+	// Lock the commit queue if there is room for the commit. Otherwise `waker` is woken once
+	// there may be room, and `None` is returned.
+	fn try_lock_queue(
+		&self,
+		commit: &[(ColId, Key, Option<Value>)],
+		waker: &std::task::Waker,
+	) -> Result<Option<MutexGuard<'_, CommitQueue>>> {
+		let bytes = self.check_commit(commit)?;
+		let mut queue = self.commit_queue.lock();
+		if self.shutdown.load(Ordering::SeqCst) {
+			return Err(Error::InvalidInput("Database is shutting down".into()));
+		}
+		if queue.bytes <= self.options.commit_queue_bytes {
+			let pending = queue.bytes as u64 + (*self.log_queue_bytes.lock()).max(0) as u64;
+			match self.options.max_uncommitted_bytes {
+				Some(limit) if bytes > 0 && pending > 0 && pending + bytes > limit => {
+					self.force_flush.store(true, Ordering::SeqCst);
+					self.signal_flush_worker();
+				},
+				_ => return Ok(Some(queue)),
+			}
+		}
+		queue.waiting.push(waker.clone());
+		Ok(None)
+	}
+
+	// Lock the commit queue once there is room for the commit. Without a deadline this waits
+	// for as long as it takes.
+	fn lock_queue(&self, commit: &[(ColId, Key, Option<Value>)], deadline: Option<Instant>) -> Result<MutexGuard<'_, CommitQueue>> {
+		let bytes = self.check_commit(commit)?;
+		let mut queue = self.commit_queue.lock();
+		while queue.bytes > self.options.commit_queue_bytes {
+			log::debug!(target: "parity-db", "Waiting, qb={}", queue.bytes);
+			match deadline {
+				None => self.commit_queue_full_cv.wait(&mut queue),
+				Some(deadline) => {
+					if self.commit_queue_full_cv.wait_until(&mut queue, deadline).timed_out()
+						&& queue.bytes > self.options.commit_queue_bytes
+					{
+						return Err(Error::QueueFull);
 					}
 				}
 			}
+		}
+		if let Some(limit) = self.options.max_uncommitted_bytes {
+			while bytes > 0 && !self.shutdown.load(Ordering::SeqCst) {
+				let pending = queue.bytes as u64 + (*self.log_queue_bytes.lock()).max(0) as u64;
+				if pending == 0 || pending + bytes <= limit {
+					break;
+				}
+				if deadline.is_some_and(|d| Instant::now() >= d) {
+					return Err(Error::QueueFull);
+				}
+				log::debug!(target: "parity-db", "Waiting, pending={}", pending);
+				self.force_flush.store(true, Ordering::SeqCst);
+				self.signal_flush_worker();
+				// Log queue shrinks without holding the commit queue lock, so wake up periodically.
+				self.commit_queue_full_cv.wait_for(&mut queue, UNCOMMITTED_WAIT_INTERVAL);
+			}
+		}
+		Ok(queue)
+	}
+
+	fn cas(&self, col: ColId, key: &[u8], expected: Option<&[u8]>, new: Option<Value>) -> Result<CasOutcome> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		let options = &self.metadata.columns[col as usize];
+		if options.ref_counted || options.trash.is_some() {
+			return Err(Error::InvalidInput(format!("Column {} is reference counted or has trash", col)));
+		}
+		let key = self.columns[col as usize].hash_key(key)?;
+		let commit = self.stamp_ttl(vec![(col, key, new)]);
+		// Other commits are queued after this one, so they can't change the value in between.
+		let mut queue = self.lock_queue(&commit, None)?;
+		let current = self.get_hashed(col, &key)?;
+		if current.as_deref() != expected {
+			return Ok(CasOutcome::Mismatch(current));
+		}
+		self.push_commit(&mut queue, commit, None, Priority::Normal)?;
+		Ok(CasOutcome::Applied)
+	}
+
+	fn move_value(&self, from: ColId, to: ColId, key: &[u8]) -> Result<bool> {
+		for col in [from, to] {
+			if col as usize >= self.options.columns.len() {
+				return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+			}
+			if self.metadata.columns[col as usize].trash.is_some() {
+				return Err(Error::InvalidInput(format!("Column {} has trash", col)));
+			}
+		}
+		if from == to {
+			return Err(Error::InvalidInput(format!("Can't move a value within column {}", from)));
+		}
+		let source = self.columns[from as usize].hash_key(key)?;
+		let target = self.columns[to as usize].hash_key(key)?;
+		// Other commits are queued after this one, so they can't change the value in between.
+		let mut queue = self.lock_queue(&[], None)?;
+		let value = match self.get_hashed(from, &source)? {
+			Some(value) => value,
+			None => return Ok(false),
+		};
+		let commit = self.stamp_ttl(vec![(from, source, None), (to, target, Some(value))]);
+		self.push_commit(&mut queue, commit, None, Priority::Normal)?;
+		Ok(true)
+	}
 
-			if reindex {
-				self.start_reindex(record_id);
+	fn pending_bytes(&self) -> PendingBytes {
+		PendingBytes {
+			commit_queue: self.commit_queue.lock().bytes as u64,
+			log_queue: (*self.log_queue_bytes.lock()).max(0) as u64,
+			appending_log: self.log.appending_size(),
+		}
+	}
+
+	fn memory_usage(&self) -> MemoryUsage {
+		let commit_queue = {
+			let queue = self.commit_queue.lock();
+			let changesets: usize = queue.commits.iter()
+				.chain(queue.background.iter().map(|(_, c)| c))
+				.map(|c| c.changeset.capacity() * std::mem::size_of::<(ColId, Key, Option<Value>)>())
+				.sum();
+			// Queued values are also copied into the overlay.
+			2 * queue.bytes + changesets
+		};
+		let commit_overlay: usize = self.commit_overlay.read().iter()
+			.map(|o| o.capacity() * std::mem::size_of::<(Key, (u64, Option<Value>))>())
+			.sum();
+		MemoryUsage {
+			commit_queue: (commit_queue + commit_overlay) as u64,
+			log_overlay: self.log.overlay_memory(),
+			cache: self.columns.iter().map(|c| c.cache_bytes() as u64).sum(),
+		}
+	}
+
+	// Add a commit to the queue without waiting for the queue to shrink.
+	fn push_commit(
+		&self,
+		queue: &mut CommitQueue,
+		commit: Vec<(ColId, Key, Option<Value>)>,
+		completion: Option<Completion>,
+		priority: Priority,
+	) -> Result<()> {
+		self.push_changes(queue, commit, None, completion, priority)
+	}
+
+	fn push_changes(
+		&self,
+		queue: &mut CommitQueue,
+		commit: Vec<(ColId, Key, Option<Value>)>,
+		clear: Option<ColId>,
+		completion: Option<Completion>,
+		priority: Priority,
+	) -> Result<()> {
+		{
+			let bg_err = self.bg_err.lock();
+			if let Some(err) = &*bg_err {
+				return Err(Error::Background(err.clone()));
+			}
+		}
+		if self.shutdown.load(Ordering::SeqCst) {
+			return Err(Error::InvalidInput("Database is shutting down".into()));
+		}
+		if let Some(c) = commit.iter().map(|(c, _, _)| *c).chain(clear).find(|c| self.bulk[*c as usize].load(Ordering::SeqCst)) {
+			return Err(Error::InvalidInput(format!("Bulk insert is running for column {}", c)));
+		}
+
+		let mut overlay = self.commit_overlay.write();
+
+		if priority == Priority::Normal && !queue.background.is_empty() {
+			// Background commits that change the same keys, or the cleared column, must be
+			// logged first.
+			let conflict = clear.is_some() || commit.iter().any(|(c, k, _)| overlay[*c as usize].get(k)
+				.is_some_and(|(id, _)| queue.background.binary_search_by_key(id, |(_, b)| b.id).is_ok()));
+			if conflict {
+				Self::admit_background(queue);
+			}
+		}
+
+		queue.record_id += 1;
+		let record_id = queue.record_id + 1;
+
+		let mut bytes = 0;
+		for (c, k, v) in &commit {
+			let change_bytes = k.len() + v.as_ref().map_or(0, |v|v.len());
+			bytes += change_bytes;
+			if let Some(counters) = self.columns[*c as usize].counters() {
+				counters.commit(change_bytes);
+			}
+			if self.metadata.columns[*c as usize].ref_counted {
+				// Don't add removed ref-counted values to overlay. A queued value is kept,
+				// since adding a reference does not replace it.
+				if let Some(v) = v {
+					overlay[*c as usize].entry(*k)
+						.and_modify(|e| e.0 = record_id)
+						.or_insert_with(|| (record_id, Some(v.clone())));
+				}
+			} else {
+				overlay[*c as usize].insert(*k, (record_id, v.clone()));
 			}
+		}
+
+		let commit = Commit {
+			id: record_id,
+			changeset: commit,
+			clear,
+			bytes,
+			completion,
+		};
+
+		log::debug!(
+			target: "parity-db",
+			"Queued commit {}, {} bytes",
+			commit.id,
+			bytes,
+		);
+		match priority {
+			Priority::Normal => queue.commits.push_back(commit),
+			Priority::Background => {
+				queue.background.push_back((Instant::now(), commit));
+				queue.background_bytes += bytes;
+			},
+		}
+		queue.bytes += bytes;
+		queue.high_water_bytes = std::cmp::max(queue.high_water_bytes, queue.bytes);
+		self.signal_log_worker();
+		Ok(())
+	}
+
+	// Merge queued background commits into a single commit, behind the normal ones.
+	fn admit_background(queue: &mut CommitQueue) {
+		let mut background = queue.background.drain(..).map(|(_, commit)| commit);
+		let mut batch = match background.next() {
+			Some(commit) => commit,
+			None => return,
+		};
+		let mut merged = 1;
+		for commit in background {
+			// Overlay entries of all merged commits are cleared by the last id.
+			batch.id = commit.id;
+			batch.bytes += commit.bytes;
+			batch.changeset.extend(commit.changeset);
+			merged += 1;
+		}
+		log::debug!(target: "parity-db", "Admitted {} background commits, {} bytes", merged, batch.bytes);
+		queue.background_bytes = 0;
+		queue.commits.push_back(batch);
+	}
+
+	fn commit_queue_depth(&self) -> CommitQueueDepth {
+		let queue = self.commit_queue.lock();
+		CommitQueueDepth {
+			normal_commits: queue.commits.len(),
+			normal_bytes: (queue.bytes - queue.background_bytes) as u64,
+			background_commits: queue.background.len(),
+			background_bytes: queue.background_bytes as u64,
+			high_water_bytes: queue.high_water_bytes as u64,
+		}
+	}
 
+	// Release the queued bytes of a commit that has been written to the log.
+	fn release_queued(&self, bytes: usize) {
+		let mut queue = self.commit_queue.lock();
+		queue.bytes -= bytes;
+		log::debug!(
+			target: "parity-db",
+			"Removed {}. Still queued commits {} bytes",
+			bytes,
+			queue.bytes,
+		);
+		let limit = self.options.commit_queue_bytes;
+		if queue.bytes <= limit && (queue.bytes + bytes) > limit {
+			// Past the waiting threshold.
 			log::debug!(
 				target: "parity-db",
-				"Processed commit {} (record {}), {} ops, {} bytes written",
-				commit.id,
-				record_id,
-				ops,
-				bytes,
+				"Waking up commit queue worker",
 			);
-			Ok(true)
-		} else {
-			Ok(false)
+			self.commit_queue_full_cv.notify_all();
+			queue.wake_waiting();
 		}
 	}
 
-	fn start_reindex(&self, record_id: u64) {
-		self.next_reindex.store(record_id, Ordering::SeqCst);
+	fn bulk_marker_path(&self, col: ColId) -> std::path::PathBuf {
+		self.options.path.join(format!("bulk_{:02}", col))
 	}
 
-	fn process_reindex(&self) -> Result<bool> {
-		let next_reindex = self.next_reindex.load(Ordering::SeqCst);
-		if next_reindex == 0 || next_reindex > self.last_enacted.load(Ordering::SeqCst) {
-			return Ok(false)
+	fn bulk_insert_progress(&self, col: ColId) -> Result<Option<u64>> {
+		match std::fs::read_to_string(self.bulk_marker_path(col)) {
+			Ok(s) => s.trim().parse().map(Some)
+				.map_err(|_| Error::Corruption(format!("Bad bulk insert marker for column {}", col))),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
 		}
-		// Process any pending reindexes
-		for column in self.columns.iter() {
+	}
+
+	fn bulk_insert<I, K>(&self, col: ColId, data: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(K, Value)>,
+		K: AsRef<[u8]>,
+	{
+		if self.secondary {
+			return Err(Error::InvalidInput("Secondary instance is read only".into()));
+		}
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		self.start_bulk(col)?;
+		let result = self.bulk_write(col, data);
+		self.bulk[col as usize].store(false, Ordering::SeqCst);
+		result
+	}
+
+	// Set the bulk insert flag for the column.
+	fn start_bulk(&self, col: ColId) -> Result<()> {
+		// Commits are queued under this lock, so no new changes to the column may appear
+		// once the flag is set.
+		let _queue = self.commit_queue.lock();
+		if self.bulk[col as usize].load(Ordering::SeqCst) {
+			return Err(Error::InvalidInput(format!("Bulk insert is running for column {}", col)));
+		}
+		if self.reindex_pauses[col as usize].load(Ordering::SeqCst) > 0 {
+			return Err(Error::InvalidInput(format!("Column {} is being iterated or compacted", col)));
+		}
+		if !self.commit_overlay.read()[col as usize].is_empty() || self.log.has_changes(col) {
+			return Err(Error::InvalidInput(format!("Column {} has pending commits", col)));
+		}
+		self.bulk[col as usize].store(true, Ordering::SeqCst);
+		Ok(())
+	}
+
+	fn bulk_write<I, K>(&self, col: ColId, data: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(K, Value)>,
+		K: AsRef<[u8]>,
+	{
+		let column = &self.columns[col as usize];
+		let marker = self.bulk_marker_path(col);
+		let mut written = self.bulk_insert_progress(col)?.unwrap_or(0);
+		let mut inserted = 0;
+		let mut data = data.into_iter().peekable();
+		while data.peek().is_some() {
+			let mut batch = Vec::new();
+			let mut bytes = 0;
+			for (k, v) in data.by_ref() {
+				if v.len() > MAX_VALUE_SIZE {
+					return Err(Error::InvalidInput(format!("Value in column {} exceeds maximum size of {} bytes", col, MAX_VALUE_SIZE)));
+				}
+				bytes += v.len();
+				batch.push((col, column.hash_key(k.as_ref())?, Some(v)));
+				if bytes >= BULK_SYNC_BYTES {
+					break;
+				}
+			}
+			let count = batch.len() as u64;
+			self.write_unlogged(col, self.stamp_ttl(batch))?;
+			column.flush()?;
+			inserted += count;
+			written += count;
+			let mut file = std::fs::File::create(&marker)?;
+			file.write_all(written.to_string().as_bytes())?;
+			file.sync_all()?;
+		}
+		if marker.exists() {
+			std::fs::remove_file(&marker)?;
+		}
+		log::debug!(target: "parity-db", "Column {}: bulk inserted {} values", col, inserted);
+		Ok(inserted)
+	}
+
+	// Write values to the column tables without logging, completing any reindex on the way.
+	fn write_unlogged(&self, col: ColId, batch: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
+		let _gate = self.file_gate.read();
+		let column = &self.columns[col as usize];
+		let mut writer = self.log.begin_unlogged();
+		for (_, key, value) in batch.iter() {
+			column.write_plan(key, value, &mut writer)?;
+		}
+		column.complete_plan(&mut writer)?;
+		let changes = writer.drain();
+		column.apply_unlogged(&changes)?;
+		self.invalidate_cached(&Self::changed_values(&changes));
+		loop {
 			let (drop_index, batch) = column.reindex(&self.log)?;
-			if !batch.is_empty() || drop_index.is_some() {
-				let mut next_reindex = false;
-				let mut writer = self.log.begin_record();
-				log::debug!(
-					target: "parity-db",
-					"Creating reindex record {}",
-					writer.record_id(),
-				);
-				for (key, address) in batch.into_iter() {
-					match column.write_reindex_plan(&key, address, &mut writer)? {
-						PlanOutcome::NeedReindex => {
-							next_reindex = true
-						},
-						_ => {},
+			if batch.is_empty() && drop_index.is_none() {
+				break;
+			}
+			let mut writer = self.log.begin_unlogged();
+			for (key, address) in batch.into_iter() {
+				column.write_reindex_plan(&key, address, &mut writer)?;
+			}
+			column.apply_unlogged(&writer.drain())?;
+			if let Some(id) = drop_index {
+				column.flush()?;
+				column.drop_index(id)?;
+			}
+		}
+		Ok(())
+	}
+
+	// Scan TTL columns for expired values and queue their removal, a batch at a time.
+	fn process_expiry(&self) -> Result<bool> {
+		if self.shutdown.load(Ordering::SeqCst) {
+			return Ok(false);
+		}
+		self.ttl_clock.save(false)?;
+		let now = self.ttl_clock.now();
+		let mut more_work = false;
+		for (c, column) in self.columns.iter().enumerate() {
+			let ttl = match self.metadata.columns[c].ttl {
+				Some(ttl) => ttl,
+				None => continue,
+			};
+			if self.bulk[c].load(Ordering::SeqCst) {
+				continue;
+			}
+			let col = c as ColId;
+			let mut expiry = self.expiry[c].lock();
+			if expiry.pending.is_empty() && expiry.due(EXPIRY_SCAN_INTERVAL) {
+				let start = expiry.next_chunk;
+				let end = start + EXPIRY_SCAN_CHUNKS;
+				let mut complete = true;
+				let mut found = Vec::new();
+				column.iter_from(&self.log, start, |state| {
+					if state.chunk_index >= end {
+						complete = false;
+						return false;
+					}
+					if ttl::is_expired(&state.value, ttl, now) {
+						found.push(state.key);
 					}
+					true
+				})?;
+				log::debug!(target: "parity-db", "Column {}: {} expired values in chunks {}..{}", col, found.len(), start, end);
+				expiry.pending = found;
+				if complete {
+					expiry.next_chunk = 0;
+					expiry.last_scan = Some(Instant::now());
+				} else {
+					expiry.next_chunk = end;
 				}
-				if let Some(table) = drop_index {
-					writer.drop_table(table);
+			}
+			if !expiry.pending.is_empty() {
+				let split = expiry.pending.len().saturating_sub(EXPIRY_BATCH);
+				let batch = expiry.pending.split_off(split);
+				let mut queue = self.commit_queue.lock();
+				// Keys may have been replaced or removed since the scan. Checked under the queue
+				// lock, so that no commit for them can be queued before the removal.
+				let mut removed = Vec::with_capacity(batch.len());
+				for key in batch {
+					if self.get_stored(col, &key)?.is_some_and(|v| ttl::is_expired(&v, ttl, now)) {
+						removed.push((col, key, None));
+					}
 				}
-				let record_id = writer.record_id();
-				let l = writer.drain();
-
-				let mut logged_bytes = self.log_queue_bytes.lock();
-				let bytes = self.log.end_record(l)?;
-				log::debug!(
-					target: "parity-db",
-					"Created reindex record {}, {} bytes",
-					record_id,
-					bytes,
-				);
-				*logged_bytes += bytes as i64;
-				if next_reindex {
-					self.start_reindex(record_id);
+				expiry.removed += removed.len() as u64;
+				if !removed.is_empty() {
+					self.push_commit(&mut queue, removed, None, Priority::Normal)?;
 				}
-				self.signal_flush_worker();
-				return Ok(true)
 			}
+			more_work |= !expiry.pending.is_empty() || expiry.next_chunk != 0;
 		}
-		self.next_reindex.store(0, Ordering::SeqCst);
-		Ok(false)
+		Ok(more_work)
 	}
 
-	fn enact_logs(&self, validation_mode: bool) -> Result<bool> {
-		let cleared = {
-			let reader = match self.log.read_next(validation_mode) {
-				Ok(reader) => reader,
-				Err(Error::Corruption(_)) if validation_mode => {
+	fn process_commits(&self) -> Result<bool> {
+		{
+			// Wait if the queue is too big.
+			let mut queue = self.log_queue_bytes.lock();
+			if !self.shutdown.load(Ordering::Relaxed) && *queue > MAX_LOG_QUEUE_BYTES {
+				if self.shares_thread(Worker::Log) {
+					// Signalled once enacted records drain the queue.
+					log::debug!(target: "parity-db", "Deferring commits, log_bytes={}", queue);
+					return Ok(false);
+				}
+				log::debug!(target: "parity-db", "Waiting, log_bytes={}", queue);
+				self.log_cv.wait(&mut queue);
+			}
+		}
+		if let Some((high, low)) = self.options.overlay_water_marks() {
+			let mut queue = self.log_queue_bytes.lock();
+			while !self.shutdown.load(Ordering::Relaxed) && self.overlay_stalled(high, low) {
+				// Only records in flushed logs are enacted.
+				self.force_flush.store(true, Ordering::SeqCst);
+				self.signal_flush_worker();
+				if self.shares_thread(Worker::Log) {
+					log::debug!(target: "parity-db", "Deferring commits, overlay_bytes={}", self.log.overlay_bytes());
+					return Ok(false);
+				}
+				log::debug!(target: "parity-db", "Waiting, overlay_bytes={}", self.log.overlay_bytes());
+				self.log_cv.wait_for(&mut queue, UNCOMMITTED_WAIT_INTERVAL);
+			}
+		}
+		let commit = {
+			let mut queue = self.commit_queue.lock();
+			if let Some((queued, _)) = queue.background.front() {
+				let normal_bytes = queue.bytes - queue.background_bytes;
+				if normal_bytes < BACKGROUND_ADMIT_BYTES
+					|| queued.elapsed() >= self.options.max_background_commit_delay
+					|| self.shutdown.load(Ordering::Relaxed)
+				{
+					Self::admit_background(&mut queue);
+				}
+			}
+			// Bytes of the commit stay queued until it is written to the log.
+			queue.commits.pop_front()
+		};
+
+		if let Some(mut commit) = commit {
+			let completion = commit.completion.take();
+			let bytes = commit.bytes;
+			let result = if commit.changeset.is_empty() && commit.clear.is_none() {
+				// Nothing to log.
+				Ok(self.log.last_record_id())
+			} else {
+				self.write_commit(commit)
+			};
+			self.release_queued(bytes);
+			match result {
+				Ok(record_id) => {
+					match completion {
+						Some(completion @ Completion::Synced(_)) => {
+							self.synced_waiters.lock().push((record_id, completion));
+							self.signal_flush_worker();
+						},
+						Some(completion) => completion.complete(Ok(record_id)),
+						None => {},
+					}
+					Ok(true)
+				},
+				Err(e) => {
+					let e = Arc::new(e);
+					if let Some(completion) = completion {
+						completion.complete(Err(Error::Background(e.clone())));
+					}
+					Err(Error::Background(e))
+				},
+			}
+		} else {
+			Ok(false)
+		}
+	}
+
+	fn overlay_stalled(&self, high: u64, low: u64) -> bool {
+		let bytes = self.log.overlay_bytes();
+		if bytes > high {
+			self.overlay_stalled.store(true, Ordering::Relaxed);
+		} else if bytes <= low {
+			self.overlay_stalled.store(false, Ordering::Relaxed);
+		}
+		self.overlay_stalled.load(Ordering::Relaxed)
+	}
+
+	fn write_commit(&self, commit: Commit) -> Result<u64> {
+		let mut reindex = false;
+		let plan = self.plan_lock.lock();
+		let mut writer = self.log.begin_record();
+		writer.reserve(commit.changeset.len());
+		log::debug!(
+			target: "parity-db",
+			"Processing commit {}, record {}, {} bytes",
+			commit.id,
+			writer.record_id(),
+			commit.bytes,
+		);
+		let mut ops: u64 = 0;
+		if let Some(col) = commit.clear {
+			self.columns[col as usize].write_clear_plan(&mut writer)?;
+		}
+		for (c, key, value) in commit.changeset.iter() {
+			let column = &self.columns[*c as usize];
+			let outcome = column.write_plan(key, value, &mut writer)?;
+			match outcome {
+				// Reindex has triggered another reindex.
+				PlanOutcome::NeedReindex => {
+					reindex = true;
+				},
+				_ => {},
+			}
+			ops += 1;
+		}
+		// Collect final changes to value tables
+		for c in self.columns.iter() {
+			c.complete_plan(&mut writer)?;
+		}
+		let record_id = writer.record_id();
+		let l = writer.drain();
+		if let Some(summary) = self.commit_summary(&commit.changeset) {
+			// Added before the record can be enacted.
+			self.hooked_commits.lock().push_back((record_id, summary));
+		}
+
+		let bytes = {
+			let bytes = self.end_record(l)?;
+			drop(plan);
+			let mut logged_bytes = self.log_queue_bytes.lock();
+			*logged_bytes += bytes as i64;
+			self.signal_flush_worker();
+			bytes
+		};
+
+		{
+			// Cleanup the commit overlay.
+			let mut overlay = self.commit_overlay.write();
+			for (c, key, _) in commit.changeset.iter() {
+				let overlay = &mut overlay[*c as usize];
+				if let std::collections::hash_map::Entry::Occupied(e) = overlay.entry(*key) {
+					// Later commits for the key have higher ids. Merged background commits
+					// have the id of the last one.
+					if e.get().0 <= commit.id {
+						e.remove_entry();
+					}
+				}
+			}
+		}
+
+		if reindex {
+			self.start_reindex(record_id);
+		}
+
+		log::debug!(
+			target: "parity-db",
+			"Processed commit {} (record {}), {} ops, {} bytes written",
+			commit.id,
+			record_id,
+			ops,
+			bytes,
+		);
+		Ok(record_id)
+	}
+
+	fn commit_summary(&self, changeset: &[(ColId, Key, Option<Value>)]) -> Option<CommitSummary> {
+		let hooks = self.commit_hooks.read();
+		let subscribed = !self.subscribers.lock().is_empty();
+		if (hooks.is_empty() && !subscribed) || changeset.is_empty() {
+			return None;
+		}
+		let values = subscribed || hooks.iter().any(|h| h.values);
+		// Changes to the trash are internal.
+		let changes = changeset.iter()
+			.filter(|(col, _, _)| Some(*col) != self.trash_col)
+			.map(|(col, key, value)| CommitChange {
+				col: *col,
+				key: *key,
+				size: value.as_ref().map(|v| v.len() as u32),
+				value: if values { value.clone() } else { None },
+			}).collect();
+		Some(CommitSummary { changes })
+	}
+
+	fn add_commit_hook(&self, hook: CommitHook, values: bool) -> u64 {
+		let id = self.next_hook_id.fetch_add(1, Ordering::Relaxed);
+		self.commit_hooks.write().push(Arc::new(RegisteredHook { id, values, hook }));
+		id
+	}
+
+	fn remove_commit_hook(&self, id: u64) -> bool {
+		let mut hooks = self.commit_hooks.write();
+		let len = hooks.len();
+		hooks.retain(|h| h.id != id);
+		hooks.len() != len
+	}
+
+	fn subscribe(&self, capacity: usize, backpressure: Backpressure) -> std::sync::mpsc::Receiver<EnactedRecord> {
+		let (sender, receiver) = std::sync::mpsc::sync_channel(capacity);
+		self.subscribers.lock().push(Subscriber { sender, backpressure, dropped: 0 });
+		receiver
+	}
+
+	fn notify_subscribers(&self, record_id: u64, summary: &CommitSummary) {
+		// Taken out of the lock, so that blocked sends do not hold up `subscribe`.
+		// Records are only enacted by a single worker at a time.
+		let mut subscribers = std::mem::take(&mut *self.subscribers.lock());
+		if subscribers.is_empty() {
+			return;
+		}
+		let record = EnactedRecord {
+			record_id,
+			dropped: 0,
+			changes: summary.changes.iter().map(|c| {
+				let op = match &c.value {
+					Some(value) => Operation::Set(value.clone()),
+					None => Operation::Remove,
+				};
+				(c.col, c.key, op)
+			}).collect(),
+		};
+		subscribers.retain_mut(|s| s.send(&record));
+		let mut current = self.subscribers.lock();
+		subscribers.append(&mut current);
+		*current = subscribers;
+	}
+
+	// Called after the record is enacted, with no locks held.
+	fn run_commit_hooks(&self, record_id: u64) {
+		let summary = {
+			let mut queue = self.hooked_commits.lock();
+			// Records that failed to be written are never enacted.
+			while queue.front().is_some_and(|(id, _)| *id < record_id) {
+				queue.pop_front();
+			}
+			match queue.front() {
+				Some((id, _)) if *id == record_id => queue.pop_front().unwrap().1,
+				_ => return,
+			}
+		};
+		self.notify_subscribers(record_id, &summary);
+		let hooks = self.commit_hooks.read().clone();
+		let mut stripped = None;
+		for hook in hooks {
+			let summary = if hook.values {
+				&summary
+			} else {
+				stripped.get_or_insert_with(|| summary.without_values())
+			};
+			let call = std::panic::AssertUnwindSafe(|| (hook.hook)(record_id, summary));
+			if std::panic::catch_unwind(call).is_err() {
+				log::warn!(target: "parity-db", "Commit hook {} panicked on record {}", hook.id, record_id);
+			}
+		}
+	}
+
+	// Log the record and drop cached values of the value table entries it changes.
+	fn end_record(&self, record: LogChange) -> Result<u64> {
+		if self.options.disable_wal {
+			self.apply_record(record)?;
+			return Ok(0);
+		}
+		let bytes = if !self.columns.iter().any(|c| c.has_cache()) {
+			self.log.end_record(record)?
+		} else {
+			let changed = Self::changed_values(&record);
+			let bytes = self.log.end_record(record)?;
+			self.invalidate_cached(&changed);
+			bytes
+		};
+		if self.log.appending_size() > self.options.max_log_size {
+			// Rotated by the flush worker regardless of `MIN_LOG_SIZE`.
+			self.signal_flush_worker();
+		}
+		self.check_backlog();
+		Ok(bytes)
+	}
+
+	// Warn if the log backlog stays above the `Options::backlog_warning` limits.
+	fn check_backlog(&self) {
+		let warning = match &self.options.backlog_warning {
+			Some(warning) => warning,
+			None => return,
+		};
+		let overlay_bytes = self.log.overlay_bytes();
+		let dirty_logs = self.log.num_dirty_logs();
+		let mut backlog = self.backlog.lock();
+		if overlay_bytes <= warning.overlay_bytes && dirty_logs <= warning.dirty_logs {
+			backlog.since = None;
+			return;
+		}
+		let now = Instant::now();
+		let since = *backlog.since.get_or_insert(now);
+		if now.duration_since(since) < warning.after {
+			return;
+		}
+		if backlog.warned.is_some_and(|warned| now.duration_since(warned) < warning.interval) {
+			backlog.suppressed += 1;
+			return;
+		}
+		log::warn!(
+			target: "parity-db",
+			"Commit rate exceeds flush rate, overlay at {} MB, {} dirty logs, for {} s ({} similar warnings suppressed)",
+			overlay_bytes / (1024 * 1024),
+			dirty_logs,
+			now.duration_since(since).as_secs(),
+			backlog.suppressed,
+		);
+		backlog.warned = Some(now);
+		backlog.suppressed = 0;
+	}
+
+	// Write the record to the tables right away, as if it was logged and enacted. It stays
+	// in the log overlay meanwhile, so that readers never see a partially applied record.
+	fn apply_record(&self, record: LogChange) -> Result<()> {
+		let _enacting = self.enact_lock.lock();
+		let record_id = record.record_id();
+		let cleared = self.log.overlay_record(&record);
+		for column in self.columns.iter() {
+			column.apply_unlogged(&record)?;
+		}
+		self.invalidate_cached(&Self::changed_values(&record));
+		for id in record.new_indexes() {
+			self.columns[id.col() as usize].replace_index(*id)?;
+		}
+		for id in record.dropped_tables() {
+			log::debug!(target: "parity-db", "Dropping index {}", id);
+			self.columns[id.col() as usize].drop_index(*id)?;
+			// Check if there's another reindex on the next iteration
+			self.start_reindex(record_id);
+		}
+		self.last_enacted.store(record_id, Ordering::SeqCst);
+		self.log.end_read(cleared, record_id);
+		self.run_commit_hooks(record_id);
+		Ok(())
+	}
+
+	fn changed_values(record: &LogChange) -> Vec<(ValueTableId, u64)> {
+		record.value_changes().map(|(id, index, _)| (id, index)).collect()
+	}
+
+	fn invalidate_cached(&self, changed: &[(ValueTableId, u64)]) {
+		for (c, column) in self.columns.iter().enumerate().filter(|(_, c)| c.has_cache()) {
+			column.invalidate_cached(changed.iter()
+				.filter(|(id, _)| id.col() as usize == c)
+				.map(|(id, index)| Address::new(*index, id.size_tier())));
+		}
+	}
+
+	fn start_reindex(&self, record_id: u64) {
+		self.next_reindex.store(record_id, Ordering::SeqCst);
+	}
+
+	fn process_reindex(&self) -> Result<bool> {
+		let next_reindex = self.next_reindex.load(Ordering::SeqCst);
+		if next_reindex == 0 || next_reindex > self.last_enacted.load(Ordering::SeqCst) {
+			return Ok(false)
+		}
+		// Process any pending reindexes
+		let _plan = self.plan_lock.lock();
+		for (c, column) in self.columns.iter().enumerate() {
+			if self.bulk[c].load(Ordering::SeqCst) || self.reindex_pauses[c].load(Ordering::SeqCst) > 0 {
+				// Bulk insert completes reindexing itself. Iterators and compaction expect entries to stay put.
+				continue;
+			}
+			let (drop_index, batch) = column.reindex(&self.log)?;
+			if !batch.is_empty() || drop_index.is_some() {
+				let mut next_reindex = false;
+				let mut writer = self.log.begin_record();
+				log::debug!(
+					target: "parity-db",
+					"Creating reindex record {}",
+					writer.record_id(),
+				);
+				for (key, address) in batch.into_iter() {
+					match column.write_reindex_plan(&key, address, &mut writer)? {
+						PlanOutcome::NeedReindex => {
+							next_reindex = true
+						},
+						_ => {},
+					}
+				}
+				if let Some(table) = drop_index {
+					writer.drop_table(table);
+				}
+				let record_id = writer.record_id();
+				let l = writer.drain();
+
+				let mut logged_bytes = self.log_queue_bytes.lock();
+				let bytes = self.end_record(l)?;
+				log::debug!(
+					target: "parity-db",
+					"Created reindex record {}, {} bytes",
+					record_id,
+					bytes,
+				);
+				*logged_bytes += bytes as i64;
+				if next_reindex {
+					self.start_reindex(record_id);
+				}
+				self.signal_flush_worker();
+				return Ok(true)
+			}
+		}
+		self.next_reindex.store(0, Ordering::SeqCst);
+		Ok(false)
+	}
+
+	fn enact_logs(&self, validation_mode: bool) -> Result<bool> {
+		// Records must be enacted in order, and one at a time.
+		let _enacting = self.enact_lock.lock();
+		let cleared = {
+			let reader = match self.log.read_next(validation_mode) {
+				Ok(reader) => reader,
+				Err(Error::Corruption(_)) if validation_mode => {
 					log::debug!(target: "parity-db", "Bad log header");
 					self.log.clear_replay_logs()?;
 					return Ok(false);
@@ -508,7 +2287,7 @@ impl DbInner {
 									return Ok(false);
 								}
 							},
-							LogAction::DropTable(_) => {
+							LogAction::DropTable(_) | LogAction::ReplaceIndex(_) => {
 								continue;
 							}
 						}
@@ -516,33 +2295,40 @@ impl DbInner {
 					reader.reset()?;
 					reader.next()?;
 				}
-				loop {
-					match reader.next()? {
-						LogAction::BeginRecord => {
-							return Err(Error::Corruption("Bad log record".into()));
-						},
-						LogAction::EndRecord => {
-							break;
-						},
-						LogAction::InsertIndex(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertIndex(insertion), &mut reader)?;
+				if self.options.enact_threads > 1 {
+					self.enact_columns(&mut reader)?;
+				} else {
+					loop {
+						match reader.next()? {
+							LogAction::BeginRecord => {
+								return Err(Error::Corruption("Bad log record".into()));
+							},
+							LogAction::EndRecord => {
+								break;
+							},
+							LogAction::InsertIndex(insertion) => {
+								self.columns[insertion.table.col() as usize]
+									.enact_plan(LogAction::InsertIndex(insertion), &mut reader)?;
 
-						},
-						LogAction::InsertValue(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertValue(insertion), &mut reader)?;
+							},
+							LogAction::InsertValue(insertion) => {
+								self.columns[insertion.table.col() as usize]
+									.enact_plan(LogAction::InsertValue(insertion), &mut reader)?;
 
-						},
-						LogAction::DropTable(id) => {
-							log::debug!(
-								target: "parity-db",
-								"Dropping index {}",
-								id,
-							);
-							self.columns[id.col() as usize].drop_index(id)?;
-							// Check if there's another reindex on the next iteration
-							self.start_reindex(reader.record_id());
+							},
+							LogAction::DropTable(id) => {
+								log::debug!(
+									target: "parity-db",
+									"Dropping index {}",
+									id,
+								);
+								self.columns[id.col() as usize].drop_index(id)?;
+								// Check if there's another reindex on the next iteration
+								self.start_reindex(reader.record_id());
+							}
+							LogAction::ReplaceIndex(id) => {
+								self.columns[id.col() as usize].replace_index(id)?;
+							}
 						}
 					}
 				}
@@ -581,27 +2367,169 @@ impl DbInner {
 					*queue -= bytes as i64;
 					if *queue <= MAX_LOG_QUEUE_BYTES && (*queue + bytes as i64) > MAX_LOG_QUEUE_BYTES {
 						self.log_cv.notify_all();
+						if self.shares_thread(Worker::Log) {
+							self.signal_log_worker();
+						}
 					}
 					log::debug!(target: "parity-db", "Log queue size: {} bytes", *queue);
+					if self.overlay_stalled.load(Ordering::Relaxed)
+						&& self.options.overlay_water_marks().is_some_and(|(_, low)| self.log.overlay_bytes() <= low)
+					{
+						self.log_cv.notify_all();
+						if self.shares_thread(Worker::Log) {
+							self.signal_log_worker();
+						}
+					}
 				}
 			}
+			if !validation_mode && self.options.max_uncommitted_bytes.is_some() {
+				// Writers check the size while holding the queue lock.
+				let mut queue = self.commit_queue.lock();
+				self.commit_queue_full_cv.notify_all();
+				queue.wake_waiting();
+			}
+			if !validation_mode {
+				self.run_commit_hooks(record_id);
+			}
 			Ok(true)
 		} else {
 			Ok(false)
 		}
 	}
 
+	fn process_logs(&self, max_records: usize) -> Result<usize> {
+		let _gate = self.file_gate.read();
+		let mut enacted = 0;
+		while enacted < max_records {
+			if !self.enact_logs(false)? {
+				// Let the flush worker hand over the next log.
+				self.signal_flush_worker();
+				break;
+			}
+			enacted += 1;
+		}
+		Ok(enacted)
+	}
+
+	// Enact the rest of a log record on the enact pool, if there is one. The record is read
+	// first, then each column's changes are enacted in order on the pool thread of the column.
+	fn enact_columns(&self, reader: &mut LogReader) -> Result<()> {
+		let mut actions: Vec<ColumnActions> = self.columns.iter().map(|_| Vec::new()).collect();
+		let mut dropped = false;
+		loop {
+			let action = reader.next()?;
+			let col = match &action {
+				LogAction::BeginRecord => return Err(Error::Corruption("Bad log record".into())),
+				LogAction::EndRecord => break,
+				LogAction::InsertIndex(insertion) => insertion.table.col(),
+				LogAction::InsertValue(insertion) => insertion.table.col(),
+				LogAction::DropTable(id) => {
+					dropped = true;
+					id.col()
+				},
+				LogAction::ReplaceIndex(id) => id.col(),
+			} as usize;
+			let data = match &action {
+				LogAction::DropTable(_) | LogAction::ReplaceIndex(_) => Vec::new(),
+				action => self.columns[col].read_plan(action, reader)?,
+			};
+			actions[col].push((action, data));
+		}
+		let columns: Vec<(usize, ColumnActions)> = actions.into_iter().enumerate()
+			.filter(|(_, actions)| !actions.is_empty())
+			.collect();
+		match &self.enact_pool {
+			Some(pool) if columns.len() > 1 => {
+				let (sender, results) = std::sync::mpsc::channel();
+				let count = columns.len();
+				for (col, actions) in columns {
+					let column = self.columns[col].clone();
+					let sender = sender.clone();
+					pool.execute(col, move || {
+						let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+							Self::enact_column(&column, actions)
+						}));
+						let _ = sender.send(result);
+					});
+				}
+				let mut result = Ok(());
+				for _ in 0..count {
+					match results.recv().expect("Jobs always send a result") {
+						Ok(r) => result = result.and(r),
+						Err(panic) => std::panic::resume_unwind(panic),
+					}
+				}
+				result?;
+			},
+			_ => for (col, actions) in columns {
+				Self::enact_column(&self.columns[col], actions)?;
+			},
+		}
+		if dropped {
+			// Check if there's another reindex on the next iteration
+			self.start_reindex(reader.record_id());
+		}
+		Ok(())
+	}
+
+	fn enact_column(column: &column::Column, actions: ColumnActions) -> Result<()> {
+		for (action, data) in actions {
+			match action {
+				LogAction::DropTable(id) => {
+					log::debug!(
+						target: "parity-db",
+						"Dropping index {}",
+						id,
+					);
+					column.drop_index(id)?;
+				},
+				LogAction::ReplaceIndex(id) => column.replace_index(id)?,
+				action => column.enact_plan(action, &mut data.as_slice())?,
+			}
+		}
+		Ok(())
+	}
+
 	fn flush_logs(&self, min_log_size: u64) -> Result<bool> {
-		let (flush_next, read_next, cleanup_next) = self.log.flush_one(min_log_size)?;
+		// Records are only enacted on the commit worker, which can't make progress if it is
+		// waiting on the same thread.
+		let wait_for_reader = Worker::Flush.thread(self.options.background_threads)
+			!= Worker::Commit.thread(self.options.background_threads);
+		let (flush_next, read_next, cleanup_next) = self.log.flush_one(min_log_size, wait_for_reader)?;
 		if read_next {
 			self.signal_commit_worker();
 		}
 		if cleanup_next {
 			self.signal_cleanup_worker();
 		}
+		self.complete_synced()?;
 		Ok(flush_next)
 	}
 
+	// Sync the log that is being appended for commits waiting on it, and complete them.
+	// Records are logged before their commits wait, so a single sync covers all of them.
+	fn complete_synced(&self) -> Result<()> {
+		let waiters = std::mem::take(&mut *self.synced_waiters.lock());
+		if waiters.is_empty() {
+			return Ok(());
+		}
+		match self.log.sync_written() {
+			Ok(()) => {
+				for (record_id, completion) in waiters {
+					completion.complete(Ok(record_id));
+				}
+				Ok(())
+			},
+			Err(e) => {
+				let e = Arc::new(e);
+				for (_, completion) in waiters {
+					completion.complete(Err(Error::Background(e.clone())));
+				}
+				Err(Error::Background(e))
+			},
+		}
+	}
+
 	fn cleanup_logs(&self) -> Result<bool> {
 		let keep_logs = if self.options.sync_data { 0 } else { KEEP_LOGS };
 		let num_cleanup = self.log.num_dirty_logs();
@@ -626,10 +2554,57 @@ impl DbInner {
 		Ok(())
 	}
 
+	// Grow tables of new columns according to `Options::size_hints`.
+	fn apply_size_hints(&self) -> Result<()> {
+		for (col, hint) in self.options.size_hints.iter() {
+			if !self.columns[*col as usize].preallocate(hint)? {
+				log::debug!(target: "parity-db", "Column {}: size hint ignored, column has values", col);
+			}
+		}
+		Ok(())
+	}
+
+	// Enact all logs left by the last run. Records are read from the log files and enacted one
+	// at a time, without loading them into the log overlay, so memory use does not depend on
+	// the size of the logs.
 	fn replay_all_logs(&mut self) -> Result<()> {
+		if self.options.repair_on_replay {
+			let discarded = self.log.repair_torn_tail()?;
+			if discarded > 0 {
+				log::warn!(target: "parity-db", "Repair: Discarded {} bytes of torn log records", discarded);
+			}
+		}
+		if self.options.validate_on_replay {
+			let report = self.log.validate_all()?;
+			match &report.failure {
+				None => log::info!(
+					target: "parity-db",
+					"Validated {} log records in {} logs, {} bytes",
+					report.records,
+					report.logs,
+					report.bytes,
+				),
+				Some(failure) => log::warn!(
+					target: "parity-db",
+					"Validated {} log records, {} bytes. Bad record in log {} at {}: {}. It is discarded along with all later records",
+					report.records,
+					report.bytes,
+					failure.log,
+					failure.offset,
+					failure.error,
+				),
+			}
+		}
+		let mut records = 0;
+		let start = Instant::now();
 		while let Some(id) = self.log.replay_next()? {
 			log::debug!(target: "parity-db", "Replaying database log {}", id);
-			while self.enact_logs(true)? { }
+			while self.enact_logs(true)? {
+				records += 1;
+			}
+		}
+		if records > 0 {
+			log::info!(target: "parity-db", "Replayed {} log records in {:?}", records, start.elapsed());
 		}
 		// Re-read any cached metadata
 		for c in self.columns.iter() {
@@ -639,8 +2614,36 @@ impl DbInner {
 		Ok(())
 	}
 
+	// Load log records written by the primary instance since the last call.
+	fn try_catch_up(&self) -> Result<bool> {
+		for c in self.columns.iter() {
+			c.refresh_tables(&self.options, &self.metadata)?;
+		}
+		let columns = &self.columns;
+		let caught_up = self.log.catch_up(|action, reader, writer| {
+			let col = match &action {
+				LogAction::InsertIndex(insertion) => insertion.table.col(),
+				LogAction::InsertValue(insertion) => insertion.table.col(),
+				_ => return Err(Error::Corruption("Unexpected log action".into())),
+			};
+			match columns.get(col as usize) {
+				Some(column) => column.load_plan(action, reader, writer),
+				None => Err(Error::Corruption(format!("Bad column {}", col))),
+			}
+		})?;
+		if caught_up {
+			for c in columns.iter() {
+				c.refresh_metadata()?;
+				c.clear_cache();
+			}
+			log::debug!(target: "parity-db", "Caught up to record {}", self.log.last_record_id());
+		}
+		Ok(caught_up)
+	}
+
 	fn shutdown(&self) {
 		self.shutdown.store(true, Ordering::SeqCst);
+		self.commit_queue.lock().wake_waiting();
 		self.log_cv.notify_all();
 		self.signal_flush_worker();
 		self.signal_log_worker();
@@ -648,336 +2651,5168 @@ impl DbInner {
 		self.signal_cleanup_worker();
 	}
 
-	fn kill_logs(&self) -> Result<()> {
-		log::debug!(target: "parity-db", "Processing leftover commits");
+	// Enact all logs and queued commits. Returns `false` if the deadline expired first.
+	fn finish_logs(&self, deadline: Option<Instant>) -> Result<bool> {
+		let enact_all = || -> Result<bool> {
+			while self.enact_logs(false)? {
+				if deadline.is_some_and(|d| Instant::now() >= d) {
+					return Ok(false);
+				}
+			}
+			Ok(true)
+		};
 		// Finish logged records and proceed to log and enact queued commits.
-		while self.enact_logs(false)? {};
+		if !enact_all()? {
+			return Ok(false);
+		}
 		self.flush_logs(0)?;
 		while self.process_commits()? {};
-		while self.enact_logs(false)? {};
-		self.flush_logs(0)?;
-		while self.enact_logs(false)? {};
+		loop {
+			let more = self.flush_logs(0)?;
+			if !enact_all()? {
+				return Ok(false);
+			}
+			if !more {
+				return Ok(true);
+			}
+		}
+	}
+
+	fn kill_logs(&self, deadline: Option<Instant>) -> Result<()> {
+		log::debug!(target: "parity-db", "Processing leftover commits");
+		let completed = self.finish_logs(deadline)?;
+		if !completed {
+			// Queued commits still need to be logged. The rest is enacted on next open.
+			while self.process_commits()? {};
+			self.log.sync_appending(false)?;
+			log::warn!(target: "parity-db", "Shutdown timed out, remaining logs will be replayed on open");
+		}
 		self.clean_all_logs()?;
-		self.log.kill_logs()?;
+		if completed {
+			self.log.kill_logs()?;
+		}
 		if self.options.stats {
 			let mut path = self.options.path.clone();
 			path.push("stats.txt");
 			match std::fs::File::create(path) {
 				Ok(file) => {
 					let mut writer = std::io::BufWriter::new(file);
-					self.collect_stats(&mut writer, None)
+					self.collect_stats(&mut writer, None)?;
 				}
 				Err(e) => log::warn!(target: "parity-db", "Error creating stats file: {:?}", e),
 			}
 		}
-		Ok(())
+		Ok(())
+	}
+
+	fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) -> Result<()> {
+		if !self.options.stats {
+			return Err(Error::StatsDisabled);
+		}
+		if let Some(col) = column {
+			self.columns[col as usize].write_stats(writer);
+			self.write_trash_stats(writer, col);
+			self.write_expiry_stats(writer, col);
+		} else {
+			for (c, column) in self.user_columns().iter().enumerate() {
+				column.write_stats(writer);
+				self.write_trash_stats(writer, c as ColId);
+				self.write_expiry_stats(writer, c as ColId);
+			}
+			self.write_log_stats(writer);
+		}
+		Ok(())
+	}
+
+	fn write_log_stats(&self, writer: &mut impl std::io::Write) {
+		let _ = writeln!(
+			writer,
+			"Logs: {} dirty, {} pooled, pool size {}",
+			self.log.num_dirty_logs(),
+			self.log.num_pooled_logs(),
+			self.options.log_pool_size,
+		);
+	}
+
+	fn column_label(&self, col: ColId) -> String {
+		column::column_label(col, self.metadata.columns[col as usize].name.as_deref())
+	}
+
+	fn write_trash_stats(&self, writer: &mut impl std::io::Write, col: ColId) {
+		if self.metadata.columns[col as usize].trash.is_none() {
+			return;
+		}
+		let mut guard = self.trash[col as usize].lock();
+		match self.load_trash(col, &mut guard) {
+			Ok(trash) => {
+				let _ = writeln!(
+					writer,
+					"Column {} trash: {} values, {} bytes\n",
+					self.column_label(col),
+					trash.len(),
+					trash.bytes(),
+				);
+			},
+			Err(e) => log::warn!(target: "parity-db", "Error loading trash: {:?}", e),
+		}
+	}
+
+	fn write_expiry_stats(&self, writer: &mut impl std::io::Write, col: ColId) {
+		if self.metadata.columns[col as usize].ttl.is_none() {
+			return;
+		}
+		let expiry = self.expiry[col as usize].lock();
+		let _ = writeln!(
+			writer,
+			"Column {} expiry: {} values pending removal, {} removed\n",
+			self.column_label(col),
+			expiry.pending.len(),
+			expiry.removed,
+		);
+	}
+
+	fn clear_stats(&self, column: Option<u8>) {
+		if let Some(col) = column {
+			self.columns[col as usize].clear_stats();
+		} else {
+			for c in self.columns.iter() {
+				c.clear_stats();
+			}
+		}
+	}
+
+	fn disk_usage(&self) -> Result<DiskUsage> {
+		let mut usage = DiskUsage::default();
+		let mut files = Vec::new();
+		for entry in std::fs::read_dir(&self.options.path)? {
+			files.push(entry?);
+		}
+		files.extend(self.external_files()?);
+		for entry in files {
+			let metadata = entry.metadata()?;
+			if !metadata.is_file() {
+				continue;
+			}
+			let size = file_disk_size(&metadata);
+			usage.total += size;
+			if entry.file_name().to_str().is_some_and(is_log_file) {
+				usage.logs += size;
+			}
+		}
+		for c in self.user_columns() {
+			usage.columns.push(c.disk_usage()?);
+		}
+		Ok(usage)
+	}
+
+	fn store_err(&self, result: Result<()>) {
+		if let Err(e) = result {
+			log::warn!(target: "parity-db", "Background worker error: {}", e);
+			let e = match e {
+				Error::Background(e) => e,
+				e => Arc::new(e),
+			};
+			let mut err =  self.bg_err.lock();
+			if err.is_none() {
+				*err = Some(e.clone());
+				self.shutdown();
+			}
+			self.commit_queue_full_cv.notify_one();
+			// Queued commits won't be logged by the workers anymore.
+			let mut queue = self.commit_queue.lock();
+			queue.wake_waiting();
+			for commit in queue.commits.iter_mut() {
+				if let Some(completion) = commit.completion.take() {
+					completion.complete(Err(Error::Background(e.clone())));
+				}
+			}
+			drop(queue);
+			for (_, completion) in self.synced_waiters.lock().drain(..) {
+				completion.complete(Err(Error::Background(e.clone())));
+			}
+		}
+	}
+
+	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		self.columns[c as usize].iter_while(&self.log, f)
+	}
+
+	fn iter(&self, col: ColId) -> Result<ColumnIter<'_>> {
+		let mut iter = self.iter_stored(col, 0)?;
+		let mut queued = Vec::new();
+		for (key, (_, value)) in self.commit_overlay.read()[col as usize].iter() {
+			if let Some(value) = value {
+				if !iter.skip.contains(key) {
+					queued.push((*key, value.clone()));
+				}
+			}
+			iter.skip.insert(*key);
+		}
+		iter.queued = queued.into_iter();
+		Ok(iter)
+	}
+
+	fn iter_keys(&self, col: ColId) -> Result<KeyIter<'_>> {
+		let mut iter = self.iter(col)?;
+		// Expired values are only known from the value itself.
+		iter.keys_only = self.metadata.columns[col as usize].ttl.is_none();
+		Ok(KeyIter(iter))
+	}
+
+	fn delete_prefix(&self, col: ColId, prefix: &[u8]) -> Result<u64> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		if !self.metadata.columns[col as usize].uniform {
+			return Err(Error::InvalidInput(format!("Column {} keys are hashed and can't be matched by prefix", col)));
+		}
+		let removed: Vec<_> = self.prefix_keys(col, prefix)?.into_iter().map(|key| (col, key, None)).collect();
+		let count = removed.len() as u64;
+		if count > 0 {
+			self.commit_hashed(removed)?;
+		}
+		log::debug!(target: "parity-db", "Column {}: removing {} keys with prefix {}", col, count, hex(prefix));
+		Ok(count)
+	}
+
+	// Stored keys that start with `prefix`, for removal. An empty prefix matches all keys, others
+	// are only supported for uniform columns.
+	fn prefix_keys(&self, col: ColId, prefix: &[u8]) -> Result<Vec<Key>> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		let options = &self.metadata.columns[col as usize];
+		if !options.uniform && !prefix.is_empty() {
+			return Err(Error::InvalidInput(format!("Column {} keys are hashed and can't be matched by prefix", col)));
+		}
+		if options.ref_counted {
+			return Err(Error::InvalidInput(format!("Column {} is reference counted", col)));
+		}
+		if prefix.len() > std::mem::size_of::<Key>() {
+			return Err(Error::InvalidInput(format!(
+				"Prefix is longer than the {} bytes of the key stored in column {}",
+				std::mem::size_of::<Key>(),
+				col,
+			)));
+		}
+		let mut keys = Vec::new();
+		for key in self.iter_keys(col)? {
+			let key = key?;
+			if key.starts_with(prefix) {
+				keys.push(key);
+			}
+		}
+		Ok(keys)
+	}
+
+	fn approximate_prefix_size(&self, col: ColId, prefix: &[u8]) -> Result<u64> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		if !self.metadata.columns[col as usize].uniform {
+			return Err(Error::InvalidInput(format!("Column {} keys are hashed and can't be matched by prefix", col)));
+		}
+		if prefix.len() > std::mem::size_of::<Key>() {
+			return Err(Error::InvalidInput(format!(
+				"Prefix is longer than the {} bytes of the key stored in column {}",
+				std::mem::size_of::<Key>(),
+				col,
+			)));
+		}
+		let mut first: Key = Default::default();
+		let mut last = [0xff; 32];
+		first[.. prefix.len()].copy_from_slice(prefix);
+		last[.. prefix.len()].copy_from_slice(prefix);
+		let column = &self.columns[col as usize];
+		let _pause = self.pause_reindex(col);
+		let tables = column.index_tables();
+		let mut total = 0;
+		for (i, id) in tables.iter().enumerate() {
+			let shift = 64 - id.index_bits();
+			let start = key_position(&first) >> shift;
+			let chunks = (key_position(&last) >> shift) - start + 1;
+			let samples = chunks.min(PREFIX_SAMPLE_CHUNKS);
+			let mut sampled = 0;
+			for n in 0 .. samples {
+				// Spread evenly over the chunks the prefix falls into.
+				let chunk = start + (n as u128 * chunks as u128 / samples as u128) as u64;
+				for (key, size) in column.chunk_sizes(&self.log, *id, chunk, &tables[.. i])? {
+					if key.starts_with(prefix) {
+						sampled += size as u64;
+					}
+				}
+			}
+			total += (sampled as u128 * chunks as u128 / samples as u128) as u64;
+		}
+		Ok(total)
+	}
+
+	fn clear_column(&self, col: ColId) -> Result<()> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		// The trash is emptied by the same record.
+		let mut guard = self.metadata.columns[col as usize].trash.is_some()
+			.then(|| self.trash[col as usize].lock());
+		let mut changes = Vec::new();
+		if let Some(guard) = &mut guard {
+			let trash = self.load_trash(col, guard)?;
+			let keys: Vec<Key> = trash.keys().copied().collect();
+			for key in keys {
+				trash.take(&key);
+				let (trash_col, key) = self.trash_key(col, &key);
+				changes.push((trash_col, key, None));
+			}
+			changes.extend(self.journal_changes(col, trash));
+		}
+		let result = self.lock_queue(&changes, None)
+			.and_then(|mut queue| self.push_changes(&mut queue, changes, Some(col), None, Priority::Normal));
+		if let Err(e) = result {
+			if let Some(guard) = &mut guard {
+				// Reloaded from the journal on next use.
+				**guard = None;
+			}
+			return Err(e);
+		}
+		let mut expiry = self.expiry[col as usize].lock();
+		expiry.pending.clear();
+		expiry.next_chunk = 0;
+		log::debug!(target: "parity-db", "Column {}: queued clear", col);
+		Ok(())
+	}
+
+	// Iterate over values in the tables and the log only, starting at the given position.
+	fn iter_stored(&self, col: ColId, position: u64) -> Result<ColumnIter<'_>> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		// Reindexing is paused before the list of index tables is taken.
+		let mut iter = ColumnIter {
+			db: self,
+			col,
+			_pause: self.pause_reindex(col),
+			queued: Vec::new().into_iter(),
+			skip: HashSet::new(),
+			tables: Vec::new(),
+			table: 0,
+			chunk: 0,
+			end: None,
+			batch: Vec::new().into_iter(),
+			keys_only: false,
+		};
+		iter.tables = self.columns[col as usize].index_tables();
+		iter.seek(position);
+		Ok(iter)
+	}
+
+	fn iter_page(&self, col: ColId, cursor: Option<IterCursor>, limit: usize) -> Result<IterPage> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		let _pause = self.pause_reindex(col);
+		let mut position = cursor.map_or(0, |c| c.0);
+		// Queued commits take precedence over the tables, same as for `get`.
+		let mut queued: Vec<(Key, Option<Value>)> = self.commit_overlay.read()[col as usize].iter()
+			.filter(|(key, _)| key_position(key) >= position)
+			.map(|(key, (_, value))| (*key, value.clone()))
+			.collect();
+		queued.sort_unstable_by_key(|(key, _)| *key);
+		let mut queued = queued.into_iter().peekable();
+		let tables = self.columns[col as usize].index_tables();
+		// Pages end at chunk boundaries of the smallest table, so that chunks of all tables fall within a single range.
+		let bits = tables.iter().map(|id| id.index_bits()).min().unwrap_or(64);
+		let mut page = Vec::new();
+		loop {
+			let end = range_end(position, bits);
+			let in_range = |key: &Key| {
+				let k = key_position(key);
+				k >= position && end.map_or(true, |end| k < end)
+			};
+			let mut overlaid = HashSet::new();
+			while let Some((key, value)) = queued.next_if(|(key, _)| in_range(key)) {
+				overlaid.insert(key);
+				if let Some(value) = value {
+					if let Some(value) = self.decode_stored(col, value)? {
+						page.push((key, value));
+					}
+				}
+			}
+			for (key, value) in self.stored_range(col, &tables, position, end)? {
+				if overlaid.contains(&key) {
+					continue;
+				}
+				if let Some(value) = self.decode_stored(col, value)? {
+					page.push((key, value));
+				}
+			}
+			match end {
+				None => return Ok((page, None)),
+				Some(end) => position = end,
+			}
+			if page.len() >= limit {
+				return Ok((page, Some(IterCursor(position))));
+			}
+		}
+	}
+
+	// Stored values of keys with positions in `start .. end`, from all index tables, in no particular order.
+	fn stored_range(&self, col: ColId, tables: &[IndexTableId], start: u64, end: Option<u64>) -> Result<Vec<(Key, Value)>> {
+		let column = &self.columns[col as usize];
+		let mut entries = Vec::new();
+		for (i, id) in tables.iter().enumerate() {
+			let shift = 64 - id.index_bits();
+			let last = end.map_or(id.total_chunks(), |end| ((end - 1) >> shift) + 1);
+			for chunk in (start >> shift) .. last {
+				for (key, _rc, value) in column.chunk_entries(&self.log, *id, chunk, &tables[.. i])? {
+					let position = key_position(&key);
+					if position >= start && end.map_or(true, |end| position < end) {
+						entries.push((key, value));
+					}
+				}
+			}
+		}
+		Ok(entries)
+	}
+
+	// Check that keys of the column are stored as is, so that they can be walked in order.
+	fn check_ordered(&self, col: ColId) -> Result<()> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		if !self.metadata.columns[col as usize].uniform {
+			return Err(Error::InvalidInput(format!("Column {} keys are hashed and have no order", col)));
+		}
+		Ok(())
+	}
+
+	// Index bits of the smallest table. Ordered walks read one chunk of it at a time.
+	fn range_bits(tables: &[IndexTableId]) -> u8 {
+		tables.iter().map(|id| id.index_bits()).min().unwrap_or(64)
+	}
+
+	// Stored values of the given chunk of the smallest table, merged with queued commits,
+	// in ascending key order.
+	fn sorted_range(
+		&self,
+		col: ColId,
+		tables: &[IndexTableId],
+		range: u64,
+	) -> Result<Vec<(Key, Value)>> {
+		let bits = Self::range_bits(tables);
+		let start = range << (64 - bits);
+		let end = range_end(start, bits);
+		// Queued commits are taken first, so that none are missed while they are logged.
+		let queued: Vec<(Key, Option<Value>)> = self.commit_overlay.read()[col as usize].iter()
+			.filter(|(key, _)| key_position(key) >= start && end.map_or(true, |end| key_position(key) < end))
+			.map(|(key, (_, value))| (*key, value.clone()))
+			.collect();
+		let overlaid: HashSet<Key> = queued.iter().map(|(key, _)| *key).collect();
+		let mut entries: Vec<(Key, Value)> = queued.into_iter().filter_map(|(key, value)| value.map(|v| (key, v))).collect();
+		for (key, value) in self.stored_range(col, tables, start, end)? {
+			if !overlaid.contains(&key) {
+				entries.push((key, value));
+			}
+		}
+		entries.sort_unstable_by_key(|(key, _)| *key);
+		Ok(entries)
+	}
+
+	fn iter_rev(&self, col: ColId, seek: Option<&[u8]>) -> Result<ReverseIter<'_>> {
+		self.check_ordered(col)?;
+		let pause = self.pause_reindex(col);
+		let tables = self.columns[col as usize].index_tables();
+		let start = seek.map_or(u64::MAX, seek_position);
+		Ok(ReverseIter {
+			db: self,
+			col,
+			_pause: pause,
+			range: Some(start >> (64 - Self::range_bits(&tables))),
+			tables,
+			seek: seek.map(|s| s.to_vec()),
+			batch: Vec::new(),
+		})
+	}
+
+	// Value of the first key after `from`, or the last key before it if not `forward`.
+	fn find_ordered(&self, col: ColId, from: &[u8], inclusive: bool, forward: bool) -> Result<Option<(Key, Value)>> {
+		let _pause = self.pause_reindex(col);
+		let tables = self.columns[col as usize].index_tables();
+		let bits = Self::range_bits(&tables);
+		let mut range = seek_position(from) >> (64 - bits);
+		loop {
+			let mut entries = self.sorted_range(col, &tables, range)?;
+			if !forward {
+				entries.reverse();
+			}
+			for (key, value) in entries {
+				let matches = match key.as_slice().cmp(from) {
+					std::cmp::Ordering::Equal => inclusive,
+					std::cmp::Ordering::Greater => forward,
+					std::cmp::Ordering::Less => !forward,
+				};
+				if matches {
+					if let Some(value) = self.decode_stored(col, value)? {
+						return Ok(Some((key, value)));
+					}
+				}
+			}
+			range = match forward {
+				true if range + 1 < 1u64 << bits => range + 1,
+				false if range > 0 => range - 1,
+				_ => return Ok(None),
+			};
+		}
+	}
+
+	fn pause_reindex(&self, col: ColId) -> ReindexPause<'_> {
+		self.reindex_pauses[col as usize].fetch_add(1, Ordering::SeqCst);
+		ReindexPause { db: self, col }
+	}
+
+	fn oldest_unflushed_record_id(&self) -> Option<u64> {
+		if let Some(id) = self.log.replay_record_id() {
+			return Some(id);
+		}
+		let read = self.log.last_read_record_id();
+		(read < self.log.last_record_id()).then(|| read + 1)
+	}
+
+	fn hot_index_chunks(&self, col: ColId, count: usize) -> Result<Vec<(u64, usize)>> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		Ok(self.columns[col as usize].hot_index_chunks(count))
+	}
+
+	fn free_space(&self, col: ColId) -> Result<FreeStats> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		self.columns[col as usize].free_space(&self.log)
+	}
+
+	fn verify(&self, columns: &[ColId]) -> Result<VerifyReport> {
+		if let Some(col) = columns.iter().find(|c| **c as usize >= self.options.columns.len()) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		// Keep the tables from changing while they are walked.
+		let _enacting = self.enact_lock.lock();
+		let mut report = VerifyReport::default();
+		for (col, column) in self.user_columns().iter().enumerate() {
+			if columns.is_empty() || columns.contains(&(col as ColId)) {
+				report.columns.push(column.verify(&self.log, col as ColId)?);
+			}
+		}
+		Ok(report)
+	}
+
+	fn compact(&self, col: ColId) -> Result<u64> {
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		if self.metadata.version < 4 {
+			return Err(Error::InvalidInput(format!("Compaction is not supported for database version {}", self.metadata.version)));
+		}
+		let _pause = self.pause_reindex(col);
+		{
+			// Bulk insert checks for pauses while holding the queue lock.
+			let _queue = self.commit_queue.lock();
+			if self.bulk[col as usize].load(Ordering::SeqCst) {
+				return Err(Error::InvalidInput(format!("Bulk insert is running for column {}", col)));
+			}
+		}
+		let column = &self.columns[col as usize];
+		let mut reclaimed = 0;
+		for stats in column.free_space(&self.log)?.tables {
+			if self.shutdown.load(Ordering::SeqCst) {
+				break;
+			}
+			if stats.reclaimable == 0 {
+				continue;
+			}
+			let tier = stats.size_tier as usize;
+			let mut moved = 0;
+			let cut = match stats.free_entries {
+				0 => None,
+				// Removed entries may have been reused since.
+				_ => self.write_plan_record(|w| column.write_compaction_start_plan(tier, w))?,
+			};
+			if let Some(cut) = cut {
+				let candidates = column.compaction_candidates(&self.log, tier, cut)?;
+				for batch in candidates.chunks(COMPACTION_BATCH) {
+					if self.shutdown.load(Ordering::SeqCst) {
+						break;
+					}
+					self.write_plan_record(|w| {
+						for candidate in batch {
+							if column.write_compaction_move_plan(candidate, cut, w)? {
+								moved += 1;
+							}
+						}
+						Ok(())
+					})?;
+				}
+				// Also completes an interrupted compaction, so that no removed slots are lost.
+				self.write_plan_record(|w| column.write_compaction_end_plan(tier, cut, w))?;
+			}
+			// Changes have to be enacted before the file is truncated.
+			let record_id = self.log.last_record_id();
+			while self.last_enacted.load(Ordering::SeqCst) < record_id {
+				if self.shutdown.load(Ordering::SeqCst) {
+					return Ok(reclaimed);
+				}
+				{
+					// Small logs are not flushed by the worker.
+					let _gate = self.file_gate.read();
+					self.flush_logs(0)?;
+				}
+				std::thread::sleep(std::time::Duration::from_millis(10));
+			}
+			let _gate = self.file_gate.write();
+			let bytes = column.shrink_value_table(tier)?;
+			if moved > 0 || bytes > 0 {
+				log::debug!(target: "parity-db", "Column {}: compacted tier {}, moved {} values, {} bytes reclaimed", col, tier, moved, bytes);
+			}
+			reclaimed += bytes;
+		}
+		Ok(reclaimed)
+	}
+
+	fn repair(&self, col: ColId, key_of: &mut dyn FnMut(&[u8]) -> Option<Vec<u8>>, force: bool) -> Result<RepairSummary> {
+		if self.secondary {
+			return Err(Error::InvalidInput("Secondary instance is read only".into()));
+		}
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		let column = &self.columns[col as usize];
+		self.wait_enacted(col)?;
+		let report = {
+			let _enacting = self.enact_lock.lock();
+			column.verify(&self.log, col)?
+		};
+		if report.is_ok() && !force {
+			return Err(Error::InvalidInput(format!("Index of column {} is consistent, repair has to be forced", col)));
+		}
+		log::info!(target: "parity-db", "Column {}: rebuilding index, {:?}", col, report);
+		// Commits to the column are rejected meanwhile, same as for a bulk insert.
+		self.start_bulk(col)?;
+		let result = self.rebuild_index(col, key_of);
+		self.bulk[col as usize].store(false, Ordering::SeqCst);
+		result
+	}
+
+	fn rebuild_index(&self, col: ColId, key_of: &mut dyn FnMut(&[u8]) -> Option<Vec<u8>>) -> Result<RepairSummary> {
+		let column = &self.columns[col as usize];
+		// Reindex records written before the flag was set.
+		self.wait_enacted(col)?;
+		let ttl = self.metadata.columns[col as usize].ttl.is_some();
+		let (entries, skipped) = column.recover_index_entries(&self.log, |value| {
+			let value = if ttl { ttl::strip_value(&value).ok()? } else { &value[..] };
+			key_of(value).and_then(|key| column.derive_key(&key).ok())
+		})?;
+		{
+			let _plan = self.plan_lock.lock();
+			column.reset_index(entries.len() as u64)?;
+		}
+		let mut indexed = 0;
+		for batch in entries.chunks(REPAIR_BATCH) {
+			let reindex = self.write_plan_record(|w| {
+				let mut reindex = false;
+				for (key, address) in batch {
+					if let PlanOutcome::NeedReindex = column.write_reindex_plan(key, *address, w)? {
+						reindex = true;
+					}
+				}
+				Ok(reindex)
+			})?;
+			if reindex {
+				self.start_reindex(self.log.last_record_id());
+			}
+			indexed += batch.len() as u64;
+			log::info!(target: "parity-db", "Column {}: indexed {}/{} values", col, indexed, entries.len());
+		}
+		self.wait_enacted(col)?;
+		Ok(RepairSummary { indexed, skipped })
+	}
+
+	// Wait for logged changes to the column to be enacted.
+	fn wait_enacted(&self, col: ColId) -> Result<()> {
+		while self.log.has_changes(col) {
+			if self.shutdown.load(Ordering::SeqCst) {
+				return Err(Error::InvalidInput("Database is shutting down".into()));
+			}
+			{
+				// Small logs are not flushed by the worker.
+				let _gate = self.file_gate.read();
+				self.flush_logs(0)?;
+			}
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		Ok(())
+	}
+
+	// Plan a record that is not part of a commit.
+	fn write_plan_record<R>(&self, plan: impl FnOnce(&mut LogWriter) -> Result<R>) -> Result<R> {
+		let _plan = self.plan_lock.lock();
+		let mut writer = self.log.begin_record();
+		let result = match plan(&mut writer) {
+			Ok(result) => result,
+			Err(e) => {
+				// The record id is taken and planned changes can't be undone.
+				self.store_err(Err(e));
+				let e = self.bg_err.lock().clone().expect("Error is stored above");
+				return Err(Error::Background(e));
+			}
+		};
+		for c in self.columns.iter() {
+			c.complete_plan(&mut writer)?;
+		}
+		let bytes = self.end_record(writer.drain())?;
+		*self.log_queue_bytes.lock() += bytes as i64;
+		self.signal_flush_worker();
+		Ok(result)
+	}
+
+	fn import(&self, col: ColId, reader: &mut dyn std::io::Read, options: &ImportOptions) -> Result<ImportSummary> {
+		if self.secondary {
+			return Err(Error::InvalidInput("Secondary instance is read only".into()));
+		}
+		if col as usize >= self.options.columns.len() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)));
+		}
+		let mut dump = export::DumpReader::new(reader);
+		let header = dump.read_header()?;
+		let column = &self.metadata.columns[col as usize];
+		if header.ref_counted != column.ref_counted || header.uniform != column.uniform || header.ttl != column.ttl.is_some() {
+			return Err(Error::InvalidInput(format!("Dump of column {} does not match options of column {}", header.col, col)));
+		}
+		if !header.uniform && Some(header.salt) != self.metadata.salt {
+			return Err(Error::InvalidInput("Dump keys are hashed with a different salt".into()));
+		}
+		if column.ref_counted && options.on_conflict == OnConflict::Overwrite {
+			return Err(Error::InvalidInput(format!("Can't overwrite values in reference counted column {}", col)));
+		}
+		// An empty column is written directly, same as with `bulk_insert`.
+		let bulk = self.start_bulk(col).is_ok() && {
+			let empty = self.columns[col as usize].is_empty();
+			if !empty {
+				self.bulk[col as usize].store(false, Ordering::SeqCst);
+			}
+			empty
+		};
+		let result = self.import_entries(col, &mut dump, options, bulk);
+		if bulk {
+			self.bulk[col as usize].store(false, Ordering::SeqCst);
+		}
+		let summary = result?;
+		log::debug!(
+			target: "parity-db",
+			"Column {}: imported {} values, {} skipped, {} replaced",
+			col,
+			summary.entries,
+			summary.skipped,
+			summary.replaced,
+		);
+		Ok(summary)
+	}
+
+	fn import_entries(
+		&self,
+		col: ColId,
+		dump: &mut export::DumpReader<&mut dyn std::io::Read>,
+		options: &ImportOptions,
+		bulk: bool,
+	) -> Result<ImportSummary> {
+		let mut summary = ImportSummary::default();
+		let mut batch = Vec::new();
+		let mut bytes = 0;
+		while let Some((key, rc, value)) = dump.next_entry()? {
+			if self.shutdown.load(Ordering::Relaxed) {
+				return Err(Error::InvalidInput("Database is shutting down".into()));
+			}
+			if rc == 0 {
+				return Err(Error::Corruption(format!("Dump entry {} has no references", hex(&key))));
+			}
+			if value.len() > MAX_VALUE_SIZE {
+				return Err(Error::InvalidInput(format!("Value in column {} exceeds maximum size of {} bytes", col, MAX_VALUE_SIZE)));
+			}
+			if !bulk && self.get_stored(col, &key)?.is_some() {
+				match options.on_conflict {
+					OnConflict::Error => return Err(Error::InvalidInput(format!("Key {} is already in column {}", hex(&key), col))),
+					OnConflict::Skip => {
+						summary.skipped += 1;
+						continue;
+					},
+					OnConflict::Overwrite => summary.replaced += 1,
+				}
+			}
+			summary.entries += 1;
+			summary.value_bytes += value.len() as u64;
+			bytes += key.len() + value.len();
+			batch.push((col, key, Some(value)));
+			// Committing a key again adds a reference.
+			for _ in 1 .. rc {
+				batch.push((col, key, Some(Vec::new())));
+			}
+			if bytes >= IMPORT_BATCH_BYTES {
+				self.import_batch(col, std::mem::take(&mut batch), bulk)?;
+				bytes = 0;
+			}
+		}
+		if !batch.is_empty() {
+			self.import_batch(col, batch, bulk)?;
+		}
+		Ok(summary)
+	}
+
+	// Values are imported as stored, with TTL insertion times already in place.
+	fn import_batch(&self, col: ColId, batch: Vec<(ColId, Key, Option<Value>)>, bulk: bool) -> Result<()> {
+		if bulk {
+			self.write_unlogged(col, batch)?;
+			self.columns[col as usize].flush()
+		} else {
+			self.commit_hashed(batch)
+		}
+	}
+
+	fn export(&self, col: ColId, writer: &mut dyn std::io::Write, options: &ExportOptions) -> Result<ExportSummary> {
+		let start = Instant::now();
+		let mut iter = self.iter_stored(col, options.start_chunk)?;
+		iter.end = options.chunks.map(|n| options.start_chunk.saturating_add(n));
+		let column = &self.metadata.columns[col as usize];
+		let header = export::Header {
+			col,
+			ref_counted: column.ref_counted,
+			uniform: column.uniform,
+			ttl: column.ttl.is_some(),
+			salt: self.metadata.salt.unwrap_or_default(),
+			start_chunk: iter.chunk_position(),
+		};
+		let mut dump = export::DumpWriter::new(writer);
+		dump.write_header(&header)?;
+		let mut summary = ExportSummary::default();
+		while let Some(entry) = iter.next_stored() {
+			if self.shutdown.load(Ordering::Relaxed) {
+				return Err(Error::InvalidInput("Database is shutting down".into()));
+			}
+			let (key, rc, value) = entry?;
+			dump.write_entry(&key, rc, &value)?;
+			summary.entries += 1;
+			summary.value_bytes += value.len() as u64;
+			if let Some(rate) = options.max_bytes_per_sec {
+				// Sleep until the average rate is back within the limit.
+				let due = std::time::Duration::from_secs_f64(dump.bytes() as f64 / rate.max(1) as f64);
+				if let Some(wait) = due.checked_sub(start.elapsed()) {
+					std::thread::sleep(wait);
+				}
+			}
+		}
+		summary.next_chunk = iter.chunk_position();
+		summary.complete = iter.complete();
+		summary.bytes = dump.finish(&summary)?;
+		log::debug!(
+			target: "parity-db",
+			"Column {}: exported {} values, {} bytes, chunks {}..{}",
+			col,
+			summary.entries,
+			summary.bytes,
+			header.start_chunk,
+			summary.next_chunk,
+		);
+		Ok(summary)
+	}
+}
+
+// Position of the key in the index, the same for all index table sizes.
+fn key_position(key: &Key) -> u64 {
+	u64::from_be_bytes(key[0..8].try_into().unwrap())
+}
+
+// Position to start an ordered walk at. Keys before or after `seek` are on the same side of it.
+fn seek_position(seek: &[u8]) -> u64 {
+	let mut padded = [0u8; 8];
+	let len = seek.len().min(8);
+	padded[.. len].copy_from_slice(&seek[.. len]);
+	u64::from_be_bytes(padded)
+}
+
+// End of the chunk of a table with `bits` index bits that holds `position`, or `None` for the last one.
+fn range_end(position: u64, bits: u8) -> Option<u64> {
+	let chunk = position >> (64 - bits);
+	(chunk + 1 < 1u64 << bits).then(|| (chunk + 1) << (64 - bits))
+}
+
+/// Position to resume paged iteration at, see `Db::iter_from`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IterCursor(u64);
+
+impl IterCursor {
+	pub fn to_bytes(&self) -> [u8; 8] {
+		self.0.to_be_bytes()
+	}
+
+	pub fn from_bytes(bytes: [u8; 8]) -> IterCursor {
+		IterCursor(u64::from_be_bytes(bytes))
+	}
+}
+
+// Iterator over all values of a column, see `Db::iter`.
+struct ColumnIter<'a> {
+	db: &'a DbInner,
+	col: ColId,
+	_pause: ReindexPause<'a>,
+	// Values in the commit queue, that are not in the log yet.
+	queued: std::vec::IntoIter<(Key, Value)>,
+	// Keys that are not taken from the tables, because they are queued.
+	skip: HashSet<Key>,
+	// Index tables in lookup order.
+	tables: Vec<IndexTableId>,
+	table: usize,
+	chunk: u64,
+	// Position to stop at.
+	end: Option<u64>,
+	batch: std::vec::IntoIter<(Key, u32, Value)>,
+	// Only read keys from the tables. Stored entries are returned with an empty value.
+	keys_only: bool,
+}
+
+impl<'a> ColumnIter<'a> {
+	// Position is a chunk index across all index tables, in lookup order.
+	fn seek(&mut self, mut position: u64) {
+		self.table = 0;
+		while let Some(id) = self.tables.get(self.table) {
+			if position < id.total_chunks() {
+				break;
+			}
+			position -= id.total_chunks();
+			self.table += 1;
+		}
+		self.chunk = position;
+	}
+
+	// Position of the next chunk to read.
+	fn chunk_position(&self) -> u64 {
+		self.tables[.. self.table].iter().map(|id| id.total_chunks()).sum::<u64>() + self.chunk
+	}
+
+	fn complete(&self) -> bool {
+		self.table == self.tables.len()
+	}
+
+	// Next entry from the tables and the log, as stored, with the reference count.
+	fn next_stored(&mut self) -> Option<Result<(Key, u32, Value)>> {
+		loop {
+			if let Some(entry) = self.batch.next() {
+				return Some(Ok(entry));
+			}
+			if self.end.is_some_and(|end| self.chunk_position() >= end) {
+				return None;
+			}
+			let table = *self.tables.get(self.table)?;
+			if self.chunk == table.total_chunks() {
+				self.table += 1;
+				self.chunk = 0;
+				continue;
+			}
+			let column = &self.db.columns[self.col as usize];
+			let newer = &self.tables[.. self.table];
+			let entries = if self.keys_only {
+				column.chunk_keys(&self.db.log, table, self.chunk, newer)
+					.map(|keys| keys.into_iter().map(|k| (k, 0, Vec::new())).collect())
+			} else {
+				column.chunk_entries(&self.db.log, table, self.chunk, newer)
+			};
+			match entries {
+				Ok(mut entries) => {
+					entries.retain(|(k, _, _)| !self.skip.contains(k));
+					self.batch = entries.into_iter();
+				},
+				Err(e) => {
+					self.table = self.tables.len();
+					return Some(Err(e));
+				},
+			}
+			self.chunk += 1;
+		}
+	}
+}
+
+impl<'a> Iterator for ColumnIter<'a> {
+	type Item = Result<(Key, Value)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let (key, value) = match self.queued.next() {
+				Some(entry) => entry,
+				None => match self.next_stored()? {
+					Ok((key, _rc, value)) => (key, value),
+					Err(e) => return Some(Err(e)),
+				},
+			};
+			match self.db.decode_stored(self.col, value) {
+				Ok(Some(value)) => return Some(Ok((key, value))),
+				Ok(None) => continue,
+				Err(e) => return Some(Err(e)),
+			}
+		}
+	}
+}
+
+// Iterator over the values of a uniform column in descending key order, see `Db::iter_rev`.
+struct ReverseIter<'a> {
+	db: &'a DbInner,
+	col: ColId,
+	_pause: ReindexPause<'a>,
+	tables: Vec<IndexTableId>,
+	// Next chunk of the smallest table to read.
+	range: Option<u64>,
+	// Keys greater than this are not returned.
+	seek: Option<Vec<u8>>,
+	// Values of the last chunk read in ascending key order, taken from the back.
+	batch: Vec<(Key, Value)>,
+}
+
+impl<'a> ReverseIter<'a> {
+	fn read_range(&mut self, range: u64) -> Result<()> {
+		self.batch = self.db.sorted_range(self.col, &self.tables, range)?;
+		if let Some(seek) = &self.seek {
+			self.batch.retain(|(key, _)| key.as_slice() <= seek.as_slice());
+		}
+		Ok(())
+	}
+}
+
+impl<'a> Iterator for ReverseIter<'a> {
+	type Item = Result<(Key, Value)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some((key, value)) = self.batch.pop() {
+				match self.db.decode_stored(self.col, value) {
+					Ok(Some(value)) => return Some(Ok((key, value))),
+					Ok(None) => continue,
+					Err(e) => return Some(Err(e)),
+				}
+			}
+			let range = self.range?;
+			self.range = range.checked_sub(1);
+			if let Err(e) = self.read_range(range) {
+				self.range = None;
+				return Some(Err(e));
+			}
+		}
+	}
+}
+
+/// Position in a uniform column, in key order. See `Db::cursor`.
+///
+/// The cursor only holds the current key and value. Each move reads the tables and queued
+/// commits again, so it continues from the current key even if that key has been removed
+/// since. A cursor starts unpositioned, and becomes unpositioned when it moves past either
+/// end of the column.
+pub struct Cursor<'a> {
+	db: &'a DbInner,
+	col: ColId,
+	current: Option<(Key, Value)>,
+}
+
+impl<'a> Cursor<'a> {
+	/// Move to the first key that is greater than or equal to `key`. Returns `false` if there is none.
+	pub fn seek(&mut self, key: &[u8]) -> Result<bool> {
+		self.current = self.db.find_ordered(self.col, key, true, true)?;
+		Ok(self.current.is_some())
+	}
+
+	/// Move to the last key that is less than or equal to `key`. Returns `false` if there is none.
+	pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool> {
+		self.current = self.db.find_ordered(self.col, key, true, false)?;
+		Ok(self.current.is_some())
+	}
+
+	/// Move to the first key of the column.
+	pub fn seek_to_first(&mut self) -> Result<bool> {
+		self.seek(&[])
+	}
+
+	/// Move to the last key of the column.
+	pub fn seek_to_last(&mut self) -> Result<bool> {
+		self.seek_for_prev(&[0xff; 33])
+	}
+
+	/// Move to the next key. Returns `false`, and leaves the cursor unpositioned, if there is none.
+	#[allow(clippy::should_implement_trait)]
+	pub fn next(&mut self) -> Result<bool> {
+		self.step(true)
+	}
+
+	/// Move to the previous key. Returns `false`, and leaves the cursor unpositioned, if there is none.
+	pub fn prev(&mut self) -> Result<bool> {
+		self.step(false)
+	}
+
+	fn step(&mut self, forward: bool) -> Result<bool> {
+		let key = match &self.current {
+			Some((key, _)) => *key,
+			None => return Ok(false),
+		};
+		self.current = self.db.find_ordered(self.col, &key, false, forward)?;
+		Ok(self.current.is_some())
+	}
+
+	/// Key at the cursor, as stored.
+	pub fn key(&self) -> Option<&Key> {
+		self.current.as_ref().map(|(key, _)| key)
+	}
+
+	/// Value at the cursor, as of when the cursor moved there.
+	pub fn value(&self) -> Option<&Value> {
+		self.current.as_ref().map(|(_, value)| value)
+	}
+}
+
+struct KeyIter<'a>(ColumnIter<'a>);
+
+impl<'a> Iterator for KeyIter<'a> {
+	type Item = Result<Key>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if !self.0.keys_only {
+			return self.0.next().map(|r| r.map(|(key, _)| key));
+		}
+		if let Some((key, _)) = self.0.queued.next() {
+			return Some(Ok(key));
+		}
+		self.0.next_stored().map(|r| r.map(|(key, _, _)| key))
+	}
+}
+
+// Reindexing of the column is paused while this is alive.
+struct ReindexPause<'a> {
+	db: &'a DbInner,
+	col: ColId,
+}
+
+impl<'a> Drop for ReindexPause<'a> {
+	fn drop(&mut self) {
+		self.db.reindex_pauses[self.col as usize].fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+// Background worker. Workers are spread over `Options::background_threads` threads.
+//
+// - Log: writes queued commits to the log, plans reindexing and expiry.
+// - Flush: rotates and syncs log files, passing complete logs to the commit worker.
+// - Commit: enacts logged records into the tables.
+// - Cleanup: recycles enacted log files.
+//
+// Each stage runs on one thread at a time. Log records are written and then read back in
+// order, by a single writer and a single reader (see `Log::read_next`), so neither logging
+// nor reading records can be split across threads. Once a record is read, the commit worker
+// may enact its columns on up to `Options::enact_threads` threads. The stages themselves run
+// concurrently: a log file is written while the previous one is enacted and an older one is
+// cleaned up. Threads are named after the workers they run, e.g. `parity-db-log`.
+// Log records of `PARALLEL_CRC_THRESHOLD` bytes or more are also checksummed on up to 8
+// `parity-db-crc` threads, started with the first such record.
+// A worker that shares a thread does not block on the stage after it: the log worker does
+// not wait for the log queue to drain and the flush worker does not wait for the reader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Worker {
+	Log,
+	Flush,
+	Commit,
+	Cleanup,
+}
+
+impl Worker {
+	const ALL: [Worker; 4] = [Worker::Log, Worker::Flush, Worker::Commit, Worker::Cleanup];
+
+	// Thread that runs the worker. The log worker keeps its own thread as long as there
+	// are at least two, followed by the commit worker.
+	fn thread(self, threads: usize) -> usize {
+		let thread = match self {
+			Worker::Log => 0,
+			Worker::Commit => 1,
+			Worker::Flush => 2,
+			Worker::Cleanup => 3,
+		};
+		std::cmp::min(thread, threads - 1)
+	}
+
+	fn name(self) -> &'static str {
+		match self {
+			Worker::Log => "log",
+			Worker::Flush => "flush",
+			Worker::Commit => "commit",
+			Worker::Cleanup => "cleanup",
+		}
+	}
+
+	// Name of a thread running `workers`, such as `parity-db-flush-commit` when it runs both.
+	fn thread_name(workers: &[Worker]) -> String {
+		let names: Vec<&str> = workers.iter().map(|w| w.name()).collect();
+		format!("parity-db-{}", names.join("-"))
+	}
+
+	fn interval(self, db: &DbInner) -> Option<std::time::Duration> {
+		match self {
+			Worker::Log => db.metadata.columns.iter().any(|c| c.ttl.is_some()).then_some(EXPIRY_SCAN_INTERVAL),
+			Worker::Flush => db.log.sync_interval(),
+			Worker::Cleanup => db.trash_col.map(|_| TRASH_EXPIRY_INTERVAL),
+			_ => None,
+		}
+	}
+
+	// Returns `true` if there's more work to do right away.
+	fn run(self, db: &DbInner) -> Result<bool> {
+		if self != Worker::Log && db.paused.load(Ordering::SeqCst) {
+			return Ok(false);
+		}
+		let _gate = db.file_gate.read();
+		match self {
+			Worker::Log => {
+				let more_commits = db.process_commits()?;
+				let more_reindex = db.process_reindex()?;
+				let more_expiry = db.process_expiry()?;
+				Ok(more_commits || more_reindex || more_expiry)
+			},
+			Worker::Flush => {
+				let min_log_size = if db.force_flush.swap(false, Ordering::SeqCst) { 0 } else { MIN_LOG_SIZE };
+				db.flush_logs(min_log_size)
+			},
+			Worker::Commit => db.enact_logs(false),
+			Worker::Cleanup => {
+				let more_cleanup = db.cleanup_logs()?;
+				db.purge_trash(false)?;
+				Ok(more_cleanup)
+			},
+		}
+	}
+}
+
+#[derive(Default)]
+struct WorkSignal {
+	work: Mutex<bool>,
+	cv: Condvar,
+}
+
+pub struct Db {
+	inner: Arc<DbInner>,
+	// Background threads. Empty for read only databases.
+	threads: Vec<std::thread::JoinHandle<()>>,
+	closed: bool,
+}
+
+impl Db {
+	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Result<Db> {
+		let options = Options::with_columns(path, num_columns);
+
+		Self::open_inner(&options, true, false)
+	}
+
+	/// Open the database with given options.
+	pub fn open(options: &Options) -> Result<Db> {
+		Self::open_inner(options, false, false)
+	}
+
+	/// Create the database using given options.
+	pub fn open_or_create(options: &Options) -> Result<Db> {
+		Self::open_inner(options, true, false)
+	}
+
+	pub fn open_read_only(options: &Options) -> Result<Db> {
+		Self::open_inner(options, false, true)
+	}
+
+	/// Open a database that is being written by another process, for reading only.
+	/// No lock is taken and no files are modified. Changes made by the primary instance
+	/// become visible after `try_catch_up`.
+	pub fn open_secondary(options: &Options) -> Result<Db> {
+		options.validate()?;
+		let db = DbInner::open(options, false, true)?;
+		db.try_catch_up()?;
+		Ok(Db {
+			inner: Arc::new(db),
+			threads: Vec::new(),
+			closed: false,
+		})
+	}
+
+	/// Remove the database at `path`, along with the directory. Fails if the database is
+	/// open, or if the directory contains files that don't belong to the database; nothing
+	/// is removed then. Succeeds if the directory does not exist.
+	///
+	/// Only files in `path` are removed. Logs kept elsewhere with `Options::wal_path`, and
+	/// tables of columns with their own `ColumnOptions::path`, are left in place.
+	pub fn drop_database(path: &std::path::Path) -> Result<()> {
+		if !path.exists() {
+			return Ok(());
+		}
+		let lock_path = path.join("lock");
+		let lock_file = lock_database(&lock_path)?;
+		let mut files = Vec::new();
+		for entry in std::fs::read_dir(path)? {
+			let entry = entry?;
+			let name = entry.file_name();
+			if name == "lock" {
+				continue;
+			}
+			if !entry.file_type()?.is_file() || !name.to_str().is_some_and(is_database_file) {
+				return Err(Error::InvalidInput(format!(
+					"Unrecognized file {} in database directory",
+					entry.path().display(),
+				)));
+			}
+			files.push(entry.path());
+		}
+		// Tables first, so that an interrupted drop doesn't leave metadata without them.
+		files.sort_by_key(|p| p.file_name().is_some_and(|n| n == "metadata"));
+		for file in files {
+			std::fs::remove_file(file)?;
+		}
+		std::mem::drop(lock_file);
+		std::fs::remove_file(&lock_path)?;
+		std::fs::remove_dir(path)?;
+		log::info!(target: "parity-db", "Dropped database {}", path.display());
+		Ok(())
+	}
+
+	/// Check the logs that would be replayed on open, without enacting them.
+	/// Fails if the database is open.
+	pub fn validate_logs(options: &Options) -> Result<ReplayReport> {
+		let mut lock_path: std::path::PathBuf = options.path.clone();
+		lock_path.push("lock");
+		let _lock_file = lock_database(&lock_path)?;
+		Log::open_read_only(options)?.validate_all()
+	}
+
+	/// Remove the logs of a database that is not open, losing all changes that are not enacted
+	/// into the tables yet. This is a last resort for logs that are corrupted beyond repair, when
+	/// the tables are intact. Requires `Options::allow_wal_discard`. Returns the number of log
+	/// files removed.
+	pub fn discard_wal(options: &Options) -> Result<usize> {
+		if !options.allow_wal_discard {
+			return Err(Error::InvalidInput("Discarding logs is not allowed by the options".into()));
+		}
+		let mut lock_path: std::path::PathBuf = options.path.clone();
+		lock_path.push("lock");
+		let _lock_file = lock_database(&lock_path)?;
+		let discarded = Log::discard_all(options.log_dir())?;
+		if discarded > 0 {
+			log::warn!(
+				target: "parity-db",
+				"Discarded {} log files in {}. Changes that were not enacted are lost",
+				discarded,
+				options.log_dir().display(),
+			);
+		}
+		Ok(discarded)
+	}
+
+	pub fn open_inner(options: &Options, create: bool, read_only: bool) -> Result<Db> {
+		options.validate()?;
+		let mut db = DbInner::open(options, create, false)?;
+		// This needs to be call before log thread: so first reindexing
+		// will run in correct state.
+		db.replay_all_logs()?;
+		if !read_only {
+			db.apply_size_hints()?;
+		}
+		let db = Arc::new(db);
+		if read_only {
+			return Ok(Db {
+				inner: db,
+				threads: Vec::new(),
+				closed: false,
+			})
+		}
+		let mut result = Db {
+			inner: db,
+			threads: Vec::new(),
+			closed: false,
+		};
+		for thread in 0..options.background_threads {
+			let workers: Vec<Worker> = Worker::ALL.iter().copied()
+				.filter(|w| w.thread(options.background_threads) == thread)
+				.collect();
+			let worker_db = result.inner.clone();
+			// If spawning fails, threads that are already running are joined on drop.
+			let handle = std::thread::Builder::new()
+				.name(Worker::thread_name(&workers))
+				.spawn(move ||
+					worker_db.store_err(Self::worker_thread(worker_db.clone(), thread, workers))
+				)?;
+			result.threads.push(handle);
+		}
+		Ok(result)
+	}
+
+	/// Query a value. Keys of any length are hashed into the 32-byte index key space,
+	/// except for `uniform` columns, that use the first 32 bytes of the key as is.
+	pub fn get(&self, col: impl Into<ColId>, key: &[u8]) -> Result<Option<Value>> {
+		let col = col.into();
+		let value = self.inner.get(col, key)?;
+		self.inner.count_get(col, &value);
+		Ok(value)
+	}
+
+	/// Same as `get`. Values found in the column cache are returned without copying.
+	/// See `ColumnOptions::cache_size_bytes`.
+	pub fn get_shared(&self, col: impl Into<ColId>, key: &[u8]) -> Result<Option<Arc<Value>>> {
+		let col = col.into();
+		let value = self.inner.get_shared(col, key)?;
+		self.inner.count_get(col, &value);
+		Ok(value)
+	}
+
+	pub fn get_size(&self, col: impl Into<ColId>, key: &[u8]) -> Result<Option<u32>> {
+		let col = col.into();
+		let size = self.inner.get_size(col, key)?;
+		self.inner.count_get(col, &size);
+		Ok(size)
+	}
+
+	/// Check which of `keys` have a value, in the order of `keys`. Queued commits are taken
+	/// into account. Only the index and value entry headers are read, except for TTL columns,
+	/// where values are read to check expiry.
+	pub fn exists_many<I, K>(&self, col: impl Into<ColId>, keys: I) -> Result<Vec<bool>>
+	where
+		I: IntoIterator<Item=K>,
+		K: AsRef<[u8]>,
+	{
+		let col = col.into();
+		let found = self.inner.exists_many(col, keys)?;
+		for exists in &found {
+			self.inner.count_get(col, &exists.then_some(()));
+		}
+		Ok(found)
+	}
+
+	/// Bytes `offset .. offset + len` of the value, clamped to the value size. Returns an empty
+	/// value if `offset` is past the end, and `None` if there is no value. Only the requested
+	/// bytes are read, except for compressed and TTL columns, where the whole value is read
+	/// and then sliced.
+	pub fn get_range(&self, col: impl Into<ColId>, key: &[u8], offset: usize, len: usize) -> Result<Option<Value>> {
+		let col = col.into();
+		let value = self.inner.get_range(col, key, offset, len)?;
+		self.inner.count_get(col, &value);
+		Ok(value)
+	}
+
+	/// Largest value, as stored after compression, that fits a single value table entry of the
+	/// column. Larger values are split into linked parts, see `ValueMeta::parts`. `None` if
+	/// there is no such column.
+	pub fn max_inline_value_size(&self, col: ColId) -> Option<u32> {
+		self.inner.columns.get(col as usize).map(|c| c.max_inline_size())
+	}
+
+	/// Get the value along with details of how it is stored. Queued commits that
+	/// change the key are written to the log first.
+	pub fn get_with_meta(&self, col: ColId, key: &[u8]) -> Result<Option<(Value, ValueMeta)>> {
+		let key = self.inner.columns[col as usize].hash_key(key)?;
+		if self.inner.is_queued(col, &key) {
+			self.flush()?;
+		}
+		self.inner.get_with_meta(col, &key)
+	}
+
+	/// Query a value of a column that takes raw keys. See `ColumnOptions::key_mode`.
+	/// Keys of any length are hashed with the salt into the 32-byte index key space.
+	pub fn get_raw(&self, col: impl Into<ColId>, key: &[u8]) -> Result<Option<Value>> {
+		let col = col.into();
+		let value = self.inner.get_raw(col, key)?;
+		self.inner.count_get(col, &value);
+		Ok(value)
+	}
+
+	/// Same as `commit`, for columns that take raw keys. See `ColumnOptions::key_mode`.
+	pub fn commit_raw<I, C, K>(&self, tx: I) -> Result<()>
+	where
+		I: IntoIterator<Item=(C, K, Option<Value>)>,
+		C: Into<ColId>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_raw(tx.into_iter().map(|(c, k, v)| (c.into(), k, v)))
+	}
+
+	/// Insert and remove values. `None` removes the key, or drops a reference
+	/// to it in reference counted columns.
+	///
+	/// Changes of a commit become visible to reads all at once, when this returns. Commits
+	/// are applied in the order they were queued, and once a read sees a commit, later
+	/// reads never see the state before it. So a reader that sees one change of a commit
+	/// sees all of its changes in later reads.
+	pub fn commit<I, C, K>(&self, tx: I) -> Result<()>
+	where
+		I: IntoIterator<Item=(C, K, Option<Value>)>,
+		C: Into<ColId>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit(tx.into_iter().map(|(c, k, v)| (c.into(), k, v)))
+	}
+
+	/// Same as `commit`, with the given priority. Reads see the changes right away,
+	/// regardless of the priority.
+	pub fn commit_with_priority<I, C, K>(&self, tx: I, priority: Priority) -> Result<()>
+	where
+		I: IntoIterator<Item=(C, K, Option<Value>)>,
+		C: Into<ColId>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_with_priority(tx.into_iter().map(|(c, k, v)| (c.into(), k, v)), priority)
+	}
+
+	/// Same as `commit`, but returns `Ok(false)` without committing anything if the commit
+	/// queue is full, instead of waiting for it to drain.
+	pub fn try_commit<I, C, K>(&self, tx: I) -> Result<bool>
+	where
+		I: IntoIterator<Item=(C, K, Option<Value>)>,
+		C: Into<ColId>,
+		K: AsRef<[u8]>,
+	{
+		match self.commit_timeout(tx, std::time::Duration::ZERO) {
+			Ok(()) => Ok(true),
+			Err(Error::QueueFull) => Ok(false),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Same as `commit`, but fails with `Error::QueueFull` without committing anything if
+	/// the commit queue is still full after `timeout`.
+	pub fn commit_timeout<I, C, K>(&self, tx: I, timeout: std::time::Duration) -> Result<()>
+	where
+		I: IntoIterator<Item=(C, K, Option<Value>)>,
+		C: Into<ColId>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_until(tx.into_iter().map(|(c, k, v)| (c.into(), k, v)), Instant::now() + timeout)
+	}
+
+	/// Commit `new` for the key if its current value is `expected`, where `None` stands for
+	/// a missing key. Queued and logged commits are taken into account, and no other commit
+	/// is queued between the check and the change. Not supported for reference counted
+	/// columns and columns with trash.
+	pub fn cas(&self, col: ColId, key: &[u8], expected: Option<&[u8]>, new: Option<Value>) -> Result<CasOutcome> {
+		self.inner.cas(col, key, expected, new)
+	}
+
+	/// Move the value of `key` from column `from` to column `to`, in a single commit that
+	/// removes the key from `from` and inserts the value into `to`. The value is read from
+	/// queued and logged commits, and is only written to the log once. Returns `false` if
+	/// the key is not in `from`.
+	///
+	/// A reader that gets the key from `from` first and from `to` after that always finds the
+	/// value in one of them. For a reference counted `from` column the move removes one
+	/// reference, so the value stays there if it had more. For a reference counted `to`
+	/// column it adds one reference. The TTL of `to` counts from the move. Not supported for
+	/// columns with trash.
+	pub fn move_value(&self, from: ColId, to: ColId, key: &[u8]) -> Result<bool> {
+		self.inner.move_value(from, to, key)
+	}
+
+	/// Commit changes collected in `builder`, leaving it empty.
+	pub fn commit_builder(&self, builder: &mut CommitBuilder) -> Result<()> {
+		self.inner.commit_builder(builder)
+	}
+
+	/// Queue a commit without waiting for it to be processed. `completion` receives
+	/// the result once the commit is written to the log, or fails to be written. With
+	/// `WalSync::EveryCommit` the log is synced to disk first, and errors syncing it are
+	/// sent to `completion` as well. With other `sync_wal` policies completion does not
+	/// imply durability: log files are synced later, when flushed, according to the policy.
+	pub fn commit_with_completion<I, K>(&self, tx: I, completion: Sender<Result<()>>) -> Result<()>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_with_completion(tx, completion)
+	}
+
+	/// Queue a commit and return a future that resolves to its log record id once the
+	/// commit is written to the log. This does not block: while the commit queue is full, or
+	/// the `max_uncommitted_bytes` limit is reached, the commit is held by the future and
+	/// queued when it is polled after there is room. Changes are visible to reads once they
+	/// are queued, which is on return if there is room. A waiting commit may be queued after
+	/// commits that are made later.
+	///
+	/// Resolution does not imply durability. The record may still be buffered in memory:
+	/// it survives a process crash once the log is flushed to the file, and a power
+	/// failure once the log is synced. `flush` does both; otherwise this happens in the
+	/// background according to the `sync_wal` policy.
+	pub fn commit_async<I, K>(&self, tx: I) -> CommitFuture
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_async(tx)
+	}
+
+	/// Write all commits made so far to the log and sync it to disk. Commits
+	/// may be made concurrently; those that are queued after this call starts
+	/// are not waited for. Logged commits survive a crash and are enacted on open.
+	/// The log is synced whatever the `sync_wal` policy, so this makes a commit durable
+	/// right away with `WalSync::Interval` or `WalSync::Never`, and restarts the interval.
+	pub fn flush(&self) -> Result<()> {
+		if self.threads.is_empty() {
+			// Read-only database.
+			return Ok(());
+		}
+		self.inner.flush()
+	}
+
+	/// Load changes made by the primary instance since the last call. Only valid for
+	/// databases opened with `open_secondary`. Returns `false` if the primary kept reusing
+	/// log files while they were being read; reads continue from the previous state then.
+	pub fn try_catch_up(&self) -> Result<bool> {
+		if !self.inner.secondary {
+			return Err(Error::InvalidInput("Not a secondary instance".into()));
+		}
+		self.inner.try_catch_up()
+	}
+
+	/// Make a consistent copy of the database in `dest`, that can be opened as usual.
+	/// Logs in `Options::wal_path` and files of columns with their own `ColumnOptions::path`
+	/// are copied into `dest` too, so the copy opens without these paths. Commits made before this call are included. Returns the id of the last
+	/// log record in the copy. Writing logs and enacting them into the tables is
+	/// paused while the files are copied, so new commits stall once the commit queue
+	/// fills up.
+	pub fn backup(&self, dest: &std::path::Path) -> Result<u64> {
+		self.backup_to(dest, |_| ())
+	}
+
+	/// Same as `backup`, calling `progress` before the first file and after each copied file.
+	pub fn backup_to(&self, dest: &std::path::Path, progress: impl FnMut(&BackupProgress)) -> Result<u64> {
+		self.flush()?;
+		self.inner.backup(dest, progress)
+	}
+
+	/// Insert values into a column, writing them to the tables directly instead of
+	/// the log. Meant for loading large data sets into a column that is not otherwise
+	/// in use: fails if the column has pending commits, and commits to the column are
+	/// rejected until it returns. Tables are synced periodically, and the number of
+	/// values written so far is saved. If loading is interrupted, the column is left
+	/// partially filled; `bulk_insert_progress` tells where to resume.
+	/// Returns the number of values inserted.
+	pub fn bulk_insert<I, K>(&self, col: ColId, data: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(K, Value)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.bulk_insert(col, data)
+	}
+
+	/// Number of values written by a `bulk_insert` that did not complete, if any.
+	/// Resuming the insert with the remaining values clears it.
+	pub fn bulk_insert_progress(&self, col: ColId) -> Result<Option<u64>> {
+		self.inner.bulk_insert_progress(col)
+	}
+
+	/// Write all values of the column to `writer`, in the dump format described in the
+	/// `export` module. Commits made before this call are included. Values are written
+	/// as stored, so that reference counts and TTL insertion times are preserved.
+	/// A range of index chunks may be exported with `ExportOptions`; the summary tells
+	/// where to continue. Chunk positions stay valid as long as the column is not
+	/// reindexed between the calls.
+	pub fn export(&self, col: ColId, mut writer: impl std::io::Write, options: &ExportOptions) -> Result<ExportSummary> {
+		self.flush()?;
+		self.inner.export(col, &mut writer, options)
+	}
+
+	/// Load a dump made with `export` into the column. Column options must match those of
+	/// the exported column, and the database salt must be the same unless keys are uniform.
+	/// Existing keys are handled according to `ImportOptions::on_conflict`. Entries are
+	/// committed in batches as they are read, so entries that precede a conflict, or damage
+	/// detected with the trailing checksum, stay imported. An empty column is written to
+	/// directly, like with `bulk_insert`.
+	pub fn import(&self, col: ColId, mut reader: impl std::io::Read, options: &ImportOptions) -> Result<ImportSummary> {
+		if self.threads.is_empty() {
+			return Err(Error::InvalidInput("Database is read only".into()));
+		}
+		let summary = self.inner.import(col, &mut reader, options)?;
+		self.flush()?;
+		Ok(summary)
+	}
+
+	/// Remove all values from the column. A single log record replaces the index with an
+	/// empty one and frees all value slots, so a crash leaves the column either cleared or
+	/// intact. Values of reference counted columns are removed regardless of the reference
+	/// count. Trash of the column is emptied by the same record. Commits made before this
+	/// call are cleared, and reads find no values once it returns.
+	pub fn clear_column(&self, col: impl Into<ColId>) -> Result<()> {
+		if self.threads.is_empty() {
+			return Err(Error::InvalidInput("Database is read only".into()));
+		}
+		self.inner.clear_column(col.into())?;
+		// The column is cleared once the record is written.
+		self.flush()
+	}
+
+	/// Space in the value tables of the column, taken by removed values, that `compact` can reclaim.
+	pub fn free_space(&self, col: ColId) -> Result<FreeStats> {
+		self.inner.free_space(col)
+	}
+
+	/// Up to `count` most loaded chunks of the column index, as `(chunk index, occupied entries)`,
+	/// most loaded first. Reads the whole index, so this is slow for large columns. See the
+	/// skew report of `collect_stats` for a cheap check.
+	pub fn hot_index_chunks(&self, col: ColId, count: usize) -> Result<Vec<(u64, usize)>> {
+		self.inner.hot_index_chunks(col, count)
+	}
+
+	/// Rebuild the index of the column from the values in its value tables, when the index
+	/// is damaged but the values are intact. Values only keep the part of the key past the
+	/// first 6 bytes, so `key_of` has to tell the key of each value, as passed to `commit`;
+	/// this works for columns where the key can be derived from the value, such as `preimage`
+	/// columns. Recovered keys are checked against the stored part. Values that `key_of`
+	/// returns `None` for, or that don't match, are left out of the index, and remain in the
+	/// value tables; this includes the trash. The new index is written through the log, and
+	/// an interrupted repair may be run again. Fails unless `Db::verify` finds the column
+	/// inconsistent, or `force` is set. Commits to the column are rejected while it runs.
+	pub fn repair(&self, col: ColId, mut key_of: impl FnMut(&[u8]) -> Option<Vec<u8>>, force: bool) -> Result<RepairSummary> {
+		if self.threads.is_empty() {
+			return Err(Error::InvalidInput("Database is read only".into()));
+		}
+		self.flush()?;
+		self.inner.repair(col, &mut key_of, force)
+	}
+
+	/// Check that the index and value tables of the columns agree: every index entry must
+	/// point to a value stored for its key, and every value in use must be referenced by the
+	/// index. All columns are checked if `columns` is empty. Inconsistencies are counted and
+	/// logged, but not repaired. Commits made before this call are included; values committed
+	/// while it runs may be reported as orphaned.
+	pub fn verify(&self, columns: &[ColId]) -> Result<VerifyReport> {
+		self.flush()?;
+		self.inner.verify(columns)
+	}
+
+	/// Move values into the slots of removed ones and truncate value table files.
+	/// Runs alongside commits; returns early when the database is shutting down.
+	/// Returns the number of bytes removed from the files.
+	pub fn compact(&self, col: ColId) -> Result<u64> {
+		if self.threads.is_empty() {
+			return Err(Error::InvalidInput("Database is read only".into()));
+		}
+		self.inner.compact(col)
+	}
+
+	/// Restore a removed value from the trash. Returns `false` if the value is not
+	/// in the trash, or has expired. Requires `ColumnOptions::trash` to be set.
+	pub fn undelete(&self, col: ColId, key: &[u8]) -> Result<bool> {
+		self.inner.undelete(col, key)
+	}
+
+	/// Purge expired values from the trash of all columns. Expired values are
+	/// also purged in the background, once a minute or when the trash goes over the size bound.
+	pub fn purge_trash(&self) -> Result<()> {
+		self.inner.purge_trash(true)
+	}
+
+	pub(crate) fn commit_hashed(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
+		self.inner.commit_hashed(commit)
+	}
+
+	#[cfg(feature = "kvdb")]
+	pub(crate) fn commit_index_keys(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
+		self.inner.commit_index_keys(commit)
+	}
+
+	#[cfg(feature = "kvdb")]
+	pub(crate) fn prefix_keys(&self, col: ColId, prefix: &[u8]) -> Result<Vec<Key>> {
+		self.inner.prefix_keys(col, prefix)
+	}
+
+	/// Compute the index key that the column would derive for `key`, in either key mode.
+	/// This and the other raw key methods fail with `Error::InvalidInput` for columns that
+	/// are not configured in `Options::columns`.
+	pub fn hash_key(&self, col: ColId, key: &[u8]) -> Result<Key> {
+		self.inner.user_column(col)?.derive_key(key)
+	}
+
+	/// Query a value by its index key, as returned by `hash_key`.
+	pub fn raw_get(&self, col: ColId, key: &Key) -> Result<Option<Value>> {
+		self.inner.user_column(col)?;
+		self.inner.get_hashed(col, key)
+	}
+
+	/// Commit a transaction with pre-hashed keys, skipping key derivation.
+	/// Each item is `(column, index key, original key, value)`.
+	///
+	/// Safety contract: the index key must be exactly what `hash_key` returns for the
+	/// original key in that column. A mismatching key is stored under the wrong index
+	/// entry and can't be found with `get`. Debug builds, and release builds with
+	/// `Options::verify_raw_keys`, verify the derivation and reject the whole commit with
+	/// `Error::InvalidInput`; other release builds trust the caller.
+	pub fn raw_commit<I, K>(&self, tx: I) -> Result<()>
+	where
+		I: IntoIterator<Item=(ColId, Key, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.raw_commit(tx)
+	}
+
+	/// Disk space used by the database files, with per-column breakdown of index and value
+	/// table files and a total for the log files. Only file metadata and the entry counts of
+	/// the table headers are read, so this is cheap to call. Compare with the logical sizes
+	/// in `collect_stats` to estimate space amplification.
+	pub fn size_on_disk(&self) -> Result<DiskUsage> {
+		self.inner.disk_usage()
+	}
+
+	/// Size of commits that are not enacted into the tables yet.
+	pub fn pending_bytes(&self) -> PendingBytes {
+		self.inner.pending_bytes()
+	}
+
+	/// Approximate heap memory held by queued commits, log overlays and caches.
+	pub fn memory_usage(&self) -> MemoryUsage {
+		self.inner.memory_usage()
+	}
+
+	/// Number of index and value table entries that are logged but not enacted yet.
+	pub fn overlay_stats(&self) -> (usize, usize) {
+		self.inner.log.overlay_stats()
+	}
+
+	/// Commits that are not written to the log yet, by priority.
+	pub fn commit_queue_depth(&self) -> CommitQueueDepth {
+		self.inner.commit_queue_depth()
+	}
+
+	/// Size in bytes of queued commits above which new commits wait for the queue to drain,
+	/// or are rejected by `try_commit` and `commit_timeout`. Compare with the total of
+	/// `commit_queue_depth`. See `Options::commit_queue_bytes`.
+	pub fn commit_queue_limit(&self) -> u64 {
+		self.inner.options.commit_queue_bytes as u64
+	}
+
+	/// Id of the last log record that was started. Record ids increase with each commit
+	/// and background change, and start from 1 when the database is opened with no logs
+	/// to replay.
+	pub fn current_record_id(&self) -> u64 {
+		self.inner.log.last_record_id()
+	}
+
+	/// Enact up to `max_records` log records into the tables on the calling thread, and
+	/// return how many were enacted. This shares the log reader with the background worker,
+	/// so each record is still enacted once, in order. Only records of the log file being
+	/// read are available. Log files are handed over for reading by the flush worker, which
+	/// syncs them first with `WalSync::EveryCommit`. With other `Options::sync_wal` policies,
+	/// records may be enacted before their log is synced.
+	pub fn process_logs(&self, max_records: usize) -> Result<usize> {
+		if self.threads.is_empty() {
+			return Err(Error::InvalidInput("Database is read only".into()));
+		}
+		self.inner.process_logs(max_records)
+	}
+
+	/// Stop flushing, enacting and cleaning up logs in the background until `resume_background`
+	/// is called. A record that is being enacted is completed first. Commits are still written
+	/// to the log, and kept in memory until they are enacted, so memory use grows for as long as
+	/// processing is paused. Log files are not synced with `WalSync::Interval` meanwhile.
+	/// Writers that wait for enactment, such as with `Options::max_uncommitted_bytes`, block
+	/// until processing is resumed. `process_logs` and `close` still enact logs.
+	pub fn pause_background(&self) {
+		self.inner.paused.store(true, Ordering::SeqCst);
+		drop(self.inner.enact_lock.lock());
+	}
+
+	/// Resume background processing stopped with `pause_background`.
+	pub fn resume_background(&self) {
+		self.inner.paused.store(false, Ordering::SeqCst);
+		self.inner.signal_flush_worker();
+		self.inner.signal_commit_worker();
+		self.inner.signal_cleanup_worker();
+	}
+
+	/// Number of enacted log files that wait for the tables to be synced before they are
+	/// reused.
+	pub fn dirty_log_count(&self) -> usize {
+		self.inner.log.num_dirty_logs()
+	}
+
+	/// Number of cleaned log files kept for reuse. See `Options::log_pool_size`.
+	pub fn pooled_log_count(&self) -> usize {
+		self.inner.log.num_pooled_logs()
+	}
+
+	/// Id of the oldest log record that is not enacted into the tables yet, or `None`
+	/// if all records up to `current_record_id` are.
+	pub fn oldest_unflushed_record_id(&self) -> Option<u64> {
+		self.inner.oldest_unflushed_record_id()
+	}
+
+	pub fn num_columns(&self) -> u8 {
+		self.inner.options.columns.len() as u8
+	}
+
+	/// Salt of the key hash, for use with `hash_key`. `None` for databases created with
+	/// version 3 or older, that hash keys without a salt.
+	pub fn salt(&self) -> Option<Salt> {
+		self.inner.metadata.salt
+	}
+
+	/// Options of the column, as stored in the metadata.
+	pub fn column_options(&self, col: ColId) -> Option<&ColumnOptions> {
+		self.inner.metadata.columns[.. self.inner.options.columns.len()].get(col as usize)
+	}
+
+	/// Column with the given `ColumnOptions::name`.
+	pub fn column(&self, name: &str) -> Option<Column> {
+		self.column_by_name(name).map(Column)
+	}
+
+	/// Index of the column with the given `ColumnOptions::name`.
+	pub fn column_by_name(&self, name: &str) -> Option<ColId> {
+		self.inner.metadata.columns.iter()
+			.position(|c| c.name.as_deref() == Some(name))
+			.map(|c| c as ColId)
+	}
+
+	/// Column with the given index, if it exists.
+	pub fn column_at(&self, index: ColId) -> Option<Column> {
+		((index as usize) < self.inner.options.columns.len()).then_some(Column(index))
+	}
+
+	pub(crate) fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		self.inner.iter_column_while(c, f)
+	}
+
+	/// Register a hook that is called after each commit is enacted into the tables, with the
+	/// log record id and the changed keys. Inserted values are included if `values` is set.
+	/// Only commits written to the log after registration are reported. Hooks are called
+	/// from a background worker with no locks held; they should be fast and must not wait for
+	/// database operations, such as `flush`, as these may need the same worker. Panics are
+	/// caught and logged. Returns an id for `remove_commit_hook`.
+	pub fn on_commit_enacted(&self, hook: CommitHook, values: bool) -> u64 {
+		self.inner.add_commit_hook(hook, values)
+	}
+
+	/// Unregister a hook added with `on_commit_enacted`. Returns `false` if there is no such hook.
+	pub fn remove_commit_hook(&self, id: u64) -> bool {
+		self.inner.remove_commit_hook(id)
+	}
+
+	/// Receive each commit once it is enacted, in record id order, with the changes as
+	/// stored. All commits with record ids above `current_record_id`, as taken after
+	/// subscribing, are sent. Up to `capacity` records are buffered for the receiver; when
+	/// it is full, records are dropped or enactment waits, according to `backpressure`.
+	/// A blocked receiver must not wait for database operations, such as `flush`. The
+	/// subscription ends when the receiver is dropped.
+	pub fn subscribe(&self, capacity: usize, backpressure: Backpressure) -> std::sync::mpsc::Receiver<EnactedRecord> {
+		self.inner.subscribe(capacity, backpressure)
+	}
+
+	/// Iterate over all values in the column, including commits that are not enacted
+	/// into the tables yet. Keys are returned as stored, i.e. hashed or salted according
+	/// to the column options. The order is unspecified, and each key is returned once.
+	/// Commits made while iterating may or may not be seen. Reindexing of the column is
+	/// paused until the iterator is dropped.
+	pub fn iter(&self, col: ColId) -> Result<impl Iterator<Item = Result<(Key, Value)>> + '_> {
+		self.inner.iter(col)
+	}
+
+	/// Read the values of the column in pages, starting at `cursor`, or at the start of the
+	/// column for `None`. Returns the page and the cursor of the next one, or `None` if this is
+	/// the last page. Pages end at index chunk boundaries and may hold more than `limit`
+	/// values. Cursors stay valid across restarts and reindexing. Values committed or removed
+	/// between pages may or may not be seen, but no key is returned twice.
+	pub fn iter_from(&self, col: ColId, cursor: Option<IterCursor>, limit: usize) -> Result<IterPage> {
+		self.inner.iter_page(col, cursor, limit)
+	}
+
+	/// Iterate over the values of a uniform column in descending key order, including commits
+	/// that are not enacted into the tables yet. Keys are the first 32 bytes of the key, as
+	/// stored. Commits made while iterating may or may not be seen. Reindexing of the column is
+	/// paused until the iterator is dropped.
+	pub fn iter_rev(&self, col: ColId) -> Result<impl Iterator<Item = Result<(Key, Value)>> + '_> {
+		self.inner.iter_rev(col, None)
+	}
+
+	/// Same as `iter_rev`, starting at the greatest key that is less than or equal to `seek`,
+	/// compared byte by byte. Nothing is returned if `seek` is less than all keys.
+	pub fn iter_rev_from(&self, col: ColId, seek: &[u8]) -> Result<impl Iterator<Item = Result<(Key, Value)>> + '_> {
+		self.inner.iter_rev(col, Some(seek))
+	}
+
+	/// Unpositioned cursor over a uniform column, in key order. Keys are the first 32 bytes of
+	/// the key, as stored, and include commits that are not enacted yet.
+	pub fn cursor(&self, col: ColId) -> Result<Cursor<'_>> {
+		self.inner.check_ordered(col)?;
+		Ok(Cursor { db: &self.inner, col, current: None })
+	}
+
+	/// Iterate over all keys in the column, same as `iter`, but without reading the values.
+	/// Only value entry headers are read from the tables, except for columns with TTL, where
+	/// values are read to skip expired entries.
+	pub fn iter_keys(&self, col: ColId) -> Result<impl Iterator<Item = Result<Key>> + '_> {
+		self.inner.iter_keys(col)
+	}
+
+	/// Remove all keys that start with `prefix` in a single commit, and return how many
+	/// there were. Only supported for uniform columns, where the first 32 bytes of the key
+	/// are stored as is, and not for reference counted ones. Keys committed while the
+	/// column is scanned may be kept.
+	pub fn delete_prefix(&self, col: ColId, prefix: &[u8]) -> Result<u64> {
+		self.inner.delete_prefix(col, prefix)
+	}
+
+	/// Estimate the size of the values stored under keys that start with `prefix`, as stored,
+	/// without reading the whole column. Only supported for uniform columns. A sample of the
+	/// index chunks the prefix falls into is read, and the result is extrapolated from it,
+	/// so the estimate is exact for prefixes that span few chunks. Commits that are not
+	/// written to the log yet are not included.
+	pub fn approximate_prefix_size(&self, col: ColId, prefix: &[u8]) -> Result<u64> {
+		self.inner.approximate_prefix_size(col, prefix)
+	}
+
+	/// Close the database. Queued commits are written and all logs are enacted
+	/// into the tables and removed, so that the next open does not need to replay them.
+	/// If `timeout` expires before all logs are enacted, the remaining logs are left
+	/// on disk and replayed on next open. The lock file is released on return.
+	pub fn close(mut self, timeout: Option<std::time::Duration>) -> Result<()> {
+		self.shutdown(timeout.map(|t| Instant::now() + t))
+	}
+
+	fn shutdown(&mut self, deadline: Option<Instant>) -> Result<()> {
+		if self.closed {
+			return Ok(());
+		}
+		self.closed = true;
+		self.inner.shutdown();
+		for thread in self.threads.drain(..) {
+			let _ = thread.join();
+		}
+		if self.inner.secondary {
+			return Ok(());
+		}
+		self.inner.kill_logs(deadline)?;
+		self.inner.ttl_clock.save(true)
+	}
+
+	fn worker_thread(db: Arc<DbInner>, thread: usize, workers: Vec<Worker>) -> Result<()> {
+		let signal = &db.worker_signals[thread];
+		// Start with pending reindex and log cleanup.
+		let mut more_work: Vec<bool> = workers.iter().map(|w| matches!(w, Worker::Log | Worker::Cleanup)).collect();
+		let mut running = vec![true; workers.len()];
+		let interval = workers.iter().filter_map(|w| w.interval(&db)).min();
+		loop {
+			if db.shutdown.load(Ordering::SeqCst) {
+				for (i, worker) in workers.iter().enumerate() {
+					// The flush worker stops right away, others finish their work first.
+					if running[i] && (*worker == Worker::Flush || !more_work[i]) {
+						running[i] = false;
+						log::debug!(target: "parity-db", "{:?} worker shutdown", worker);
+					}
+				}
+				if !running.contains(&true) {
+					return Ok(());
+				}
+			}
+			if !more_work.iter().zip(running.iter()).any(|(more, running)| *more && *running) {
+				let mut work = signal.work.lock();
+				match interval {
+					// Wake up periodically to sync the log or look for expired values.
+					Some(interval) => if !*work {
+						signal.cv.wait_for(&mut work, interval);
+					},
+					None => while !*work {
+						signal.cv.wait(&mut work)
+					},
+				}
+				*work = false;
+			}
+			for (i, worker) in workers.iter().enumerate() {
+				if running[i] {
+					more_work[i] = worker.run(&db)?;
+				}
+			}
+		}
+	}
+
+	/// Write statistics of a column, or of all columns and the logs if `column` is `None`.
+	/// Fails with `Error::StatsDisabled` unless `Options::stats` is set.
+	pub fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) -> Result<()> {
+		self.inner.collect_stats(writer, column)
+	}
+
+	pub fn clear_stats(&self, column: Option<u8>) {
+		self.inner.clear_stats(column)
+	}
+
+	/// Operation counters of the column, kept in memory since the database was opened or
+	/// `reset_counters` was called. Fails with `Error::StatsDisabled` unless `Options::stats`
+	/// is set.
+	pub fn counters(&self, col: ColId) -> Result<ColumnCounters> {
+		let column = self.inner.columns.get(col as usize)
+			.ok_or_else(|| Error::InvalidInput(format!("Invalid column {}", col)))?;
+		column.read_counters().ok_or(Error::StatsDisabled)
+	}
+
+	/// Reset the operation counters of all columns.
+	pub fn reset_counters(&self) {
+		for column in self.inner.columns.iter() {
+			column.reset_counters();
+		}
+	}
+
+	pub fn check_from_index(&self, check_param: check::CheckOptions) -> Result<()> {
+		if let Some(col) = check_param.column.clone() {
+			self.inner.columns[col as usize].check_from_index(&self.inner.log, &check_param, col)?;
+		} else {
+			for (ix, c) in self.inner.columns.iter().enumerate() {
+				c.check_from_index(&self.inner.log, &check_param, ix as ColId)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Db {
+	fn drop(&mut self) {
+		if let Err(e) = self.shutdown(None) {
+			log::warn!(target: "parity-db", "Shutdown error: {:?}", e);
+		}
+	}
+}
+
+#[cfg(feature = "parity-util-mem")]
+impl parity_util_mem::MallocSizeOf for Db {
+	fn size_of(&self, _ops: &mut parity_util_mem::MallocSizeOfOps) -> usize {
+		self.memory_usage().total() as usize
+	}
+}
+
+/// Verification operation utilities.
+pub mod check {
+	pub enum CheckDisplay {
+		None,
+		Full,
+		Short(u64),
+	}
+
+	pub struct CheckOptions {
+		pub column: Option<u8>,
+		pub from: Option<u64>,
+		pub bound: Option<u64>,
+		pub display: CheckDisplay,
+	}
+
+	impl CheckOptions {
+		pub fn new(
+			column: Option<u8>,
+			from: Option<u64>,
+			bound: Option<u64>,
+			display_content: bool,
+			truncate_value_display: Option<u64>,
+		) -> Self {
+			let display = if display_content {
+				match truncate_value_display {
+					Some(t) => CheckDisplay::Short(t),
+					None => CheckDisplay::Full,
+				}
+			} else {
+				CheckDisplay::None
+			};
+			CheckOptions {
+				column,
+				from,
+				bound,
+				display,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+	use parking_lot::Mutex;
+	use super::{Db, Options, Backpressure, BackupProgress, CasOutcome, CommitBuilder, CommitChange, ExportOptions, ImportOptions, IterCursor, OnConflict, Operation, Priority, export};
+	use crate::options::SizeHint;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_db_open_should_fail() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 5);
+		assert!(
+			Db::open(&options).is_err(),
+			"Database does not exist, so it should fail to open"
+		);
+		assert!(Db::open(&options).map(|_| ()).unwrap_err().to_string().contains("use open_or_create"));
+	}
+
+	#[test]
+	fn test_db_open_or_create() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 5);
+		assert!(
+			Db::open_or_create(&options).is_ok(),
+			"New database should be created"
+		);
+		assert!(
+			Db::open(&options).is_ok(),
+			"Existing database should be reopened"
+		);
+	}
+
+	#[test]
+	fn test_raw_commit() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.verify_raw_keys = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = db.hash_key(0, b"key").unwrap();
+		db.raw_commit(vec![(0, key, b"key".to_vec(), Some(b"value".to_vec()))]).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.raw_get(0, &key).unwrap(), Some(b"value".to_vec()));
+		let wrong = db.hash_key(0, b"other").unwrap();
+		assert!(matches!(
+			db.raw_commit(vec![(0, wrong, b"key".to_vec(), None)]),
+			Err(crate::Error::InvalidInput(_)),
+		));
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+
+		// The trash column is reserved, and columns out of range are rejected.
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].trash = Some(crate::TrashOptions { max_bytes: 1024, max_age: std::time::Duration::from_secs(60) });
+		let db = Db::open_or_create(&options).unwrap();
+		for col in [1, 9] {
+			assert!(matches!(db.hash_key(col, b"key"), Err(crate::Error::InvalidInput(_))));
+			assert!(matches!(db.raw_get(col, &key), Err(crate::Error::InvalidInput(_))));
+			assert!(matches!(
+				db.raw_commit(vec![(col, key, b"key".to_vec(), Some(b"value".to_vec()))]),
+				Err(crate::Error::InvalidInput(_)),
+			));
+		}
+	}
+
+	#[test]
+	fn test_size_on_disk() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key", Some(vec![0u8; 100]))]).unwrap();
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		let usage = db.size_on_disk().unwrap();
+		assert_eq!(usage.columns.len(), 2);
+		assert!(usage.columns[0].index > 0);
+		assert_eq!(usage.columns[1].index, 0);
+		let table = &usage.columns[0].tables[0];
+		assert_eq!(table.allocated, table.entry_size as u64);
+		assert!(table.on_disk >= table.allocated);
+		assert!(usage.total >= usage.logs + usage.columns.iter().map(|c| c.total()).sum::<u64>());
+	}
+
+	#[test]
+	fn test_size_hints() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		let hint = SizeHint { entries: 10000, histogram: vec![(100, 9), (100_000, 1)] };
+		options.size_hints.insert(0, hint.clone());
+		let table_bytes = |col: u8| -> u64 {
+			std::fs::read_dir(tmp.path()).unwrap()
+				.map(|e| e.unwrap())
+				.filter(|e| e.file_name().to_string_lossy().starts_with(&format!("table_{:02}_", col)))
+				.map(|e| e.metadata().unwrap().len())
+				.sum()
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		drop(db);
+		assert!(table_bytes(0) >= 9000 * 100 + 1000 * 100_000);
+		assert_eq!(table_bytes(1), 0);
+
+		// Ignored once the column has values.
+		options.size_hints.insert(1, hint.clone());
+		let db = Db::open(&options).unwrap();
+		db.commit(vec![(1, b"a", Some(vec![0u8; 100]))]).unwrap();
+		drop(db);
+		let size = table_bytes(1);
+		options.size_hints.insert(1, SizeHint { entries: 1_000_000, ..hint });
+		let db = Db::open(&options).unwrap();
+		drop(db);
+		assert_eq!(table_bytes(1), size);
+
+		options.size_hints.insert(1, SizeHint { entries: 10, histogram: vec![(100, 0)] });
+		assert!(!options.is_valid());
+		options.size_hints.insert(1, SizeHint { entries: 10, histogram: Vec::new() });
+		assert!(options.is_valid());
+		options.size_hints.clear();
+		options.size_hints.insert(2, SizeHint { entries: 10, histogram: vec![(100, 1)] });
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_size_hint_index() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		let index_files = || {
+			let mut files: Vec<_> = std::fs::read_dir(tmp.path()).unwrap()
+				.map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+				.filter(|n| n.starts_with("index_"))
+				.collect();
+			files.sort();
+			files
+		};
+		// Room for twice the entries in 64 entry chunks takes 17 bits.
+		options.size_hints.insert(0, SizeHint { entries: 4_000_000, histogram: Vec::new() });
+		options.size_hints.insert(1, SizeHint { entries: 1000, histogram: Vec::new() });
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..1000).flat_map(|i| vec![(0, i.to_le_bytes(), Some(vec![1])), (1, i.to_le_bytes(), Some(vec![1]))])).unwrap();
+		drop(db);
+		assert_eq!(index_files(), vec!["index_00_17", "index_01_16"]);
+
+		// Existing columns are not resized.
+		options.size_hints.insert(1, SizeHint { entries: 4_000_000, histogram: Vec::new() });
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &7u32.to_le_bytes()).unwrap(), Some(vec![1]));
+		drop(db);
+		assert_eq!(index_files(), vec!["index_00_17", "index_01_16"]);
+	}
+
+	#[test]
+	fn test_exists_many() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].ref_counted = true;
+		options.columns[2].ttl = Some(std::time::Duration::from_secs(3600));
+		let db = Db::open_or_create(&options).unwrap();
+		for col in 0..3u8 {
+			db.commit((0u32..1000).map(|i| (col, i.to_le_bytes(), Some(vec![i as u8; 1 + i as usize % 300])))).unwrap();
+			db.commit((0u32..1000).filter(|i| i % 3 == 0).map(|i| (col, i.to_le_bytes(), None))).unwrap();
+		}
+		db.flush().unwrap();
+		// Queued changes are seen.
+		db.commit(vec![(0, 1u32.to_le_bytes(), None), (0, 3u32.to_le_bytes(), Some(vec![1]))]).unwrap();
+
+		let keys: Vec<_> = (0u32..1200).rev().map(|i| i.to_le_bytes()).collect();
+		for col in 0..3u8 {
+			let found = db.exists_many(col, &keys).unwrap();
+			let expected: Vec<_> = (0u32..1200).rev().map(|i| {
+				if col == 0 && (i == 1 || i == 3) {
+					return i == 3;
+				}
+				i < 1000 && i % 3 != 0
+			}).collect();
+			assert_eq!(found, expected, "column {}", col);
+		}
+		assert_eq!(db.exists_many(0, Vec::<Vec<u8>>::new()).unwrap(), Vec::<bool>::new());
+	}
+
+	#[test]
+	fn test_get_range() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].compression = crate::CompressionType::Lz4;
+		options.columns[2].ttl = Some(std::time::Duration::from_secs(3600));
+		let value: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+		let check = |db: &Db, col: u8| {
+			assert_eq!(db.get_range(col, b"big", 0, 10).unwrap(), Some(value[..10].to_vec()));
+			// Spans several parts of a multipart value.
+			assert_eq!(db.get_range(col, b"big", 30_000, 40_000).unwrap(), Some(value[30_000..70_000].to_vec()));
+			assert_eq!(db.get_range(col, b"big", 99_990, 100).unwrap(), Some(value[99_990..].to_vec()));
+			assert_eq!(db.get_range(col, b"big", 200_000, 10).unwrap(), Some(Vec::new()));
+			assert_eq!(db.get_range(col, b"big", 5, usize::MAX).unwrap(), Some(value[5..].to_vec()));
+			assert_eq!(db.get_range(col, b"small", 1, 2).unwrap(), Some(vec![2, 3]));
+			assert_eq!(db.get_range(col, b"none", 0, 10).unwrap(), None);
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		let commit = (0..3u8).flat_map(|col| vec![(col, b"big".to_vec(), Some(value.clone())), (col, b"small".to_vec(), Some(vec![1, 2, 3, 4]))]);
+		db.commit(commit).unwrap();
+		// From the commit overlay, the log overlay and the tables.
+		for col in 0..3 {
+			check(&db, col);
+		}
+		db.flush().unwrap();
+		for col in 0..3 {
+			check(&db, col);
+		}
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		for col in 0..3 {
+			check(&db, col);
+		}
+	}
+
+	#[test]
+	fn test_get_with_meta() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"a", Some(vec![0u8; 10000])), (0, b"b", Some(vec![1, 2, 3]))]).unwrap();
+		db.commit(vec![(1, b"a", Some(vec![7u8; 100000])), (1, b"a", Some(vec![7u8; 100000]))]).unwrap();
+
+		let (value, meta) = db.get_with_meta(0, b"a").unwrap().unwrap();
+		assert_eq!(value, vec![0u8; 10000]);
+		assert!(meta.compressed);
+		assert!(meta.stored_size < 10000);
+		assert_eq!(meta.ref_count, None);
+		let (_, meta) = db.get_with_meta(0, b"b").unwrap().unwrap();
+		assert!(!meta.compressed && !meta.is_multipart());
+		assert_eq!(meta.stored_size, 3);
+		assert!(meta.entry_size >= 3);
+		let (_, meta) = db.get_with_meta(1, b"a").unwrap().unwrap();
+		assert!(meta.is_multipart());
+		assert_eq!(meta.stored_size, 100000);
+		assert_eq!(meta.ref_count, Some(2));
+		assert!(db.get_with_meta(1, b"b").unwrap().is_none());
+	}
+
+	#[test]
+	fn test_value_cache() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"a", Some(b"value_a".to_vec()))]).unwrap();
+		drop(db);
+
+		// The cache is not part of the column metadata.
+		options.columns[0].cache_size_bytes = 1024 * 1024;
+		let db = Db::open(&options).unwrap();
+		let first = db.get_shared(0, b"a").unwrap().unwrap();
+		let second = db.get_shared(0, b"a").unwrap().unwrap();
+		assert!(std::sync::Arc::ptr_eq(&first, &second));
+
+		db.commit(vec![(0, b"a", Some(b"value_a2".to_vec()))]).unwrap();
+		db.flush().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"value_a2".to_vec()));
+		// The freed entry is reused for another key.
+		db.commit(vec![(0, b"a", None)]).unwrap();
+		db.commit(vec![(0, b"b", Some(b"value_b2".to_vec()))]).unwrap();
+		db.flush().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"value_b2".to_vec()));
+		assert_eq!(*db.get_shared(0, b"b").unwrap().unwrap(), b"value_b2".to_vec());
+
+		let mut stats = Vec::new();
+		db.collect_stats(&mut stats, Some(0)).unwrap();
+		assert!(String::from_utf8(stats).unwrap().contains("Cache: 1 values"));
+	}
+
+	#[test]
+	fn test_max_log_size() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_log_size = 16 * 1024;
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..20 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 20 * 1024]))]).unwrap();
+		}
+		// Each record takes the log past the limit, so all of them are enacted without a flush.
+		let start = Instant::now();
+		while db.pending_bytes().total() > 0 {
+			assert!(start.elapsed() < std::time::Duration::from_secs(10), "{:?}", db.pending_bytes());
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(db.pending_bytes().appending_log, 0);
+		for i in 0u32..20 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 20 * 1024]));
+		}
+	}
+
+	#[test]
+	fn test_log_pool_size() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_log_size = 16 * 1024;
+		options.log_pool_size = 2;
+		let logs = || std::fs::read_dir(tmp.path()).unwrap()
+			.filter(|e| e.as_ref().unwrap().file_name().to_str().is_some_and(super::is_log_file))
+			.count();
+		let write = |db: &Db| {
+			for i in 0u32..20 {
+				db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 20 * 1024]))]).unwrap();
+			}
+			let start = Instant::now();
+			while db.pending_bytes().total() > 0 || db.dirty_log_count() > 0 {
+				assert!(start.elapsed() < std::time::Duration::from_secs(10), "{:?}", db.pending_bytes());
+				std::thread::sleep(std::time::Duration::from_millis(10));
+			}
+		};
+
+		let db = Db::open_or_create(&options).unwrap();
+		write(&db);
+		assert!((1..=2).contains(&db.pooled_log_count()), "{}", db.pooled_log_count());
+		let mut stats = Vec::new();
+		db.collect_stats(&mut stats, None).unwrap();
+		let stats = String::from_utf8(stats).unwrap();
+		assert!(stats.contains(&format!("Logs: 0 dirty, {} pooled, pool size 2", db.pooled_log_count())), "{}", stats);
+		assert!(logs() <= 3, "{}", logs());
+		drop(db);
+
+		// Nothing is pooled.
+		options.log_pool_size = 0;
+		let db = Db::open(&options).unwrap();
+		write(&db);
+		assert_eq!(db.pooled_log_count(), 0);
+		assert!(logs() <= 1, "{}", logs());
+		for i in 0u32..20 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 20 * 1024]));
+		}
+	}
+
+	#[test]
+	fn test_max_uncommitted_bytes() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_uncommitted_bytes = Some(64 * 1024);
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(db.commit(vec![(0, b"a", Some(vec![0u8; 65 * 1024]))]).is_err());
+		for i in 0u32..50 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![0u8; 16 * 1024]))]).unwrap();
+		}
+		for i in 0u32..50 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![0u8; 16 * 1024]));
+		}
+		db.flush().unwrap();
+		assert_eq!(db.pending_bytes().commit_queue, 0);
+	}
+
+	#[test]
+	fn test_max_uncommitted_bytes_blocks() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_uncommitted_bytes = Some(64 * 1024);
+		let db = Db::open_or_create(&options).unwrap();
+		db.pause_background();
+		db.commit(vec![(0, b"a", Some(vec![1u8; 40 * 1024]))]).unwrap();
+		// Logged, but not enacted while processing is paused.
+		let start = Instant::now();
+		while db.pending_bytes().commit_queue > 0 {
+			assert!(start.elapsed() < std::time::Duration::from_secs(10), "{:?}", db.pending_bytes());
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert!(db.pending_bytes().log_queue > 0);
+
+		let done = AtomicBool::new(false);
+		std::thread::scope(|scope| {
+			let writer = scope.spawn(|| {
+				db.commit(vec![(0, b"b", Some(vec![2u8; 40 * 1024]))]).unwrap();
+				done.store(true, Ordering::SeqCst);
+			});
+			std::thread::sleep(std::time::Duration::from_millis(300));
+			assert!(!done.load(Ordering::SeqCst), "Commit above the limit did not block");
+			db.resume_background();
+			writer.join().unwrap();
+		});
+		assert!(done.load(Ordering::SeqCst));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1u8; 40 * 1024]));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2u8; 40 * 1024]));
+	}
+
+	#[test]
+	fn test_commit_builder() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		let mut builder = CommitBuilder::with_capacity(2);
+		builder.insert(0, b"a", b"1".to_vec());
+		builder.insert(1, b"bb", b"2".to_vec());
+		assert_eq!(builder.len(), 2);
+		db.commit_builder(&mut builder).unwrap();
+		assert!(builder.is_empty());
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get(1, b"bb").unwrap(), Some(b"2".to_vec()));
+
+		builder.remove(0, b"a");
+		builder.insert(1, b"c", b"3".to_vec());
+		db.commit_builder(&mut builder).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(1, b"c").unwrap(), Some(b"3".to_vec()));
+
+		builder.insert(2, b"a", b"1".to_vec());
+		assert!(db.commit_builder(&mut builder).is_err());
+		assert!(builder.is_empty());
+	}
+
+	#[test]
+	fn test_commit_hooks() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let seen = std::sync::Arc::new(parking_lot::Mutex::new(Vec::new()));
+		let with_values = seen.clone();
+		db.on_commit_enacted(Box::new(move |id, summary| with_values.lock().push((id, summary.clone()))), true);
+		db.on_commit_enacted(Box::new(|_, _| panic!("hook failure")), false);
+		let removed = db.on_commit_enacted(Box::new(|_, _| unreachable!()), false);
+		assert!(db.remove_commit_hook(removed));
+		assert!(!db.remove_commit_hook(removed));
+
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"key1", None), (0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		let key1 = db.inner.columns[0].hash_key(b"key1").unwrap();
+		let key2 = db.inner.columns[0].hash_key(b"key2").unwrap();
+		db.close(None).unwrap();
+
+		let seen = seen.lock();
+		assert_eq!(seen.len(), 2);
+		assert!(seen[0].0 < seen[1].0);
+		assert_eq!(seen[0].1.changes, vec![
+			CommitChange { col: 0, key: key1, size: Some(6), value: Some(b"value1".to_vec()) },
+		]);
+		assert_eq!(seen[1].1.changes, vec![
+			CommitChange { col: 0, key: key1, size: None, value: None },
+			CommitChange { col: 0, key: key2, size: Some(6), value: Some(b"value2".to_vec()) },
+		]);
+	}
+
+	#[test]
+	fn test_subscribe() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let start = db.current_record_id();
+		let all = db.subscribe(16, Backpressure::Block);
+		let lossy = db.subscribe(1, Backpressure::Drop);
+		let dropped = db.subscribe(1, Backpressure::Block);
+		drop(dropped);
+		for i in 0u32..10 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8])), (0, (i + 100).to_le_bytes(), None)]).unwrap();
+		}
+		db.close(None).unwrap();
+
+		let records: Vec<_> = all.iter().collect();
+		assert_eq!(records.len(), 10);
+		for (i, record) in records.iter().enumerate() {
+			assert!(record.record_id > start);
+			assert_eq!(record.dropped, 0);
+			assert_eq!(record.changes.len(), 2);
+			assert_eq!(record.changes[0].2, Operation::Set(vec![i as u8]));
+			assert_eq!(record.changes[1].2, Operation::Remove);
+		}
+		assert!(records.windows(2).all(|r| r[0].record_id < r[1].record_id));
+		let first = lossy.recv().unwrap();
+		assert_eq!((first.record_id, first.dropped), (records[0].record_id, 0));
+		assert!(lossy.try_recv().is_err());
+	}
+
+	#[test]
+	fn test_cas() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.cas(0, b"key", Some(b"old"), Some(b"new".to_vec())).unwrap(), CasOutcome::Mismatch(None));
+		assert!(db.cas(0, b"key", None, Some(b"old".to_vec())).unwrap().is_applied());
+		// Logged, but not enacted.
+		db.flush().unwrap();
+		assert!(db.inner.log.overlay_bytes() > 0);
+		assert_eq!(db.cas(0, b"key", None, None).unwrap(), CasOutcome::Mismatch(Some(b"old".to_vec())));
+		assert!(db.cas(0, b"key", Some(b"old"), Some(0u32.to_le_bytes().to_vec())).unwrap().is_applied());
+		assert!(db.cas(1, b"key", None, Some(vec![1])).is_err());
+
+		let db = Arc::new(db);
+		let threads: Vec<_> = (0..4).map(|_| {
+			let db = db.clone();
+			std::thread::spawn(move || {
+				let mut current = 0u32;
+				for _ in 0..100 {
+					loop {
+						match db.cas(0, b"key", Some(&current.to_le_bytes()), Some((current + 1).to_le_bytes().to_vec())).unwrap() {
+							CasOutcome::Applied => break,
+							CasOutcome::Mismatch(value) => {
+								let value = value.unwrap();
+								current = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+							},
+						}
+					}
+					current += 1;
+				}
+			})
+		}).collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+		assert_eq!(db.get(0, b"key").unwrap(), Some(400u32.to_le_bytes().to_vec()));
+	}
+
+	#[test]
+	fn test_move_value() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[2].ref_counted = true;
+		let db = Arc::new(Db::open_or_create(&options).unwrap());
+		db.commit((0u32..1000).map(|i| (0, i.to_le_bytes(), Some(i.to_be_bytes().to_vec())))).unwrap();
+		let reader = {
+			let db = db.clone();
+			std::thread::spawn(move || {
+				for i in 0u32..1000 {
+					let key = i.to_le_bytes();
+					// Whenever the move happens, the value is in one of the columns.
+					let value = match db.get(0, &key).unwrap() {
+						Some(value) => value,
+						None => db.get(1, &key).unwrap().unwrap(),
+					};
+					assert_eq!(value, i.to_be_bytes());
+				}
+			})
+		};
+		for i in 0u32..1000 {
+			assert!(db.move_value(0, 1, &i.to_le_bytes()).unwrap());
+		}
+		reader.join().unwrap();
+		assert!(!db.move_value(0, 1, &7u32.to_le_bytes()).unwrap());
+		assert_eq!(db.get(0, &7u32.to_le_bytes()).unwrap(), None);
+		assert_eq!(db.get(1, &7u32.to_le_bytes()).unwrap(), Some(7u32.to_be_bytes().to_vec()));
+		assert!(db.move_value(1, 1, b"key").is_err());
+		assert!(db.move_value(1, 3, b"key").is_err());
+
+		// A move takes one reference.
+		db.commit(vec![(2, b"shared", Some(b"value".to_vec())), (2, b"shared", Some(b"value".to_vec()))]).unwrap();
+		assert!(db.move_value(2, 0, b"shared").unwrap());
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"shared").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(2, b"shared").unwrap(), Some(b"value".to_vec()));
+		assert!(db.move_value(2, 1, b"shared").unwrap());
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(1, b"shared").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(2, b"shared").unwrap(), None);
+	}
+
+	#[test]
+	fn test_hash_key() {
+		use crate::{display::hex, hash_key};
+		// Stored keys depend on these, so they must never change.
+		assert_eq!(
+			hex(&hash_key(b"key", None, false).unwrap()).to_string(),
+			"447f0b47ade868b5ba625ee132e0b70814231c780f362e883bb3fda138e1476f",
+		);
+		assert_eq!(
+			hex(&hash_key(b"key", Some(&[1u8; 32]), false).unwrap()).to_string(),
+			"9bfd728e3d76792e9f7f7f46587f191b554d46f3c7cfac7f5329beefba1fc224",
+		);
+		let uniform: Vec<u8> = (0u8..40).collect();
+		assert_eq!(&hash_key(&uniform, Some(&[1u8; 32]), true).unwrap()[..], &uniform[..32]);
+		assert!(hash_key(&uniform[..31], None, true).is_err());
+
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.salt = Some([1u8; 32]);
+		options.columns[1].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.salt(), Some([1u8; 32]));
+		db.commit(vec![(0, b"key".to_vec(), Some(vec![1])), (1, uniform.clone(), Some(vec![2]))]).unwrap();
+		for (col, key, uniform) in [(0, &b"key"[..], false), (1, &uniform[..], true)] {
+			let stored: Vec<_> = db.iter(col).unwrap().map(|r| r.unwrap().0).collect();
+			assert_eq!(stored, vec![hash_key(key, db.salt().as_ref(), uniform).unwrap()]);
+		}
+	}
+
+	#[test]
+	fn test_key_len() {
+		// Uniform keys must be evenly distributed.
+		let key = |i: u64| i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes();
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[0].key_len = 8;
+		options.columns[0].uniform = true;
+		options.columns[1].key_len = 20;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit((0u64..1000).flat_map(|i| vec![
+				(0, key(i).to_vec(), Some(vec![i as u8; 10])),
+				(1, key(i).to_vec(), Some(vec![i as u8; 10])),
+				(2, key(i).to_vec(), Some(vec![i as u8; 10])),
+			])).unwrap();
+			assert!(db.get(0, &[1u8; 7]).is_err());
+		}
+		assert_eq!(Options::from_existing(tmp.path()).unwrap().columns[0].key_len, 8);
+		let db = Db::open(&options).unwrap();
+		for i in 0u64..1000 {
+			for col in 0..3 {
+				assert_eq!(db.get(col, &key(i)).unwrap(), Some(vec![i as u8; 10]));
+			}
+		}
+		// Uniform keys are used as is, and only the first 8 bytes count.
+		let keys: Vec<_> = db.iter(0).unwrap().map(|r| r.unwrap().0).collect();
+		assert_eq!(keys.len(), 1000);
+		assert!(keys.iter().any(|k| k[..8] == key(7)));
+		assert!(keys.iter().all(|k| k[8..] == [0u8; 24]));
+		assert_eq!(db.get(0, &[&key(7)[..], &[1u8; 24]].concat()).unwrap(), Some(vec![7u8; 10]));
+		let hashed = db.iter(1).unwrap().next().unwrap().unwrap().0;
+		assert_eq!(&hashed[20..], &[0u8; 12]);
+		drop(db);
+
+		let mut other = options.clone();
+		other.columns[0].key_len = 32;
+		match Db::open(&other) {
+			Err(crate::Error::ConfigMismatch(m)) => assert_eq!(m[0].field, "key_len"),
+			_ => panic!("Expected mismatch"),
+		}
+		other.columns[0].key_len = 7;
+		assert!(!other.is_valid());
+	}
+
+	#[test]
+	fn test_key_hash() {
+		use crate::{display::hex, KeyHash};
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.salt = Some([1u8; 32]);
+		options.columns[1].key_hash = KeyHash::XxHash;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			// Stored keys depend on this, so it must never change.
+			assert_eq!(hex(&db.hash_key(1, b"key").unwrap()).to_string(), "0f3910a3b3a4c4efd72417450f5a97bda16b7668164f7e37f07b332b0c123b9c");
+			assert_ne!(db.hash_key(0, b"key").unwrap(), db.hash_key(1, b"key").unwrap());
+			db.commit((0u64..1000).flat_map(|i| vec![
+				(0, i.to_be_bytes().to_vec(), Some(vec![i as u8; 10])),
+				(1, i.to_be_bytes().to_vec(), Some(vec![i as u8; 10])),
+			])).unwrap();
+			// Sequential keys are spread over the index.
+			let chunks: std::collections::HashSet<_> = (0u64..1000)
+				.map(|i| db.hash_key(1, &i.to_be_bytes()).unwrap()[..2].to_vec())
+				.collect();
+			assert!(chunks.len() > 950, "{}", chunks.len());
+			for i in 0u64..1000 {
+				assert_eq!(db.get(1, &i.to_be_bytes()).unwrap(), Some(vec![i as u8; 10]));
+			}
+		}
+		assert_eq!(Options::from_existing(tmp.path()).unwrap().columns[1].key_hash, KeyHash::XxHash);
+		let db = Db::open(&options).unwrap();
+		for i in 0u64..1000 {
+			for col in 0..2 {
+				assert_eq!(db.get(col, &i.to_be_bytes()).unwrap(), Some(vec![i as u8; 10]));
+			}
+		}
+		let keys: Vec<_> = db.iter(1).unwrap().map(|r| r.unwrap().0).collect();
+		assert_eq!(keys.len(), 1000);
+		assert!(keys.contains(&db.hash_key(1, &7u64.to_be_bytes()).unwrap()));
+		drop(db);
+
+		let mut other = options.clone();
+		other.columns[1].key_hash = KeyHash::Blake2;
+		match Db::open(&other) {
+			Err(crate::Error::ConfigMismatch(m)) => assert_eq!(m[0].field, "key_hash"),
+			_ => panic!("Expected mismatch"),
+		}
+		other.columns[1].key_hash = KeyHash::XxHash;
+		other.columns[1].uniform = true;
+		assert!(!other.is_valid());
+	}
+
+	#[test]
+	fn test_raw_keys() {
+		use crate::KeyMode;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.salt = Some([2u8; 32]);
+		options.columns[1].key_mode = KeyMode::Raw;
+		let address = [7u8; 20];
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit_raw((0u64..100).map(|i| (1, i.to_be_bytes().to_vec(), Some(vec![i as u8; 10])))).unwrap();
+			db.commit_raw(vec![(1, address, Some(b"address".to_vec()))]).unwrap();
+			// Pre-hashed keys take the same path.
+			let key = db.hash_key(1, b"hashed").unwrap();
+			db.raw_commit(vec![(1, key, b"hashed", Some(b"value".to_vec()))]).unwrap();
+			assert_eq!(db.get_raw(1, b"hashed").unwrap(), Some(b"value".to_vec()));
+			assert_eq!(db.raw_get(1, &db.hash_key(1, &address).unwrap()).unwrap(), Some(b"address".to_vec()));
+
+			// Keys of the other mode are rejected.
+			assert!(matches!(db.get(1, &address), Err(crate::Error::InvalidInput(_))));
+			assert!(matches!(db.commit(vec![(1, address, None)]), Err(crate::Error::InvalidInput(_))));
+			assert!(matches!(db.get_raw(0, &address), Err(crate::Error::InvalidInput(_))));
+			assert!(matches!(db.commit_raw(vec![(0, address, None)]), Err(crate::Error::InvalidInput(_))));
+			assert_eq!(db.get_raw(1, &[7u8; 19]).unwrap(), None);
+		}
+		assert_eq!(Options::from_existing(tmp.path()).unwrap().columns[1].key_mode, KeyMode::Raw);
+		let db = Db::open(&options).unwrap();
+		for i in 0u64..100 {
+			assert_eq!(db.get_raw(1, &i.to_be_bytes()).unwrap(), Some(vec![i as u8; 10]));
+		}
+		assert_eq!(db.get_raw(1, &address).unwrap(), Some(b"address".to_vec()));
+		drop(db);
+
+		let mut other = options.clone();
+		other.columns[1].key_mode = KeyMode::Typed;
+		match Db::open(&other) {
+			Err(crate::Error::ConfigMismatch(m)) => assert_eq!(m[0].field, "key_mode"),
+			_ => panic!("Expected mismatch"),
+		}
+		other.columns[1].key_mode = KeyMode::Raw;
+		other.columns[1].uniform = true;
+		assert!(!other.is_valid());
+	}
+
+	#[test]
+	fn test_counters() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1".to_vec(), Some(vec![1u8; 10])), (0, b"key2".to_vec(), Some(vec![2u8; 20]))]).unwrap();
+		assert!(db.get(0, b"key1").unwrap().is_some());
+		assert!(db.get_size(0, b"key3").unwrap().is_none());
+		let entry_size = db.get_with_meta(0, b"key2").unwrap().unwrap().1.entry_size;
+		db.commit(vec![(0, b"key2".to_vec(), None)]).unwrap();
+		db.flush().unwrap();
+		let counters = db.counters(0).unwrap();
+		assert_eq!((counters.gets, counters.hits, counters.misses), (2, 1, 1));
+		assert_eq!((counters.committed_keys, counters.committed_bytes), (3, 3 * 32 + 10 + 20));
+		assert_eq!(counters.freed_bytes, entry_size as u64);
+		assert_eq!(db.counters(1).unwrap(), Default::default());
+		assert!(matches!(db.counters(2), Err(crate::Error::InvalidInput(_))));
+
+		db.reset_counters();
+		assert_eq!(db.counters(0).unwrap(), Default::default());
+	}
+
+	#[test]
+	fn test_options_builder() {
+		let tmp = tempdir().unwrap();
+		let invalid = |result: crate::Result<Options>, field: &str| match result {
+			Err(crate::Error::InvalidConfiguration(m)) => assert!(m.contains(field), "{}", m),
+			_ => panic!("Expected invalid {}", field),
+		};
+		let options = Options::builder(tmp.path())
+			.default_columns(1)
+			.column(crate::ColumnOptions { ref_counted: true, ..Default::default() })
+			.background_threads(2)
+			.sync_wal(crate::WalSync::Never)
+			.build()
+			.unwrap();
+		assert_eq!(options.columns.len(), 2);
+		assert!(options.columns[1].ref_counted);
+		assert_eq!(options.background_threads, 2);
+
+		invalid(Options::builder(tmp.path()).build(), "columns");
+		invalid(Options::builder("").default_columns(1).build(), "path");
+		invalid(Options::builder(tmp.path()).default_columns(1).background_threads(5).build(), "background_threads");
+		invalid(Options::builder(tmp.path()).default_columns(1).enact_threads(0).build(), "enact_threads");
+		invalid(Options::builder(tmp.path()).default_columns(1).commit_queue_bytes(0).build(), "commit_queue_bytes");
+		invalid(Options::builder(tmp.path()).default_columns(1).overlay_water_marks(10, 20).build(), "overlay_low_water_bytes");
+		invalid(
+			Options::builder(tmp.path()).column(crate::ColumnOptions { sizes: vec![64, 32], ..Default::default() }).build(),
+			"column 0 sizes",
+		);
+		invalid(
+			Options::builder(tmp.path()).default_columns(1).column(crate::ColumnOptions { key_len: 4, ..Default::default() }).build(),
+			"column 1 key_len",
+		);
+		invalid(
+			Options::builder(tmp.path()).default_columns(1).size_hint(3, Default::default()).build(),
+			"size_hints",
+		);
+
+		// Opening validates the same way, instead of panicking.
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].index_chunk_entries = 7;
+		assert!(matches!(Db::open_or_create(&options), Err(crate::Error::InvalidConfiguration(_))));
+	}
+
+	#[test]
+	fn test_repair() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		// Keys can be told from values: each value starts with its key.
+		let value = |i: u32| [&i.to_le_bytes()[..], &vec![i as u8; if i % 10 == 0 { 5000 } else { 20 }]].concat();
+		let key_of = |value: &[u8]| value.get(0 .. 4).map(|k| k.to_vec());
+		let check = |db: &Db| {
+			for i in 0u32..1000 {
+				let expected = if i % 3 == 0 { None } else { Some(value(i)) };
+				assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), expected);
+			}
+		};
+
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..1000).map(|i| (0, i.to_le_bytes(), Some(value(i))))).unwrap();
+		db.commit((0u32..1000).filter(|i| i % 3 == 0).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		db.flush().unwrap();
+		// Healthy indexes are only rebuilt on request.
+		assert!(db.repair(0, key_of, false).is_err());
+		assert_eq!(db.repair(0, key_of, true).unwrap(), crate::RepairSummary { indexed: 666, skipped: 0 });
+		check(&db);
+		drop(db);
+
+		// Lose the index file, but keep the value tables.
+		let index = std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap().path())
+			.find(|p| p.file_name().unwrap().to_str().unwrap().starts_with("index_00_"))
+			.unwrap();
+		std::fs::OpenOptions::new().write(true).open(&index).unwrap().set_len(0).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), None);
+		assert!(!db.verify(&[0]).unwrap().is_ok());
+		// Values with unknown keys are skipped.
+		let summary = db.repair(0, |v| if v[0] == 1 { None } else { key_of(v) }, false).unwrap();
+		assert_eq!(summary, crate::RepairSummary { indexed: 663, skipped: 3 });
+		assert!(!db.verify(&[0]).unwrap().is_ok());
+		assert_eq!(db.repair(0, key_of, false).unwrap(), crate::RepairSummary { indexed: 666, skipped: 0 });
+		assert!(db.verify(&[0]).unwrap().is_ok());
+		check(&db);
+		db.commit(vec![(0, 5000u32.to_le_bytes(), Some(value(5000)))]).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		check(&db);
+		assert_eq!(db.get(0, &5000u32.to_le_bytes()).unwrap(), Some(value(5000)));
+		assert!(db.verify(&[0]).unwrap().is_ok());
+	}
+
+	#[test]
+	fn test_options_file() {
+		let tmp = tempdir().unwrap();
+		let file = tmp.path().join("options.conf");
+		let options = Options::builder(tmp.path())
+			.default_columns(1)
+			.column(crate::ColumnOptions {
+				name: Some("blocks".into()),
+				trash: Some(crate::TrashOptions { max_bytes: 1024, max_age: std::time::Duration::from_secs(60) }),
+				cache_size_bytes: 4096,
+				sizes: vec![64, 128],
+				path: Some("/mnt/cold".into()),
+				..Default::default()
+			})
+			.sync_wal(crate::WalSync::Interval(std::time::Duration::from_millis(250)))
+			.salt([7; 32])
+			.overlay_water_marks(2000, 1000)
+			.max_uncommitted_bytes(5000)
+			.wal_path(tmp.path().join("wal"))
+			.log_pool_size(4)
+			.background_threads(2)
+			.backlog_warning(None)
+			.mmap_value_tables(true)
+			.verify_raw_keys(true)
+			.build()
+			.unwrap();
+		options.write_to(&file).unwrap();
+		let loaded = Options::load_from(&file).unwrap();
+		assert_eq!(format!("{:?}", loaded), format!("{:?}", options));
+
+		// Unknown settings are ignored, bad ones are not.
+		let contents = std::fs::read_to_string(&file).unwrap();
+		std::fs::write(&file, format!("# comment\nfuture_option=1\ncol1.future_option=2\n{}", contents)).unwrap();
+		assert_eq!(format!("{:?}", Options::load_from(&file).unwrap()), format!("{:?}", options));
+		std::fs::write(&file, format!("{}stats=maybe\n", contents)).unwrap();
+		assert!(matches!(Options::load_from(&file), Err(crate::Error::InvalidConfiguration(m)) if m.contains("stats")));
+		std::fs::write(&file, contents.replace("col1=", "col2=")).unwrap();
+		assert!(Options::load_from(&file).is_err());
+
+		// Created databases can be opened with the options they wrote.
+		let path = tmp.path().join("db");
+		let mut options = Options::with_columns(&path, 2);
+		options.persist_options = true;
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(1, b"key", Some(b"value".to_vec()))]).unwrap();
+		let salt = db.salt();
+		drop(db);
+		let loaded = Options::load_from(&path.join(crate::OPTIONS_FILE)).unwrap();
+		assert_eq!(loaded.path, path);
+		assert_eq!(loaded.salt, salt);
+		assert!(loaded.columns[1].ref_counted);
+		let db = Db::open(&loaded).unwrap();
+		assert_eq!(db.get(1, b"key").unwrap(), Some(b"value".to_vec()));
+		drop(db);
+		Db::drop_database(&path).unwrap();
+	}
+
+	#[test]
+	fn test_stats_disabled() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.stats = false;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1".to_vec(), Some(vec![1u8; 10]))]).unwrap();
+		assert!(db.get(0, b"key1").unwrap().is_some());
+		assert!(db.counters(0).unwrap_err().is_stats_disabled());
+		assert!(db.collect_stats(&mut Vec::new(), None).unwrap_err().is_stats_disabled());
+		db.close(None).unwrap();
+		assert!(!tmp.path().join("stats.txt").exists());
+
+		options.stats = true;
+		let db = Db::open(&options).unwrap();
+		assert!(db.get(0, b"key1").unwrap().is_some());
+		assert_eq!(db.counters(0).unwrap().hits, 1);
+		let mut stats = Vec::new();
+		db.collect_stats(&mut stats, None).unwrap();
+		assert!(!stats.is_empty());
+	}
+
+	#[test]
+	fn test_commit_visibility() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Arc::new(Db::open_or_create(&options).unwrap());
+		let done = Arc::new(AtomicBool::new(false));
+		let key = |writer: u8, k: u8| [writer, k];
+		// Each writer sets all of its keys to the batch number.
+		let writers: Vec<_> = (0u8..2).map(|writer| {
+			let db = db.clone();
+			std::thread::spawn(move || {
+				for batch in 1u32..=500 {
+					db.commit((0u8..16).map(|k| (0, key(writer, k), Some(batch.to_le_bytes().to_vec())))).unwrap();
+				}
+			})
+		}).collect();
+		let readers: Vec<_> = (0u8..2).map(|writer| {
+			let db = db.clone();
+			let done = done.clone();
+			std::thread::spawn(move || {
+				while !done.load(Ordering::SeqCst) {
+					// Once a batch is seen, later keys must show it too.
+					let mut last = 0u32;
+					for k in 0u8..16 {
+						let batch = db.get(0, &key(writer, k)).unwrap()
+							.map_or(0, |v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]));
+						assert!(batch >= last, "Partial batch {} observed after {}", batch, last);
+						last = batch;
+					}
+				}
+			})
+		}).collect();
+		for writer in writers {
+			writer.join().unwrap();
+		}
+		db.flush().unwrap();
+		done.store(true, Ordering::SeqCst);
+		for reader in readers {
+			reader.join().unwrap();
+		}
+		for k in 0u8..16 {
+			assert_eq!(db.get(0, &key(1, k)).unwrap(), Some(500u32.to_le_bytes().to_vec()));
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn test_commit_with_completion() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		assert!(options.sync_wal == crate::WalSync::EveryCommit);
+		let db = Db::open_or_create(&options).unwrap();
+		// Queue thousands of commits from the runtime without waiting for any of them.
+		let mut pending = Vec::new();
+		for i in 0u32..5000 {
+			let (completion, done) = std::sync::mpsc::channel();
+			db.commit_with_completion(vec![(0, i.to_le_bytes(), Some(i.to_le_bytes().to_vec()))], completion).unwrap();
+			pending.push(done);
+		}
+		// Commits complete in order, so all others are done once the last one is.
+		let last = pending.pop().unwrap();
+		tokio::task::spawn_blocking(move || last.recv().unwrap()).await.unwrap().unwrap();
+		for done in pending {
+			assert!(matches!(done.try_recv(), Ok(Ok(()))));
+		}
+		assert_eq!(db.get(0, &4999u32.to_le_bytes()).unwrap(), Some(4999u32.to_le_bytes().to_vec()));
+
+		// The log is synced by the flush worker, which does not run while paused.
+		db.pause_background();
+		let (completion, done) = std::sync::mpsc::channel();
+		db.commit_with_completion(vec![(0, b"paused", Some(b"value".to_vec()))], completion).unwrap();
+		while db.inner.synced_waiters.lock().is_empty() {
+			tokio::task::yield_now().await;
+		}
+		assert!(done.try_recv().is_err());
+		db.resume_background();
+		tokio::task::spawn_blocking(move || done.recv().unwrap()).await.unwrap().unwrap();
+	}
+
+	#[test]
+	fn test_commit_async() {
+		struct ThreadWaker(std::thread::Thread);
+		impl std::task::Wake for ThreadWaker {
+			fn wake(self: std::sync::Arc<Self>) {
+				self.0.unpark();
+			}
+		}
+		struct FlagWaker(AtomicBool);
+		impl std::task::Wake for FlagWaker {
+			fn wake(self: std::sync::Arc<Self>) {
+				self.0.store(true, Ordering::SeqCst);
+			}
+		}
+		fn block_on<F: std::future::Future>(future: F) -> F::Output {
+			let waker = std::sync::Arc::new(ThreadWaker(std::thread::current())).into();
+			let mut cx = std::task::Context::from_waker(&waker);
+			let mut future = Box::pin(future);
+			loop {
+				match future.as_mut().poll(&mut cx) {
+					std::task::Poll::Ready(result) => return result,
+					std::task::Poll::Pending => std::thread::park(),
+				}
+			}
+		}
+
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let pending: Vec<_> = (0u32..100)
+			.map(|i| db.commit_async(vec![(0, i.to_le_bytes(), Some(i.to_le_bytes().to_vec()))]))
+			.collect();
+		let records: Vec<u64> = pending.into_iter().map(|f| block_on(f).unwrap()).collect();
+		assert!(records.windows(2).all(|w| w[0] < w[1]));
+		assert_eq!(db.current_record_id(), *records.last().unwrap());
+		assert_eq!(db.get(0, &99u32.to_le_bytes()).unwrap(), Some(99u32.to_le_bytes().to_vec()));
+
+		// A commit that has to wait for room does not block the caller.
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_uncommitted_bytes = Some(64 * 1024);
+		let db = Db::open_or_create(&options).unwrap();
+		db.pause_background();
+		db.commit(vec![(0, b"a", Some(vec![1u8; 40 * 1024]))]).unwrap();
+		let deadline = Instant::now() + std::time::Duration::from_secs(10);
+		while db.pending_bytes().commit_queue > 0 {
+			assert!(Instant::now() < deadline, "Commit not logged");
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		let mut future = db.commit_async(vec![(0, b"b", Some(vec![2u8; 40 * 1024]))]);
+		let woken = std::sync::Arc::new(FlagWaker(AtomicBool::new(false)));
+		let waker = woken.clone().into();
+		let poll = std::future::Future::poll(std::pin::Pin::new(&mut future), &mut std::task::Context::from_waker(&waker));
+		assert!(poll.is_pending());
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+		db.resume_background();
+		assert!(block_on(future).unwrap() > 0);
+		assert!(woken.0.load(Ordering::SeqCst));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2u8; 40 * 1024]));
+	}
+
+	#[test]
+	fn test_large_commit_replay() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let values: Vec<_> = (0u32..64).map(|i| (0, i.to_le_bytes(), Some(vec![i as u8; 128 * 1024]))).collect();
+		db.commit(values).unwrap();
+		drop(db);
+		// Record checksum is verified when the log is enacted.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &63u32.to_le_bytes()).unwrap(), Some(vec![63u8; 128 * 1024]));
+	}
+
+	#[test]
+	fn test_locked_reports_owner() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let _db = Db::open_or_create(&options).unwrap();
+		let err = Db::open(&options).map(|_| ()).unwrap_err();
+		assert!(err.is_locked());
+		assert!(err.to_string().contains(&format!("held by process {}", std::process::id())));
+	}
+
+	#[test]
+	fn test_index_skew() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		// All keys share the high bits and land in the same index chunk.
+		let keys: Vec<[u8; 32]> = (0u8..40).map(|i| {
+			let mut key = [0xab; 32];
+			key[4] = i;
+			key
+		}).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(k.to_vec())))).unwrap();
+		let start = Instant::now();
+		while !db.inner.columns[0].skew_reported() {
+			assert!(start.elapsed() < std::time::Duration::from_secs(10), "Skew was not reported");
+			db.inner.force_flush.store(true, Ordering::SeqCst);
+			db.inner.signal_flush_worker();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		let mut stats = Vec::new();
+		db.collect_stats(&mut stats, Some(0)).unwrap();
+		assert!(String::from_utf8(stats).unwrap().contains("Index skew detected: true"));
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		for k in &keys {
+			assert_eq!(db.get(0, k).unwrap(), Some(k.to_vec()));
+		}
+		assert_eq!(db.hot_index_chunks(0, 10).unwrap(), vec![(0xabab, 40)]);
+		assert!(db.hot_index_chunks(1, 10).is_err());
+	}
+
+	#[test]
+	fn test_close() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.close(None).unwrap();
+		let logs = std::fs::read_dir(tmp.path()).unwrap()
+			.filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("log"))
+			.count();
+		assert_eq!(logs, 0);
+
+		// Expired timeout leaves logs for replay.
+		let db = Db::open(&options).unwrap();
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_validate_on_replay() {
+		use std::io::Write;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		// Append a partial record to the last log.
+		let log = std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap().path())
+			.filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("log"))
+			.max()
+			.unwrap();
+		std::fs::OpenOptions::new().append(true).open(log).unwrap().write_all(&[1, 0xff]).unwrap();
+
+		options.validate_on_replay = true;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+	}
+
+	#[test]
+	fn test_replay_bypasses_overlay() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.pause_background();
+		for i in 0u32..200 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 10_000]))]).unwrap();
+		}
+		db.flush().unwrap();
+		assert!(db.inner.log.overlay_bytes() >= 200 * 10_000);
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		let log_bytes: u64 = std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap())
+			.filter(|e| e.file_name().to_string_lossy().starts_with("log"))
+			.map(|e| e.metadata().unwrap().len())
+			.sum();
+		assert!(log_bytes >= 100 * 10_000);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.inner.log.overlay_bytes(), 0);
+		assert_eq!(db.inner.log.overlay_stats(), (0, 0));
+		for i in 0u32..200 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 10_000]));
+		}
+	}
+
+	#[test]
+	fn test_repair_on_replay() {
+		use std::io::Write;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.repair_on_replay = true;
+		let log_path = || std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap().path())
+			.filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("log"))
+			.max()
+			.unwrap();
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		std::fs::OpenOptions::new().append(true).open(log_path()).unwrap().write_all(&[1, 0xff, 0xff]).unwrap();
+
+		// Torn tail.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+		db.commit(vec![(0, b"key3", Some(b"value3".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"key4", Some(b"value4".to_vec()))]).unwrap();
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+
+		// A bad record followed by a valid one.
+		let log = log_path();
+		let mut data = std::fs::read(&log).unwrap();
+		data[12] ^= 0xff;
+		std::fs::write(&log, &data).unwrap();
+		assert!(matches!(Db::open(&options), Err(crate::Error::Corruption(_))));
+	}
+
+	#[test]
+	fn test_discard_wal() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.repair_on_replay = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.close(None).unwrap();
+		let db = Db::open(&options).unwrap();
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"key3", Some(b"value3".to_vec()))]).unwrap();
+		assert!(Db::discard_wal(&options).is_err());
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		let log = std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap().path())
+			.filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("log"))
+			.max()
+			.unwrap();
+		let mut data = std::fs::read(&log).unwrap();
+		data[12] ^= 0xff;
+		std::fs::write(&log, &data).unwrap();
+		assert!(matches!(Db::open(&options), Err(crate::Error::Corruption(_))));
+
+		assert!(Db::discard_wal(&options).is_err());
+		options.allow_wal_discard = true;
+		assert!(Db::discard_wal(&options).unwrap() > 0);
+		assert_eq!(Db::discard_wal(&options).unwrap(), 0);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+	}
+
+	#[test]
+	fn test_validate_logs() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		assert!(Db::validate_logs(&options).is_err());
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+
+		// Empty logs are removed on open, but not by validation.
+		let empty = tmp.path().join("log100");
+		std::fs::write(&empty, []).unwrap();
+		let report = Db::validate_logs(&options).unwrap();
+		assert!(report.failure.is_none());
+		assert!(empty.exists());
+	}
+
+	#[test]
+	fn test_wal_path() {
+		let tmp = tempdir().unwrap();
+		let backup = tempdir().unwrap();
+		let wal = tmp.path().join("wal");
+		let mut options = Options::with_columns(&tmp.path().join("db"), 1);
+		options.wal_path = Some(wal.clone());
+		let logs = |path: &std::path::Path| std::fs::read_dir(path).unwrap()
+			.filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("log"))
+			.count();
+
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		assert!(logs(&wal) > 0);
+		assert_eq!(logs(&options.path), 0);
+
+		// Logs are replayed from the log directory.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+		db.commit(vec![(0, b"key3", Some(b"value3".to_vec()))]).unwrap();
+		assert!(db.size_on_disk().unwrap().logs > 0);
+		db.backup(backup.path()).unwrap();
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		assert_eq!(logs(&options.path), 0);
+
+		let db = Db::open(&Options::with_columns(backup.path(), 1)).unwrap();
+		assert_eq!(db.get(0, b"key3").unwrap(), Some(b"value3".to_vec()));
+		drop(db);
+
+		options.allow_wal_discard = true;
+		assert!(Db::discard_wal(&options).unwrap() > 0);
+		assert_eq!(logs(&wal), 0);
+		options.wal_path = Some(std::path::PathBuf::new());
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_pause_background() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.pause_background();
+		for i in 0u32..50 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 100]))]).unwrap();
+		}
+		db.flush().unwrap();
+		db.inner.force_flush.store(true, Ordering::SeqCst);
+		db.inner.signal_flush_worker();
+		std::thread::sleep(std::time::Duration::from_millis(100));
+		assert_eq!(db.oldest_unflushed_record_id(), Some(1));
+		assert!(db.inner.log.overlay_stats().1 >= 50);
+		assert_eq!(db.get(0, &49u32.to_le_bytes()).unwrap(), Some(vec![49; 100]));
+
+		db.resume_background();
+		let deadline = Instant::now() + std::time::Duration::from_secs(10);
+		while db.oldest_unflushed_record_id().is_some() {
+			db.inner.force_flush.store(true, Ordering::SeqCst);
+			db.inner.signal_flush_worker();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+			assert!(Instant::now() < deadline, "Logs not enacted");
+		}
+		assert_eq!(db.inner.log.overlay_stats(), (0, 0));
+
+		// Closing enacts everything regardless.
+		db.pause_background();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		db.close(None).unwrap();
+		assert_eq!(Db::open_read_only(&options).unwrap().dirty_log_count(), 0);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_disable_wal() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].index_chunk_entries = 8;
+		options.columns[1].uniform = true;
+		let log_bytes = || std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap())
+			.filter(|e| e.file_name().to_string_lossy().starts_with("log"))
+			.map(|e| e.metadata().unwrap().len())
+			.sum::<u64>();
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.close(Some(std::time::Duration::from_secs(0))).unwrap();
+		assert!(log_bytes() > 0);
+
+		// Leftover logs are replayed.
+		options.disable_wal = true;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		// Nine keys in the same chunk overflow it and trigger reindexing.
+		let keys: Vec<_> = (0u8..9).map(|i| {
+			let mut key = [0u8; 32];
+			key[2] = if i % 2 == 0 { i } else { 0x80 | i };
+			key
+		}).collect();
+		for k in &keys {
+			db.commit(vec![(1, k.to_vec(), Some(vec![k[2]; 5000]))]).unwrap();
+		}
+		db.commit(vec![(0, b"key1", None)]).unwrap();
+		while tmp.path().join("index_01_16").exists() {
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(db.get(0, b"key1").unwrap(), None);
+		assert_eq!(db.get(1, &keys[3]).unwrap(), Some(vec![keys[3][2]; 5000]));
+		db.close(None).unwrap();
+		assert_eq!(log_bytes(), 0);
+
+		let db = Db::open(&options).unwrap();
+		assert!(tmp.path().join("index_01_17").exists());
+		assert_eq!(db.get(0, b"key1").unwrap(), None);
+		for k in &keys {
+			assert_eq!(db.get(1, k).unwrap(), Some(vec![k[2]; 5000]));
+		}
+	}
+
+	#[test]
+	fn test_drop_database() {
+		let tmp = tempdir().unwrap();
+		let path = tmp.path().join("db");
+		let options = Options::with_columns(&path, 2);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key".to_vec(), Some(vec![1; 100]))]).unwrap();
+		assert!(Db::drop_database(&path).unwrap_err().is_locked());
+		drop(db);
+
+		// Unknown files are left alone.
+		std::fs::write(path.join("notes"), b"keep").unwrap();
+		assert!(Db::drop_database(&path).is_err());
+		assert!(path.join("metadata").exists());
+		std::fs::remove_file(path.join("notes")).unwrap();
+
+		// Partially removed database.
+		std::fs::remove_file(path.join("metadata")).unwrap();
+		Db::drop_database(&path).unwrap();
+		assert!(!path.exists());
+		Db::drop_database(&path).unwrap();
+
+		// Files of columns past 99 have 3 digit names.
+		let options = Options::with_columns(&path, 101);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(100, b"key".to_vec(), Some(vec![1; 100]))]).unwrap();
+		db.close(None).unwrap();
+		assert!(path.join("index_100_16").exists());
+		std::fs::write(path.join("bulk_100"), []).unwrap();
+		Db::drop_database(&path).unwrap();
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn test_approximate_prefix_size() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = |i: u64| {
+			let mut key = [0u8; 32];
+			key[0..8].copy_from_slice(&i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+			key[8..16].copy_from_slice(&i.to_le_bytes());
+			key
+		};
+		db.commit((0u64..50_000).map(|i| (0, key(i), Some(vec![1u8; (i % 200 + 1) as usize])))).unwrap();
+		db.flush().unwrap();
+		let exact = |prefix: &[u8]| -> u64 {
+			db.iter(0).unwrap().map(|e| e.unwrap()).filter(|(k, _)| k.starts_with(prefix)).map(|(_, v)| v.len() as u64).sum()
+		};
+		let first = key(7);
+		for prefix in [&[][..], &first[..1], &first[..2], &first[..32], &[0x42, 0x42, 0x42][..]] {
+			let exact = exact(prefix);
+			let estimate = db.approximate_prefix_size(0, prefix).unwrap();
+			assert!(estimate.abs_diff(exact) <= exact / 10, "prefix {:?}: estimate {}, exact {}", prefix, estimate, exact);
+		}
+		assert!(db.approximate_prefix_size(1, &[]).is_err());
+		assert!(db.approximate_prefix_size(0, &[0; 33]).is_err());
+	}
+
+	#[test]
+	fn test_clear_column() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].ref_counted = true;
+		options.columns[2].trash = Some(crate::options::TrashOptions {
+			max_bytes: 1 << 20,
+			max_age: std::time::Duration::from_secs(3600),
+		});
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for i in 0u32..100 {
+				let value = Some(i.to_le_bytes().repeat(10));
+				db.commit(vec![(0, i.to_le_bytes(), value.clone()), (1, i.to_le_bytes(), value.clone()), (2, i.to_le_bytes(), value)]).unwrap();
+			}
+			db.commit(vec![(1, 0u32.to_le_bytes(), Some(0u32.to_le_bytes().repeat(10)))]).unwrap();
+			db.commit(vec![(2, 0u32.to_le_bytes(), None)]).unwrap();
+
+			db.clear_column(0).unwrap();
+			assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), None);
+			assert_eq!(db.get(2, &1u32.to_le_bytes()).unwrap(), Some(1u32.to_le_bytes().repeat(10)));
+			// Removed regardless of the reference count.
+			db.clear_column(1).unwrap();
+			assert_eq!(db.get(1, &0u32.to_le_bytes()).unwrap(), None);
+			db.clear_column(2).unwrap();
+			assert!(!db.undelete(2, &0u32.to_le_bytes()).unwrap());
+			db.commit(vec![(0, b"new".to_vec(), Some(vec![1]))]).unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.iter_keys(0).unwrap().count(), 1);
+		assert_eq!(db.iter_keys(1).unwrap().count(), 0);
+		assert_eq!(db.iter_keys(2).unwrap().count(), 0);
+
+		// Cleared by a single record, however many values there are.
+		db.commit((0..10_000u32).map(|i| (0, i.to_le_bytes(), Some(vec![1])))).unwrap();
+		db.flush().unwrap();
+		let record_id = db.current_record_id();
+		db.clear_column(0).unwrap();
+		assert_eq!(db.current_record_id(), record_id + 1);
+		assert_eq!(db.iter_keys(0).unwrap().count(), 0);
+		// Freed slots are reused.
+		db.commit((0..100u32).map(|i| (0, i.to_le_bytes(), Some(vec![2])))).unwrap();
+		assert_eq!(db.get(0, &5u32.to_le_bytes()).unwrap(), Some(vec![2]));
+		assert_eq!(db.iter_keys(0).unwrap().count(), 100);
+	}
+
+	#[test]
+	fn test_clear_column_crash() {
+		let tmp = tempdir().unwrap();
+		let path = tmp.path().join("db");
+		let options = Options::with_columns(&path, 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..1000).map(|i| (0, i.to_le_bytes(), Some(vec![i as u8; 100])))).unwrap();
+		db.close(None).unwrap();
+		let db = Db::open(&options).unwrap();
+		db.pause_background();
+		db.clear_column(0).unwrap();
+		assert_eq!(db.get(0, &5u32.to_le_bytes()).unwrap(), None);
+		// Files as left by a crash before the clear record is enacted.
+		let (log, len) = std::fs::read_dir(&path).unwrap()
+			.map(|e| e.unwrap())
+			.filter(|e| e.file_name().to_string_lossy().starts_with("log"))
+			.map(|e| (e.file_name(), e.metadata().unwrap().len()))
+			.find(|(_, len)| *len > 0)
+			.unwrap();
+		let cuts = [0, 1, len / 2, len];
+		for cut in cuts {
+			let copy = tmp.path().join(format!("copy{}", cut));
+			std::fs::create_dir(&copy).unwrap();
+			for entry in std::fs::read_dir(&path).unwrap() {
+				let entry = entry.unwrap();
+				std::fs::copy(entry.path(), copy.join(entry.file_name())).unwrap();
+			}
+			let file = std::fs::OpenOptions::new().write(true).open(copy.join(&log)).unwrap();
+			file.set_len(len - cut).unwrap();
+		}
+		drop(db);
+
+		// Replayed in full, or not at all if the record is cut short.
+		for cut in cuts {
+			let options = Options::with_columns(&tmp.path().join(format!("copy{}", cut)), 1);
+			let db = Db::open(&options).unwrap();
+			let expected = if cut == 0 { 0 } else { 1000 };
+			assert_eq!(db.iter_keys(0).unwrap().count(), expected, "cut {}", cut);
+			assert_eq!(db.get(0, &5u32.to_le_bytes()).unwrap(), (cut != 0).then(|| vec![5; 100]));
+			db.commit(vec![(0, b"new", Some(vec![1]))]).unwrap();
+			db.close(None).unwrap();
+			let db = Db::open(&options).unwrap();
+			assert_eq!(db.iter_keys(0).unwrap().count(), expected + 1);
+			assert_eq!(db.get(0, b"new").unwrap(), Some(vec![1]));
+		}
+	}
+
+	#[test]
+	fn test_lock_of_exited_owner() {
+		use fs2::FileExt;
+		use std::io::Write;
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		drop(Db::open_or_create(&options).unwrap());
+		// Lock still held through a file inherited from a process that is gone.
+		let mut lock = std::fs::OpenOptions::new().write(true).open(tmp.path().join("lock")).unwrap();
+		lock.try_lock_exclusive().unwrap();
+		lock.set_len(0).unwrap();
+		write!(lock, "{}", i32::MAX).unwrap();
+
+		assert!(Db::open(&options).map(|_| ()).unwrap_err().is_locked());
+		// The lock file is kept.
+		assert_eq!(std::fs::read_to_string(tmp.path().join("lock")).unwrap(), i32::MAX.to_string());
+		drop(lock);
+		assert!(Db::open(&options).is_ok());
+	}
+
+	#[test]
+	fn test_stale_lock() {
+		use fs2::FileExt;
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let lock_path = tmp.path().join("lock");
+		let db = Db::open_or_create(&options).unwrap();
+		let owner = std::fs::read_to_string(&lock_path).unwrap();
+		let pid = std::process::id().to_string();
+		assert_eq!(owner.split(' ').next(), Some(pid.as_str()));
+		let err = Db::open(&options).map(|_| ()).unwrap_err();
+		assert!(err.is_locked());
+		assert!(err.to_string().contains(&format!("held by process {} since ", pid)));
+		drop(db);
+		// Cleared on close.
+		assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), "");
+
+		// Left behind by a crashed process, but not locked.
+		std::fs::write(&lock_path, "12345 1000").unwrap();
+		let db = Db::open(&options).unwrap();
+		assert!(std::fs::read_to_string(&lock_path).unwrap().starts_with(&pid));
+		drop(db);
+
+		// Actually locked by someone else.
+		std::fs::write(&lock_path, "12345 1000").unwrap();
+		let lock = std::fs::File::open(&lock_path).unwrap();
+		lock.try_lock_exclusive().unwrap();
+		let err = Db::open(&options).map(|_| ()).unwrap_err();
+		assert!(err.to_string().contains("held by process 12345 since 1000"));
+	}
+
+	#[test]
+	fn test_interval_wal_sync() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.sync_wal = crate::options::WalSync::Interval(std::time::Duration::from_millis(10));
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..100 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![1]))]).unwrap();
+		}
+		std::thread::sleep(std::time::Duration::from_millis(20));
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &99u32.to_le_bytes()).unwrap(), Some(vec![1]));
+	}
+
+	#[test]
+	fn test_interval_wal_flush() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.sync_wal = crate::options::WalSync::Interval(std::time::Duration::from_secs(3600));
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		// The interval has not passed, but the commit is on disk once flushed.
+		db.flush().unwrap();
+		db.pause_background();
+		let copy = tempdir().unwrap();
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			std::fs::copy(&path, copy.path().join(path.file_name().unwrap())).unwrap();
+		}
+		drop(db);
+		let db = Db::open(&Options::with_columns(copy.path(), 1)).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_trash() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].trash = Some(crate::options::TrashOptions {
+			max_bytes: 10,
+			max_age: std::time::Duration::from_secs(3600),
+		});
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"a", Some(b"12345".to_vec())), (0, b"b", Some(b"12345678".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"a", None)]).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert!(db.undelete(0, b"a").unwrap());
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"12345".to_vec()));
+		assert!(!db.undelete(0, b"a").unwrap());
+
+		db.commit(vec![(0, b"a", None)]).unwrap();
+		// Trash is not visible in the column.
+		assert_eq!(db.iter_keys(0).unwrap().count(), 1);
+		assert_eq!(db.num_columns(), 1);
+		drop(db);
+		// Trash survives reopen. Removing "b" pushes "a" out of the size bound, and the
+		// cleanup worker purges it.
+		let db = Db::open(&options).unwrap();
+		db.commit(vec![(0, b"b", None)]).unwrap();
+		let deadline = Instant::now() + std::time::Duration::from_secs(10);
+		while db.inner.trash[0].lock().as_ref().unwrap().len() > 1 {
+			assert!(Instant::now() < deadline, "Trash not purged");
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert!(!db.undelete(0, b"a").unwrap());
+		assert!(db.undelete(0, b"b").unwrap());
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"12345678".to_vec()));
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+	}
+
+	#[test]
+	fn test_trash_replay() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].trash = Some(crate::options::TrashOptions {
+			max_bytes: 1 << 20,
+			max_age: std::time::Duration::from_secs(3600),
+		});
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for i in 0u32..10 {
+				db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 10]))]).unwrap();
+			}
+			db.flush().unwrap();
+			// Removals are only in the log when the database goes down.
+			db.pause_background();
+			for i in 0u32..5 {
+				db.commit(vec![(0, i.to_le_bytes(), None)]).unwrap();
+			}
+			assert!(db.undelete(0, &0u32.to_le_bytes()).unwrap());
+			db.close(Some(std::time::Duration::ZERO)).unwrap();
+		}
+		let logs = std::fs::read_dir(tmp.path()).unwrap()
+			.filter(|e| e.as_ref().unwrap().file_name().to_str().is_some_and(super::is_log_file))
+			.count();
+		assert!(logs > 0);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &0u32.to_le_bytes()).unwrap(), Some(vec![0; 10]));
+		assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), None);
+		assert_eq!(db.inner.trash[0].lock().as_ref().map(|t| t.len()), None);
+		for i in 1u32..5 {
+			assert!(db.undelete(0, &i.to_le_bytes()).unwrap());
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 10]));
+		}
+		assert!(!db.undelete(0, &0u32.to_le_bytes()).unwrap());
+		assert_eq!(db.iter_keys(0).unwrap().count(), 10);
+	}
+
+	#[test]
+	fn test_large_values() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let big = (0 .. 64 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+		db.commit(vec![(0, b"big", Some(big.clone()))]).unwrap();
+		assert_eq!(db.get(0, b"big").unwrap().as_ref(), Some(&big));
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"big").unwrap().as_ref(), Some(&big));
+		assert_eq!(db.get_size(0, b"big").unwrap(), Some(big.len() as u32));
+
+		let inline = db.max_inline_value_size(0).unwrap() as usize;
+		db.commit(vec![(0, &b"inline"[..], Some(vec![1; inline])), (0, &b"split"[..], Some(vec![2; inline + 1]))]).unwrap();
+		assert_eq!(db.get_with_meta(0, b"inline").unwrap().unwrap().1.parts, 1);
+		assert!(db.get_with_meta(0, b"split").unwrap().unwrap().1.parts > 1);
+		assert_eq!(db.max_inline_value_size(1), None);
+	}
+
+	#[test]
+	fn test_uniform_short_key() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(db.get(0, b"short").is_err());
+		assert!(db.commit(vec![(0, b"short", Some(vec![1]))]).is_err());
+		db.commit(vec![(0, [1u8; 32], Some(vec![1]))]).unwrap();
+		assert_eq!(db.get(0, &[1u8; 32]).unwrap(), Some(vec![1]));
+	}
+
+	#[test]
+	fn test_flush() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.sync_wal = crate::WalSync::Never;
+		let db = Db::open_or_create(&options).unwrap();
+		db.flush().unwrap();
+		assert_eq!(db.current_record_id(), 0);
+		for i in 0u32..100 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![1]))]).unwrap();
+		}
+		db.flush().unwrap();
+		assert!(db.inner.commit_queue.lock().commits.is_empty());
+		assert_eq!(db.get(0, &99u32.to_le_bytes()).unwrap(), Some(vec![1]));
+		// Flushing writes no records of its own.
+		let record_id = db.current_record_id();
+		db.flush().unwrap();
+		db.flush().unwrap();
+		assert_eq!(db.current_record_id(), record_id);
+	}
+
+	#[test]
+	fn test_record_ids() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.current_record_id(), 0);
+		assert_eq!(db.oldest_unflushed_record_id(), None);
+		db.commit(vec![(0, b"key", Some(vec![1]))]).unwrap();
+		db.flush().unwrap();
+		assert!(db.current_record_id() >= 1);
+		assert_eq!(db.oldest_unflushed_record_id(), Some(1));
+
+		let deadline = Instant::now() + std::time::Duration::from_secs(10);
+		while db.oldest_unflushed_record_id().is_some() && Instant::now() < deadline {
+			db.inner.flush_logs(0).unwrap();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(db.oldest_unflushed_record_id(), None);
+	}
+
+	#[test]
+	fn test_backup() {
+		let tmp = tempdir().unwrap();
+		let backup = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..100 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(i.to_le_bytes().to_vec()))]).unwrap();
+		}
+		let mut last = BackupProgress::default();
+		assert!(db.backup_to(backup.path(), |p| last = p.clone()).unwrap() > 0);
+		assert!(last.files_total > 0);
+		assert_eq!(last.files_copied, last.files_total);
+		assert_eq!(last.bytes_copied, last.bytes_total);
+		db.commit(vec![(0, 100u32.to_le_bytes(), Some(vec![0]))]).unwrap();
+		drop(db);
+
+		let backup_options = Options::with_columns(backup.path(), 1);
+		let db = Db::open(&backup_options).unwrap();
+		for i in 0u32..100 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(i.to_le_bytes().to_vec()));
+		}
+		assert_eq!(db.get(0, &100u32.to_le_bytes()).unwrap(), None);
+	}
+
+	#[test]
+	fn test_column_path() {
+		let tmp = tempdir().unwrap();
+		let cold = tmp.path().join("cold");
+		let backup = tempdir().unwrap();
+		let mut options = Options::with_columns(&tmp.path().join("db"), 2);
+		options.columns[1].path = Some(cold.clone());
+		let files = |path: &std::path::Path| -> Vec<String> {
+			std::fs::read_dir(path).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect()
+		};
+		let check = |db: &Db| {
+			for i in 0u32..1000 {
+				assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![0; i as usize % 100]));
+				assert_eq!(db.get(1, &i.to_le_bytes()).unwrap(), Some(vec![1; i as usize % 100]));
+			}
+		};
+
+		match Db::open_or_create(&options) {
+			Err(crate::Error::InvalidConfiguration(m)) => assert!(m.contains("column 1 path"), "{}", m),
+			_ => panic!("Expected missing column path"),
+		}
+		std::fs::create_dir(&cold).unwrap();
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..1000).flat_map(|i| vec![
+			(0, i.to_le_bytes(), Some(vec![0; i as usize % 100])),
+			(1, i.to_le_bytes(), Some(vec![1; i as usize % 100])),
+		])).unwrap();
+		db.flush().unwrap();
+		check(&db);
+		let usage = db.size_on_disk().unwrap();
+		let column_bytes: u64 = usage.columns.iter()
+			.map(|c| c.index + c.tables.iter().map(|t| t.on_disk).sum::<u64>())
+			.sum();
+		assert!(usage.total >= column_bytes);
+		db.backup(backup.path()).unwrap();
+		drop(db);
+
+		// Only files of the column are in its directory, the log and metadata are not.
+		let cold_files = files(&cold);
+		assert!(cold_files.iter().any(|f| f.starts_with("index_01_")));
+		assert!(cold_files.iter().any(|f| f.starts_with("table_01_")));
+		assert!(cold_files.iter().all(|f| f.starts_with("index_01_") || f.starts_with("table_01_")), "{:?}", cold_files);
+		let db_files = files(&options.path);
+		assert!(db_files.iter().any(|f| f.starts_with("table_00_")));
+		assert!(!db_files.iter().any(|f| f.starts_with("index_01_") || f.starts_with("table_01_")));
+
+		let db = Db::open(&options).unwrap();
+		check(&db);
+		drop(db);
+		// Backups have all files in one directory.
+		let db = Db::open(&Options::with_columns(backup.path(), 2)).unwrap();
+		check(&db);
+		drop(db);
+
+		options.columns[1].path = Some(tmp.path().join("missing"));
+		assert!(Db::open(&options).is_err());
+		options.columns[1].path = Some(std::path::PathBuf::new());
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_bulk_insert() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		let data = (0u32..10000).map(|i| (i.to_le_bytes(), vec![i as u8; (i % 300) as usize]));
+		assert_eq!(db.bulk_insert(0, data).unwrap(), 10000);
+		assert_eq!(db.bulk_insert_progress(0).unwrap(), None);
+		for i in (0u32..10000).step_by(7) {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; (i % 300) as usize]));
+		}
+
+		db.inner.bulk[0].store(true, std::sync::atomic::Ordering::SeqCst);
+		assert!(db.commit(vec![(0, b"a".to_vec(), Some(b"1".to_vec()))]).is_err());
+		db.commit(vec![(1, b"a".to_vec(), Some(b"1".to_vec()))]).unwrap();
+		db.inner.bulk[0].store(false, std::sync::atomic::Ordering::SeqCst);
+		db.commit(vec![(0, 1u32.to_le_bytes(), Some(b"1".to_vec()))]).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get(0, &9999u32.to_le_bytes()).unwrap(), Some(vec![9999u32 as u8; 99]));
+		assert_eq!(db.get(1, b"a").unwrap(), Some(b"1".to_vec()));
+	}
+
+	#[test]
+	fn test_iter() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let check = |db: &Db| {
+			let entries: Vec<_> = db.iter(0).unwrap().collect::<Result<_, _>>().unwrap();
+			let keys: std::collections::HashSet<_> = entries.iter().map(|(k, _)| *k).collect();
+			assert_eq!(keys.len(), entries.len());
+			assert_eq!(entries.len(), 900);
+			for (key, value) in entries {
+				let i = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+				assert!(i >= 200 || i % 2 == 1);
+				assert_eq!(db.inner.columns[0].hash_key(&i.to_le_bytes()).unwrap(), key);
+			}
+		};
+
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..1000 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(i.to_le_bytes().repeat(3)))]).unwrap();
+		}
+		db.commit((0u32..200).step_by(2).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		check(&db);
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		check(&db);
+	}
+
+	#[test]
+	fn test_iter_from() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].uniform = true;
+		let key = |i: u64| {
+			let mut key = [0u8; 32];
+			key[0..8].copy_from_slice(&i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+			key
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u64..300).map(|i| (0, key(i), Some(i.to_le_bytes().to_vec())))).unwrap();
+		let (mut seen, mut cursor) = db.iter_from(0, None, 50).unwrap();
+		assert!(seen.len() >= 50 && seen.len() < 300);
+		let cursor_bytes = cursor.unwrap().to_bytes();
+		drop(db);
+
+		// Fill a single chunk to trigger reindexing.
+		let db = Db::open(&options).unwrap();
+		let mut full = [0xffu8; 32];
+		db.commit((0u8..70).map(|i| {
+			// Split evenly by the next index bit.
+			full[2] = (i % 2) << 7;
+			full[3] = i;
+			(0, full, Some(vec![i]))
+		})).unwrap();
+		let start = Instant::now();
+		while db.inner.columns[0].index_tables().len() != 1 || db.inner.columns[0].index_tables()[0].index_bits() == 16 {
+			assert!(start.elapsed() < std::time::Duration::from_secs(10), "Reindex did not complete");
+			db.inner.force_flush.store(true, Ordering::SeqCst);
+			db.inner.signal_flush_worker();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		cursor = Some(IterCursor::from_bytes(cursor_bytes));
+		while cursor.is_some() {
+			let (page, next) = db.iter_from(0, cursor, 50).unwrap();
+			seen.extend(page);
+			cursor = next;
+		}
+		let keys: std::collections::HashSet<_> = seen.iter().map(|(k, _)| *k).collect();
+		assert_eq!(keys.len(), seen.len());
+		for i in 0u64..300 {
+			assert!(keys.contains(&key(i)));
+		}
+	}
+
+	#[test]
+	fn test_iter_rev() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = |i: u64| {
+			let mut key = [0u8; 32];
+			key[0..8].copy_from_slice(&i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+			key
+		};
+		db.commit((0u64..3000).map(|i| (0, key(i), Some(i.to_le_bytes().to_vec())))).unwrap();
+		db.commit((0u64..3000).step_by(3).map(|i| (0, key(i), None))).unwrap();
+		let mut expected: Vec<_> = (0u64..3000).filter(|i| i % 3 != 0).map(|i| (key(i), i.to_le_bytes().to_vec())).collect();
+		expected.sort();
+		expected.reverse();
+
+		let all: Vec<_> = db.iter_rev(0).unwrap().collect::<Result<_, _>>().unwrap();
+		assert_eq!(all, expected);
+		let seek = expected[100].0;
+		let from: Vec<_> = db.iter_rev_from(0, &seek).unwrap().collect::<Result<_, _>>().unwrap();
+		assert_eq!(from, &expected[100 ..]);
+		let mut between = seek;
+		between[31] = 1;
+		let from: Vec<_> = db.iter_rev_from(0, &between).unwrap().collect::<Result<_, _>>().unwrap();
+		assert_eq!(from, &expected[100 ..]);
+		assert_eq!(db.iter_rev_from(0, &[0xff; 40]).unwrap().count(), expected.len());
+		assert_eq!(db.iter_rev_from(0, &[]).unwrap().count(), 0);
+		assert!(db.iter_rev(1).is_err());
+	}
+
+	#[test]
+	fn test_cursor() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = |i: u64| {
+			let mut key = [0u8; 32];
+			key[0..8].copy_from_slice(&i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+			key
+		};
+		db.commit((0u64..2000).map(|i| (0, key(i), Some(i.to_le_bytes().to_vec())))).unwrap();
+		let mut expected: Vec<_> = (0u64..2000).map(|i| (key(i), i.to_le_bytes().to_vec())).collect();
+		expected.sort();
+
+		let mut cursor = db.cursor(0).unwrap();
+		assert!(cursor.key().is_none());
+		assert!(!cursor.next().unwrap());
+		assert!(cursor.seek_to_first().unwrap());
+		let mut forward = Vec::new();
+		while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+			forward.push((*key, value.clone()));
+			cursor.next().unwrap();
+		}
+		assert_eq!(forward, expected);
+		assert!(cursor.seek_to_last().unwrap());
+		assert_eq!(cursor.key(), Some(&expected[1999].0));
+		assert!(cursor.prev().unwrap());
+		assert_eq!(cursor.key(), Some(&expected[1998].0));
+
+		let mut between = expected[500].0;
+		between[31] = 1;
+		assert!(cursor.seek(&between).unwrap());
+		assert_eq!(cursor.key(), Some(&expected[501].0));
+		assert!(cursor.seek_for_prev(&between).unwrap());
+		assert_eq!(cursor.key(), Some(&expected[500].0));
+		// Removing the current key and the one after it does not lose the position.
+		db.commit(vec![(0, expected[500].0, None), (0, expected[501].0, None)]).unwrap();
+		assert!(cursor.next().unwrap());
+		assert_eq!(cursor.key(), Some(&expected[502].0));
+		assert!(cursor.prev().unwrap());
+		assert_eq!(cursor.key(), Some(&expected[499].0));
+		assert!(!cursor.seek(&[0xff; 32]).unwrap());
+		assert!(cursor.value().is_none());
+		assert!(db.cursor(1).is_err());
+	}
+
+	#[test]
+	fn test_iter_keys() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let check = |db: &Db| {
+			let keys: Vec<_> = db.iter_keys(0).unwrap().collect::<Result<_, _>>().unwrap();
+			let unique: std::collections::HashSet<_> = keys.iter().cloned().collect();
+			assert_eq!(unique.len(), keys.len());
+			let expected: std::collections::HashSet<_> = db.iter(0).unwrap().map(|e| e.unwrap().0).collect();
+			assert_eq!(unique, expected);
+			assert_eq!(keys.len(), 450);
+		};
+
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..500 {
+			// Some values span multiple entries.
+			let len = if i % 10 == 0 { 40000 } else { 12 };
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; len]))]).unwrap();
+		}
+		db.commit((0u32..100).step_by(2).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		check(&db);
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		check(&db);
+	}
+
+	#[test]
+	fn test_delete_prefix() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = |a: u8, b: u8| {
+			let mut key = [b; 32];
+			key[0] = a;
+			key
+		};
+		for a in 0u8..4 {
+			db.commit((0u8..50).map(|b| (0, key(a, b), Some(vec![a, b])))).unwrap();
+		}
+		assert_eq!(db.delete_prefix(0, &[1]).unwrap(), 50);
+		assert_eq!(db.delete_prefix(0, &[2, 7]).unwrap(), 1);
+		assert_eq!(db.delete_prefix(0, &[1]).unwrap(), 0);
+		assert_eq!(db.get(0, &key(1, 5)).unwrap(), None);
+		assert_eq!(db.get(0, &key(2, 7)).unwrap(), None);
+		assert_eq!(db.get(0, &key(2, 8)).unwrap(), Some(vec![2, 8]));
+		assert_eq!(db.iter_keys(0).unwrap().count(), 149);
+		assert!(db.delete_prefix(0, &[0; 33]).is_err());
+		assert!(db.delete_prefix(1, &[1]).is_err());
+	}
+
+	#[test]
+	fn test_background_commits() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for i in 0u8..20 {
+				db.commit_with_priority(vec![(0, [i], Some(vec![i]))], Priority::Background).unwrap();
+				db.commit(vec![(0, [i / 2], Some(vec![i, i]))]).unwrap();
+			}
+			assert_eq!(db.get(0, &[19]).unwrap(), Some(vec![19]));
+			assert_eq!(db.get(0, &[9]).unwrap(), Some(vec![19, 19]));
+			db.flush().unwrap();
+			let depth = db.commit_queue_depth();
+			assert_eq!(depth.background_commits, 0);
+			assert_eq!(depth.background_bytes, 0);
+		}
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.get(0, &[19]).unwrap(), Some(vec![19]));
+		assert_eq!(db.get(0, &[9]).unwrap(), Some(vec![19, 19]));
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(vec![1, 1]));
+	}
+
+	#[test]
+	fn test_try_commit() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(db.try_commit(vec![(0, b"a", Some(vec![1]))]).unwrap());
+		db.commit_timeout(vec![(0, b"b", Some(vec![2]))], std::time::Duration::from_millis(10)).unwrap();
+
+		// Simulate a full queue.
+		let limit = db.commit_queue_limit();
+		db.inner.commit_queue.lock().bytes += limit as usize + 1;
+		assert!(!db.try_commit(vec![(0, b"a", Some(vec![3]))]).unwrap());
+		let start = std::time::Instant::now();
+		let err = db.commit_timeout(vec![(0, b"b", Some(vec![4]))], std::time::Duration::from_millis(50)).unwrap_err();
+		assert!(err.is_queue_full());
+		assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+
+		db.inner.commit_queue.lock().bytes -= limit as usize + 1;
+		assert!(db.try_commit(vec![(0, b"a", Some(vec![3]))]).unwrap());
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![3]));
+	}
+
+	#[test]
+	fn test_commit_queue_bytes() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.commit_queue_bytes = 256 * 1024;
+		options.overlay_high_water_bytes = Some(128 * 1024);
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.commit_queue_limit(), 256 * 1024);
+		// With background work paused, the log worker stops once the log overlay is full,
+		// as it would with a log that is slow to flush.
+		db.pause_background();
+		let committed = std::sync::atomic::AtomicUsize::new(0);
+		std::thread::scope(|scope| {
+			let writer = scope.spawn(|| {
+				for i in 0u32..100 {
+					db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 16 * 1024]))]).unwrap();
+					committed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+				}
+			});
+			let deadline = Instant::now() + std::time::Duration::from_secs(10);
+			while db.commit_queue_depth().total_bytes() <= 256 * 1024 {
+				assert!(Instant::now() < deadline, "Commit queue not filled");
+				std::thread::sleep(std::time::Duration::from_millis(10));
+			}
+			std::thread::sleep(std::time::Duration::from_millis(100));
+			assert!(committed.load(std::sync::atomic::Ordering::SeqCst) < 100);
+			assert!(!db.try_commit(vec![(0, b"key", Some(vec![1]))]).unwrap());
+			let depth = db.commit_queue_depth();
+			assert!(depth.total_bytes() <= 256 * 1024 + 16 * 1024 + 4, "{:?}", depth);
+			db.resume_background();
+			writer.join().unwrap();
+		});
+		db.flush().unwrap();
+		let depth = db.commit_queue_depth();
+		assert_eq!(depth.total_bytes(), 0);
+		// Queued bytes are keys and values, bounded by the limit and one commit.
+		assert!(depth.high_water_bytes > 256 * 1024);
+		assert!(depth.high_water_bytes <= 256 * 1024 + 16 * 1024 + 4, "{:?}", depth);
+		for i in 0u32..100 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 16 * 1024]));
+		}
+	}
+
+	#[test]
+	fn test_backlog_warning() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.backlog_warning = Some(crate::BacklogWarning {
+			overlay_bytes: 64 * 1024,
+			dirty_logs: 1000,
+			after: std::time::Duration::ZERO,
+			interval: std::time::Duration::from_secs(3600),
+		});
+		let db = Db::open_or_create(&options).unwrap();
+		let written = |db: &Db| {
+			let deadline = Instant::now() + std::time::Duration::from_secs(10);
+			while db.commit_queue_depth().total_bytes() != 0 {
+				assert!(Instant::now() < deadline, "Commits not written");
+				std::thread::sleep(std::time::Duration::from_millis(10));
+			}
+		};
+		// Nothing is enacted while paused, so the overlay grows with each commit.
+		db.pause_background();
+		for i in 0u32..5 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 32 * 1024]))]).unwrap();
+			written(&db);
+		}
+		{
+			let backlog = db.inner.backlog.lock();
+			assert!(backlog.since.is_some());
+			assert!(backlog.warned.is_some());
+			// Later warnings are rate limited.
+			assert!(backlog.suppressed >= 2, "{}", backlog.suppressed);
+		}
+		db.resume_background();
+		let deadline = Instant::now() + std::time::Duration::from_secs(10);
+		while db.inner.log.overlay_bytes() > 0 {
+			assert!(Instant::now() < deadline, "Logs not enacted");
+			db.inner.force_flush.store(true, std::sync::atomic::Ordering::SeqCst);
+			db.inner.signal_flush_worker();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		db.commit(vec![(0, b"key", Some(vec![1]))]).unwrap();
+		written(&db);
+		assert!(db.inner.backlog.lock().since.is_none());
+		drop(db);
+
+		// No warning until the backlog lasts long enough.
+		options.backlog_warning.as_mut().unwrap().after = std::time::Duration::from_secs(3600);
+		let db = Db::open_or_create(&options).unwrap();
+		db.pause_background();
+		for i in 0u32..5 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 32 * 1024]))]).unwrap();
+			written(&db);
+		}
+		let backlog = db.inner.backlog.lock();
+		assert!(backlog.since.is_some());
+		assert!(backlog.warned.is_none());
+	}
+
+	#[test]
+	fn test_column_handles() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].name = Some("state".into());
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			let state = db.column("state").unwrap();
+			assert_eq!(state.index(), 1);
+			assert_eq!(db.column("headers"), None);
+			assert_eq!(db.column_at(0).map(|c| c.index()), Some(0));
+			assert_eq!(db.column_at(2), None);
+			db.commit(vec![(state, b"key", Some(b"value".to_vec()))]).unwrap();
+			let mut builder = CommitBuilder::new();
+			builder.insert(state, b"other", b"other".to_vec());
+			db.commit_builder(&mut builder).unwrap();
+			assert_eq!(db.get(state, b"key").unwrap(), Some(b"value".to_vec()));
+			assert_eq!(db.get(1, b"other").unwrap(), Some(b"other".to_vec()));
+			assert_eq!(db.get(0, b"key").unwrap(), None);
+		}
+		// Stored names are used when not configured.
+		options.columns[1].name = None;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			assert_eq!(db.column_by_name("state"), Some(1));
+			let mut stats = Vec::new();
+			db.collect_stats(&mut stats, Some(1)).unwrap();
+			assert!(String::from_utf8(stats).unwrap().starts_with("Column 1 (state)\n"));
+		}
+		options.columns[1].name = Some("headers".into());
+		match Db::open_or_create(&options) {
+			Err(crate::Error::ConfigMismatch(m)) => assert_eq!(m, vec![crate::ConfigMismatch {
+				column: Some(1),
+				field: "name",
+				stored: "state".into(),
+				configured: "headers".into(),
+			}]),
+			_ => panic!("Expected name mismatch"),
+		}
+		options.columns[1].name = None;
+		options.columns[0].name = Some("state".into());
+		assert!(Db::open_or_create(&options).is_err());
+		// Names configured for unnamed columns are stored.
+		options.columns[0].name = Some("headers".into());
+		Db::open_or_create(&options).unwrap();
+		options.columns[0].name = None;
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.column_by_name("headers"), Some(0));
+		assert_eq!(db.column_by_name("state"), Some(1));
 	}
 
-	fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) {
-		if let Some(col) = column {
-			self.columns[col as usize].write_stats(writer);
-		} else {
-			for c in self.columns.iter() {
-				c.write_stats(writer);
+	#[test]
+	fn test_background_threads() {
+		for threads in 1..=3 {
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 1);
+			options.background_threads = threads;
+			{
+				let db = Db::open_or_create(&options).unwrap();
+				let names: Vec<_> = db.threads.iter().map(|t| t.thread().name().unwrap().to_string()).collect();
+				match threads {
+					1 => assert_eq!(names, ["parity-db-log-flush-commit-cleanup"]),
+					2 => assert_eq!(names, ["parity-db-log", "parity-db-flush-commit-cleanup"]),
+					_ => assert_eq!(names, ["parity-db-log", "parity-db-commit", "parity-db-flush-cleanup"]),
+				}
+				for i in 0u32..200 {
+					db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 64 * 1024]))]).unwrap();
+				}
+				db.flush().unwrap();
+				let deadline = Instant::now() + std::time::Duration::from_secs(10);
+				while db.oldest_unflushed_record_id().is_some() {
+					db.inner.force_flush.store(true, std::sync::atomic::Ordering::SeqCst);
+					db.inner.signal_flush_worker();
+					assert!(Instant::now() < deadline, "Logs not enacted with {} threads", threads);
+					std::thread::sleep(std::time::Duration::from_millis(10));
+				}
+				assert_eq!(db.get(0, &199u32.to_le_bytes()).unwrap(), Some(vec![199; 64 * 1024]));
 			}
+			let db = Db::open(&options).unwrap();
+			assert_eq!(db.get(0, &0u32.to_le_bytes()).unwrap(), Some(vec![0; 64 * 1024]));
 		}
 	}
 
-	fn clear_stats(&self, column: Option<u8>) {
-		if let Some(col) = column {
-			self.columns[col as usize].clear_stats();
-		} else {
-			for c in self.columns.iter() {
-				c.clear_stats();
+	#[test]
+	fn test_enact_threads() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.enact_threads = 2;
+		options.columns[1].index_chunk_entries = 8;
+		options.columns[1].uniform = true;
+		let keys: Vec<_> = (0u8..9).map(|i| {
+			let mut key = [0u8; 32];
+			key[2] = if i % 2 == 0 { i } else { 0x80 | i };
+			key
+		}).collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			// Each record changes all columns. Nine keys in the same chunk of column 1 trigger
+			// reindexing, and a record that drops the old index.
+			for k in &keys {
+				db.commit(vec![
+					(0, k.to_vec(), Some(vec![k[2]; 100])),
+					(1, k.to_vec(), Some(vec![k[2]; 5000])),
+					(2, k.to_vec(), Some(vec![k[2]; 100000])),
+				]).unwrap();
+			}
+			db.commit(vec![(0, keys[0].to_vec(), None), (2, keys[0].to_vec(), None)]).unwrap();
+			while tmp.path().join("index_01_16").exists() {
+				db.inner.force_flush.store(true, std::sync::atomic::Ordering::SeqCst);
+				db.inner.signal_flush_worker();
+				std::thread::sleep(std::time::Duration::from_millis(10));
 			}
+			db.close(None).unwrap();
 		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &keys[0]).unwrap(), None);
+		assert_eq!(db.get(2, &keys[0]).unwrap(), None);
+		for k in &keys[1..] {
+			assert_eq!(db.get(0, k).unwrap(), Some(vec![k[2]; 100]));
+			assert_eq!(db.get(1, k).unwrap(), Some(vec![k[2]; 5000]));
+			assert_eq!(db.get(2, k).unwrap(), Some(vec![k[2]; 100000]));
+		}
+		assert_eq!(db.get(1, &keys[0]).unwrap(), Some(vec![keys[0][2]; 5000]));
 	}
 
-	fn store_err(&self, result: Result<()>) {
-		if let Err(e) = result {
-			log::warn!(target: "parity-db", "Background worker error: {}", e);
-			let mut err =  self.bg_err.lock();
-			if err.is_none() {
-				*err = Some(Arc::new(e));
-				self.shutdown();
+	#[test]
+	fn test_overlay_water_marks() {
+		for threads in [1, 4] {
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 1);
+			options.background_threads = threads;
+			options.overlay_high_water_bytes = Some(1024 * 1024);
+			let db = Db::open_or_create(&options).unwrap();
+			let mut max = 0;
+			for i in 0u32..100 {
+				db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 64 * 1024]))]).unwrap();
+				max = std::cmp::max(max, db.inner.log.overlay_bytes());
+			}
+			db.flush().unwrap();
+			max = std::cmp::max(max, db.inner.log.overlay_bytes());
+			// A commit that starts below the mark may take it over by its own size.
+			assert!(max <= 1024 * 1024 + 128 * 1024, "Overlay reached {} bytes with {} threads", max, threads);
+			for i in 0u32..100 {
+				assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 64 * 1024]));
 			}
-			self.commit_queue_full_cv.notify_one();
 		}
+		let mut options = Options::with_columns(std::path::Path::new("unused"), 1);
+		options.overlay_low_water_bytes = Some(1);
+		assert!(!options.is_valid());
+		options.overlay_high_water_bytes = Some(0);
+		assert!(!options.is_valid());
 	}
 
-	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
-		self.columns[c as usize].iter_while(&self.log, f)
+	#[test]
+	fn test_memory_usage() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].cache_size_bytes = 1024 * 1024;
+		let db = Db::open_or_create(&options).unwrap();
+		let empty = db.memory_usage();
+		assert_eq!(empty.cache, 0);
+		db.commit(vec![(0, [1u8; 32], Some(vec![1u8; 64 * 1024]))]).unwrap();
+		db.flush().unwrap();
+		// Logs are not enacted until rotated.
+		assert!(db.memory_usage().log_overlay >= empty.log_overlay + 64 * 1024);
+		db.inner.force_flush.store(true, Ordering::SeqCst);
+		db.inner.signal_flush_worker();
+		while db.oldest_unflushed_record_id().is_some() {
+			db.inner.force_flush.store(true, Ordering::SeqCst);
+			db.inner.signal_flush_worker();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(db.get(0, &[1u8; 32]).unwrap(), Some(vec![1u8; 64 * 1024]));
+		let usage = db.memory_usage();
+		assert!(usage.cache >= 64 * 1024);
+		assert_eq!(usage.total(), usage.commit_queue + usage.log_overlay + usage.cache);
 	}
-}
 
-pub struct Db {
-	inner: Arc<DbInner>,
-	commit_thread: Option<std::thread::JoinHandle<()>>,
-	flush_thread: Option<std::thread::JoinHandle<()>>,
-	log_thread: Option<std::thread::JoinHandle<()>>,
-	cleanup_thread: Option<std::thread::JoinHandle<()>>,
-}
+	#[test]
+	fn test_overlay_stats() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.overlay_stats(), (0, 0));
+		db.commit(vec![(0, [1u8; 32], Some(vec![1u8; 16])), (0, [2u8; 32], Some(vec![2u8; 16]))]).unwrap();
+		db.flush().unwrap();
+		let (index, value) = db.overlay_stats();
+		assert!(index >= 1);
+		assert!(value >= 2);
+		while db.overlay_stats() != (0, 0) {
+			db.inner.force_flush.store(true, Ordering::SeqCst);
+			db.inner.signal_flush_worker();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(db.get(0, &[1u8; 32]).unwrap(), Some(vec![1u8; 16]));
+	}
 
-impl Db {
-	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Result<Db> {
-		let options = Options::with_columns(path, num_columns);
+	#[test]
+	fn test_index_chunk_entries() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].index_chunk_entries = 8;
+		options.columns[0].uniform = true;
+		// Nine keys in the same chunk overflow it, and split in two once reindexed.
+		let keys: Vec<_> = (0u8..9).map(|i| {
+			let mut key = [0u8; 32];
+			key[2] = if i % 2 == 0 { i } else { 0x80 | i };
+			key
+		}).collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(keys.iter().map(|k| (0, k.to_vec(), Some(k[2..4].to_vec())))).unwrap();
+			db.flush().unwrap();
+			while db.oldest_unflushed_record_id().is_some() || tmp.path().join("index_00_16").exists() {
+				db.inner.force_flush.store(true, Ordering::SeqCst);
+				db.inner.signal_flush_worker();
+				std::thread::sleep(std::time::Duration::from_millis(10));
+			}
+		}
+		let size = std::fs::metadata(tmp.path().join("index_00_17")).unwrap().len();
+		assert_eq!(size, (1 << 17) * 8 * 8 + 16 * 1024);
+		assert_eq!(Options::from_existing(tmp.path()).unwrap().columns[0].index_chunk_entries, 8);
+		let db = Db::open(&options).unwrap();
+		for k in &keys {
+			assert_eq!(db.get(0, k).unwrap(), Some(k[2..4].to_vec()));
+		}
+		drop(db);
 
-		Self::open_inner(&options, true, false)
+		let mut other = options.clone();
+		other.columns[0].index_chunk_entries = 64;
+		match Db::open(&other) {
+			Err(crate::Error::ConfigMismatch(m)) => assert_eq!(m[0].field, "index_chunk_entries"),
+			_ => panic!("Expected mismatch"),
+		}
+		other.columns[0].index_chunk_entries = 24;
+		assert!(!other.is_valid());
 	}
 
-	/// Open the database with given options.
-	pub fn open(options: &Options) -> Result<Db> {
-		Self::open_inner(options, false, false)
-	}
+	#[test]
+	fn test_metadata_validation() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].uniform = true;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![(0, [7u8; 32], Some(vec![1, 2, 3])), (1, [7u8; 32], Some(vec![1]))]).unwrap();
+		}
+		let existing = Options::from_existing(tmp.path()).unwrap();
+		assert_eq!(existing.columns, options.columns);
+		assert!(Options::from_existing(&tmp.path().join("missing")).is_err());
 
-	/// Create the database using given options.
-	pub fn open_or_create(options: &Options) -> Result<Db> {
-		Self::open_inner(options, true, false)
-	}
+		let mut other = options.clone();
+		other.columns[0].uniform = false;
+		other.columns[1].ref_counted = true;
+		match Db::open(&other) {
+			Err(crate::Error::ConfigMismatch(m)) => {
+				let fields: Vec<_> = m.iter().map(|m| (m.column, m.field)).collect();
+				assert_eq!(fields, vec![(Some(0), "uniform"), (Some(1), "ref_counted")]);
+			},
+			_ => panic!("Expected mismatch"),
+		}
+		match Db::open(&Options::with_columns(tmp.path(), 3)) {
+			Err(crate::Error::ConfigMismatch(m)) => assert_eq!(m[0].field, "columns"),
+			_ => panic!("Expected mismatch"),
+		}
 
-	pub fn open_read_only(options: &Options) -> Result<Db> {
-		Self::open_inner(options, false, true)
+		// Metadata is synthesized for databases created without it.
+		std::fs::remove_file(tmp.path().join("metadata")).unwrap();
+		assert!(Db::open(&Options::with_columns(tmp.path(), 1)).is_err());
+		{
+			let db = Db::open(&options).unwrap();
+			assert_eq!(db.get(0, &[7u8; 32]).unwrap(), Some(vec![1, 2, 3]));
+		}
+		assert_eq!(Options::from_existing(tmp.path()).unwrap().salt, None);
+		assert!(Db::open(&other).is_err());
 	}
 
-	pub fn open_inner(options: &Options, create: bool, read_only: bool) -> Result<Db> {
-		assert!(options.is_valid());
-		let mut db = DbInner::open(options, create)?;
-		// This needs to be call before log thread: so first reindexing
-		// will run in correct state.
-		db.replay_all_logs()?;
-		let db = Arc::new(db);
-		if read_only {
-			return Ok(Db {
-				inner: db,
-				commit_thread: None,
-				flush_thread: None,
-				log_thread: None,
-				cleanup_thread: None,
-			})
+	#[test]
+	fn test_salt_mismatch() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.salt = Some([1u8; 32]);
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![(0, b"key".to_vec(), Some(vec![1]))]).unwrap();
 		}
-		let commit_worker_db = db.clone();
-		let commit_thread = std::thread::spawn(move ||
-			commit_worker_db.store_err(Self::commit_worker(commit_worker_db.clone()))
-		);
-		let flush_worker_db = db.clone();
-		let flush_thread = std::thread::spawn(move ||
-			flush_worker_db.store_err(Self::flush_worker(flush_worker_db.clone()))
-		);
-		let log_worker_db = db.clone();
-		let log_thread = std::thread::spawn(move ||
-			log_worker_db.store_err(Self::log_worker(log_worker_db.clone()))
-		);
-		let cleanup_worker_db = db.clone();
-		let cleanup_thread = std::thread::spawn(move ||
-			cleanup_worker_db.store_err(Self::cleanup_worker(cleanup_worker_db.clone()))
-		);
-		Ok(Db {
-			inner: db,
-			commit_thread: Some(commit_thread),
-			flush_thread: Some(flush_thread),
-			log_thread: Some(log_thread),
-			cleanup_thread: Some(cleanup_thread),
-		})
+		options.salt = Some([2u8; 32]);
+		match Db::open(&options) {
+			Err(crate::Error::ConfigMismatch(m)) => assert_eq!(m[0].field, "salt"),
+			_ => panic!("Expected mismatch"),
+		}
+		options.salt = Some([1u8; 32]);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(vec![1]));
+		std::mem::drop(db);
+		options.salt = None;
+		assert!(Db::open(&options).is_ok());
 	}
 
-	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
-		self.inner.get(col, key)
-	}
+	#[test]
+	fn test_process_logs() {
+		struct Enacted(Mutex<Vec<u64>>);
+		impl crate::DbMetrics for Enacted {
+			fn on_record_enacted(&self, record_id: u64) {
+				self.0.lock().push(record_id);
+			}
+		}
 
-	pub fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
-		self.inner.get_size(col, key)
+		let tmp = tempdir().unwrap();
+		let enacted = Arc::new(Enacted(Mutex::new(Vec::new())));
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.metrics = Some(enacted.clone());
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..50 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 100]))]).unwrap();
+		}
+		db.flush().unwrap();
+		let deadline = Instant::now() + std::time::Duration::from_secs(10);
+		while db.oldest_unflushed_record_id().is_some() {
+			db.inner.force_flush.store(true, Ordering::SeqCst);
+			db.inner.signal_flush_worker();
+			// Competes with the commit worker.
+			assert!(db.process_logs(1).unwrap() <= 1);
+			assert!(Instant::now() < deadline, "Logs not enacted");
+		}
+		assert_eq!(*enacted.0.lock(), (1 ..= db.current_record_id()).collect::<Vec<_>>());
+		assert_eq!(db.process_logs(10).unwrap(), 0);
+		assert_eq!(db.get(0, &49u32.to_le_bytes()).unwrap(), Some(vec![49; 100]));
+		db.close(None).unwrap();
+		assert_eq!(Db::open_read_only(&options).unwrap().dirty_log_count(), 0);
+		assert!(Db::open_read_only(&options).unwrap().process_logs(1).is_err());
 	}
 
-	pub fn commit<I, K>(&self, tx: I) -> Result<()>
-	where
-		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
-		K: AsRef<[u8]>,
-	{
-		self.inner.commit(tx)
-	}
+	#[test]
+	fn test_metrics() {
+		#[derive(Default)]
+		struct Counters {
+			flushed_bytes: AtomicU64,
+			enacted: Mutex<Vec<u64>>,
+			cleaned: AtomicU64,
+		}
+		impl crate::DbMetrics for Counters {
+			fn on_flush(&self, bytes: u64, _duration: std::time::Duration) {
+				self.flushed_bytes.fetch_add(bytes, Ordering::Relaxed);
+			}
+			fn on_record_enacted(&self, record_id: u64) {
+				self.enacted.lock().push(record_id);
+			}
+			fn on_log_cleaned(&self, _id: u32) {
+				self.cleaned.fetch_add(1, Ordering::Relaxed);
+			}
+		}
 
-	pub(crate) fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
-		self.inner.commit_raw(commit)
+		let tmp = tempdir().unwrap();
+		let counters = Arc::new(Counters::default());
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.metrics = Some(counters.clone());
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for i in 0u32..3 {
+				db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 100]))]).unwrap();
+				// Let each commit go through a separate log file.
+				db.flush().unwrap();
+				let deadline = Instant::now() + std::time::Duration::from_secs(10);
+				while db.oldest_unflushed_record_id().is_some() {
+					db.inner.force_flush.store(true, Ordering::SeqCst);
+					db.inner.signal_flush_worker();
+					assert!(Instant::now() < deadline, "Logs not enacted");
+					std::thread::sleep(std::time::Duration::from_millis(10));
+				}
+			}
+		}
+		assert!(counters.flushed_bytes.load(Ordering::Relaxed) > 300);
+		assert_eq!(*counters.enacted.lock(), vec![1, 2, 3]);
+		assert_eq!(counters.cleaned.load(Ordering::Relaxed), 2);
 	}
 
-	pub fn num_columns(&self) -> u8 {
-		self.inner.columns.len() as u8
+	#[test]
+	fn test_export() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..1000 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(i.to_le_bytes().repeat(3)))]).unwrap();
+		}
+		let mut dump = Vec::new();
+		let summary = db.export(0, &mut dump, &ExportOptions::default()).unwrap();
+		assert_eq!(summary.entries, 1000);
+		assert_eq!(summary.value_bytes, 12000);
+		assert_eq!(summary.bytes, dump.len() as u64);
+		assert!(summary.complete);
+		assert_eq!(&dump[..8], export::MAGIC);
+		let (body, checksum) = dump.split_at(dump.len() - 4);
+		assert_eq!(crc32fast::hash(body).to_le_bytes(), checksum);
+
+		// Export in parts.
+		let mut options = ExportOptions { chunks: Some(1000), ..Default::default() };
+		let mut entries = 0;
+		loop {
+			let summary = db.export(0, std::io::sink(), &options).unwrap();
+			entries += summary.entries;
+			if summary.complete {
+				break;
+			}
+			assert_eq!(summary.next_chunk, options.start_chunk + 1000);
+			options.start_chunk = summary.next_chunk;
+		}
+		assert_eq!(entries, 1000);
 	}
 
-	pub(crate) fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
-		self.inner.iter_column_while(c, f)
+	#[test]
+	fn test_compression_treshold() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[0].compression_treshold = 128;
+		let small = vec![7u8; 128];
+		let large = vec![7u8; 129];
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"small", Some(small.clone())), (0, b"large", Some(large.clone()))]).unwrap();
+		let (value, meta) = db.get_with_meta(0, b"small").unwrap().unwrap();
+		assert_eq!((value, meta.compressed, meta.stored_size), (small.clone(), false, 128));
+		let (value, meta) = db.get_with_meta(0, b"large").unwrap().unwrap();
+		assert_eq!(value, large);
+		assert!(meta.compressed);
+		assert!(meta.stored_size < 129);
+		drop(db);
+
+		// Stored values are read back according to their flag.
+		options.columns[0].compression_treshold = 4096;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"small").unwrap(), Some(small));
+		assert_eq!(db.get(0, b"large").unwrap(), Some(large));
 	}
 
-	fn commit_worker(db: Arc<DbInner>) -> Result<()> {
-		let mut more_work = false;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				let mut work = db.commit_work.lock();
-				while !*work {
-					db.commit_worker_cv.wait(&mut work)
-				};
-				*work = false;
+	#[test]
+	fn test_import() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[0].compression_treshold = 8;
+		options.columns[1].ref_counted = true;
+		let value = |i: u32| i.to_le_bytes().repeat(1 + i as usize % 50);
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..500 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(value(i))), (1, i.to_le_bytes(), Some(value(i)))]).unwrap();
+			if i % 3 == 0 {
+				db.commit(vec![(1, i.to_le_bytes(), Some(value(i)))]).unwrap();
 			}
+		}
+		let mut dumps = Vec::new();
+		for col in 0..2 {
+			let mut dump = Vec::new();
+			db.export(col, &mut dump, &ExportOptions::default()).unwrap();
+			dumps.push(dump);
+		}
+		let sorted = |db: &Db, col| {
+			let mut entries: Vec<_> = db.iter(col).unwrap().map(|e| e.unwrap()).collect();
+			entries.sort();
+			entries
+		};
+		let expected = [sorted(&db, 0), sorted(&db, 1)];
+		drop(db);
 
-			more_work = db.enact_logs(false)?;
+		// Into an empty database with the same salt.
+		let restored = tempdir().unwrap();
+		let mut restored_options = options.clone();
+		restored_options.path = restored.path().into();
+		restored_options.salt = Some(std::convert::TryInto::try_into(&dumps[0][14..46]).unwrap());
+		let db = Db::open_or_create(&restored_options).unwrap();
+		for col in 0..2 {
+			let summary = db.import(col, &dumps[col as usize][..], &ImportOptions::default()).unwrap();
+			assert_eq!(summary.entries, 500);
+			assert_eq!(sorted(&db, col), expected[col as usize]);
 		}
-		log::debug!(target: "parity-db", "Commit worker shutdown");
-		Ok(())
+		// Reference counts are preserved.
+		db.commit(vec![(1, 3u32.to_le_bytes(), None), (1, 4u32.to_le_bytes(), None)]).unwrap();
+		db.flush().unwrap();
+		assert_eq!(db.get(1, &3u32.to_le_bytes()).unwrap(), Some(value(3)));
+		assert_eq!(db.get(1, &4u32.to_le_bytes()).unwrap(), None);
+
+		// Into a non-empty column.
+		db.commit(vec![(0, 1u32.to_le_bytes(), Some(b"x".to_vec())), (0, 1000u32.to_le_bytes(), Some(b"y".to_vec()))]).unwrap();
+		assert!(db.import(0, &dumps[0][..], &ImportOptions::default()).is_err());
+		let skip = ImportOptions { on_conflict: OnConflict::Skip };
+		let summary = db.import(0, &dumps[0][..], &skip).unwrap();
+		assert_eq!((summary.entries, summary.skipped), (0, 500));
+		assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), Some(b"x".to_vec()));
+		let overwrite = ImportOptions { on_conflict: OnConflict::Overwrite };
+		let summary = db.import(0, &dumps[0][..], &overwrite).unwrap();
+		assert_eq!((summary.entries, summary.replaced), (500, 500));
+		assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), Some(value(1)));
+		assert_eq!(db.get(0, &1000u32.to_le_bytes()).unwrap(), Some(b"y".to_vec()));
+		assert!(db.import(1, &dumps[1][..], &overwrite).is_err());
+		// Options must match.
+		assert!(db.import(1, &dumps[0][..], &skip).is_err());
+
+		// Damaged dumps are detected.
+		let mut damaged = dumps[0].clone();
+		let last = damaged.len() - 1;
+		damaged[last] ^= 1;
+		assert!(db.import(0, &damaged[..], &skip).is_err());
 	}
 
-	fn log_worker(db: Arc<DbInner>) -> Result<()> {
-		// Start with pending reindex.
-		let mut more_work = db.process_reindex()?;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				let mut work = db.log_work.lock();
-				while !*work {
-					db.log_worker_cv.wait(&mut work)
-				};
-				*work = false;
+	#[test]
+	fn test_compact() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let value = |i: u32| if i % 10 == 0 { i.to_le_bytes().repeat(2000) } else { i.to_le_bytes().repeat(8) };
+		let check = |db: &Db| {
+			for i in 0u32..2000 {
+				let expected = if i % 4 == 0 { Some(value(i)) } else { None };
+				assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), expected);
 			}
+		};
 
-			let more_commits = db.process_commits()?;
-			let more_reindex = db.process_reindex()?;
-			more_work = more_commits || more_reindex;
-		}
-		log::debug!(target: "parity-db", "Log worker shutdown");
-		Ok(())
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..2000).map(|i| (0, i.to_le_bytes(), Some(value(i))))).unwrap();
+		db.commit((0u32..2000).filter(|i| i % 4 != 0).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		db.flush().unwrap();
+		let before = db.free_space(0).unwrap();
+		assert!(before.reclaimable() > 0);
+
+		let reclaimed = db.compact(0).unwrap();
+		assert!(reclaimed > 0);
+		assert!(db.free_space(0).unwrap().reclaimable() < before.reclaimable());
+		check(&db);
+		// Freed slots are reused.
+		db.commit(vec![(0, 5000u32.to_le_bytes(), Some(value(5000)))]).unwrap();
+		assert_eq!(db.get(0, &5000u32.to_le_bytes()).unwrap(), Some(value(5000)));
+		db.commit(vec![(0, 5000u32.to_le_bytes(), None)]).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		check(&db);
+		assert!(db.compact(1).is_err());
 	}
 
-	fn flush_worker(db: Arc<DbInner>) -> Result<()> {
-		let mut more_work = false;
-		while !db.shutdown.load(Ordering::SeqCst) {
-			if !more_work {
-				let mut work = db.flush_work.lock();
-				while !*work {
-					db.flush_worker_cv.wait(&mut work)
-				};
-				*work = false;
+	#[test]
+	fn test_mmap_value_tables() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.mmap_value_tables = true;
+		let value = |i: u32| i.to_le_bytes().repeat(1 + (i as usize % 50) * 20);
+		let check = |db: &Db| {
+			for i in 0u32..3000 {
+				let expected = if i % 3 == 0 { Some(value(i)) } else { None };
+				assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), expected);
 			}
-			more_work = db.flush_logs(MIN_LOG_SIZE)?;
-		}
-		log::debug!(target: "parity-db", "Flush worker shutdown");
-		Ok(())
-	}
+		};
 
-	fn cleanup_worker(db: Arc<DbInner>) -> Result<()> {
-		let mut more_work = true;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				let mut work = db.cleanup_work.lock();
-				while !*work {
-					db.cleanup_worker_cv.wait(&mut work)
-				};
-				*work = false;
+		let db = Db::open_or_create(&options).unwrap();
+		// Tables grow while values are read back.
+		for batch in 0u32..3 {
+			db.commit((batch * 1000..(batch + 1) * 1000).map(|i| (0, i.to_le_bytes(), Some(value(i))))).unwrap();
+			db.flush().unwrap();
+			for i in 0..(batch + 1) * 1000 {
+				assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(value(i)));
 			}
-			more_work = db.cleanup_logs()?;
 		}
-		log::debug!(target: "parity-db", "Cleanup worker shutdown");
-		Ok(())
-	}
+		db.commit((0u32..3000).filter(|i| i % 3 != 0).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		db.flush().unwrap();
+		assert!(db.compact(0).unwrap() > 0);
+		check(&db);
+		drop(db);
 
-	pub fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) {
-		self.inner.collect_stats(writer, column)
+		let db = Db::open(&options).unwrap();
+		check(&db);
+		drop(db);
+		options.mmap_value_tables = false;
+		let db = Db::open(&options).unwrap();
+		check(&db);
 	}
 
-	pub fn clear_stats(&self, column: Option<u8>) {
-		self.inner.clear_stats(column)
-	}
+	#[test]
+	fn test_verify() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].sizes = vec![64];
+		let value = |i: u32| if i % 10 == 0 { i.to_le_bytes().repeat(2000) } else { i.to_le_bytes().repeat(4) };
 
-	pub fn check_from_index(&self, check_param: check::CheckOptions) -> Result<()> {
-		if let Some(col) = check_param.column.clone() {
-			self.inner.columns[col as usize].check_from_index(&self.inner.log, &check_param, col)?;
-		} else {
-			for (ix, c) in self.inner.columns.iter().enumerate() {
-				c.check_from_index(&self.inner.log, &check_param, ix as ColId)?;
-			}
-		}
-		Ok(())
-	}
-}
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..1000).map(|i| (0, i.to_le_bytes(), Some(value(i))))).unwrap();
+		db.commit((0u32..1000).filter(|i| i % 4 != 0).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		db.commit((0u32..10).map(|i| (1, i.to_le_bytes(), Some(value(i))))).unwrap();
+		let report = db.verify(&[]).unwrap();
+		assert!(report.is_ok(), "{:?}", report);
+		assert_eq!(report.columns.len(), 2);
+		assert_eq!(report.columns[0].index_entries, 250);
+		assert_eq!(report.columns[1].index_entries, 10);
+		let report = db.verify(&[1]).unwrap();
+		assert_eq!(report.columns.len(), 1);
+		assert_eq!(report.columns[0].col, 1);
+		assert!(db.verify(&[2]).is_err());
+		drop(db);
 
-impl Drop for Db {
-	fn drop(&mut self) {
-		self.inner.shutdown();
-		self.log_thread.take().map(|t| t.join());
-		self.flush_thread.take().map(|t| t.join());
-		self.commit_thread.take().map(|t| t.join());
-		self.cleanup_thread.take().map(|t| t.join());
-		if let Err(e) = self.inner.kill_logs() {
-			log::warn!(target: "parity-db", "Shutdown error: {:?}", e);
+		// Values of column 0 take slots 1, 2 and 3 of the only sized table.
+		let tmp = tempdir().unwrap();
+		options.path = tmp.path().into();
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 1u8..4 {
+			db.commit(vec![(0, vec![i], Some(vec![i; 10]))]).unwrap();
+		}
+		db.commit(vec![(0, vec![3], None)]).unwrap();
+		drop(db);
+		{
+			use std::io::{Read, Seek, SeekFrom, Write};
+			let mut file = std::fs::OpenOptions::new().read(true).write(true).open(tmp.path().join("table_00_00")).unwrap();
+			// Remove the first value, change the key of the second and restore the removed third.
+			file.seek(SeekFrom::Start(64)).unwrap();
+			file.write_all(&[0xff, 0xff]).unwrap();
+			let mut key = [0u8];
+			file.seek(SeekFrom::Start(2 * 64 + 2)).unwrap();
+			file.read_exact(&mut key).unwrap();
+			file.seek(SeekFrom::Start(2 * 64 + 2)).unwrap();
+			file.write_all(&[key[0] ^ 0xfc]).unwrap();
+			file.seek(SeekFrom::Start(3 * 64)).unwrap();
+			file.write_all(&36u16.to_le_bytes()).unwrap();
 		}
+		let db = Db::open(&options).unwrap();
+		let report = db.verify(&[0]).unwrap();
+		assert!(!report.is_ok());
+		assert_eq!(report.columns[0].index_entries, 2);
+		assert_eq!(report.columns[0].dangling, 1);
+		assert_eq!(report.columns[0].mismatched, 1);
+		assert_eq!(report.columns[0].orphaned, 1);
 	}
-}
 
-/// Verification operation utilities.
-pub mod check {
-	pub enum CheckDisplay {
-		None,
-		Full,
-		Short(u64),
-	}
+	#[test]
+	fn test_secondary() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"a".to_vec(), Some(b"1".to_vec()))]).unwrap();
+		db.flush().unwrap();
 
-	pub struct CheckOptions {
-		pub column: Option<u8>,
-		pub from: Option<u64>,
-		pub bound: Option<u64>,
-		pub display: CheckDisplay,
+		let secondary = Db::open_secondary(&options).unwrap();
+		assert_eq!(secondary.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert!(secondary.commit(vec![(0, b"b".to_vec(), Some(b"2".to_vec()))]).is_err());
+		assert!(db.try_catch_up().is_err());
+
+		db.commit(vec![(0, b"a".to_vec(), None), (0, b"b".to_vec(), Some(b"2".to_vec()))]).unwrap();
+		db.flush().unwrap();
+		assert!(secondary.try_catch_up().unwrap());
+		assert_eq!(secondary.get(0, b"a").unwrap(), None);
+		assert_eq!(secondary.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+
+		// Enacted and cleaned up logs are read from the tables.
+		db.close(None).unwrap();
+		assert!(secondary.try_catch_up().unwrap());
+		assert_eq!(secondary.get(0, b"a").unwrap(), None);
+		assert_eq!(secondary.get(0, b"b").unwrap(), Some(b"2".to_vec()));
 	}
 
-	impl CheckOptions {
-		pub fn new(
-			column: Option<u8>,
-			from: Option<u64>,
-			bound: Option<u64>,
-			display_content: bool,
-			truncate_value_display: Option<u64>,
-		) -> Self {
-			let display = if display_content {
-				match truncate_value_display {
-					Some(t) => CheckDisplay::Short(t),
-					None => CheckDisplay::Full,
-				}
-			} else {
-				CheckDisplay::None
-			};
-			CheckOptions {
-				column,
-				from,
-				bound,
-				display,
-			}
+	#[test]
+	fn test_ttl() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].ttl = Some(std::time::Duration::from_secs(2));
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"a", Some(b"1".to_vec())), (1, b"a", Some(b"1".to_vec()))]).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get_size(0, b"a").unwrap(), Some(1));
+		db.inner.ttl_clock.advance(std::time::Duration::from_secs(3));
+		db.commit(vec![(0, b"b", Some(b"2".to_vec()))]).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get_size(0, b"a").unwrap(), None);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(db.get(1, b"a").unwrap(), Some(b"1".to_vec()));
+
+		// Force a scan and check that the expired value is removed.
+		db.flush().unwrap();
+		db.inner.expiry[0].lock().last_scan = None;
+		while db.inner.process_expiry().unwrap() {}
+		db.flush().unwrap();
+		let key = db.hash_key(0, b"a").unwrap();
+		assert_eq!(db.inner.get_stored(0, &key).unwrap(), None);
+		assert_eq!(db.inner.expiry[0].lock().removed, 1);
+
+		// Keys inserted again after the scan found them expired are kept.
+		db.commit(vec![(0, b"a", Some(b"3".to_vec()))]).unwrap();
+		db.flush().unwrap();
+		{
+			let mut expiry = db.inner.expiry[0].lock();
+			expiry.pending.push(key);
+			expiry.last_scan = Some(Instant::now());
 		}
-	}
-}
+		while db.inner.process_expiry().unwrap() {}
+		db.flush().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"3".to_vec()));
+		assert_eq!(db.inner.expiry[0].lock().removed, 1);
+		db.commit(vec![(0, b"a", None)]).unwrap();
+		drop(db);
 
-#[cfg(test)]
-mod tests {
-	use super::{Db, Options};
-	use tempfile::tempdir;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(1, b"a").unwrap(), Some(b"1".to_vec()));
+		options.columns[0].ref_counted = true;
+		assert!(!options.is_valid());
+		options.columns[0].ref_counted = false;
+		// TTL is stored as whole seconds.
+		options.columns[0].ttl = Some(std::time::Duration::from_millis(1500));
+		assert!(!options.is_valid());
+		options.columns[0].ttl = Some(std::time::Duration::from_secs(0));
+		assert!(!options.is_valid());
+		options.columns[0].ttl = None;
+		options.columns[0].trash = Some(crate::TrashOptions { max_bytes: 1024, max_age: std::time::Duration::from_millis(500) });
+		assert!(!options.is_valid());
+	}
 
 	#[test]
-	fn test_db_open_should_fail() {
+	fn test_ttl_clock() {
 		let tmp = tempdir().unwrap();
-		let options = Options::with_columns(tmp.path(), 5);
-		assert!(
-			Db::open(&options).is_err(),
-			"Database does not exist, so it should fail to open"
-		);
-		assert!(Db::open(&options).map(|_| ()).unwrap_err().to_string().contains("use open_or_create"));
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].ttl = Some(std::time::Duration::from_secs(3600));
+		let path = tmp.path().join(crate::ttl::CLOCK_FILE);
+		let read_clock = || {
+			let mut buf = [0u8; 8];
+			buf.copy_from_slice(&std::fs::read(&path).unwrap());
+			u64::from_le_bytes(buf)
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		let now = db.inner.ttl_clock.now();
+		db.close(None).unwrap();
+		assert!(read_clock() >= now);
+
+		// System time behind the kept clock.
+		std::fs::write(&path, (now + 10_000).to_le_bytes()).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert!(db.inner.ttl_clock.now() >= now + 10_000);
+		drop(db);
+		assert!(read_clock() >= now + 10_000);
+
+		// System time far ahead of the kept clock.
+		let day = 24 * 3600;
+		std::fs::write(&path, (now - 10 * day).to_le_bytes()).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert!((now - 9 * day .. now - 8 * day).contains(&db.inner.ttl_clock.now()));
 	}
 
 	#[test]
-	fn test_db_open_or_create() {
+	fn test_ref_counted() {
 		let tmp = tempdir().unwrap();
-		let options = Options::with_columns(tmp.path(), 5);
-		assert!(
-			Db::open_or_create(&options).is_ok(),
-			"New database should be created"
-		);
-		assert!(
-			Db::open(&options).is_ok(),
-			"Existing database should be reopened"
-		);
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"a", Some(b"1".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"a", Some(b"1".to_vec())), (0, b"a", Some(b"1".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"a", None), (0, b"b", None)]).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		db.commit(vec![(0, b"a", None), (0, b"a", None)]).unwrap();
+		db.flush().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		// Dropping more references than there are is ignored.
+		db.commit(vec![(0, b"a", None)]).unwrap();
+		db.commit(vec![(0, b"a", Some(b"3".to_vec()))]).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"3".to_vec()));
 	}
 }
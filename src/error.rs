@@ -16,30 +16,112 @@
 
 use std::fmt;
 use std::sync::Arc;
+use crate::options::ConfigMismatch;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
 	Io(std::io::Error),
+	/// IO operation failed. `context` names the file and the part of it that was accessed.
+	IoWith { context: String, source: std::io::Error },
 	Corruption(String),
 	InvalidConfiguration(String),
 	InvalidInput(String),
 	Background(Arc<Error>),
 	Locked(std::io::Error),
 	Migration(String),
+	/// Database was created with different options.
+	ConfigMismatch(Vec<ConfigMismatch>),
+	/// Commit queue stayed full until the deadline. Nothing was committed.
+	QueueFull,
+	/// Statistics were requested, but are not collected. See `Options::stats`.
+	StatsDisabled,
+}
+
+impl Error {
+	/// Database files are inconsistent or damaged.
+	pub fn is_corruption(&self) -> bool {
+		match self {
+			Error::Corruption(_) => true,
+			Error::Background(e) => e.is_corruption(),
+			_ => false,
+		}
+	}
+
+	/// Database is already opened by another process.
+	pub fn is_locked(&self) -> bool {
+		match self {
+			Error::Locked(_) => true,
+			Error::Background(e) => e.is_locked(),
+			_ => false,
+		}
+	}
+
+	/// Commit was rejected because the commit queue is full.
+	pub fn is_queue_full(&self) -> bool {
+		matches!(self, Error::QueueFull)
+	}
+
+	/// Statistics were requested with `Options::stats` off.
+	pub fn is_stats_disabled(&self) -> bool {
+		matches!(self, Error::StatsDisabled)
+	}
+
+	/// IO operation failed in a way that may succeed on retry.
+	pub fn is_io_transient(&self) -> bool {
+		match self.io_error() {
+			Some(e) => matches!(
+				e.kind(),
+				std::io::ErrorKind::Interrupted
+					| std::io::ErrorKind::WouldBlock
+					| std::io::ErrorKind::TimedOut
+			),
+			None => false,
+		}
+	}
+
+	/// Underlying error of a failed IO operation, with or without context.
+	pub fn io_error(&self) -> Option<&std::io::Error> {
+		match self {
+			Error::Io(e) | Error::IoWith { source: e, .. } => Some(e),
+			Error::Background(e) => e.io_error(),
+			_ => None,
+		}
+	}
+
+	/// Add context to IO errors, such as the file being accessed.
+	pub(crate) fn with_context(self, context: impl FnOnce() -> String) -> Error {
+		match self {
+			Error::Io(source) => Error::IoWith { context: context(), source },
+			e => e,
+		}
+	}
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Error::Io(e) => write!(f, "IO Error: {}", e),
+			Error::IoWith { context, source } => write!(f, "IO Error: {}: {}", context, source),
 			Error::Corruption(e) => write!(f, "Corruption: {}", e),
 			Error::InvalidConfiguration(e) => write!(f, "Invalid configuration: {}", e),
 			Error::InvalidInput(e) => write!(f, "Invalid input: {}", e),
 			Error::Background(e) => write!(f, "Background worker error: {}", e),
 			Error::Locked(e) => write!(f, "Database file is in use. ({})", e),
 			Error::Migration(e) => write!(f, "Migration error: {}", e),
+			Error::QueueFull => write!(f, "Commit queue is full"),
+			Error::StatsDisabled => write!(f, "Statistics are disabled"),
+			Error::ConfigMismatch(mismatch) => {
+				write!(f, "Configuration mismatch: ")?;
+				for (i, m) in mismatch.iter().enumerate() {
+					if i != 0 {
+						write!(f, "; ")?;
+					}
+					write!(f, "{}", m)?;
+				}
+				Ok(())
+			},
 		}
     }
 }
@@ -56,3 +138,17 @@ impl From<std::io::ErrorKind> for Error {
 		e.into()
 	}
 }
+
+/// Text read from a database file is not valid UTF-8.
+impl From<std::str::Utf8Error> for Error {
+	fn from(e: std::str::Utf8Error) -> Self {
+		Error::Corruption(format!("Invalid UTF-8: {}", e))
+	}
+}
+
+/// A field read from a database file does not have the expected size.
+impl From<std::array::TryFromSliceError> for Error {
+	fn from(e: std::array::TryFromSliceError) -> Self {
+		Error::Corruption(format!("Bad field size: {}", e))
+	}
+}
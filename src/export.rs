@@ -0,0 +1,288 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Portable column dump format.
+//!
+//! All integers are little endian.
+//!
+//! Header:
+//! - magic `b"PDBDUMP\0"`
+//! - `u32` format version
+//! - `u8` column index
+//! - `u8` column flags: 1 - ref counted, 2 - uniform keys, 4 - TTL
+//! - 32 bytes of database salt. Keys are hashed with it, unless uniform.
+//! - `u64` first exported index chunk
+//!
+//! Each entry: `u8` tag `1`, 32 bytes of hashed key, `u32` reference count,
+//! `u32` value length, value bytes.
+//!
+//! Trailer: `u8` tag `0`, `u64` number of entries, `u64` total value bytes,
+//! `u64` next index chunk, `u8` 1 if the dump reaches the end of the column,
+//! `u32` CRC32 of everything before it.
+
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use crate::{
+	column::{ColId, Salt},
+	error::{Error, Result},
+	table::Key,
+};
+
+pub const MAGIC: &[u8; 8] = b"PDBDUMP\0";
+pub const VERSION: u32 = 1;
+
+const FLAG_REF_COUNTED: u8 = 1;
+const FLAG_UNIFORM: u8 = 2;
+const FLAG_TTL: u8 = 4;
+
+const TAG_END: u8 = 0;
+const TAG_ENTRY: u8 = 1;
+
+#[derive(Clone, Debug, Default)]
+pub struct ExportOptions {
+	/// Index chunk to start at. Use `ExportSummary::next_chunk` of a previous export to resume.
+	pub start_chunk: u64,
+	/// Maximum number of index chunks to export. `None` exports up to the end of the column.
+	pub chunks: Option<u64>,
+	/// Limit the average output rate.
+	pub max_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+	/// Number of exported entries.
+	pub entries: u64,
+	/// Total size of exported values.
+	pub value_bytes: u64,
+	/// Total size of the dump.
+	pub bytes: u64,
+	/// Index chunk to continue from.
+	pub next_chunk: u64,
+	/// All of the column has been exported.
+	pub complete: bool,
+}
+
+/// What to do with dump entries for keys that are already in the column.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnConflict {
+	/// Fail the import.
+	#[default]
+	Error,
+	/// Keep the existing value.
+	Skip,
+	/// Replace the existing value. Not supported for reference counted columns.
+	Overwrite,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+	pub on_conflict: OnConflict,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+	/// Number of imported entries.
+	pub entries: u64,
+	/// Total size of imported values.
+	pub value_bytes: u64,
+	/// Entries skipped because the key was already in the column.
+	pub skipped: u64,
+	/// Entries that replaced an existing value.
+	pub replaced: u64,
+}
+
+pub struct Header {
+	pub col: ColId,
+	pub ref_counted: bool,
+	pub uniform: bool,
+	pub ttl: bool,
+	pub salt: Salt,
+	pub start_chunk: u64,
+}
+
+pub struct DumpWriter<W: Write> {
+	writer: W,
+	crc32: crc32fast::Hasher,
+	bytes: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+	pub fn new(writer: W) -> Self {
+		DumpWriter {
+			writer,
+			crc32: crc32fast::Hasher::new(),
+			bytes: 0,
+		}
+	}
+
+	fn write(&mut self, buf: &[u8]) -> Result<()> {
+		self.writer.write_all(buf)?;
+		self.crc32.update(buf);
+		self.bytes += buf.len() as u64;
+		Ok(())
+	}
+
+	pub fn write_header(&mut self, header: &Header) -> Result<()> {
+		let mut flags = 0;
+		if header.ref_counted {
+			flags |= FLAG_REF_COUNTED;
+		}
+		if header.uniform {
+			flags |= FLAG_UNIFORM;
+		}
+		if header.ttl {
+			flags |= FLAG_TTL;
+		}
+		self.write(MAGIC)?;
+		self.write(&VERSION.to_le_bytes())?;
+		self.write(&[header.col, flags])?;
+		self.write(&header.salt)?;
+		self.write(&header.start_chunk.to_le_bytes())
+	}
+
+	pub fn write_entry(&mut self, key: &Key, rc: u32, value: &[u8]) -> Result<()> {
+		let len: u32 = value.len().try_into()
+			.map_err(|_| Error::InvalidInput("Value is too large to export".into()))?;
+		self.write(&[TAG_ENTRY])?;
+		self.write(key)?;
+		self.write(&rc.to_le_bytes())?;
+		self.write(&len.to_le_bytes())?;
+		self.write(value)
+	}
+
+	/// Bytes written so far.
+	pub fn bytes(&self) -> u64 {
+		self.bytes
+	}
+
+	/// Write the trailer and return the total size of the dump.
+	pub fn finish(mut self, summary: &ExportSummary) -> Result<u64> {
+		self.write(&[TAG_END])?;
+		self.write(&summary.entries.to_le_bytes())?;
+		self.write(&summary.value_bytes.to_le_bytes())?;
+		self.write(&summary.next_chunk.to_le_bytes())?;
+		self.write(&[summary.complete as u8])?;
+		let checksum = self.crc32.clone().finalize();
+		self.writer.write_all(&checksum.to_le_bytes())?;
+		self.writer.flush()?;
+		Ok(self.bytes + 4)
+	}
+}
+
+pub struct DumpReader<R: Read> {
+	reader: R,
+	crc32: crc32fast::Hasher,
+	entries: u64,
+	value_bytes: u64,
+	done: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+	pub fn new(reader: R) -> Self {
+		DumpReader {
+			reader,
+			crc32: crc32fast::Hasher::new(),
+			entries: 0,
+			value_bytes: 0,
+			done: false,
+		}
+	}
+
+	fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+		self.reader.read_exact(buf)?;
+		self.crc32.update(buf);
+		Ok(())
+	}
+
+	fn read_u32(&mut self) -> Result<u32> {
+		let mut buf = [0u8; 4];
+		self.read(&mut buf)?;
+		Ok(u32::from_le_bytes(buf))
+	}
+
+	fn read_u64(&mut self) -> Result<u64> {
+		let mut buf = [0u8; 8];
+		self.read(&mut buf)?;
+		Ok(u64::from_le_bytes(buf))
+	}
+
+	pub fn read_header(&mut self) -> Result<Header> {
+		let mut magic = [0u8; 8];
+		self.read(&mut magic)?;
+		if &magic != MAGIC {
+			return Err(Error::InvalidInput("Not a database dump".into()));
+		}
+		let version = self.read_u32()?;
+		if version != VERSION {
+			return Err(Error::InvalidInput(format!("Unsupported dump version {}", version)));
+		}
+		let mut buf = [0u8; 2];
+		self.read(&mut buf)?;
+		let [col, flags] = buf;
+		let mut salt = Salt::default();
+		self.read(&mut salt)?;
+		let start_chunk = self.read_u64()?;
+		Ok(Header {
+			col,
+			ref_counted: flags & FLAG_REF_COUNTED != 0,
+			uniform: flags & FLAG_UNIFORM != 0,
+			ttl: flags & FLAG_TTL != 0,
+			salt,
+			start_chunk,
+		})
+	}
+
+	/// Read the next entry. Returns `None` once the trailer is read and verified.
+	pub fn next_entry(&mut self) -> Result<Option<(Key, u32, Vec<u8>)>> {
+		if self.done {
+			return Ok(None);
+		}
+		let mut tag = [0u8];
+		self.read(&mut tag)?;
+		match tag[0] {
+			TAG_ENTRY => {
+				let mut key = Key::default();
+				self.read(&mut key)?;
+				let rc = self.read_u32()?;
+				let len = self.read_u32()? as usize;
+				let mut value = vec![0; len];
+				self.read(&mut value)?;
+				self.entries += 1;
+				self.value_bytes += len as u64;
+				Ok(Some((key, rc, value)))
+			},
+			TAG_END => {
+				let entries = self.read_u64()?;
+				let value_bytes = self.read_u64()?;
+				let _next_chunk = self.read_u64()?;
+				let mut complete = [0u8];
+				self.read(&mut complete)?;
+				let expected = self.crc32.clone().finalize();
+				let mut checksum = [0u8; 4];
+				self.reader.read_exact(&mut checksum)?;
+				if u32::from_le_bytes(checksum) != expected {
+					return Err(Error::Corruption("Dump checksum mismatch".into()));
+				}
+				if entries != self.entries || value_bytes != self.value_bytes {
+					return Err(Error::Corruption("Dump trailer does not match its entries".into()));
+				}
+				self.done = true;
+				Ok(None)
+			},
+			tag => Err(Error::Corruption(format!("Bad dump entry tag {}", tag))),
+		}
+	}
+}
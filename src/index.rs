@@ -19,15 +19,18 @@ use parking_lot::{RwLockUpgradableReadGuard, RwLock};
 use crate::{
 	error::{Error, Result},
 	column::ColId,
-	log::{LogReader, LogWriter, LogQuery},
+	log::{LogRead, LogReader, LogWriter, LogQuery},
 	display::hex,
 	stats::{self, ColumnStats},
 	table::{SIZE_TIERS_BITS},
 };
 
+// Chunks may be configured smaller, but log records have a 64 bit mask of modified entries.
 const CHUNK_LEN: usize = CHUNK_ENTRIES * ENTRY_BYTES; // 512 bytes
 const CHUNK_ENTRIES: usize = 1 << CHUNK_ENTRIES_BITS;
 const CHUNK_ENTRIES_BITS: u8 = 6;
+pub const MIN_CHUNK_ENTRIES: usize = 8;
+pub const MAX_CHUNK_ENTRIES: usize = CHUNK_ENTRIES;
 const HEADER_SIZE: usize = 512;
 const META_SIZE: usize = 16 * 1024; // Contains header and column stats
 const KEY_LEN: usize = 32;
@@ -37,6 +40,7 @@ pub const ENTRY_BYTES: usize = ENTRY_LEN as usize / 8;
 const EMPTY_CHUNK: Chunk = [0u8; CHUNK_LEN];
 
 pub type Key = [u8; KEY_LEN];
+/// Chunk of the largest size. Smaller chunks only use the leading entries.
 pub type Chunk = [u8; CHUNK_LEN];
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -50,10 +54,17 @@ impl Entry {
 
 	#[inline]
 	pub fn address_bits(index_bits: u8) -> u8 {
-		// with n index bits there are n * 64 possible entries and 16 size tiers
+		// with n index bits there are n * 64 possible entries and 16 size tiers.
+		// Same for smaller chunks, so that entries have the same layout.
 		index_bits + CHUNK_ENTRIES_BITS + SIZE_TIERS_BITS
 	}
 
+	/// Leading bits of the key restored by `IndexTable::recover_key_prefix`.
+	#[inline]
+	pub fn key_prefix_bits() -> u8 {
+		64 - CHUNK_ENTRIES_BITS - SIZE_TIERS_BITS
+	}
+
 	#[inline]
 	pub fn last_address(index_bits: u8) -> u64 {
 		(1u64 << Self::address_bits(index_bits)) - 1
@@ -131,6 +142,7 @@ pub enum PlanOutcome {
 
 pub struct IndexTable {
 	pub id: TableId,
+	chunk_entries: usize,
 	map: RwLock<Option<memmap2::MmapMut>>,
 	path: std::path::PathBuf,
 }
@@ -143,8 +155,8 @@ fn total_chunks(index_bits: u8) -> u64 {
 	1u64 << index_bits
 }
 
-fn file_size(index_bits: u8) -> u64 {
-	total_entries(index_bits) * 8 + META_SIZE as u64
+fn file_size(index_bits: u8, chunk_entries: usize) -> u64 {
+	total_chunks(index_bits) * (chunk_entries * ENTRY_BYTES) as u64 + META_SIZE as u64
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Hash)]
@@ -195,7 +207,7 @@ impl std::fmt::Display for TableId {
 }
 
 impl IndexTable {
-	pub fn open_existing(path: &std::path::Path, id: TableId) -> Result<Option<IndexTable>> {
+	pub fn open_existing(path: &std::path::Path, id: TableId, chunk_entries: usize) -> Result<Option<IndexTable>> {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
 
@@ -207,21 +219,23 @@ impl IndexTable {
 			Ok(file) => file,
 		};
 
-		file.set_len(file_size(id.index_bits()))?;
+		file.set_len(file_size(id.index_bits(), chunk_entries))?;
 		let map = unsafe { memmap2::MmapMut::map_mut(&file)? };
 		log::debug!(target: "parity-db", "Opened existing index {}", id);
 		Ok(Some(IndexTable {
 			id,
+			chunk_entries,
 			path,
 			map: RwLock::new(Some(map)),
 		}))
 	}
 
-	pub fn create_new(path: &std::path::Path, id: TableId) -> IndexTable {
+	pub fn create_new(path: &std::path::Path, id: TableId, chunk_entries: usize) -> IndexTable {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
 		IndexTable {
 			id,
+			chunk_entries,
 			path,
 			map: RwLock::new(None),
 		}
@@ -244,14 +258,69 @@ impl IndexTable {
 		}
 	}
 
-	fn chunk_at(index: u64, map: &memmap2::MmapMut) -> &[u8] {
-		let offset = META_SIZE + index as usize * CHUNK_LEN;
-		&map[offset .. offset + CHUNK_LEN]
+	/// Index file has been created.
+	pub fn is_allocated(&self) -> bool {
+		self.map.read().is_some()
+	}
+
+	pub fn disk_size(&self) -> Result<u64> {
+		if self.map.read().is_none() {
+			return Ok(0);
+		}
+		Ok(stats::file_disk_size(&std::fs::metadata(&self.path)?))
+	}
+
+	/// Number of entries in a chunk.
+	pub fn chunk_entries(&self) -> usize {
+		self.chunk_entries
+	}
+
+	fn chunk_len(&self) -> usize {
+		self.chunk_entries * ENTRY_BYTES
+	}
+
+	/// Number of occupied entries in the chunk.
+	pub fn chunk_occupancy(&self, chunk_index: u64) -> usize {
+		if let Some(map) = &*self.map.read() {
+			let chunk = self.chunk_at(chunk_index, map);
+			(0 .. self.chunk_entries).filter(|i| !Self::read_entry(chunk, *i).is_empty()).count()
+		} else {
+			0
+		}
+	}
+
+	/// Average chunk occupancy, estimated from `samples` evenly spaced chunks.
+	pub fn sampled_occupancy(&self, samples: u64) -> f64 {
+		let total = self.id.total_chunks();
+		let samples = std::cmp::min(samples, total);
+		let step = total / samples;
+		let occupied: usize = (0 .. samples).map(|i| self.chunk_occupancy(i * step)).sum();
+		occupied as f64 / samples as f64
+	}
+
+	/// Most loaded chunks, as `(chunk index, occupied entries)`, most loaded first.
+	pub fn hot_chunks(&self, count: usize) -> Vec<(u64, usize)> {
+		let mut hot: Vec<(u64, usize)> = Vec::with_capacity(count + 1);
+		for chunk_index in 0 .. self.id.total_chunks() {
+			let occupancy = self.chunk_occupancy(chunk_index);
+			if occupancy == 0 || (hot.len() == count && hot[count - 1].1 >= occupancy) {
+				continue;
+			}
+			let pos = hot.iter().position(|(_, o)| *o < occupancy).unwrap_or(hot.len());
+			hot.insert(pos, (chunk_index, occupancy));
+			hot.truncate(count);
+		}
+		hot
+	}
+
+	fn chunk_at<'a>(&self, index: u64, map: &'a memmap2::MmapMut) -> &'a [u8] {
+		let offset = META_SIZE + index as usize * self.chunk_len();
+		&map[offset .. offset + self.chunk_len()]
 	}
 
 	fn find_entry(&self, key: u64, sub_index: usize, chunk: &[u8]) -> (Entry, usize) {
 		let partial_key = Entry::extract_key(key, self.id.index_bits());
-		for i in sub_index .. CHUNK_ENTRIES {
+		for i in sub_index .. self.chunk_entries {
 			let entry = Self::read_entry(&chunk, i);
 			if !entry.is_empty() && entry.key_material(self.id.index_bits()) == partial_key {
 				return (entry, i);
@@ -286,7 +355,7 @@ impl IndexTable {
 
 		if let Some(map) = &*self.map.read() {
 			log::trace!(target: "parity-db", "{}: Querying chunk at {}", self.id, chunk_index);
-			let chunk = Self::chunk_at(chunk_index, map);
+			let chunk = self.chunk_at(chunk_index, map);
 			return self.find_entry(key, sub_index, chunk);
 
 		}
@@ -300,8 +369,8 @@ impl IndexTable {
 			return entry;
 		}
 		if let Some(map) = &*self.map.read() {
-			let source = Self::chunk_at(chunk_index, map);
-			chunk.copy_from_slice(source);
+			let source = self.chunk_at(chunk_index, map);
+			chunk[.. source.len()].copy_from_slice(source);
 			return Self::transmute_chunk(chunk);
 		}
 		return Self::transmute_chunk(EMPTY_CHUNK);
@@ -348,7 +417,7 @@ impl IndexTable {
 			return Ok(PlanOutcome::NeedReindex);
 		}
 		let mut chunk = [0; CHUNK_LEN];
-		chunk.copy_from_slice(source);
+		chunk[.. source.len()].copy_from_slice(source);
 		let partial_key = Entry::extract_key(key, self.id.index_bits());
 		let new_entry = Entry::new(address, partial_key, self.id.index_bits());
 		if let Some(i) = sub_index {
@@ -359,7 +428,7 @@ impl IndexTable {
 			log.insert_index(self.id, chunk_index, i as u8, &chunk);
 			return Ok(PlanOutcome::Written);
 		}
-		for i in 0 .. CHUNK_ENTRIES {
+		for i in 0 .. self.chunk_entries {
 			let entry = Self::read_entry(&chunk, i);
 			if entry.is_empty() {
 				Self::write_entry(&new_entry, i, &mut chunk);
@@ -382,7 +451,7 @@ impl IndexTable {
 		}
 
 		if let Some(map) = &*self.map.read() {
-			let chunk = Self::chunk_at(chunk_index, map);
+			let chunk = self.chunk_at(chunk_index, map);
 			return self.plan_insert_chunk(key, address, chunk, sub_index, log);
 		}
 
@@ -390,9 +459,28 @@ impl IndexTable {
 		self.plan_insert_chunk(key, address, chunk, sub_index, log)
 	}
 
+	pub fn entry_at(&self, chunk_index: u64, sub_index: usize, log: &impl LogQuery) -> Entry {
+		self.entries(chunk_index, log)[sub_index]
+	}
+
+	/// Point an existing entry to a new address.
+	pub fn write_address_plan(&self, chunk_index: u64, sub_index: usize, address: Address, log: &mut LogWriter) {
+		let mut chunk = [0; CHUNK_LEN];
+		if let Some(c) = log.with_index(self.id, chunk_index, |c| *c) {
+			chunk = c;
+		} else if let Some(map) = &*self.map.read() {
+			chunk[.. self.chunk_len()].copy_from_slice(self.chunk_at(chunk_index, map));
+		}
+		let entry = Self::read_entry(&chunk, sub_index);
+		let new_entry = Entry::new(address, entry.key_material(self.id.index_bits()), self.id.index_bits());
+		Self::write_entry(&new_entry, sub_index, &mut chunk);
+		log::trace!(target: "parity-db", "{}: Moved {}.{} to {}", self.id, chunk_index, sub_index, address);
+		log.insert_index(self.id, chunk_index, sub_index as u8, &chunk);
+	}
+
 	fn plan_remove_chunk(&self, key: u64, source: &[u8], sub_index: usize, log: &mut LogWriter) -> Result<PlanOutcome> {
 		let mut chunk = [0; CHUNK_LEN];
-		chunk.copy_from_slice(source);
+		chunk[.. source.len()].copy_from_slice(source);
 		let chunk_index = self.chunk_index(key);
 		let partial_key = Entry::extract_key(key, self.id.index_bits());
 
@@ -418,21 +506,21 @@ impl IndexTable {
 		}
 
 		if let Some(map) = &*self.map.read() {
-			let chunk = Self::chunk_at(chunk_index, map);
+			let chunk = self.chunk_at(chunk_index, map);
 			return self.plan_remove_chunk(key, chunk, sub_index, log);
 		}
 
 		Ok(PlanOutcome::Skipped)
 	}
 
-	pub fn enact_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
+	fn with_chunk_mut<R>(&self, index: u64, f: impl FnOnce(&mut [u8]) -> Result<R>) -> Result<R> {
 		let mut map = self.map.upgradable_read();
 		if map.is_none() {
 			let mut wmap = RwLockUpgradableReadGuard::upgrade(map);
 			let file = std::fs::OpenOptions::new().write(true).read(true).create_new(true).open(self.path.as_path())?;
 			log::debug!(target: "parity-db", "Created new index {}", self.id);
 			//TODO: check for potential overflows on 32-bit platforms
-			file.set_len(file_size(self.id.index_bits()))?;
+			file.set_len(file_size(self.id.index_bits(), self.chunk_entries))?;
 			let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file)? };
 			self.madvise_random(&mut mmap);
 			*wmap = Some(mmap);
@@ -440,26 +528,57 @@ impl IndexTable {
 		}
 
 		let map = map.as_ref().unwrap();
-		let offset = META_SIZE + index as usize * CHUNK_LEN;
+		let offset = META_SIZE + index as usize * self.chunk_len();
 		// Nasty mutable pointer cast. We do ensure that all chunks that are being written are accessed
 		// through the overlay in other threads.
 		let ptr: *mut u8 = map.as_ptr() as *mut u8;
 		let chunk: &mut[u8] = unsafe {
 			let ptr = ptr.offset(offset as isize);
-			std::slice::from_raw_parts_mut(ptr, CHUNK_LEN)
+			std::slice::from_raw_parts_mut(ptr, self.chunk_len())
 		};
-		let mut mask_buf = [0u8; 8];
-		log.read(&mut mask_buf)?;
-		let mut mask = u64::from_le_bytes(mask_buf);
-		while mask != 0 {
-			let i = mask.trailing_zeros();
-			mask = mask & !(1 << i);
-			log.read(&mut chunk[i as usize *ENTRY_BYTES .. (i as usize + 1)*ENTRY_BYTES])?;
-		}
+		f(chunk)
+	}
+
+	pub fn enact_plan(&self, index: u64, log: &mut impl LogRead) -> Result<()> {
+		self.with_chunk_mut(index, |chunk| {
+			let mut mask_buf = [0u8; 8];
+			log.read(&mut mask_buf)?;
+			let mut mask = u64::from_le_bytes(mask_buf);
+			self.check_mask(mask)?;
+			while mask != 0 {
+				let i = mask.trailing_zeros();
+				mask = mask & !(1 << i);
+				log.read(&mut chunk[i as usize *ENTRY_BYTES .. (i as usize + 1)*ENTRY_BYTES])?;
+			}
+			Ok(())
+		})?;
 		log::trace!(target: "parity-db", "{}: Enacted chunk {}", self.id, index);
 		Ok(())
 	}
 
+	// Modified entries must be in the chunk.
+	fn check_mask(&self, mask: u64) -> Result<()> {
+		if self.chunk_entries < 64 && mask >> self.chunk_entries != 0 {
+			return Err(Error::Corruption("Bad index chunk mask".into()));
+		}
+		Ok(())
+	}
+
+	/// Write modified entries of a chunk directly, without going through the log.
+	pub fn write_chunk(&self, index: u64, mask: u64, data: &Chunk) -> Result<()> {
+		self.with_chunk_mut(index, |chunk| {
+			let mut mask = mask;
+			while mask != 0 {
+				let i = mask.trailing_zeros() as usize;
+				mask &= !(1 << i);
+				chunk[i * ENTRY_BYTES .. (i + 1) * ENTRY_BYTES].copy_from_slice(&data[i * ENTRY_BYTES .. (i + 1) * ENTRY_BYTES]);
+			}
+			Ok(())
+		})?;
+		log::trace!(target: "parity-db", "{}: Wrote chunk {}", self.id, index);
+		Ok(())
+	}
+
 	pub fn validate_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
 		if index >= self.id.total_entries() {
 			return Err(Error::Corruption("Bad index".into()));
@@ -467,6 +586,7 @@ impl IndexTable {
 		let mut buf = [0u8; 8];
 		log.read(&mut buf)?;
 		let mut mask = u64::from_le_bytes(buf);
+		self.check_mask(mask)?;
 		while mask != 0 {
 			let i = mask.trailing_zeros();
 			mask = mask & !(1 << i);
@@ -476,6 +596,42 @@ impl IndexTable {
 		Ok(())
 	}
 
+	/// Read the contents of a chunk update from the log, to be passed to `enact_plan` later.
+	pub fn read_plan(log: &mut LogReader) -> Result<Vec<u8>> {
+		let mut mask_buf = [0u8; 8];
+		log.read(&mut mask_buf)?;
+		let mask = u64::from_le_bytes(mask_buf);
+		let mut data = vec![0; 8 + mask.count_ones() as usize * ENTRY_BYTES];
+		data[0..8].copy_from_slice(&mask_buf);
+		log.read(&mut data[8..])?;
+		Ok(data)
+	}
+
+	/// Read a chunk update from the log into the overlay, without modifying the table.
+	pub fn load_plan(&self, index: u64, log: &mut LogReader, writer: &mut LogWriter) -> Result<()> {
+		if index >= self.id.total_entries() {
+			return Err(Error::Corruption("Bad index".into()));
+		}
+		let mut chunk = [0; CHUNK_LEN];
+		if let Some(c) = writer.with_index(self.id, index, |c| *c) {
+			chunk = c;
+		} else if let Some(map) = &*self.map.read() {
+			chunk[.. self.chunk_len()].copy_from_slice(self.chunk_at(index, map));
+		}
+		let mut mask_buf = [0u8; 8];
+		log.read(&mut mask_buf)?;
+		let mut mask = u64::from_le_bytes(mask_buf);
+		self.check_mask(mask)?;
+		while mask != 0 {
+			let i = mask.trailing_zeros();
+			mask &= !(1 << i);
+			log.read(&mut chunk[i as usize * ENTRY_BYTES .. (i as usize + 1) * ENTRY_BYTES])?;
+			writer.insert_index(self.id, index, i as u8, &chunk);
+		}
+		log::trace!(target: "parity-db", "{}: Loaded chunk {}", self.id, index);
+		Ok(())
+	}
+
 	pub fn drop_file(self) -> Result<()> {
 		std::mem::drop(self.map);
 		std::fs::remove_file(self.path.as_path())?;
@@ -493,7 +649,7 @@ impl IndexTable {
 	#[cfg(unix)]
 	fn madvise_random(&self, map: &mut memmap2::MmapMut) {
 		unsafe {
-			libc::madvise(map.as_mut_ptr() as _, file_size(self.id.index_bits()) as usize, libc::MADV_RANDOM);
+			libc::madvise(map.as_mut_ptr() as _, file_size(self.id.index_bits(), self.chunk_entries) as usize, libc::MADV_RANDOM);
 		}
 	}
 
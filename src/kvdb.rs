@@ -0,0 +1,233 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `kvdb::KeyValueDB` implementation.
+//!
+//! Keys of columns that are not `uniform` are hashed, so these columns can't be iterated
+//! and `get_by_prefix`, `iter` and `iter_with_prefix` fail with `ErrorKind::Unsupported`.
+//! Uniform columns return the stored 32 byte keys, in no particular order, and not in
+//! the lexicographic order of `kvdb-rocksdb`. `DBOp::DeletePrefix` is supported for uniform
+//! columns, or with an empty prefix for any column, but not for reference counted columns.
+//! The keys it matches are removed in the same commit as the rest of the transaction.
+//! IO statistics are not collected.
+
+use std::io;
+use ::kvdb::{DBKey, DBKeyValue, DBOp, DBTransaction, DBValue, KeyValueDB};
+use crate::{db::Db, column::ColId, error::Error, table::Key};
+
+/// `KeyValueDB` over a database. Column indices map to parity-db columns as is.
+pub struct DbAdapter(Db);
+
+impl DbAdapter {
+	pub fn new(db: Db) -> DbAdapter {
+		DbAdapter(db)
+	}
+
+	pub fn db(&self) -> &Db {
+		&self.0
+	}
+
+	pub fn into_inner(self) -> Db {
+		self.0
+	}
+
+	fn column(&self, col: u32) -> io::Result<ColId> {
+		if col >= self.0.num_columns() as u32 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("No such column family: {}", col)));
+		}
+		Ok(col as ColId)
+	}
+
+	fn hash_key(&self, col: u32, key: &[u8]) -> io::Result<Key> {
+		self.0.hash_key(self.column(col)?, key).map_err(io_error)
+	}
+
+	fn iter_column(&self, col: u32) -> io::Result<impl Iterator<Item = io::Result<DBKeyValue>> + '_> {
+		let col = self.column(col)?;
+		if !self.0.column_options(col).is_some_and(|o| o.uniform) {
+			return Err(io::Error::new(
+				io::ErrorKind::Unsupported,
+				format!("Column {} keys are hashed and can't be iterated", col),
+			));
+		}
+		Ok(self.0.iter(col).map_err(io_error)?
+			.map(|entry| entry.map(|(key, value)| (DBKey::from_slice(&key), value)).map_err(io_error)))
+	}
+}
+
+fn io_error(e: Error) -> io::Error {
+	match e {
+		Error::Io(e) => e,
+		Error::IoWith { context, source } => io::Error::new(source.kind(), format!("{}: {}", context, source)),
+		e => io::Error::other(e.to_string()),
+	}
+}
+
+impl KeyValueDB for DbAdapter {
+	fn get(&self, col: u32, key: &[u8]) -> io::Result<Option<DBValue>> {
+		self.0.get(self.column(col)?, key).map_err(io_error)
+	}
+
+	fn get_by_prefix(&self, col: u32, prefix: &[u8]) -> io::Result<Option<DBValue>> {
+		self.iter_with_prefix(col, prefix).next().transpose().map(|entry| entry.map(|(_, value)| value))
+	}
+
+	fn write(&self, transaction: DBTransaction) -> io::Result<()> {
+		let mut commit = Vec::with_capacity(transaction.ops.len());
+		for op in transaction.ops {
+			match op {
+				DBOp::Insert { col, key, value } => commit.push((self.column(col)?, self.hash_key(col, &key)?, Some(value))),
+				DBOp::Delete { col, key } => commit.push((self.column(col)?, self.hash_key(col, &key)?, None)),
+				DBOp::DeletePrefix { col, prefix } => {
+					let col = self.column(col)?;
+					let keys = self.0.prefix_keys(col, &prefix).map_err(io_error)?;
+					// Changes earlier in the transaction are matched by the index key too.
+					commit.retain(|(c, key, _)| *c != col || !key.starts_with(&prefix));
+					commit.extend(keys.into_iter().map(|key| (col, key, None)));
+				},
+			}
+		}
+		if !commit.is_empty() {
+			self.0.commit_index_keys(commit).map_err(io_error)?;
+		}
+		Ok(())
+	}
+
+	fn iter<'a>(&'a self, col: u32) -> Box<dyn Iterator<Item = io::Result<DBKeyValue>> + 'a> {
+		match self.iter_column(col) {
+			Ok(iter) => Box::new(iter),
+			Err(e) => Box::new(std::iter::once(Err(e))),
+		}
+	}
+
+	fn iter_with_prefix<'a>(
+		&'a self,
+		col: u32,
+		prefix: &'a [u8],
+	) -> Box<dyn Iterator<Item = io::Result<DBKeyValue>> + 'a> {
+		Box::new(self.iter(col).filter(move |entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(prefix))))
+	}
+
+	fn has_key(&self, col: u32, key: &[u8]) -> io::Result<bool> {
+		self.0.get_size(self.column(col)?, key).map(|size| size.is_some()).map_err(io_error)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ::kvdb::KeyValueDB;
+	use super::DbAdapter;
+	use crate::{Db, Options};
+
+	fn adapter(path: &std::path::Path) -> DbAdapter {
+		let mut options = Options::with_columns(path, 2);
+		options.columns[1].uniform = true;
+		DbAdapter::new(Db::open_or_create(&options).unwrap())
+	}
+
+	fn key(prefix: u8, i: u8) -> [u8; 32] {
+		let mut key = [0u8; 32];
+		key[0] = prefix;
+		key[31] = i;
+		key
+	}
+
+	// The shared tests of iteration and prefixes expect keys in lexicographic order, and
+	// `test_io_stats` expects IO statistics, so these are not run.
+	#[test]
+	fn shared_tests() {
+		let tmp = tempfile::tempdir().unwrap();
+		let db = DbAdapter::new(Db::open_or_create(&Options::with_columns(tmp.path(), 1)).unwrap());
+		kvdb_shared_tests::test_put_and_get(&db).unwrap();
+		kvdb_shared_tests::test_delete_and_get(&db).unwrap();
+		kvdb_shared_tests::test_get_fails_with_non_existing_column(&db).unwrap();
+		kvdb_shared_tests::test_write_clears_buffered_ops(&db).unwrap();
+	}
+
+	#[test]
+	fn put_delete_and_get() {
+		let tmp = tempfile::tempdir().unwrap();
+		let db = adapter(tmp.path());
+		let mut tx = db.transaction();
+		tx.put(0, b"key1", b"horse");
+		tx.put(0, b"key2", b"pig");
+		tx.delete(0, b"key2");
+		tx.put(1, &key(1, 1), b"cat");
+		db.write(tx).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"horse".to_vec()));
+		assert_eq!(db.get(0, b"key2").unwrap(), None);
+		assert!(db.has_key(0, b"key1").unwrap());
+		// Too short for a uniform column.
+		assert!(db.has_key(1, b"key1").is_err());
+		assert_eq!(db.get(1, &key(1, 1)).unwrap(), Some(b"cat".to_vec()));
+
+		// Missing column.
+		assert!(db.get(2, b"key1").is_err());
+		let mut tx = db.transaction();
+		tx.put(2, b"key1", b"dog");
+		assert!(db.write(tx).is_err());
+	}
+
+	#[test]
+	fn iter_and_delete_prefix() {
+		let tmp = tempfile::tempdir().unwrap();
+		let db = adapter(tmp.path());
+		let mut tx = db.transaction();
+		for i in 0 .. 10 {
+			tx.put(1, &key(1, i), &[i]);
+			tx.put(1, &key(2, i), &[i]);
+		}
+		db.write(tx).unwrap();
+		assert_eq!(db.iter(1).count(), 20);
+		let mut values: Vec<_> = db.iter_with_prefix(1, &[2]).map(|e| e.unwrap()).collect();
+		values.sort();
+		assert_eq!(values.len(), 10);
+		assert_eq!(values[3], (key(2, 3).as_ref().into(), vec![3]));
+		assert!(db.get_by_prefix(1, &[2]).unwrap().is_some());
+		assert!(db.get_by_prefix(1, &[3]).unwrap().is_none());
+		// Keys of the first column are hashed.
+		assert!(db.iter(0).next().unwrap().is_err());
+
+		let mut tx = db.transaction();
+		tx.delete_prefix(1, &[1]);
+		tx.put(1, &key(1, 20), &[20]);
+		db.write(tx).unwrap();
+		assert_eq!(db.iter(1).count(), 11);
+		assert_eq!(db.get(1, &key(1, 20)).unwrap(), Some(vec![20]));
+
+		// Changes before the prefix deletion in the transaction are removed too.
+		let mut tx = db.transaction();
+		tx.put(1, &key(2, 20), &[20]);
+		tx.put(0, b"key", b"value");
+		tx.delete_prefix(1, &[2]);
+		tx.put(1, &key(2, 21), &[21]);
+		db.write(tx).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(1, &key(2, 20)).unwrap(), None);
+		assert_eq!(db.iter_with_prefix(1, &[2]).count(), 1);
+
+		let mut tx = db.transaction();
+		tx.put(0, b"key2", b"value");
+		tx.delete_prefix(0, &[]);
+		db.write(tx).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), None);
+		assert_eq!(db.get(0, b"key2").unwrap(), None);
+		// Hashed keys can't be matched by a prefix.
+		let mut tx = db.transaction();
+		tx.delete_prefix(0, &[1]);
+		assert!(db.write(tx).is_err());
+	}
+}
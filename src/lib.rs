@@ -25,10 +25,25 @@ mod options;
 mod stats;
 mod compress;
 mod migration;
+mod trash;
+mod ttl;
+mod export;
+mod cache;
+mod pool;
+mod metrics;
+#[cfg(feature = "kvdb")]
+mod kvdb;
 
-pub use db::{Db, Value, check::CheckOptions};
+pub use db::{Db, Column, Value, CasOutcome, Cursor, IterCursor, IterPage, Backpressure, EnactedRecord, Operation, BackupProgress, CommitBuilder, CommitFuture, CommitChange, CommitHook, CommitSummary, Priority, check::CheckOptions};
 pub use table::Key;
+pub use column::{KeyHash, KeyMode, Salt, hash_key};
 pub use error::{Error, Result};
-pub use options::{ColumnOptions, Options};
-pub use migration::migrate;
+pub use options::{BacklogWarning, ColumnOptions, ConfigMismatch, Options, OptionsBuilder, SizeHint, TrashOptions, WalSync, OPTIONS_FILE};
+pub use migration::{migrate, resalt};
 pub use compress::CompressionType;
+pub use stats::{ColumnCounters, DiskUsage, ColumnDiskUsage, TableDiskUsage, FreeStats, TableFreeStats, PendingBytes, MemoryUsage, CommitQueueDepth, ValueMeta, VerifyReport, ColumnVerifyReport, RepairSummary};
+pub use log::{ReplayReport, ReplayFailure, ReplaySession, ReplayAction};
+pub use metrics::DbMetrics;
+#[cfg(feature = "kvdb")]
+pub use crate::kvdb::DbAdapter;
+pub use export::{ExportOptions, ExportSummary, ImportOptions, ImportSummary, OnConflict};
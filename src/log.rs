@@ -17,16 +17,166 @@
 use std::collections::{VecDeque, HashMap};
 use std::io::{Read, Write, Seek};
 use std::convert::TryInto;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU32, Ordering};
 use parking_lot::{Condvar, Mutex, RwLock, RwLockWriteGuard, MappedRwLockWriteGuard};
 use crate::{
 	error::{Error, Result},
-	table::TableId as ValueTableId,
+	column::ColId,
+	table::{TableId as ValueTableId, MULTIPART_ENTRY_SIZE, logged_entry_size},
 	index::{TableId as IndexTableId, Chunk as IndexChunk, ENTRY_BYTES},
-	options::Options,
+	options::{Options, WalSync},
+	metrics::DbMetrics,
+	pool::ThreadPool,
 };
 
-const MAX_LOG_POOL_SIZE: usize = 16;
+// Records at least this large are checksummed on multiple threads.
+const PARALLEL_CRC_THRESHOLD: u64 = 4 * 1024 * 1024;
+const CRC_SEGMENT_SIZE: usize = 1024 * 1024;
+const MAX_CRC_THREADS: usize = 8;
+// Secondary instance gives up catching up after this many log files were reused while reading.
+const CATCH_UP_ATTEMPTS: usize = 4;
+// Each log starts with a record header: the action type and the record id.
+const LOG_HEADER_SIZE: u64 = 9;
+
+/// Result of checking log files without enacting them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+	/// Number of log files checked.
+	pub logs: usize,
+	/// Number of valid records.
+	pub records: u64,
+	/// Total size of valid records.
+	pub bytes: u64,
+	/// First invalid record. Nothing after it is checked.
+	pub failure: Option<ReplayFailure>,
+}
+
+/// Location of an invalid log record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayFailure {
+	/// Log file id.
+	pub log: u32,
+	/// Offset of the record in the log file.
+	pub offset: u64,
+	/// Record id, if the record header could be read.
+	pub record_id: Option<u64>,
+	pub error: String,
+}
+
+/// Change recorded in a log record, as read by `ReplaySession`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplayAction {
+	/// Modified entries of chunk `chunk` of the index table of column `col` with `index_bits`.
+	/// `mask` has a bit set for each modified entry, `entries` holds their new values in order.
+	InsertIndex { col: ColId, index_bits: u8, chunk: u64, mask: u64, entries: Vec<u64> },
+	/// Entry `index` of value table `size_tier` of column `col`, as written to the table.
+	InsertValue { col: ColId, size_tier: u8, index: u64, data: Vec<u8> },
+	/// Index table of column `col` with `index_bits` is dropped after reindexing, or when the
+	/// column is cleared.
+	DropTable { col: ColId, index_bits: u8 },
+	/// Index of column `col` is replaced with an empty table with `index_bits`, when the column
+	/// is cleared. Tables it replaces are dropped by the same record.
+	ReplaceIndex { col: ColId, index_bits: u8 },
+}
+
+/// Reads the records of the log files of a database in order, without enacting them or
+/// modifying any files. Does not take the database lock, so it may be used on a copy of
+/// the files for offline analysis. Yields the record id and the changes of each record up
+/// to the stop record. Records are checked, and iteration ends after the first bad one.
+pub struct ReplaySession {
+	log: Log,
+	stop: u64,
+	done: bool,
+}
+
+impl ReplaySession {
+	/// Open the logs in `Options::log_dir`, to read records up to `stop` inclusive. Column
+	/// options must match the database, since they define the size of logged values.
+	pub fn open(options: &Options, stop: u64) -> Result<ReplaySession> {
+		Ok(ReplaySession { log: Log::open_read_only(options)?, stop, done: false })
+	}
+
+	fn read_record(&mut self) -> Result<Option<(u64, Vec<ReplayAction>)>> {
+		loop {
+			if !self.log.is_reading() {
+				// End of the current log file.
+				if self.log.replay_next()?.is_none() {
+					return Ok(None);
+				}
+			}
+			let mut reader = match self.log.read_next(true)? {
+				Some(reader) => reader,
+				None => continue,
+			};
+			let record_id = reader.record_id();
+			if record_id > self.stop {
+				return Ok(None);
+			}
+			let mut actions = Vec::new();
+			loop {
+				match reader.next()? {
+					LogAction::BeginRecord => return Err(Error::Corruption("Unexpected record header".into())),
+					LogAction::EndRecord => return Ok(Some((record_id, actions))),
+					LogAction::InsertIndex(insertion) => {
+						let mut buf = [0u8; 8];
+						reader.read(&mut buf)?;
+						let mask = u64::from_le_bytes(buf);
+						let mut entries = Vec::with_capacity(mask.count_ones() as usize);
+						for _ in 0 .. mask.count_ones() {
+							reader.read(&mut buf[0 .. ENTRY_BYTES])?;
+							entries.push(u64::from_le_bytes(buf));
+						}
+						actions.push(ReplayAction::InsertIndex {
+							col: insertion.table.col(),
+							index_bits: insertion.table.index_bits(),
+							chunk: insertion.index,
+							mask,
+							entries,
+						});
+					},
+					LogAction::InsertValue(insertion) => {
+						let entry_size = self.log.entry_size(insertion.table)
+							.ok_or_else(|| Error::Corruption(format!("Bad value table {}", insertion.table)))?;
+						let mut head = [0u8; 2];
+						reader.read(&mut head)?;
+						let size = logged_entry_size(insertion.index, head, entry_size, false)?;
+						let mut data = vec![0; size];
+						data[0 .. 2].copy_from_slice(&head);
+						reader.read(&mut data[2 ..])?;
+						actions.push(ReplayAction::InsertValue {
+							col: insertion.table.col(),
+							size_tier: insertion.table.size_tier(),
+							index: insertion.index,
+							data,
+						});
+					},
+					LogAction::DropTable(table) => actions.push(ReplayAction::DropTable {
+						col: table.col(),
+						index_bits: table.index_bits(),
+					}),
+					LogAction::ReplaceIndex(table) => actions.push(ReplayAction::ReplaceIndex {
+						col: table.col(),
+						index_bits: table.index_bits(),
+					}),
+				}
+			}
+		}
+	}
+}
+
+impl Iterator for ReplaySession {
+	type Item = Result<(u64, Vec<ReplayAction>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+		let result = self.read_record().transpose();
+		self.done = !matches!(result, Some(Ok(_)));
+		result
+	}
+}
 
 pub struct InsertIndexAction {
 	pub table: IndexTableId,
@@ -43,6 +193,7 @@ pub enum LogAction {
 	InsertIndex(InsertIndexAction),
 	InsertValue(InsertValueAction),
 	DropTable(IndexTableId),
+	ReplaceIndex(IndexTableId),
 	EndRecord,
 }
 
@@ -55,6 +206,42 @@ pub trait LogQuery {
 pub struct LogOverlays {
 	index: HashMap<IndexTableId, IndexLogOverlay>,
 	value: HashMap<ValueTableId, ValueLogOverlay>,
+	// Size of the index chunks and values held.
+	bytes: u64,
+}
+
+// Overlay size of a logged index chunk.
+const INDEX_OVERLAY_ENTRY_BYTES: u64 = std::mem::size_of::<(u64, u64, IndexChunk)>() as u64;
+
+impl LogOverlays {
+	fn extend(
+		&mut self,
+		index: HashMap<IndexTableId, IndexLogOverlay>,
+		values: HashMap<ValueTableId, ValueLogOverlay>,
+	) -> (usize, usize) {
+		let mut total_index = 0;
+		for (id, overlay) in index.into_iter() {
+			total_index += overlay.map.len();
+			let map = &mut self.index.entry(id).or_default().map;
+			for (index, entry) in overlay.map.into_iter() {
+				if map.insert(index, entry).is_none() {
+					self.bytes += INDEX_OVERLAY_ENTRY_BYTES;
+				}
+			}
+		}
+		let mut total_value = 0;
+		for (id, overlay) in values.into_iter() {
+			total_value += overlay.map.len();
+			let map = &mut self.value.entry(id).or_default().map;
+			for (index, entry) in overlay.map.into_iter() {
+				self.bytes += entry.1.len() as u64;
+				if let Some((_, replaced)) = map.insert(index, entry) {
+					self.bytes -= replaced.len() as u64;
+				}
+			}
+		}
+		(total_index, total_value)
+	}
 }
 
 impl LogQuery for RwLock<LogOverlays> {
@@ -79,10 +266,12 @@ impl LogQuery for RwLock<LogOverlays> {
 pub struct Cleared {
 	index: Vec<(IndexTableId, u64)>,
 	values: Vec<(ValueTableId, u64)>,
+	dropped: Vec<IndexTableId>,
 }
 
 pub struct LogReader<'a> {
 	file: MappedRwLockWriteGuard<'a, std::io::BufReader<std::fs::File>>,
+	log_id: u32,
 	record_id: u64,
 	read_bytes: u64,
 	crc32: crc32fast::Hasher,
@@ -97,11 +286,13 @@ impl<'a> LogReader<'a> {
 
 	fn new(
 		file: MappedRwLockWriteGuard<'a, std::io::BufReader<std::fs::File>>,
+		log_id: u32,
 		validate: bool,
 	) -> LogReader<'a> {
 		LogReader {
 			cleared: Default::default(),
 			file,
+			log_id,
 			record_id: 0,
 			read_bytes: 0,
 			crc32: crc32fast::Hasher::new(),
@@ -119,6 +310,22 @@ impl<'a> LogReader<'a> {
 	}
 
 	pub fn next(&mut self) -> Result<LogAction> {
+		self.read_action().map_err(|e| self.read_error(e))
+	}
+
+	// Name the log file and the record in read errors.
+	fn read_error(&self, e: Error) -> Error {
+		let location = match self.record_id {
+			0 => format!("log {}", self.log_id),
+			record_id => format!("log {}, record {}", self.log_id, record_id),
+		};
+		match e {
+			Error::Corruption(e) => Error::Corruption(format!("{}: {}", location, e)),
+			e => e.with_context(|| format!("reading {}", location)),
+		}
+	}
+
+	fn read_action(&mut self) -> Result<LogAction> {
 		let mut read_buf = |size, buf: &mut [u8; 8]| -> Result<()> {
 			self.file.read_exact(&mut buf[0..size])?;
 			self.read_bytes += size as u64;
@@ -139,7 +346,7 @@ impl<'a> LogReader<'a> {
 			},
 			2 => { // InsertIndex
 				read_buf(2, &mut buf)?;
-				let table = IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
+				let table = IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into()?));
 				read_buf(8, &mut buf)?;
 				let index = u64::from_le_bytes(buf);
 				self.cleared.index.push((table, index));
@@ -147,7 +354,7 @@ impl<'a> LogReader<'a> {
 			},
 			3 => { // InsertValue
 				read_buf(2, &mut buf)?;
-				let table = ValueTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
+				let table = ValueTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into()?));
 				read_buf(8, &mut buf)?;
 				let index = u64::from_le_bytes(buf);
 				self.cleared.values.push((table, index));
@@ -157,7 +364,7 @@ impl<'a> LogReader<'a> {
 				self.file.read_exact(&mut buf[0..4])?;
 				self.read_bytes += 4;
 				if self.validate {
-					let checksum = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+					let checksum = u32::from_le_bytes(buf[0..4].try_into()?);
 					let expected = std::mem::take(&mut self.crc32).finalize();
 					log::trace!(target: "parity-db",
 						"Read end of record, checksum={:#x}, expected={:#x}",
@@ -174,9 +381,15 @@ impl<'a> LogReader<'a> {
 			},
 			5 => { // DropTable
 				read_buf(2, &mut buf)?;
-				let table = IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
+				let table = IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into()?));
+				self.cleared.dropped.push(table);
 				Ok(LogAction::DropTable(table))
 			}
+			6 => { // ReplaceIndex
+				read_buf(2, &mut buf)?;
+				let table = IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into()?));
+				Ok(LogAction::ReplaceIndex(table))
+			}
 			_ => {
 				Err(Error::Corruption("Bad log entry type".into()))
 			}
@@ -184,7 +397,7 @@ impl<'a> LogReader<'a> {
 	}
 
 	pub fn read(&mut self, buf: &mut [u8]) -> Result<()> {
-		self.file.read_exact(buf)?;
+		self.file.read_exact(buf).map_err(|e| self.read_error(e.into()))?;
 		self.read_bytes += buf.len() as u64;
 		if self.validate {
 			self.crc32.update(buf);
@@ -201,11 +414,39 @@ impl<'a> LogReader<'a> {
 	}
 }
 
+/// Source of the contents of a log record action, as read by the tables when the action is enacted.
+pub trait LogRead {
+	fn read(&mut self, buf: &mut [u8]) -> Result<()>;
+}
+
+impl LogRead for LogReader<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+		LogReader::read(self, buf)
+	}
+}
+
+// Action contents that were read ahead of enaction.
+impl LogRead for &[u8] {
+	fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+		if self.len() < buf.len() {
+			return Err(Error::Corruption("Truncated log action".into()));
+		}
+		let (head, tail) = self.split_at(buf.len());
+		buf.copy_from_slice(head);
+		*self = tail;
+		Ok(())
+	}
+}
+
+// Overlays of the tables changed by a record, and the size of the record.
+pub type RecordOverlays = (HashMap<IndexTableId, IndexLogOverlay>, HashMap<ValueTableId, ValueLogOverlay>, u64);
+
 pub struct LogChange {
 	local_index: HashMap<IndexTableId, IndexLogOverlay>,
 	local_values: HashMap<ValueTableId, ValueLogOverlay>,
 	record_id: u64,
 	dropped_tables: Vec<IndexTableId>,
+	new_indexes: Vec<IndexTableId>,
 }
 
 impl LogChange {
@@ -216,23 +457,56 @@ impl LogChange {
 			local_index: Default::default(),
 			local_values: Default::default(),
 			dropped_tables: Default::default(),
+			new_indexes: Default::default(),
 			record_id,
 		}
 	}
 
-	pub fn to_file(self, file: &mut std::io::BufWriter<std::fs::File>)
-		-> Result<(HashMap<IndexTableId, IndexLogOverlay>, HashMap<ValueTableId, ValueLogOverlay>, u64)>
-	{
-		let mut crc32 = crc32fast::Hasher::new();
-		let mut bytes: u64 = 0;
+	pub fn record_id(&self) -> u64 {
+		self.record_id
+	}
 
-		let mut write = |buf: &[u8]| -> Result<()> {
-			file.write(buf)?;
-			crc32.update(buf);
-			bytes += buf.len() as u64;
-			Ok(())
-		};
+	/// Index tables dropped by this record.
+	pub fn dropped_tables(&self) -> &[IndexTableId] {
+		&self.dropped_tables
+	}
+
+	/// Empty index tables that replace the index of their column, see `LogWriter::replace_index`.
+	pub fn new_indexes(&self) -> &[IndexTableId] {
+		&self.new_indexes
+	}
+
+	/// Modified index chunks, with the mask of modified entries.
+	pub fn index_changes(&self) -> impl Iterator<Item = (IndexTableId, u64, u64, &IndexChunk)> {
+		self.local_index.iter().flat_map(|(id, overlay)|
+			overlay.map.iter().map(move |(index, (_, mask, chunk))| (*id, *index, *mask, chunk))
+		)
+	}
+
+	/// Modified value table entries.
+	pub fn value_changes(&self) -> impl Iterator<Item = (ValueTableId, u64, &[u8])> {
+		self.local_values.iter().flat_map(|(id, overlay)|
+			overlay.map.iter().map(move |(index, (_, value))| (*id, *index, value.as_slice()))
+		)
+	}
 
+	// Size of the serialized record, excluding the checksum.
+	fn payload_size(&self) -> u64 {
+		let mut size = 1 + 8 + 1;
+		for overlay in self.local_index.values() {
+			for (_, modified_entries_mask, _) in overlay.map.values() {
+				size += 1 + 2 + 8 + 8 + modified_entries_mask.count_ones() as u64 * ENTRY_BYTES as u64;
+			}
+		}
+		for overlay in self.local_values.values() {
+			for (_, value) in overlay.map.values() {
+				size += 1 + 2 + 8 + value.len() as u64;
+			}
+		}
+		size + (self.new_indexes.len() + self.dropped_tables.len()) as u64 * 3
+	}
+
+	fn serialize(&self, mut write: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
 		write(&1u8.to_le_bytes())?; // Begin record
 		write(&self.record_id.to_le_bytes())?;
 
@@ -258,15 +532,87 @@ impl LogChange {
 				write(value)?;
 			}
 		}
+		// Replaced tables are dropped after the new index is in place.
+		for id in self.new_indexes.iter() {
+			write(&6u8.to_le_bytes())?;
+			write(&id.as_u16().to_le_bytes())?;
+		}
 		for id in self.dropped_tables.iter() {
 			log::debug!(target: "parity-db", "Finalizing drop {}", id);
 			write(&5u8.to_le_bytes().as_ref())?;
 			write(&id.as_u16().to_le_bytes())?;
 		}
 
-		write(&4u8.to_le_bytes())?; // End record
-		let checksum: u32 = crc32.finalize();
-		file.write(&checksum.to_le_bytes())?;
+		write(&4u8.to_le_bytes()) // End record
+	}
+
+	// Serialize into segments and checksum them on the pool threads,
+	// while the segments are written to the file.
+	fn write_segmented(&self, file: &mut std::io::BufWriter<std::fs::File>, pool: &ThreadPool) -> Result<u32> {
+		let mut segments = Vec::new();
+		let mut segment = Vec::with_capacity(CRC_SEGMENT_SIZE);
+		self.serialize(|buf| {
+			segment.extend_from_slice(buf);
+			if segment.len() >= CRC_SEGMENT_SIZE {
+				segments.push(std::mem::replace(&mut segment, Vec::with_capacity(CRC_SEGMENT_SIZE)));
+			}
+			Ok(())
+		})?;
+		if !segment.is_empty() {
+			segments.push(segment);
+		}
+
+		let segments = Arc::new(segments);
+		let workers = pool.threads().min(segments.len());
+		let per_worker = segments.len().div_ceil(workers);
+		let (sender, results) = std::sync::mpsc::channel();
+		for worker in 0..workers {
+			let segments = segments.clone();
+			let sender = sender.clone();
+			pool.execute(worker, move || {
+				let result = std::panic::catch_unwind(|| {
+					let mut crc32 = crc32fast::Hasher::new();
+					for segment in segments.iter().skip(worker * per_worker).take(per_worker) {
+						crc32.update(segment);
+					}
+					crc32
+				});
+				let _ = sender.send((worker, result));
+			});
+		}
+		for segment in segments.iter() {
+			file.write_all(segment)?;
+		}
+		let mut parts: Vec<_> = (0..workers).map(|_| None).collect();
+		for _ in 0..workers {
+			let (worker, result) = results.recv().expect("Jobs always send a result");
+			match result {
+				Ok(crc32) => parts[worker] = Some(crc32),
+				Err(panic) => std::panic::resume_unwind(panic),
+			}
+		}
+		let mut crc32 = crc32fast::Hasher::new();
+		for part in parts {
+			crc32.combine(&part.expect("All parts are received"));
+		}
+		Ok(crc32.finalize())
+	}
+
+	pub fn to_file(self, file: &mut std::io::BufWriter<std::fs::File>, crc_pool: Option<&ThreadPool>) -> Result<RecordOverlays> {
+		let mut bytes = self.payload_size();
+		let checksum = match crc_pool {
+			Some(pool) if bytes >= PARALLEL_CRC_THRESHOLD => self.write_segmented(file, pool)?,
+			_ => {
+				let mut crc32 = crc32fast::Hasher::new();
+				self.serialize(|buf| {
+					file.write_all(buf)?;
+					crc32.update(buf);
+					Ok(())
+				})?;
+				crc32.finalize()
+			},
+		};
+		file.write_all(&checksum.to_le_bytes())?;
 		bytes += 4;
 		file.flush()?;
 		Ok((self.local_index, self.local_values, bytes))
@@ -276,6 +622,8 @@ impl LogChange {
 pub struct LogWriter<'a> {
 	overlays: &'a RwLock<LogOverlays>,
 	log: LogChange,
+	// Expected number of changes per table.
+	capacity: usize,
 }
 
 impl<'a> LogWriter<'a> {
@@ -286,15 +634,24 @@ impl<'a> LogWriter<'a> {
 		LogWriter {
 			overlays,
 			log: LogChange::new(record_id),
+			capacity: 0,
 		}
 	}
 
+	/// Pre-size maps of tables that are modified from now on for this many changes.
+	pub fn reserve(&mut self, changes: usize) {
+		self.capacity = changes;
+	}
+
 	pub fn record_id(&self) -> u64 {
 		self.log.record_id
 	}
 
 	pub fn insert_index(&mut self, table: IndexTableId, index: u64, sub: u8, data: &IndexChunk) {
-		match self.log.local_index.entry(table).or_default().map.entry(index) {
+		let capacity = self.capacity;
+		let overlay = self.log.local_index.entry(table)
+			.or_insert_with(|| IndexLogOverlay { map: HashMap::with_capacity(capacity) });
+		match overlay.map.entry(index) {
 			std::collections::hash_map::Entry::Occupied(mut entry) => {
 				*entry.get_mut() = (self.log.record_id, entry.get().1 | (1 << sub), data.clone());
 			}
@@ -305,13 +662,22 @@ impl<'a> LogWriter<'a> {
 	}
 
 	pub fn insert_value(&mut self, table: ValueTableId, index: u64, data: Vec<u8>) {
-		self.log.local_values.entry(table).or_default().map.insert(index, (self.log.record_id, data.clone()));
+		let capacity = self.capacity;
+		let overlay = self.log.local_values.entry(table)
+			.or_insert_with(|| ValueLogOverlay { map: HashMap::with_capacity_and_hasher(capacity, Default::default()) });
+		overlay.map.insert(index, (self.log.record_id, data));
 	}
 
 	pub fn drop_table(&mut self, id: IndexTableId) {
 		self.log.dropped_tables.push(id);
 	}
 
+	/// Replace the index of the column with the empty table `id`. The tables it replaces must
+	/// be dropped by the same record.
+	pub fn replace_index(&mut self, id: IndexTableId) {
+		self.log.new_indexes.push(id);
+	}
+
 	pub fn drain(self) -> LogChange {
 		self.log
 	}
@@ -357,13 +723,13 @@ impl std::hash::Hasher for IdentityHash {
     fn finish(&self) -> u64 { self.0 }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct IndexLogOverlay {
 	pub map: HashMap<u64, (u64, u64, IndexChunk)>, // index -> (record_id, modified_mask, entry)
 }
 
 // We use identity hash for value overlay/log records so that writes to value tables are in order.
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct ValueLogOverlay {
 	pub map: HashMap<u64, (u64, Vec<u8>), BuildIdHash>, // index -> (record_id, entry)
 }
@@ -398,46 +764,84 @@ pub struct Log {
 	done_reading_cv: Condvar,
 	flushing: Mutex<Option<Flushing>>,
 	next_record_id: AtomicU64,
+	// Last record that was read and enacted.
+	last_read_record_id: AtomicU64,
 	dirty: AtomicBool,
 	log_pool: RwLock<VecDeque<(u32, std::fs::File)>>,
 	cleanup_queue: RwLock<VecDeque<(u32, std::fs::File)>>,
 	replay_queue: RwLock<VecDeque<(u32, u64, std::fs::File)>>,
 	path: std::path::PathBuf,
 	next_log_id: AtomicU32,
-	sync: bool,
+	sync: WalSync,
+	last_sync: Mutex<std::time::Instant>,
+	// Value table entry sizes for each column, used to validate logs.
+	value_sizes: Vec<Vec<u16>>,
+	metrics: Option<Arc<dyn DbMetrics>>,
+	// Appending log is flushed past this size, whatever the `min_size` of the flush.
+	max_size: u64,
+	// Cleaned logs kept for reuse, see `Options::log_pool_size`.
+	pool_size: usize,
+	// Threads that checksum large records, started with the first record of at least
+	// `PARALLEL_CRC_THRESHOLD` bytes. 0 threads for logs that are only read.
+	crc_pool: Mutex<Option<ThreadPool>>,
+	crc_threads: usize,
 }
 
 impl Log {
 	pub fn open(options: &Options) -> Result<Log> {
-		let path = options.path.clone();
+		let path = options.log_dir();
 		let mut logs = VecDeque::new();
 		let mut max_log_id = 0;
-		for entry in std::fs::read_dir(&path)? {
-			let entry = entry?;
-			if let Some(name) = entry.file_name().as_os_str().to_str() {
-				if entry.metadata()?.is_file() && name.starts_with("log") {
-					if let Ok(nlog) = std::str::FromStr::from_str(&name[3..]) {
-						let path = Self::log_path(&path, nlog);
-						let (file, record_id) = Self::open_log_file(&path)?;
-						if let Some(record_id) = record_id {
-							log::debug!(target: "parity-db", "Opened log {}, record {}", nlog, record_id);
-							logs.push_back((nlog, record_id, file));
-							if nlog > max_log_id {
-								max_log_id = nlog
-							}
-						} else {
-							log::debug!(target: "parity-db", "Removing log {}", nlog);
-							std::mem::drop(file);
-							std::fs::remove_file(&path)?;
-						}
-					}
+		for nlog in Self::log_ids(path)? {
+			let path = Self::log_path(path, nlog);
+			let (file, record_id) = match Self::open_log_file(&path) {
+				Err(Error::Corruption(_)) if options.repair_on_replay && std::fs::metadata(&path)?.len() < LOG_HEADER_SIZE => {
+					// Shorter than a record header.
+					log::warn!(target: "parity-db", "Repair: Removing torn log {}", nlog);
+					std::fs::remove_file(&path)?;
+					continue;
+				},
+				result => result?,
+			};
+			if let Some(record_id) = record_id {
+				log::debug!(target: "parity-db", "Opened log {}, record {}", nlog, record_id);
+				logs.push_back((nlog, record_id, file));
+				if nlog > max_log_id {
+					max_log_id = nlog
 				}
+			} else {
+				log::debug!(target: "parity-db", "Removing log {}", nlog);
+				std::mem::drop(file);
+				std::fs::remove_file(&path)?;
 			}
 		}
 		logs.make_contiguous().sort_by_key(|(_id, record_id,  _)| *record_id);
 		let next_log_id = if logs.is_empty() { 0 } else { max_log_id + 1 };
+		let mut log = Self::with_logs(options, logs, next_log_id);
+		log.crc_threads = std::thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_CRC_THREADS);
+		Ok(log)
+	}
 
-		Ok(Log {
+	/// Open the logs of a database for reading only. Log files are not modified.
+	pub fn open_read_only(options: &Options) -> Result<Log> {
+		let mut logs = VecDeque::new();
+		for nlog in Self::log_ids(options.log_dir())? {
+			let mut file = std::fs::File::open(Self::log_path(options.log_dir(), nlog))?;
+			if let Some(record_id) = Self::first_record_id(&mut file)? {
+				logs.push_back((nlog, record_id, file));
+			}
+		}
+		logs.make_contiguous().sort_by_key(|(_id, record_id,  _)| *record_id);
+		Ok(Self::with_logs(options, logs, 0))
+	}
+
+	/// Open the log of a database that is written by another process. Log files are not modified.
+	pub fn open_secondary(options: &Options) -> Log {
+		Self::with_logs(options, Default::default(), 0)
+	}
+
+	fn with_logs(options: &Options, logs: VecDeque<(u32, u64, std::fs::File)>, next_log_id: u32) -> Log {
+		Log {
 			overlays: Default::default(),
 			appending: RwLock::new(None),
 			reading: RwLock::new(None),
@@ -445,14 +849,47 @@ impl Log {
 			done_reading_cv: Condvar::new(),
 			flushing: Mutex::new(None),
 			next_record_id: AtomicU64::new(1),
+			last_read_record_id: AtomicU64::new(0),
 			next_log_id: AtomicU32::new(next_log_id),
 			dirty: AtomicBool::new(true),
 			sync: options.sync_wal,
+			metrics: options.metrics.clone(),
+			last_sync: Mutex::new(std::time::Instant::now()),
 			replay_queue: RwLock::new(logs),
 			cleanup_queue: RwLock::new(VecDeque::new()),
 			log_pool: RwLock::new(Default::default()),
-			path,
-		})
+			path: options.log_dir().to_path_buf(),
+			value_sizes: options.columns.iter().map(|c| c.sizes.clone()).collect(),
+			max_size: options.max_log_size,
+			pool_size: options.log_pool_size,
+			crc_pool: Mutex::new(None),
+			crc_threads: 0,
+		}
+	}
+
+	fn log_ids(path: &std::path::Path) -> Result<Vec<u32>> {
+		let mut ids = Vec::new();
+		for entry in std::fs::read_dir(path)? {
+			let entry = entry?;
+			if let Some(name) = entry.file_name().as_os_str().to_str() {
+				if entry.metadata()?.is_file() && name.starts_with("log") {
+					if let Ok(nlog) = std::str::FromStr::from_str(&name[3..]) {
+						ids.push(nlog);
+					}
+				}
+			}
+		}
+		Ok(ids)
+	}
+
+	/// Remove all log files of a database that is not open. Returns the number of files removed.
+	pub fn discard_all(path: &std::path::Path) -> Result<usize> {
+		let ids = Self::log_ids(path)?;
+		for id in ids.iter() {
+			log::debug!(target: "parity-db", "Discarding log {}", id);
+			std::fs::remove_file(Self::log_path(path, *id))?;
+		}
+		Ok(ids.len())
 	}
 
 	fn log_path(root: &std::path::Path, id: u32) -> std::path::PathBuf {
@@ -461,22 +898,273 @@ impl Log {
 		path
 	}
 
+	// Make creation of a log file durable. The file contents are synced separately,
+	// but the directory entry may be lost on crash until the directory is synced too.
+	#[cfg(unix)]
+	fn sync_dir(&self) -> Result<()> {
+		std::fs::File::open(&self.path)?.sync_all()?;
+		Ok(())
+	}
+
+	#[cfg(not(unix))]
+	fn sync_dir(&self) -> Result<()> {
+		// Directories can't be opened as files. NTFS journals metadata anyway.
+		Ok(())
+	}
+
+	/// Id of the last record that was read from the log and enacted. 0 if there is none.
+	pub fn last_read_record_id(&self) -> u64 {
+		self.last_read_record_id.load(Ordering::Relaxed)
+	}
+
 	pub fn replay_record_id(&self) -> Option<u64> {
 		self.replay_queue.read().front().map(|(_id, record_id, _)| *record_id)
 	}
 
 	pub fn open_log_file(path: &std::path::Path) -> Result<(std::fs::File, Option<u64>)> {
 		let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
-		if file.metadata()?.len() == 0 {
-			return Ok((file, None));
+		let id = Self::first_record_id(&mut file).map_err(|e| match e {
+			Error::Corruption(e) => Error::Corruption(format!("log header {}: {}", path.display(), e)),
+			e => e.with_context(|| format!("reading log header {}", path.display())),
+		})?;
+		if let Some(id) = id {
+			log::debug!(target: "parity-db", "Opened existing log {}, first record_id = {}", path.display(), id);
+		}
+		Ok((file, id))
+	}
+
+	fn first_record_id(file: &mut std::fs::File) -> Result<Option<u64>> {
+		let len = file.metadata()?.len();
+		if len == 0 {
+			return Ok(None);
 		}
-		// read first record id
-		let mut buf = [0; 9];
+		if len < LOG_HEADER_SIZE {
+			return Err(Error::Corruption(format!("Truncated log header, {} bytes", len)));
+		}
+		let mut buf = [0; LOG_HEADER_SIZE as usize];
+		file.seek(std::io::SeekFrom::Start(0))?;
 		file.read_exact(&mut buf)?;
 		file.seek(std::io::SeekFrom::Start(0))?;
-		let id = u64::from_le_bytes(buf[1..].try_into().unwrap());
-		log::debug!(target: "parity-db", "Opened existing log {}, first record_id = {}", path.display(), id);
-		Ok((file, Some(id)))
+		let (action, record_id) = buf.split_at(1);
+		if action[0] != 1 {
+			return Err(Error::Corruption(format!("Log does not start with a record header, action type {}", action[0])));
+		}
+		Ok(Some(u64::from_le_bytes(record_id.try_into()?)))
+	}
+
+	/// Rebuild the overlays from log files that are written by another process.
+	/// `load` reads the data for a log action into the record. Returns `false` if
+	/// log files kept being reused while reading and the overlays were left unchanged.
+	pub fn catch_up<F>(&self, mut load: F) -> Result<bool>
+	where
+		F: FnMut(LogAction, &mut LogReader, &mut LogWriter) -> Result<()>,
+	{
+		for _ in 0 .. CATCH_UP_ATTEMPTS {
+			if let Some((overlays, last_record_id)) = self.load_logs(&mut load)? {
+				*self.overlays.write() = overlays;
+				self.next_record_id.store(last_record_id + 1, Ordering::Relaxed);
+				return Ok(true);
+			}
+			log::debug!(target: "parity-db", "Catch up: Log file reused while reading, resyncing");
+		}
+		Ok(false)
+	}
+
+	fn load_logs<F>(&self, load: &mut F) -> Result<Option<(LogOverlays, u64)>>
+	where
+		F: FnMut(LogAction, &mut LogReader, &mut LogWriter) -> Result<()>,
+	{
+		let mut logs = Vec::new();
+		for id in Self::log_ids(&self.path)? {
+			match std::fs::File::open(Self::log_path(&self.path, id)) {
+				// Header may still be being written.
+				Ok(mut file) => if let Ok(Some(record_id)) = Self::first_record_id(&mut file) {
+					logs.push((id, record_id, file));
+				},
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(e.into()),
+			}
+		}
+		logs.sort_by_key(|(_id, record_id, _)| *record_id);
+
+		let overlays = RwLock::new(LogOverlays::default());
+		let mut last_record_id = 0;
+		for (id, first_record_id, file) in logs {
+			let reading = RwLock::new(std::io::BufReader::new(file));
+			loop {
+				let mut reader = LogReader::new(RwLockWriteGuard::map(reading.write(), |r| r), id, true);
+				match reader.next() {
+					Ok(LogAction::BeginRecord) => {},
+					// End of file or a record that is still being written.
+					_ => break,
+				}
+				let mut writer = LogWriter::new(&overlays, reader.record_id());
+				let complete = loop {
+					match reader.next() {
+						Ok(LogAction::EndRecord) => break true,
+						Ok(LogAction::BeginRecord) | Err(_) => break false,
+						Ok(LogAction::DropTable(_)) | Ok(LogAction::ReplaceIndex(_)) => continue,
+						Ok(action) => if load(action, &mut reader, &mut writer).is_err() {
+							break false;
+						},
+					}
+				};
+				if !complete {
+					break;
+				}
+				let change = writer.drain();
+				last_record_id = last_record_id.max(change.record_id);
+				overlays.write().extend(change.local_index, change.local_values);
+			}
+			// The file may have been cleaned up and reused for new records while reading.
+			let mut file = reading.into_inner().into_inner();
+			if Self::first_record_id(&mut file).ok().flatten() != Some(first_record_id) {
+				log::debug!(target: "parity-db", "Catch up: Log {} was reused", id);
+				return Ok(None);
+			}
+		}
+		Ok(Some((overlays.into_inner(), last_record_id)))
+	}
+
+	/// Check all logs that are pending replay, without modifying the overlays or the tables.
+	pub fn validate_all(&self) -> Result<ReplayReport> {
+		let mut report = ReplayReport::default();
+		let mut last_record_id = None;
+		for (id, _record_id, _file) in self.replay_queue.read().iter() {
+			report.logs += 1;
+			let file = std::fs::File::open(Self::log_path(&self.path, *id))?;
+			let len = file.metadata()?.len();
+			let reading = RwLock::new(std::io::BufReader::new(file));
+			let mut offset = 0;
+			while offset < len {
+				let mut reader = LogReader::new(RwLockWriteGuard::map(reading.write(), |r| r), *id, true);
+				let result = self.validate_record(&mut reader, last_record_id);
+				let record_id = Some(reader.record_id()).filter(|id| *id != 0);
+				if let Err(e) = result {
+					log::debug!(target: "parity-db", "Validate: Bad record in log {} at {}: {}", id, offset, e);
+					report.failure = Some(ReplayFailure { log: *id, offset, record_id, error: e.to_string() });
+					return Ok(report);
+				}
+				last_record_id = record_id;
+				offset += reader.read_bytes();
+				report.records += 1;
+				report.bytes += reader.read_bytes();
+			}
+		}
+		Ok(report)
+	}
+
+	/// Truncate the logs pending replay at the end of the last valid record, if what follows
+	/// it is a torn record. Fails if the bad record is intact, or if a valid record follows it.
+	/// Returns the number of discarded bytes.
+	pub fn repair_torn_tail(&self) -> Result<u64> {
+		let failure = match self.validate_all()?.failure {
+			Some(failure) => failure,
+			None => return Ok(0),
+		};
+		let corruption = |reason: &str| Err(Error::Corruption(format!(
+			"Log {} has a bad record at {}: {}. Not a torn write, {}",
+			failure.log,
+			failure.offset,
+			failure.error,
+			reason,
+		)));
+		if self.valid_record_at(failure.log, failure.offset, None)? {
+			return corruption("the record is intact");
+		}
+		// A complete record after a torn one would be the next in sequence.
+		if let Some(record_id) = failure.record_id {
+			let data = std::fs::read(Self::log_path(&self.path, failure.log))?;
+			let next = (record_id + 1).to_le_bytes();
+			for offset in failure.offset as usize + 1 .. data.len().saturating_sub(8) {
+				if data[offset] == 1 && data[offset + 1 .. offset + 9] == next
+					&& self.valid_record_at(failure.log, offset as u64, Some(record_id))?
+				{
+					return corruption(&format!("record {} follows it", record_id + 1));
+				}
+			}
+		}
+		let mut queue = self.replay_queue.write();
+		let position = queue.iter().position(|(id, _, _)| *id == failure.log)
+			.expect("Failure is in one of the replayed logs");
+		for (id, _, _) in queue.iter().skip(position + 1) {
+			if self.valid_record_at(*id, 0, None)? {
+				return corruption(&format!("log {} follows it", id));
+			}
+		}
+		let mut discarded = 0;
+		for (id, _, file) in queue.drain(position + 1 ..) {
+			discarded += file.metadata()?.len();
+			std::mem::drop(file);
+			self.drop_log(id)?;
+		}
+		let len = queue[position].2.metadata()?.len();
+		discarded += len - failure.offset;
+		if failure.offset == 0 {
+			let (id, _, file) = queue.remove(position).expect("Position is in the queue");
+			std::mem::drop(file);
+			self.drop_log(id)?;
+		} else {
+			let file = &mut queue[position].2;
+			file.set_len(failure.offset)?;
+			file.sync_all()?;
+			file.seek(std::io::SeekFrom::Start(0))?;
+		}
+		Ok(discarded)
+	}
+
+	// Check if there is a complete record with a valid checksum at the offset.
+	fn valid_record_at(&self, id: u32, offset: u64, last_record_id: Option<u64>) -> Result<bool> {
+		let mut file = std::fs::File::open(Self::log_path(&self.path, id))?;
+		file.seek(std::io::SeekFrom::Start(offset))?;
+		let reading = RwLock::new(std::io::BufReader::new(file));
+		let mut reader = LogReader::new(RwLockWriteGuard::map(reading.write(), |r| r), id, true);
+		Ok(self.validate_record(&mut reader, last_record_id).is_ok())
+	}
+
+	fn validate_record(&self, reader: &mut LogReader, last_record_id: Option<u64>) -> Result<()> {
+		match reader.next()? {
+			LogAction::BeginRecord => {},
+			_ => return Err(Error::Corruption("Missing record header".into())),
+		}
+		if let Some(last) = last_record_id {
+			if reader.record_id() != last + 1 {
+				return Err(Error::Corruption(format!("Log sequence error. Expected record {}, got {}", last + 1, reader.record_id())));
+			}
+		}
+		loop {
+			match reader.next()? {
+				LogAction::BeginRecord => return Err(Error::Corruption("Unexpected record header".into())),
+				LogAction::EndRecord => return Ok(()),
+				LogAction::InsertIndex(_) => {
+					let mut buf = [0u8; 8];
+					reader.read(&mut buf)?;
+					for _ in 0 .. u64::from_le_bytes(buf).count_ones() {
+						reader.read(&mut buf[0 .. ENTRY_BYTES])?;
+					}
+				},
+				LogAction::InsertValue(insertion) => {
+					let entry_size = self.entry_size(insertion.table)
+						.ok_or_else(|| Error::Corruption(format!("Bad value table {}", insertion.table)))?;
+					let mut head = [0u8; 2];
+					reader.read(&mut head)?;
+					let size = logged_entry_size(insertion.index, head, entry_size, false)?;
+					let mut buf = vec![0; size - head.len()];
+					reader.read(&mut buf)?;
+				},
+				LogAction::DropTable(_) | LogAction::ReplaceIndex(_) => {},
+			}
+		}
+	}
+
+	fn entry_size(&self, table: ValueTableId) -> Option<u16> {
+		let sizes = self.value_sizes.get(table.col() as usize)?;
+		let tier = table.size_tier() as usize;
+		match tier.cmp(&sizes.len()) {
+			std::cmp::Ordering::Less => Some(sizes[tier]),
+			std::cmp::Ordering::Equal => Some(MULTIPART_ENTRY_SIZE),
+			std::cmp::Ordering::Greater => None,
+		}
 	}
 
 	fn drop_log(&self, id: u32) -> Result<()> {
@@ -505,11 +1193,17 @@ impl Log {
 		let mut overlays = self.overlays.write();
 		overlays.index.clear();
 		overlays.value.clear();
+		overlays.bytes = 0;
 		*self.reading_state.lock() = ReadingState::Idle;
 		self.dirty.store(false, Ordering::Relaxed);
 		Ok(())
 	}
 
+	/// Id of the last record that was started.
+	pub fn last_record_id(&self) -> u64 {
+		self.next_record_id.load(Ordering::Relaxed) - 1
+	}
+
 	pub fn begin_record<'a>(&'a self) -> LogWriter<'a> {
 		let id = self.next_record_id.fetch_add(1, Ordering::Relaxed);
 		let writer = LogWriter::new(
@@ -531,7 +1225,10 @@ impl Log {
 				// find a free id
 				let id = self.next_log_id.fetch_add(1, Ordering::SeqCst);
 				let path = Self::log_path(&self.path, id);
-				let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(path)?;
+				let file = std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)?;
+				if self.sync != WalSync::Never {
+					self.sync_dir()?;
+				}
 				log::debug!(target: "parity-db", "Flush: Activated new writer {}", id);
 				(id, file)
 			};
@@ -543,18 +1240,17 @@ impl Log {
 		}
 		let mut appending = self.appending.write();
 		let appending = appending.as_mut().unwrap();
-		let (index, values, bytes) = log.to_file(&mut appending.file)?;
-		let mut overlays = self.overlays.write();
-		let mut total_index = 0;
-		for (id, overlay) in index.into_iter() {
-			total_index += overlay.map.len();
-			overlays.index.entry(id).or_default().map.extend(overlay.map.into_iter());
-		}
-		let mut total_value = 0;
-		for (id, overlay) in values.into_iter() {
-			total_value += overlay.map.len();
-			overlays.value.entry(id).or_default().map.extend(overlay.map.into_iter());
+		let mut crc_pool = self.crc_pool.lock();
+		if crc_pool.is_none() && self.crc_threads > 1 && log.payload_size() >= PARALLEL_CRC_THRESHOLD {
+			match ThreadPool::new("parity-db-crc", self.crc_threads) {
+				Ok(pool) => *crc_pool = Some(pool),
+				// Checksum on this thread and try again with the next large record.
+				Err(e) => log::warn!(target: "parity-db", "Failed to start checksum threads: {}", e),
+			}
 		}
+		let (index, values, bytes) = log.to_file(&mut appending.file, crc_pool.as_ref())?;
+		drop(crc_pool);
+		let (total_index, total_value) = self.overlays.write().extend(index, values);
 		log::debug!(
 			target: "parity-db",
 			"Finalizing log record {} ({} index, {} value)",
@@ -567,10 +1263,28 @@ impl Log {
 		Ok(bytes)
 	}
 
+	/// Add a record to the overlays without writing it to a log file, so that it can be
+	/// queried while it is applied to the tables. Returns the entries to pass to `end_read`
+	/// once it is applied.
+	pub fn overlay_record(&self, log: &LogChange) -> Cleared {
+		assert!(log.record_id + 1 == self.next_record_id.load(Ordering::Relaxed));
+		let cleared = Cleared {
+			index: log.index_changes().map(|(id, index, _, _)| (id, index)).collect(),
+			values: log.value_changes().map(|(id, index, _)| (id, index)).collect(),
+			dropped: log.dropped_tables.clone(),
+		};
+		self.overlays.write().extend(log.local_index.clone(), log.local_values.clone());
+		cleared
+	}
+
 	pub fn end_read(&self, cleared: Cleared, record_id: u64) {
 		if record_id >= self.next_record_id.load(Ordering::Relaxed) {
 			self.next_record_id.store(record_id + 1, Ordering::Relaxed);
 		}
+		self.last_read_record_id.fetch_max(record_id, Ordering::Relaxed);
+		if let Some(metrics) = &self.metrics {
+			metrics.on_record_enacted(record_id);
+		}
 		let mut overlays = self.overlays.write();
 		for (table, index) in cleared.index.into_iter() {
 			if let Some(ref mut overlay) = overlays.index.get_mut(&table) {
@@ -578,6 +1292,7 @@ impl Log {
 					std::collections::hash_map::Entry::Occupied(e) => {
 						if e.get().0 == record_id {
 							e.remove_entry();
+							overlays.bytes -= INDEX_OVERLAY_ENTRY_BYTES;
 						}
 					}
 					_ => {},
@@ -589,18 +1304,29 @@ impl Log {
 				match overlay.map.entry(index) {
 					std::collections::hash_map::Entry::Occupied(e) => {
 						if e.get().0 == record_id {
-							e.remove_entry();
+							let (_, (_, value)) = e.remove_entry();
+							overlays.bytes -= value.len() as u64;
 						}
 					}
 					_ => {},
 				}
 			}
 		}
+		// Entries of a dropped table are never read again, whichever record they belong to.
+		for table in cleared.dropped.into_iter() {
+			if let Some(overlay) = overlays.index.remove(&table) {
+				overlays.bytes -= overlay.map.len() as u64 * INDEX_OVERLAY_ENTRY_BYTES;
+				log::debug!(target: "parity-db", "Dropped {} overlay entries of index {}", overlay.map.len(), table);
+			}
+		}
 		// Cleanup index overlays
 		overlays.index.retain(|_, overlay| !overlay.map.is_empty());
 	}
 
-	pub fn flush_one(&self, min_size: u64) -> Result<(bool, bool, bool)> {
+	/// Rotate the logs: the flushed log is passed to the reader, and the one being appended
+	/// to is flushed if it is larger than `min_size` or `Options::max_log_size`. This waits for the reader to finish
+	/// the previous log, unless `wait_for_reader` is `false`; it returns without rotating then.
+	pub fn flush_one(&self, min_size: u64, wait_for_reader: bool) -> Result<(bool, bool, bool)> {
 		// Wait for the reader to finish reading
 		let mut flushing = self.flushing.lock();
 		let mut read_next = false;
@@ -608,6 +1334,10 @@ impl Log {
 		if flushing.is_some() {
 			let mut reading_state = self.reading_state.lock();
 
+			if !wait_for_reader && *reading_state == ReadingState::Reading {
+				log::trace!(target: "parity-db", "Flush: Log reader is busy");
+				return Ok((true, false, false));
+			}
 			while *reading_state == ReadingState::Reading  {
 				log::debug!(target: "parity-db", "Flush: Awaiting log reader");
 				self.done_reading_cv.wait(&mut reading_state)
@@ -636,28 +1366,51 @@ impl Log {
 			}
 		}
 
+		let mut flushed = None;
 		{
 			// Lock writer and reset it
 			let cur_size = self.appending.read().as_ref().map_or(0, |r| r.size);
-			if cur_size > 0 && cur_size > min_size {
+			if cur_size > 0 && (cur_size > min_size || cur_size > self.max_size) {
 				let mut appending = self.appending.write();
 				let to_flush = appending.take();
 				*flushing = to_flush.map(|to_flush| Flushing {
 					file: to_flush.file.into_inner().unwrap(),
 					id: to_flush.id,
 				});
+				if self.metrics.is_some() {
+					flushed = Some((cur_size, std::time::Instant::now()));
+				}
 			}
 		}
 
 		// Flush to disk
-		if self.sync {
-			if let Some(flushing) = flushing.as_ref() {
-				log::debug!(target: "parity-db", "Flush: Flushing log to disk");
-				flushing.file.sync_data()?;
-				log::debug!(target: "parity-db", "Flush: Flushing log completed");
-			}
+		match self.sync {
+			WalSync::Never => {},
+			WalSync::EveryCommit => {
+				if let Some(flushing) = flushing.as_ref() {
+					log::debug!(target: "parity-db", "Flush: Flushing log to disk");
+					flushing.file.sync_data()?;
+					log::debug!(target: "parity-db", "Flush: Flushing log completed");
+				}
+			},
+			WalSync::Interval(interval) => {
+				let mut last_sync = self.last_sync.lock();
+				if last_sync.elapsed() >= interval {
+					log::debug!(target: "parity-db", "Flush: Periodic log sync");
+					if let Some(flushing) = flushing.as_ref() {
+						flushing.file.sync_data()?;
+					}
+					if let Some(appending) = self.appending.read().as_ref() {
+						appending.file.get_ref().sync_data()?;
+					}
+					*last_sync = std::time::Instant::now();
+				}
+			},
 		}
 
+		if let (Some(metrics), Some((bytes, start))) = (&self.metrics, flushed) {
+			metrics.on_flush(bytes, start.elapsed());
+		}
 		Ok((flushing.is_some(), read_next, cleanup))
 	}
 
@@ -692,14 +1445,17 @@ impl Log {
 			log::debug!(target: "parity-db", "Cleaned: {}", id);
 			file.seek(std::io::SeekFrom::Start(0))?;
 			file.set_len(0)?;
+			if let Some(metrics) = &self.metrics {
+				metrics.on_log_cleaned(*id);
+			}
 		}
 		// Move cleaned logs back to the pool
 		let mut pool = self.log_pool.write();
 		pool.extend(cleaned);
 		// Sort to reuse lower IDs an prevent IDs from growing.
 		pool.make_contiguous().sort_by_key(|(id, _)| *id);
-		if pool.len() > MAX_LOG_POOL_SIZE {
-			let removed = pool.drain(MAX_LOG_POOL_SIZE..);
+		if pool.len() > self.pool_size {
+			let removed = pool.drain(self.pool_size..);
 			for (id, file) in removed {
 				std::mem::drop(file);
 				self.drop_log(id)?;
@@ -712,6 +1468,14 @@ impl Log {
 		self.cleanup_queue.read().len()
 	}
 
+	pub fn num_pooled_logs(&self) -> usize {
+		self.log_pool.read().len()
+	}
+
+	fn is_reading(&self) -> bool {
+		*self.reading_state.lock() == ReadingState::Reading
+	}
+
 	pub fn read_next<'a>(&'a self, validate: bool) -> Result<Option<LogReader<'a>>> {
 		let mut reading_state = self.reading_state.lock();
 		if *reading_state != ReadingState::Reading {
@@ -724,14 +1488,15 @@ impl Log {
 			log::trace!(target: "parity-db", "No active reader");
 			return Ok(None);
 		}
+		let id = reading.as_ref().unwrap().id;
 		let reading = RwLockWriteGuard::map(reading, |r| &mut r.as_mut().unwrap().file);
-		let mut reader = LogReader::new(reading, validate);
+		let mut reader = LogReader::new(reading, id, validate);
 		match reader.next() {
 			Ok(LogAction::BeginRecord) => {
 				return Ok(Some(reader));
 			}
-			Ok(_) => return Err(Error::Corruption("Bad log record structure".into())),
-			Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+			Ok(_) => return Err(Error::Corruption(format!("log {}: Bad log record structure", id))),
+			Err(e) if e.io_error().is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof) => {
 				*reading_state = ReadingState::Idle;
 				self.done_reading_cv.notify_one();
 				log::debug!(target: "parity-db", "Read: End of log");
@@ -741,10 +1506,99 @@ impl Log {
 		};
 	}
 
+	/// Start a change that is applied to the tables directly and never written to the log.
+	/// Does not allocate a record id.
+	pub fn begin_unlogged<'a>(&'a self) -> LogWriter<'a> {
+		LogWriter::new(&self.overlays, 0)
+	}
+
+	/// Check if there are any logged changes to the column that are not enacted yet.
+	pub fn has_changes(&self, col: ColId) -> bool {
+		let overlays = self.overlays.read();
+		overlays.index.iter().any(|(id, o)| id.col() == col && !o.map.is_empty())
+			|| overlays.value.iter().any(|(id, o)| id.col() == col && !o.map.is_empty())
+	}
+
+	/// Memory taken by logged changes that are not enacted yet.
+	/// Bytes written to the log file being appended to.
+	pub fn appending_size(&self) -> u64 {
+		self.appending.read().as_ref().map_or(0, |a| a.size)
+	}
+
+	pub fn overlay_bytes(&self) -> u64 {
+		self.overlays.read().bytes
+	}
+
+	/// Number of pending index and value entries in the overlays.
+	pub fn overlay_stats(&self) -> (usize, usize) {
+		let overlays = self.overlays.read();
+		(
+			overlays.index.values().map(|o| o.map.len()).sum(),
+			overlays.value.values().map(|o| o.map.len()).sum(),
+		)
+	}
+
+	/// Memory held by the overlays, including the hash map overhead.
+	pub fn overlay_memory(&self) -> u64 {
+		let overlays = self.overlays.read();
+		let index_entries: usize = overlays.index.values().map(|o| o.map.len()).sum();
+		let index_capacity: usize = overlays.index.values().map(|o| o.map.capacity()).sum();
+		let value_capacity: usize = overlays.value.values().map(|o| o.map.capacity()).sum();
+		let value_bytes = overlays.bytes - index_entries as u64 * INDEX_OVERLAY_ENTRY_BYTES;
+		value_bytes
+			+ (index_capacity * std::mem::size_of::<(u64, (u64, u64, IndexChunk))>()) as u64
+			+ (value_capacity * std::mem::size_of::<(u64, (u64, Vec<u8>))>()) as u64
+	}
+
 	pub fn overlays(&self) -> &RwLock<LogOverlays> {
 		&self.overlays
 	}
 
+	pub fn sync_interval(&self) -> Option<std::time::Duration> {
+		match self.sync {
+			WalSync::Interval(interval) => Some(interval),
+			_ => None,
+		}
+	}
+
+	/// Write out the current log file, and sync it to disk unless sync policy is `Never`,
+	/// or `force` is set.
+	pub fn sync_appending(&self, force: bool) -> Result<()> {
+		if let Some(appending) = self.appending.write().as_mut() {
+			appending.file.flush()?;
+			if force || self.sync != WalSync::Never {
+				appending.file.get_ref().sync_data()?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Sync all records written so far. With `WalSync::EveryCommit` logs are synced once they
+	/// are flushed, which leaves records in the log that is being appended.
+	pub fn sync_written(&self) -> Result<()> {
+		// Waits for a flush in progress to sync the log it took.
+		let _flushing = self.flushing.lock();
+		self.sync_appending(true)
+	}
+
+	/// Sync all logs that may hold records that are not enacted yet. With `WalSync::Never`
+	/// and `WalSync::Interval` these may not have been synced since they were written.
+	pub fn sync_all(&self) -> Result<()> {
+		self.sync_appending(true)?;
+		if let Some(flushing) = self.flushing.lock().as_ref() {
+			flushing.file.sync_data()?;
+		}
+		if let Some(reading) = self.reading.read().as_ref() {
+			reading.file.get_ref().sync_data()?;
+		}
+		for (_, _, file) in self.replay_queue.read().iter() {
+			file.sync_data()?;
+		}
+		// Everything is synced, so the next `WalSync::Interval` sync is a full interval away.
+		*self.last_sync.lock() = std::time::Instant::now();
+		Ok(())
+	}
+
 	pub fn kill_logs(&self) -> Result<()> {
 		let mut log_pool = self.log_pool.write();
 		for (id, file) in log_pool.drain(..) {
@@ -758,3 +1612,230 @@ impl Log {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::{Log, LogAction, ReplayAction, ReplaySession};
+	use crate::{options::Options, table::TableId as ValueTableId, index::TableId as IndexTableId};
+	use std::io::{Read, Seek, Write};
+
+	fn tombstone(index: u64) -> Vec<u8> {
+		let mut buf = vec![0xff, 0xff];
+		buf.extend_from_slice(&index.to_le_bytes());
+		buf
+	}
+
+	#[test]
+	fn interval_sync() {
+		let tmp = tempfile::tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		let interval = std::time::Duration::from_secs(3600);
+		options.sync_wal = crate::options::WalSync::Interval(interval);
+		let log = Log::open(&options).unwrap();
+		let mut writer = log.begin_record();
+		writer.insert_value(ValueTableId::new(0, 0), 1, tombstone(1));
+		log.end_record(writer.drain()).unwrap();
+
+		// Interval has not passed, no sync is performed.
+		let last_sync = *log.last_sync.lock();
+		assert_eq!(log.flush_one(u64::MAX, false).unwrap(), (false, false, false));
+		assert_eq!(*log.last_sync.lock(), last_sync);
+
+		// The log is synced once the interval passes, even if it is too small to be flushed.
+		let last_sync = std::time::Instant::now() - interval;
+		*log.last_sync.lock() = last_sync;
+		assert_eq!(log.flush_one(u64::MAX, false).unwrap(), (false, false, false));
+		assert!(*log.last_sync.lock() > last_sync);
+		assert!(std::fs::metadata(tmp.path().join("log0")).unwrap().len() > 0);
+
+		// Syncing all logs, as `Db::flush` does, starts a new interval.
+		let mut writer = log.begin_record();
+		writer.insert_value(ValueTableId::new(0, 0), 2, tombstone(2));
+		log.end_record(writer.drain()).unwrap();
+		let last_sync = std::time::Instant::now() - interval;
+		*log.last_sync.lock() = last_sync;
+		log.sync_all().unwrap();
+		assert!(*log.last_sync.lock() > last_sync);
+		let synced = *log.last_sync.lock();
+		assert_eq!(log.flush_one(u64::MAX, false).unwrap(), (false, false, false));
+		assert_eq!(*log.last_sync.lock(), synced);
+	}
+
+	#[test]
+	fn max_size() {
+		let tmp = tempfile::tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_log_size = 100;
+		let log = Log::open(&options).unwrap();
+		let mut writer = log.begin_record();
+		writer.insert_value(ValueTableId::new(0, 0), 1, tombstone(1));
+		log.end_record(writer.drain()).unwrap();
+		assert!(log.appending_size() <= 100);
+		assert_eq!(log.flush_one(u64::MAX, false).unwrap(), (false, false, false));
+
+		// A record that takes the log past the limit is written whole, and flushed after.
+		let mut writer = log.begin_record();
+		writer.insert_value(ValueTableId::new(0, 0), 2, vec![0; 200]);
+		log.end_record(writer.drain()).unwrap();
+		let size = log.appending_size();
+		assert!(size > 200);
+		assert_eq!(log.flush_one(u64::MAX, false).unwrap(), (true, false, false));
+		assert_eq!(log.appending_size(), 0);
+		assert_eq!(std::fs::metadata(tmp.path().join("log0")).unwrap().len(), size);
+	}
+
+	#[test]
+	fn crc_pool_started_by_large_record() {
+		let tmp = tempfile::tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_log_size = u64::MAX;
+		let mut log = Log::open(&options).unwrap();
+		log.crc_threads = 2;
+		let mut writer = log.begin_record();
+		writer.insert_value(ValueTableId::new(0, 0), 1, tombstone(1));
+		log.end_record(writer.drain()).unwrap();
+		assert!(log.crc_pool.lock().is_none());
+
+		let mut writer = log.begin_record();
+		for index in 2 .. super::PARALLEL_CRC_THRESHOLD / 10 {
+			writer.insert_value(ValueTableId::new(0, 0), index, tombstone(index));
+		}
+		log.end_record(writer.drain()).unwrap();
+		assert!(log.crc_pool.lock().is_some());
+		drop(log);
+		let report = Log::open(&options).unwrap().validate_all().unwrap();
+		assert_eq!(report.records, 2);
+		assert!(report.failure.is_none());
+	}
+
+	#[test]
+	fn validate_all() {
+		let tmp = tempfile::tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		{
+			let log = Log::open(&options).unwrap();
+			for index in 1 .. 3 {
+				let mut writer = log.begin_record();
+				writer.insert_value(ValueTableId::new(0, 0), index, tombstone(index));
+				log.end_record(writer.drain()).unwrap();
+			}
+		}
+
+		let report = Log::open(&options).unwrap().validate_all().unwrap();
+		assert_eq!((report.logs, report.records), (1, 2));
+		assert!(report.failure.is_none());
+
+		// Break the checksum of the second record.
+		let mut file = std::fs::OpenOptions::new().read(true).write(true).open(tmp.path().join("log0")).unwrap();
+		let mut last = [0u8];
+		file.seek(std::io::SeekFrom::End(-1)).unwrap();
+		file.read_exact(&mut last).unwrap();
+		file.seek(std::io::SeekFrom::End(-1)).unwrap();
+		file.write_all(&[!last[0]]).unwrap();
+		drop(file);
+		let report = Log::open(&options).unwrap().validate_all().unwrap();
+		assert_eq!(report.records, 1);
+		let failure = report.failure.unwrap();
+		assert_eq!(failure.record_id, Some(2));
+		assert_eq!(failure.offset, report.bytes);
+	}
+
+	#[test]
+	fn replay_session() {
+		let tmp = tempfile::tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		{
+			let log = Log::open(&options).unwrap();
+			for index in 1 .. 4 {
+				let mut writer = log.begin_record();
+				writer.insert_value(ValueTableId::new(0, 0), index, tombstone(index));
+				log.end_record(writer.drain()).unwrap();
+			}
+			let mut writer = log.begin_record();
+			let mut chunk = [0u8; 512];
+			chunk[8 .. 16].copy_from_slice(&7u64.to_le_bytes());
+			writer.insert_index(IndexTableId::new(0, 16), 5, 1, &chunk);
+			writer.drop_table(IndexTableId::new(0, 17));
+			log.end_record(writer.drain()).unwrap();
+		}
+
+		let records: Vec<_> = ReplaySession::open(&options, 2).unwrap().map(|r| r.unwrap()).collect();
+		assert_eq!(records, vec![
+			(1, vec![ReplayAction::InsertValue { col: 0, size_tier: 0, index: 1, data: tombstone(1) }]),
+			(2, vec![ReplayAction::InsertValue { col: 0, size_tier: 0, index: 2, data: tombstone(2) }]),
+		]);
+		let (record_id, actions) = ReplaySession::open(&options, u64::MAX).unwrap().last().unwrap().unwrap();
+		assert_eq!(record_id, 4);
+		assert_eq!(actions, vec![
+			ReplayAction::InsertIndex { col: 0, index_bits: 16, chunk: 5, mask: 2, entries: vec![7] },
+			ReplayAction::DropTable { col: 0, index_bits: 17 },
+		]);
+
+		// Break the checksum of the last record.
+		let mut file = std::fs::OpenOptions::new().read(true).write(true).open(tmp.path().join("log0")).unwrap();
+		let mut last = [0u8];
+		file.seek(std::io::SeekFrom::End(-1)).unwrap();
+		file.read_exact(&mut last).unwrap();
+		file.seek(std::io::SeekFrom::End(-1)).unwrap();
+		file.write_all(&[!last[0]]).unwrap();
+		drop(file);
+		let records: Vec<_> = ReplaySession::open(&options, u64::MAX).unwrap().collect();
+		assert_eq!(records.len(), 4);
+		assert_eq!(records[3].as_ref().unwrap_err().to_string(), "Corruption: log 0, record 4: Log record CRC-32 mismatch");
+
+		// Cut the last record short.
+		let file = std::fs::OpenOptions::new().write(true).open(tmp.path().join("log0")).unwrap();
+		file.set_len(file.metadata().unwrap().len() - 8).unwrap();
+		drop(file);
+		let e = ReplaySession::open(&options, u64::MAX).unwrap().last().unwrap().unwrap_err();
+		assert_eq!(e.io_error().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+		assert!(e.to_string().starts_with("IO Error: reading log 0, record 4: "));
+		assert_eq!(Log::open(&options).unwrap().validate_all().unwrap().records, 3);
+	}
+
+	#[test]
+	fn drop_table_clears_overlay() {
+		let tmp = tempfile::tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let log = Log::open(&options).unwrap();
+		let table = IndexTableId::new(0, 16);
+
+		let mut writer = log.begin_record();
+		writer.drop_table(table);
+		log.end_record(writer.drain()).unwrap();
+		// Logged after the drop, but not enacted yet.
+		let mut writer = log.begin_record();
+		writer.insert_index(table, 5, 0, &[1u8; 512]);
+		log.end_record(writer.drain()).unwrap();
+		assert!(log.overlays.read().index.contains_key(&table));
+
+		log.flush_one(0, true).unwrap();
+		log.flush_one(0, true).unwrap();
+		let mut reader = log.read_next(false).unwrap().unwrap();
+		assert_eq!(reader.record_id(), 1);
+		assert!(matches!(reader.next().unwrap(), LogAction::DropTable(t) if t == table));
+		assert!(matches!(reader.next().unwrap(), LogAction::EndRecord));
+		let cleared = reader.drain();
+		assert!(cleared.dropped == vec![table]);
+		log.end_read(cleared, 1);
+		assert!(!log.overlays.read().index.contains_key(&table));
+	}
+	#[test]
+	fn malformed_log_header() {
+		let tmp = tempfile::tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		let path = tmp.path().join("log0");
+		std::fs::write(&path, [1, 0, 0]).unwrap();
+		assert!(matches!(Log::open(&options), Err(crate::Error::Corruption(m)) if m.contains("Truncated log header")));
+		assert!(matches!(Log::open_read_only(&options), Err(crate::Error::Corruption(_))));
+		std::fs::write(&path, [7; 9]).unwrap();
+		assert!(matches!(Log::open(&options), Err(crate::Error::Corruption(m)) if m.contains("record header")));
+
+		// Torn headers are removed by repair, bad ones are not.
+		options.repair_on_replay = true;
+		assert!(Log::open(&options).is_err());
+		std::fs::write(&path, [1, 0, 0]).unwrap();
+		Log::open(&options).unwrap();
+		assert!(!path.exists());
+	}
+}
@@ -0,0 +1,37 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Callbacks for the background log processing of the database.
+
+use std::time::Duration;
+
+/// Sink for timings and progress of the write-ahead log. Callbacks are invoked from
+/// the background threads and should not block. All of them do nothing by default.
+pub trait DbMetrics: Send + Sync {
+	/// A log file of `bytes` was flushed and handed over to the reader. `duration` includes
+	/// syncing the file to disk, as configured by `Options::sync_wal`.
+	fn on_flush(&self, _bytes: u64, _duration: Duration) {}
+	/// All changes of a log record were written to the tables.
+	fn on_record_enacted(&self, _record_id: u64) {}
+	/// An enacted log file was truncated and returned to the pool.
+	fn on_log_cleaned(&self, _id: u32) {}
+}
+
+impl std::fmt::Debug for dyn DbMetrics {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str("DbMetrics")
+	}
+}
@@ -17,10 +17,13 @@
 /// Database migration.
 
 use std::path::Path;
-use crate::{options::Options, db::Db, Error, Result, column::{ColId, IterState}};
+use std::collections::HashMap;
+use crate::{options::Options, db::Db, Error, Result, column::{ColId, IterState, Salt}, table::Key};
 
 const COMMIT_SIZE: usize = 10240;
 const OVERWRITE_TMP_PATH: &str = "to_revert_overwrite";
+const RESALT_TMP_PATH: &str = "resalt";
+const RESALT_OLD_PATH: &str = "to_revert_resalt";
 
 pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Vec<u8>) -> Result<()> {
 	let mut metadata_path: std::path::PathBuf = from.into();
@@ -75,7 +78,7 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 				commit.push((c, key.clone(), Some(value)));
 				if commit.len() == COMMIT_SIZE {
 					ncommits += 1;
-					if let Err(e) = dest.commit_raw(std::mem::take(&mut commit)) {
+					if let Err(e) = dest.commit_hashed(std::mem::take(&mut commit)) {
 						log::warn!("Migration error: {:?}", e);
 						return false;
 					}
@@ -90,7 +93,7 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 			true
 		})?;
 		if overwrite {
-			dest.commit_raw(commit)?;
+			dest.commit_hashed(commit)?;
 			commit = Vec::with_capacity(COMMIT_SIZE);
 			std::mem::drop(dest);
 			dest = Db::open_or_create(&to)?; // This is needed to flush logs.
@@ -124,7 +127,102 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 			log::info!("Collection migrated {}, migrated", c);
 		}
 	}
-	dest.commit_raw(commit)?;
+	dest.commit_hashed(commit)?;
+	Ok(())
+}
+
+/// Rehash the keys of the database at `path` with a new salt. Keys of uniform columns are
+/// not hashed. Keys of preimage columns are the hash of the value. The keys of other columns
+/// are not stored in the database: `keys` is called for each of them and must return all
+/// of the keys in the column. Fails before anything is changed if any of the entries are
+/// missing. Columns with trash are not supported.
+///
+/// The database is rebuilt next to the original one, and the table files are swapped once
+/// it is complete. If that is interrupted, the original files are in the `to_revert_resalt`
+/// directory.
+pub fn resalt<K, I>(path: &Path, salt: Salt, mut keys: K) -> Result<()>
+where
+	K: FnMut(ColId) -> I,
+	I: IntoIterator<Item = Vec<u8>>,
+{
+	let source_options = Options::from_existing(path)?;
+	if let Some(c) = source_options.columns.iter().position(|c| c.trash.is_some()) {
+		return Err(Error::Migration(format!("Column {} has trash enabled and can't be resalted", c)));
+	}
+	let tmp_dir = path.join(RESALT_TMP_PATH);
+	if tmp_dir.exists() {
+		std::fs::remove_dir_all(&tmp_dir)?;
+	}
+	let mut dest_options = source_options.clone();
+	dest_options.path = tmp_dir.clone();
+	dest_options.salt = Some(salt);
+	dest_options.sync_wal = crate::WalSync::Never;
+
+	{
+		let source = Db::open(&source_options)?;
+		let dest = Db::open_or_create(&dest_options)?;
+		for (c, options) in source_options.columns.iter().enumerate() {
+			let c = c as ColId;
+			// Hashed keys in the source column, mapped to hashed keys in the destination column.
+			let rekey: Option<HashMap<Key, Key>> = if options.uniform || options.preimage {
+				None
+			} else {
+				Some(keys(c).into_iter()
+					.map(|k| Ok((source.hash_key(c, &k)?, dest.hash_key(c, &k)?)))
+					.collect::<Result<_>>()?)
+			};
+			let mut commit = Vec::with_capacity(COMMIT_SIZE);
+			let mut result = Ok(());
+			source.iter_column_while(c, |IterState { key, rc, mut value, .. }| {
+				let key = match &rekey {
+					None if options.uniform => key,
+					None => match dest.hash_key(c, blake2_rfc::blake2b::blake2b(32, &[], &value).as_bytes()) {
+						Ok(key) => key,
+						Err(e) => {
+							result = Err(e);
+							return false;
+						},
+					},
+					Some(rekey) => match rekey.get(&key) {
+						Some(key) => *key,
+						None => {
+							result = Err(Error::Migration(format!("Column {}: no key given for entry {}", c, crate::display::hex(&key))));
+							return false;
+						},
+					},
+				};
+				for _ in 0 .. rc {
+					commit.push((c, key, Some(std::mem::take(&mut value))));
+					if commit.len() == COMMIT_SIZE {
+						if let Err(e) = dest.commit_hashed(std::mem::take(&mut commit)) {
+							result = Err(e);
+							return false;
+						}
+					}
+				}
+				true
+			})?;
+			if let Err(e) = result {
+				std::mem::drop(dest);
+				std::fs::remove_dir_all(&tmp_dir)?;
+				return Err(e);
+			}
+			dest.commit_hashed(commit)?;
+			log::info!("Column {} resalted", c);
+		}
+	}
+
+	let old_dir = path.join(RESALT_OLD_PATH);
+	std::fs::create_dir_all(&old_dir)?;
+	for c in 0 .. source_options.columns.len() as ColId {
+		move_column(c, path, &old_dir)?;
+		move_column(c, &tmp_dir, path)?;
+	}
+	source_options.write_metadata(&path.join("metadata"), &salt)
+		.map_err(|e| Error::Migration(format!("Error {:?}\nFail updating metadata after resalting, \
+			please restore the table files from {} manually.", e, RESALT_OLD_PATH)))?;
+	std::fs::remove_dir_all(&old_dir)?;
+	std::fs::remove_dir_all(&tmp_dir)?;
 	Ok(())
 }
 
@@ -160,7 +258,7 @@ fn deplace_column(c: ColId, from: &Path, to: &Path, copy: bool) -> Result<()> {
 
 #[cfg(test)]
 mod test {
-	use crate::{Db, Options, migration::migrate};
+	use crate::{Db, Options, migration::{migrate, resalt}};
 
 	struct TempDir(std::path::PathBuf);
 
@@ -210,4 +308,36 @@ mod test {
 		let dest = Db::with_columns(&dest_dir, 1).unwrap();
 		assert_eq!(dest.get(0, b"1").unwrap(), Some("value".as_bytes().to_vec()));
 	}
+
+	#[test]
+	fn resalt_simple() {
+		let dir = TempDir::new("resalt_simple");
+		let db_dir = dir.path("db");
+		let mut options = Options::with_columns(&db_dir, 3);
+		options.columns[1].uniform = true;
+		options.columns[2].preimage = true;
+		let preimage_key = blake2_rfc::blake2b::blake2b(32, &[], b"preimage").as_bytes().to_vec();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit([
+				(0, b"1".to_vec(), Some(b"one".to_vec())),
+				(0, b"2".to_vec(), Some(b"two".to_vec())),
+				(1, [5u8; 32].to_vec(), Some(b"uniform".to_vec())),
+				(2, preimage_key.clone(), Some(b"preimage".to_vec())),
+			]).unwrap();
+		}
+
+		// Fails without all of the keys and leaves the database intact.
+		assert!(resalt(&db_dir, [9u8; 32], |_| vec![b"1".to_vec()]).is_err());
+		assert!(Db::open(&Options::from_existing(&db_dir).unwrap()).is_ok());
+
+		resalt(&db_dir, [9u8; 32], |_| vec![b"1".to_vec(), b"2".to_vec()]).unwrap();
+		let options = Options::from_existing(&db_dir).unwrap();
+		assert_eq!(options.salt, Some([9u8; 32]));
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"1").unwrap(), Some(b"one".to_vec()));
+		assert_eq!(db.get(0, b"2").unwrap(), Some(b"two".to_vec()));
+		assert_eq!(db.get(1, &[5u8; 32]).unwrap(), Some(b"uniform".to_vec()));
+		assert_eq!(db.get(2, &preimage_key).unwrap(), Some(b"preimage".to_vec()));
+	}
 }
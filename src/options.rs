@@ -17,33 +17,238 @@
 use std::io::Write;
 use std::collections::HashMap;
 use std::path::{PathBuf, Path};
+use std::sync::Arc;
 use crate::error::{Error, Result};
-use crate::column::Salt;
+use crate::column::{ColId, KeyHash, KeyMode, Salt};
 use crate::compress::CompressionType;
+use crate::metrics::DbMetrics;
 use rand::Rng;
 
 pub const CURRENT_VERSION: u32 = 4;
 // TODO on last supported 4, remove `ValueTable` `no_compression` field.
 const LAST_SUPPORTED_VERSION: u32 = 3;
+/// Name of the file in the database directory that `Options::persist_options` writes.
+pub const OPTIONS_FILE: &str = "options";
+
+/// Option of an existing database that is different from the one it is opened with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigMismatch {
+	/// `None` for options of the whole database.
+	pub column: Option<ColId>,
+	/// Name of the option, as in `ColumnOptions` or `Options`, or `columns` for the number of columns.
+	pub field: &'static str,
+	pub stored: String,
+	pub configured: String,
+}
+
+impl std::fmt::Display for ConfigMismatch {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if let Some(col) = self.column {
+			write!(f, "column {} ", col)?;
+		}
+		write!(f, "{}: stored {}, configured {}", self.field, self.stored, self.configured)
+	}
+}
+
+/// When log files are synced to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalSync {
+	/// Never fsync the log. Committed data may be lost on power failure or OS crash,
+	/// up to all of the logs that are not yet enacted. A process crash loses nothing.
+	Never,
+	/// fsync the log before any of its changes are enacted into the tables.
+	/// Provides extra consistency guarantees.
+	EveryCommit,
+	/// fsync the log at most once per interval. A power failure or OS crash may lose
+	/// commits made within the last interval, in exchange for higher write throughput.
+	/// A commit that has to be durable right away may be followed by `Db::flush`, which
+	/// syncs the log regardless of the interval.
+	Interval(std::time::Duration),
+}
+
+impl WalSync {
+	fn as_string(&self) -> String {
+		match self {
+			WalSync::Never => "never".into(),
+			WalSync::EveryCommit => "every_commit".into(),
+			WalSync::Interval(interval) => format!("interval_ms: {}", interval.as_millis()),
+		}
+	}
+
+	fn from_string(s: &str) -> Option<Self> {
+		match s {
+			"never" => Some(WalSync::Never),
+			"every_commit" => Some(WalSync::EveryCommit),
+			s => Some(WalSync::Interval(std::time::Duration::from_millis(s.strip_prefix("interval_ms: ")?.parse().ok()?))),
+		}
+	}
+}
 
 /// Database configuration.
 #[derive(Clone, Debug)]
 pub struct Options {
 	/// Database path.
 	pub path: std::path::PathBuf,
+	/// Directory of the write-ahead log files, for keeping the log on a faster disk than the
+	/// tables. Logs are created and replayed from there; the metadata and the lock file stay
+	/// in `path`. Created along with `path` by `Db::open_or_create`. `None` by default, for `path`.
+	pub wal_path: Option<PathBuf>,
 	/// Column settings
 	pub columns: Vec<ColumnOptions>,
-	/// fsync WAL to disk before committing any changes. Provides extra consistency
-	/// guarantees. On by default.
-	pub sync_wal: bool,
+	/// WAL sync policy. `WalSync::EveryCommit` by default.
+	pub sync_wal: WalSync,
 	/// fsync/msync data to disk before removing logs. Provides crash resistance guarantee.
 	/// On by default.
 	pub sync_data: bool,
-	/// Collect database statistics. May have effect on performance.
+	/// Collect database statistics and operation counters. Checked once on open: when off,
+	/// no statistics are kept in memory, and the statistics API returns `Error::StatsDisabled`.
+	/// On by default.
 	pub stats: bool,
 	/// Override salt value. If `None` is specified salt is loaded from metadata
 	/// or randomly generated when creating a new database.
 	pub salt: Option<Salt>,
+	/// Check all pending log records before replaying any of them on open, and log how
+	/// many are valid. Replay validates each record just before enacting it anyway, and
+	/// discards the record that fails along with everything after it. Off by default.
+	pub validate_on_replay: bool,
+	/// Truncate a torn record at the end of the logs on open. Opening fails instead if the
+	/// bad record is intact or a valid record follows it, as that is not a torn write.
+	/// Without this, replay discards the bad record together with everything after it, and
+	/// a log file that is shorter than a record header fails the open. Off by default.
+	pub repair_on_replay: bool,
+	/// Write commits into the tables directly instead of logging them first. Halves the
+	/// amount of data written, but a crash or power failure while a commit is being written
+	/// may corrupt the database. Only suitable for databases that can be thrown away.
+	/// Logs left by an earlier run are still replayed on open. Off by default.
+	pub disable_wal: bool,
+	/// Allow `Db::discard_wal` to remove the logs, losing all changes that are not enacted
+	/// into the tables yet. Off by default.
+	pub allow_wal_discard: bool,
+	/// Limit for the total size of keys and values of commits that are queued and not
+	/// written to the log yet. `Db::commit` blocks while the queue is above it, and
+	/// `Db::try_commit` fails. A commit is let in as long as the queue is below the limit,
+	/// so the queue may exceed it by the size of one commit. 16 MiB by default.
+	pub commit_queue_bytes: usize,
+	/// Limit for the size of commits that are not enacted into the tables yet. Writers
+	/// block until it drops below the limit, and larger commits are rejected. `None` by default.
+	pub max_uncommitted_bytes: Option<u64>,
+	/// Longest time a background priority commit waits while normal commits keep the queue busy.
+	/// 1 second by default.
+	pub max_background_commit_delay: std::time::Duration,
+	/// Stop writing commits to the log once changes that are logged but not enacted into the
+	/// tables yet take more memory than this, until enactment brings them down to
+	/// `overlay_low_water_bytes`. Commits queue up meanwhile. `None` by default.
+	pub overlay_high_water_bytes: Option<u64>,
+	/// Resume writing commits to the log below this size. Half of `overlay_high_water_bytes`
+	/// by default.
+	pub overlay_low_water_bytes: Option<u64>,
+	/// Log a warning when logged changes pile up faster than they are enacted. `Some` with
+	/// `BacklogWarning::default()` by default.
+	pub backlog_warning: Option<BacklogWarning>,
+	/// Flush the log file being appended to as soon as it grows past this size, so that
+	/// bursts of commits don't pile up in a single large log before they are enacted.
+	/// Records are not split: the record that takes the log past the limit is written
+	/// whole, and the log is flushed after it. 64 MiB by default.
+	pub max_log_size: u64,
+	/// Number of cleaned log files kept for reuse instead of being deleted, saving the file
+	/// creation on write heavy databases. 0 deletes every log once it is cleaned. Pooled
+	/// files are empty and removed on open, so the setting may be changed between opens.
+	/// 16 by default.
+	pub log_pool_size: usize,
+	/// Number of background threads, 1 to 4. With 4, writing commits to the log, flushing
+	/// the log, enacting it into the tables and cleaning up enacted logs each get a thread.
+	/// Fewer threads take turns running these stages. 4 by default.
+	pub background_threads: usize,
+	/// Maximum number of threads that enact a log record. Records are enacted one at a time,
+	/// in order, but changes to different columns in a record may be enacted concurrently,
+	/// each column on a single thread. The threads are started on open. 1 by default, enacting
+	/// on the commit worker only.
+	pub enact_threads: usize,
+	/// Read value tables through memory maps of the table files. Writes still go through
+	/// the files, and the maps are renewed when tables are resized. Only used on 64-bit
+	/// Unix, and not by secondary instances; reads use the files otherwise. Off by default.
+	pub mmap_value_tables: bool,
+	/// Check that the index keys passed to `Db::raw_commit` are derived from the original
+	/// keys the way the column derives them, and reject the commit otherwise. Always done in
+	/// debug builds. Off by default, trusting the caller.
+	pub verify_raw_keys: bool,
+	/// Expected contents of columns, by column index. Columns that have no values yet get
+	/// an index sized for the expected number of values, and value table files grown to
+	/// fit, on open. This avoids repeated reindexing while the column is first filled.
+	pub size_hints: HashMap<u8, SizeHint>,
+	/// Receives log flush, enactment and cleanup events. `None` by default.
+	pub metrics: Option<Arc<dyn DbMetrics>>,
+	/// Write the options to the `OPTIONS_FILE` in the database directory when the database
+	/// is created, with the salt it is created with. See `Options::write_to`. Off by default.
+	pub persist_options: bool,
+}
+
+/// Limits of the log backlog above which a warning is logged. A growing backlog means that
+/// commits are written faster than the disk can take them, and takes memory until they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BacklogWarning {
+	/// Size of changes that are logged but not enacted into the tables yet. See
+	/// `Db::memory_usage`. 512 MiB by default.
+	pub overlay_bytes: u64,
+	/// Number of enacted logs that wait for the tables to be synced. See
+	/// `Db::dirty_log_count`. 32 by default.
+	pub dirty_logs: usize,
+	/// Warn once either limit has been exceeded for this long. 30 seconds by default.
+	pub after: std::time::Duration,
+	/// Shortest time between two warnings. 5 minutes by default.
+	pub interval: std::time::Duration,
+}
+
+impl Default for BacklogWarning {
+	fn default() -> Self {
+		BacklogWarning {
+			overlay_bytes: 512 * 1024 * 1024,
+			dirty_logs: 32,
+			after: std::time::Duration::from_secs(30),
+			interval: std::time::Duration::from_secs(5 * 60),
+		}
+	}
+}
+
+impl BacklogWarning {
+	// `none`, or `<overlay_bytes>/<dirty_logs>/<after_ms>/<interval_ms>`.
+	fn as_string(warning: &Option<BacklogWarning>) -> String {
+		match warning {
+			None => "none".into(),
+			Some(w) => format!("{}/{}/{}/{}", w.overlay_bytes, w.dirty_logs, w.after.as_millis(), w.interval.as_millis()),
+		}
+	}
+
+	fn from_string(s: &str) -> Option<Option<BacklogWarning>> {
+		if s == "none" {
+			return Some(None);
+		}
+		let mut parts = s.split('/');
+		let warning = BacklogWarning {
+			overlay_bytes: parts.next()?.parse().ok()?,
+			dirty_logs: parts.next()?.parse().ok()?,
+			after: std::time::Duration::from_millis(parts.next()?.parse().ok()?),
+			interval: std::time::Duration::from_millis(parts.next()?.parse().ok()?),
+		};
+		parts.next().is_none().then_some(Some(warning))
+	}
+}
+
+/// Expected contents of a column.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeHint {
+	/// Expected number of values.
+	pub entries: u64,
+	/// Value sizes as stored, after compression, with the relative number of values of
+	/// each size. If empty, only the index is sized.
+	pub histogram: Vec<(u32, u64)>,
+}
+
+impl SizeHint {
+	pub fn is_valid(&self) -> bool {
+		(self.histogram.is_empty() || self.histogram.iter().any(|(_, count)| *count > 0))
+			&& self.histogram.iter().try_fold(0u64, |total, (_, count)| total.checked_add(*count)).is_some()
+	}
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -55,15 +260,90 @@ pub struct ColumnOptions {
 	/// Indicates that the keys are at least 32 bytes and
 	/// the first 32 bytes have uniform distribution.
 	/// Allows for skipping additional key hashing.
+	/// Only use this for keys that are hashes already, such as trie node hashes. Keys of
+	/// uniform columns are placed in the index by their leading bits, so sequential or
+	/// otherwise structured keys pile up in a few index chunks, which makes lookups slow
+	/// and triggers reindexing over and over. Use `key_hash` for such keys instead.
 	pub uniform: bool,
+	/// Hash function for keys of a column that is not `uniform`. `KeyHash::Blake2` suits
+	/// any keys, including keys chosen by untrusted parties. `KeyHash::XxHash` is faster
+	/// and suits keys from a trusted source, such as sequential numbers. Queries, commits
+	/// and the commit overlay all use the index key it produces. Stored in the metadata
+	/// and fixed at creation. `KeyHash::Blake2` by default.
+	pub key_hash: KeyHash,
+	/// Whether the column takes typed keys through `Db::get` and `Db::commit`, or raw keys
+	/// of any length through `Db::get_raw` and `Db::commit_raw`. Raw keys are hashed with
+	/// `key_hash` and the salt, so `uniform` must not be set. Using the API of the other
+	/// mode fails with `Error::InvalidInput`. The pre-hashed `Db::raw_get` and
+	/// `Db::raw_commit` work with both. Stored in the metadata and fixed at creation.
+	/// `KeyMode::Typed` by default.
+	pub key_mode: KeyMode,
 	/// Value size tiers.
 	pub sizes: Vec<u16>,
-	/// Use reference counting for values.
+	/// Use reference counting for values. Committing a value for an existing key
+	/// increments its reference count, the existing value is kept. Committing `None`
+	/// decrements it, and the value is removed once the count reaches zero. Removing
+	/// a key that is not in the column does nothing. Counts saturate at `u32::MAX`,
+	/// after which the value is never removed.
 	pub ref_counted: bool,
 	/// Compression to use for this column.
 	pub compression: CompressionType,
-	/// Minimal value size threshold to attempt compressing a value.
+	/// Minimal value size threshold to attempt compressing a value. Values of this size
+	/// or smaller, and values that don't get any smaller when compressed, are stored as
+	/// is and flagged as uncompressed in the value entry header. Reads only decompress
+	/// flagged values, so the threshold may be changed for an existing column.
 	pub compression_treshold: u32,
+	/// Keep removed values for a while so that they can be restored with `Db::undelete`.
+	/// Removed values of all columns are kept in a reserved column, stored after the
+	/// configured ones. Not supported for reference counted columns.
+	pub trash: Option<TrashOptions>,
+	/// Values expire this long after they were inserted. Expired values are not returned
+	/// by queries and are removed in the background. A whole number of seconds, at least 1.
+	/// Expiry time is kept in the `ttl_clock` file, so it does not go back when the system
+	/// clock does between runs. Not supported for reference counted, preimage or trash
+	/// enabled columns.
+	pub ttl: Option<std::time::Duration>,
+	/// Size of the in-memory cache of recently read values, in bytes. 0 disables the cache.
+	/// Not stored in the metadata and may be changed between opens. 0 by default.
+	pub cache_size_bytes: u64,
+	/// Bytes to extend a value table file by when it is full, clamped to 64 KiB to 1 GiB.
+	/// Files are extended without writing, so on file systems with sparse files the space is
+	/// only allocated as entries are written, and a large step mostly saves resizing. On
+	/// others the whole step is allocated at once. `Options::size_hints` grow files of new
+	/// columns up front instead. Not stored in the metadata and may be changed between
+	/// opens. 256 KiB by default.
+	pub table_growth_bytes: u64,
+	/// Name to look the column up with `Db::column`. Names must be unique and may only
+	/// contain ASCII letters, digits, `_`, `-` and `.`. Stored in the metadata: opening
+	/// fails if the stored name is different. Stored names are used if this is `None`.
+	pub name: Option<String>,
+	/// Number of entries in an index chunk. A power of two from 8 to 64. Keys are spread
+	/// over chunks, and a chunk that fills up triggers a reindex, so small chunks save
+	/// index space for sparse columns and large chunks suit dense ones. Stored in the
+	/// metadata and fixed at creation. 64 by default.
+	pub index_chunk_entries: usize,
+	/// Number of bytes of the key, from 8 to 32. Hashed keys are cut to this length, and
+	/// `uniform` columns use this many leading bytes of the key. Each value stores the part
+	/// of the key past the first 6 bytes, so short keys save space in the value tables of
+	/// columns with many small values. Keys shorter than 20 bytes should only be used with
+	/// `uniform` columns, since hashes that short may collide. Stored in the metadata and
+	/// fixed at creation. 32 by default.
+	pub key_len: usize,
+	/// Directory of the index and value table files of the column, for placing columns on
+	/// different disks. The log, the metadata and the lock file stay in `Options::path`, and
+	/// the lock covers all directories. The directory must exist and be writable when the
+	/// database is opened. Not stored in the metadata; moving the files of an existing column
+	/// is up to the user. `None` by default, for `Options::path`.
+	pub path: Option<PathBuf>,
+}
+
+/// Trash window for removed values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrashOptions {
+	/// Total size of values kept in the trash. Oldest values are purged first.
+	pub max_bytes: u64,
+	/// Removed values are purged after this time. A whole number of seconds, at least 1.
+	pub max_age: std::time::Duration,
 }
 
 
@@ -80,11 +360,37 @@ pub struct Metadata {
 
 impl ColumnOptions {
 	fn as_string(&self) -> String {
-		format!("preimage: {}, uniform: {}, refc: {}, compression: {}, sizes: [{}]",
+		let trash = self.trash.map_or(String::new(), |t| format!("trash: {}/{}, ", t.max_bytes, t.max_age.as_secs()));
+		let ttl = self.ttl.map_or(String::new(), |t| format!("ttl: {}, ", t.as_secs()));
+		let name = self.name.as_ref().map_or(String::new(), |n| format!("name: {}, ", n));
+		let index_chunk = match self.index_chunk_entries {
+			crate::index::MAX_CHUNK_ENTRIES => String::new(),
+			entries => format!("index_chunk: {}, ", entries),
+		};
+		let key_len = match self.key_len {
+			crate::table::KEY_LEN => String::new(),
+			len => format!("key_len: {}, ", len),
+		};
+		let key_hash = match self.key_hash {
+			KeyHash::Blake2 => String::new(),
+			key_hash => format!("key_hash: {}, ", key_hash.as_str()),
+		};
+		let key_mode = match self.key_mode {
+			KeyMode::Typed => String::new(),
+			key_mode => format!("key_mode: {}, ", key_mode.as_str()),
+		};
+		format!("preimage: {}, uniform: {}, refc: {}, compression: {}, {}{}{}{}{}{}{}sizes: [{}]",
 			self.preimage,
 			self.uniform,
 			self.ref_counted,
 			self.compression as u8,
+			trash,
+			ttl,
+			name,
+			index_chunk,
+			key_len,
+			key_hash,
+			key_mode,
 			self.sizes.iter().fold(String::new(), |mut r, s| {
 				if !r.is_empty() {
 					r.push_str(", ");
@@ -95,16 +401,103 @@ impl ColumnOptions {
 		)
 	}
 
+	// Differences from the configured `options`, for options stored in the metadata.
+	// Names only differ if both are set.
+	fn mismatch(&self, col: ColId, options: &ColumnOptions) -> Vec<ConfigMismatch> {
+		let mut mismatch = Vec::new();
+		let mut check = |field: &'static str, stored: String, configured: String| {
+			if stored != configured {
+				mismatch.push(ConfigMismatch { column: Some(col), field, stored, configured });
+			}
+		};
+		check("preimage", self.preimage.to_string(), options.preimage.to_string());
+		check("uniform", self.uniform.to_string(), options.uniform.to_string());
+		check("ref_counted", self.ref_counted.to_string(), options.ref_counted.to_string());
+		check("compression", format!("{:?}", self.compression), format!("{:?}", options.compression));
+		check("sizes", format!("{:?}", self.sizes), format!("{:?}", options.sizes));
+		check("trash", format!("{:?}", self.trash), format!("{:?}", options.trash));
+		check("ttl", format!("{:?}", self.ttl), format!("{:?}", options.ttl));
+		check("index_chunk_entries", self.index_chunk_entries.to_string(), options.index_chunk_entries.to_string());
+		check("key_len", self.key_len.to_string(), options.key_len.to_string());
+		check("key_hash", self.key_hash.as_str().to_string(), options.key_hash.as_str().to_string());
+		check("key_mode", self.key_mode.as_str().to_string(), options.key_mode.as_str().to_string());
+		if let (Some(stored), Some(configured)) = (&self.name, &options.name) {
+			check("name", stored.clone(), configured.clone());
+		}
+		mismatch
+	}
+
 	pub fn is_valid(&self) -> bool {
-		if self.sizes.len() > crate::table::SIZE_TIERS - 1 {
-			return false;
+		self.check().is_ok()
+	}
+
+	/// Check the options. Fails with `Error::InvalidConfiguration` naming the first invalid
+	/// field and its allowed values.
+	pub fn validate(&self) -> Result<()> {
+		self.check().map_err(Error::InvalidConfiguration)
+	}
+
+	fn check(&self) -> std::result::Result<(), String> {
+		let max_tiers = crate::table::SIZE_TIERS - 1;
+		if self.sizes.len() > max_tiers {
+			return Err(format!("sizes: {} tiers, at most {} are allowed", self.sizes.len(), max_tiers));
 		}
+		let (min_size, max_size) = (crate::table::MIN_ENTRY_SIZE as u16, crate::table::COMPRESSED_MASK - 1);
 		for size in &self.sizes {
-			if *size >= crate::table::COMPRESSED_MASK {
-				return false;
+			if *size < min_size || *size > max_size {
+				return Err(format!("sizes: {} is out of range {} to {}", size, min_size, max_size));
+			}
+		}
+		if self.sizes.windows(2).any(|w| w[0] >= w[1]) {
+			return Err("sizes: must be in ascending order, without duplicates".into());
+		}
+		if self.ref_counted && self.trash.is_some() {
+			return Err("trash: not supported for reference counted columns".into());
+		}
+		if self.ttl.is_some() && (self.ref_counted || self.preimage || self.trash.is_some()) {
+			return Err("ttl: not supported for reference counted, preimage or trash enabled columns".into());
+		}
+		// Stored as whole seconds.
+		if let Some(ttl) = self.ttl.filter(|t| t.as_secs() == 0 || t.subsec_nanos() != 0) {
+			return Err(format!("ttl: {:?} is not a whole number of seconds, at least 1", ttl));
+		}
+		if let Some(trash) = self.trash.filter(|t| t.max_age.as_secs() == 0 || t.max_age.subsec_nanos() != 0) {
+			return Err(format!("trash: max_age {:?} is not a whole number of seconds, at least 1", trash.max_age));
+		}
+		if !self.index_chunk_entries.is_power_of_two()
+			|| self.index_chunk_entries < crate::index::MIN_CHUNK_ENTRIES
+			|| self.index_chunk_entries > crate::index::MAX_CHUNK_ENTRIES
+		{
+			return Err(format!(
+				"index_chunk_entries: {} is not a power of two from {} to {}",
+				self.index_chunk_entries,
+				crate::index::MIN_CHUNK_ENTRIES,
+				crate::index::MAX_CHUNK_ENTRIES,
+			));
+		}
+		if self.key_len < crate::table::MIN_KEY_LEN || self.key_len > crate::table::KEY_LEN {
+			return Err(format!(
+				"key_len: {} is out of range {} to {}",
+				self.key_len,
+				crate::table::MIN_KEY_LEN,
+				crate::table::KEY_LEN,
+			));
+		}
+		if self.uniform && self.key_hash != KeyHash::Blake2 {
+			return Err("key_hash: not used by uniform columns".into());
+		}
+		if self.uniform && self.key_mode == KeyMode::Raw {
+			return Err("key_mode: raw keys are hashed, uniform columns can't take them".into());
+		}
+		if let Some(name) = &self.name {
+			if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+				return Err(format!("name: {:?} must be non-empty ASCII letters, digits, `_`, `-` and `.`", name));
 			}
 		}
-		true
+		if self.path.as_ref().is_some_and(|p| p.as_os_str().is_empty()) {
+			return Err("path: must not be empty".into());
+		}
+		Ok(())
 	}
 
 	fn from_string(s: &str) -> Option<Self> {
@@ -123,6 +516,35 @@ impl ColumnOptions {
 		let uniform = vals.get("uniform")?.parse().ok()?;
 		let ref_counted = vals.get("refc")?.parse().ok()?;
 		let compression: u8 = vals.get("compression").and_then(|c| c.parse().ok()).unwrap_or(0);
+		let trash = match vals.get("trash") {
+			Some(t) => {
+				let mut t = t.split('/');
+				let max_bytes = t.next()?.parse().ok()?;
+				let max_age = std::time::Duration::from_secs(t.next()?.parse().ok()?);
+				Some(TrashOptions { max_bytes, max_age })
+			},
+			None => None,
+		};
+		let ttl = match vals.get("ttl") {
+			Some(t) => Some(std::time::Duration::from_secs(t.parse().ok()?)),
+			None => None,
+		};
+		let index_chunk_entries = match vals.get("index_chunk") {
+			Some(entries) => entries.parse().ok()?,
+			None => crate::index::MAX_CHUNK_ENTRIES,
+		};
+		let key_len = match vals.get("key_len") {
+			Some(len) => len.parse().ok()?,
+			None => crate::table::KEY_LEN,
+		};
+		let key_hash = match vals.get("key_hash") {
+			Some(key_hash) => KeyHash::from_str(key_hash)?,
+			None => KeyHash::Blake2,
+		};
+		let key_mode = match vals.get("key_mode") {
+			Some(key_mode) => KeyMode::from_str(key_mode)?,
+			None => KeyMode::Typed,
+		};
 
 		Some(ColumnOptions {
 			preimage,
@@ -131,6 +553,16 @@ impl ColumnOptions {
 			compression: compression.into(),
 			sizes,
 			compression_treshold: ColumnOptions::default().compression_treshold,
+			trash,
+			ttl,
+			cache_size_bytes: 0,
+			table_growth_bytes: ColumnOptions::default().table_growth_bytes,
+			name: vals.get("name").map(|n| n.to_string()),
+			index_chunk_entries,
+			key_len,
+			key_hash,
+			key_mode,
+			path: None,
 		})
 	}
 }
@@ -153,9 +585,19 @@ impl Default for ColumnOptions {
 		ColumnOptions {
 			preimage: false,
 			uniform: false,
+			key_hash: KeyHash::Blake2,
+			key_mode: KeyMode::Typed,
 			ref_counted: false,
 			compression: CompressionType::NoCompression,
 			compression_treshold: 4096,
+			trash: None,
+			ttl: None,
+			cache_size_bytes: 0,
+			table_growth_bytes: 256 * 1024,
+			name: None,
+			index_chunk_entries: crate::index::MAX_CHUNK_ENTRIES,
+			key_len: crate::table::KEY_LEN,
+			path: None,
 			sizes,
 		}
 	}
@@ -165,53 +607,313 @@ impl Options {
 	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Options {
 		Options {
 			path: path.into(),
-			sync_wal: true,
+			wal_path: None,
+			sync_wal: WalSync::EveryCommit,
 			sync_data: true,
 			stats: true,
 			salt: None,
+			validate_on_replay: false,
+			repair_on_replay: false,
+			disable_wal: false,
+			allow_wal_discard: false,
 			columns: (0..num_columns).map(|_| Default::default()).collect(),
+			commit_queue_bytes: 16 * 1024 * 1024,
+			backlog_warning: Some(Default::default()),
+			max_uncommitted_bytes: None,
+			max_background_commit_delay: std::time::Duration::from_secs(1),
+			overlay_high_water_bytes: None,
+			overlay_low_water_bytes: None,
+			max_log_size: 64 * 1024 * 1024,
+			log_pool_size: 16,
+			background_threads: 4,
+			enact_threads: 1,
+			mmap_value_tables: false,
+			verify_raw_keys: false,
+			size_hints: Default::default(),
+			metrics: None,
+			persist_options: false,
 		}
 	}
 
 	pub fn write_metadata(&self, path: &std::path::Path, salt: &Salt) -> Result<()> {
+		Self::write_columns_metadata(path, Some(salt), &self.columns)
+	}
+
+	fn write_columns_metadata(path: &std::path::Path, salt: Option<&Salt>, columns: &[ColumnOptions]) -> Result<()> {
 		let mut file = std::fs::File::create(path)?;
 		writeln!(file, "version={}", CURRENT_VERSION)?;
-		writeln!(file, "salt={}", hex::encode(salt))?;
-		for i in 0..self.columns.len() {
-			writeln!(file, "col{}={}", i, self.columns[i].as_string())?;
+		if let Some(salt) = salt {
+			writeln!(file, "salt={}", hex::encode(salt))?;
+		}
+		for (i, column) in columns.iter().enumerate() {
+			writeln!(file, "col{}={}", i, column.as_string())?;
 		}
 		Ok(())
 	}
 
-	pub fn load_and_validate_metadata(&self, create: bool) -> Result<Metadata> {
+	/// Options of an existing database, as stored in its metadata. Other options are defaults.
+	pub fn from_existing(path: &Path) -> Result<Options> {
+		let metadata = Self::load_metadata(&path.join("metadata"))?
+			.ok_or_else(|| Error::InvalidConfiguration(format!("No database metadata in {}", path.display())))?;
+		let mut options = Options::with_columns(path, 0);
+		options.columns = metadata.columns;
+		options.salt = metadata.salt;
+		Ok(options)
+	}
+
+	/// Write the options to a file, one `key=value` setting per line. Columns are written in
+	/// the metadata format, followed by their settings that are not stored in the metadata.
+	/// `path`, `size_hints` and `metrics` are not written.
+	pub fn write_to(&self, path: &Path) -> Result<()> {
+		let mut file = std::fs::File::create(path)?;
+		writeln!(file, "sync_wal={}", self.sync_wal.as_string())?;
+		writeln!(file, "sync_data={}", self.sync_data)?;
+		writeln!(file, "stats={}", self.stats)?;
+		if let Some(salt) = &self.salt {
+			writeln!(file, "salt={}", hex::encode(salt))?;
+		}
+		if let Some(path) = &self.wal_path {
+			writeln!(file, "wal_path={}", path.display())?;
+		}
+		writeln!(file, "validate_on_replay={}", self.validate_on_replay)?;
+		writeln!(file, "repair_on_replay={}", self.repair_on_replay)?;
+		writeln!(file, "disable_wal={}", self.disable_wal)?;
+		writeln!(file, "allow_wal_discard={}", self.allow_wal_discard)?;
+		if let Some(bytes) = self.max_uncommitted_bytes {
+			writeln!(file, "max_uncommitted_bytes={}", bytes)?;
+		}
+		writeln!(file, "max_background_commit_delay_ms={}", self.max_background_commit_delay.as_millis())?;
+		if let Some(bytes) = self.overlay_high_water_bytes {
+			writeln!(file, "overlay_high_water_bytes={}", bytes)?;
+		}
+		if let Some(bytes) = self.overlay_low_water_bytes {
+			writeln!(file, "overlay_low_water_bytes={}", bytes)?;
+		}
+		writeln!(file, "commit_queue_bytes={}", self.commit_queue_bytes)?;
+		writeln!(file, "backlog_warning={}", BacklogWarning::as_string(&self.backlog_warning))?;
+		writeln!(file, "max_log_size={}", self.max_log_size)?;
+		writeln!(file, "log_pool_size={}", self.log_pool_size)?;
+		writeln!(file, "background_threads={}", self.background_threads)?;
+		writeln!(file, "enact_threads={}", self.enact_threads)?;
+		writeln!(file, "mmap_value_tables={}", self.mmap_value_tables)?;
+		writeln!(file, "verify_raw_keys={}", self.verify_raw_keys)?;
+		writeln!(file, "persist_options={}", self.persist_options)?;
+		for (i, column) in self.columns.iter().enumerate() {
+			writeln!(file, "col{}={}", i, column.as_string())?;
+			writeln!(file, "col{}.compression_treshold={}", i, column.compression_treshold)?;
+			writeln!(file, "col{}.cache_size_bytes={}", i, column.cache_size_bytes)?;
+			writeln!(file, "col{}.table_growth_bytes={}", i, column.table_growth_bytes)?;
+			if let Some(path) = &column.path {
+				writeln!(file, "col{}.path={}", i, path.display())?;
+			}
+		}
+		file.sync_all()?;
+		Ok(())
+	}
+
+	/// Read options written with `write_to`. The database path is set to the directory of
+	/// the file. Settings that are missing keep their defaults, and unknown keys are logged
+	/// and ignored, so that files written by newer versions can be read. The options are
+	/// checked with `validate`.
+	pub fn load_from(path: &Path) -> Result<Options> {
+		use std::io::BufRead;
+
+		let dir = path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+		let mut options = Options::with_columns(&dir, 0);
+		let mut columns: std::collections::BTreeMap<usize, ColumnOptions> = Default::default();
+		let mut column_settings = Vec::new();
+		let file = std::io::BufReader::new(std::fs::File::open(path)?);
+		for (n, line) in file.lines().enumerate() {
+			let line = line?;
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let bad = |what: &str| Error::InvalidConfiguration(format!("{}:{}: {}", path.display(), n + 1, what));
+			let mut pair = line.splitn(2, '=');
+			let (k, v) = match (pair.next(), pair.next()) {
+				(Some(k), Some(v)) => (k.trim(), v.trim()),
+				_ => return Err(bad("expected key=value")),
+			};
+			let flag = || v.parse::<bool>().map_err(|_| bad(&format!("{}: expected true or false", k)));
+			let number = || v.parse::<u64>().map_err(|_| bad(&format!("{}: expected a number", k)));
+			match k {
+				"sync_wal" => options.sync_wal = WalSync::from_string(v).ok_or_else(|| bad("sync_wal: expected never, every_commit or interval_ms: <ms>"))?,
+				"sync_data" => options.sync_data = flag()?,
+				"stats" => options.stats = flag()?,
+				"salt" => {
+					let salt = hex::decode(v).ok().filter(|s| s.len() == 32).ok_or_else(|| bad("salt: expected 32 hex encoded bytes"))?;
+					let mut s = Salt::default();
+					s.copy_from_slice(&salt);
+					options.salt = Some(s);
+				},
+				"wal_path" => options.wal_path = Some(PathBuf::from(v)),
+				"validate_on_replay" => options.validate_on_replay = flag()?,
+				"repair_on_replay" => options.repair_on_replay = flag()?,
+				"disable_wal" => options.disable_wal = flag()?,
+				"allow_wal_discard" => options.allow_wal_discard = flag()?,
+				"max_uncommitted_bytes" => options.max_uncommitted_bytes = Some(number()?),
+				"max_background_commit_delay_ms" => options.max_background_commit_delay = std::time::Duration::from_millis(number()?),
+				"overlay_high_water_bytes" => options.overlay_high_water_bytes = Some(number()?),
+				"overlay_low_water_bytes" => options.overlay_low_water_bytes = Some(number()?),
+				"commit_queue_bytes" => options.commit_queue_bytes = number()? as usize,
+				"backlog_warning" => {
+					options.backlog_warning = BacklogWarning::from_string(v)
+						.ok_or_else(|| bad("backlog_warning: expected none or <overlay_bytes>/<dirty_logs>/<after_ms>/<interval_ms>"))?;
+				},
+				"max_log_size" => options.max_log_size = number()?,
+				"log_pool_size" => options.log_pool_size = number()? as usize,
+				"background_threads" => options.background_threads = number()? as usize,
+				"enact_threads" => options.enact_threads = number()? as usize,
+				"mmap_value_tables" => options.mmap_value_tables = flag()?,
+				"verify_raw_keys" => options.verify_raw_keys = flag()?,
+				"persist_options" => options.persist_options = flag()?,
+				k => {
+					let col = k.strip_prefix("col").map(|c| c.splitn(2, '.'));
+					match col.map(|mut c| (c.next().and_then(|c| c.parse::<usize>().ok()), c.next())) {
+						Some((Some(col), None)) => {
+							let column = ColumnOptions::from_string(v).ok_or_else(|| bad(&format!("column {}: bad options", col)))?;
+							columns.insert(col, column);
+						},
+						Some((Some(col), Some(setting))) => column_settings.push((n + 1, col, setting.to_string(), v.to_string())),
+						_ => log::warn!(target: "parity-db", "{}:{}: Ignoring unknown option {}", path.display(), n + 1, k),
+					}
+				},
+			}
+		}
+		if columns.keys().enumerate().any(|(i, col)| i != *col) {
+			return Err(Error::InvalidConfiguration(format!("{}: columns are not numbered from 0 in order", path.display())));
+		}
+		options.columns = columns.into_values().collect();
+		for (n, col, setting, v) in column_settings {
+			let bad = |what: &str| Error::InvalidConfiguration(format!("{}:{}: {}", path.display(), n, what));
+			let column = options.columns.get_mut(col).ok_or_else(|| bad(&format!("column {} does not exist", col)))?;
+			let number = || v.parse::<u64>().map_err(|_| bad(&format!("{}: expected a number", setting)));
+			match setting.as_str() {
+				"compression_treshold" => column.compression_treshold = number()? as u32,
+				"cache_size_bytes" => column.cache_size_bytes = number()?,
+				"table_growth_bytes" => column.table_growth_bytes = number()?,
+				"path" => column.path = Some(PathBuf::from(&v)),
+				_ => log::warn!(target: "parity-db", "{}:{}: Ignoring unknown option col{}.{}", path.display(), n, col, setting),
+			}
+		}
+		options.validate()?;
+		Ok(options)
+	}
+
+	// Number of columns that have index or value table files, if any.
+	fn columns_on_disk(&self) -> Result<Option<usize>> {
+		if !self.path.exists() {
+			return Ok(None);
+		}
+		let mut columns = None;
+		for entry in std::fs::read_dir(&self.path)? {
+			let name = entry?.file_name();
+			let name = name.to_string_lossy();
+			let col = name.strip_prefix("index_").or_else(|| name.strip_prefix("table_"))
+				.and_then(|n| n.get(..2))
+				.and_then(|n| n.parse::<usize>().ok());
+			if let Some(col) = col {
+				columns = Some(std::cmp::max(columns.unwrap_or(0), col + 1));
+			}
+		}
+		Ok(columns)
+	}
+
+	/// Column names that are configured but not stored yet are written to the metadata,
+	/// unless `read_only` is set. Metadata is synthesized from the options for databases
+	/// that were created without it.
+	pub fn load_and_validate_metadata(&self, create: bool, read_only: bool) -> Result<Metadata> {
 		let mut path: PathBuf = self.path.clone();
 		path.push("metadata");
-		let meta = Self::load_metadata(&path)?;
 
-		if let Some(meta) = meta {
-			if meta.columns.len() != self.columns.len() {
-				return Err(Error::InvalidConfiguration("Column config mismatch".into()));
-			}
+		if let Some(name) = duplicate_name(&self.columns) {
+			return Err(Error::InvalidConfiguration(format!("Duplicate column name {}", name)));
+		}
 
-			for c in 0..meta.columns.len() {
-				if meta.columns[c] != self.columns[c] {
-					return Err(Error::InvalidConfiguration(format!(
-								"Column config mismatch for column {}. Expected \"{}\", got \"{}\"",
-								c, self.columns[c].as_string(), meta.columns[c].as_string())));
-				}
+		let mut meta = match Self::load_metadata(&path)? {
+			Some(meta) => meta,
+			None => match self.columns_on_disk()? {
+				Some(columns) => {
+					if columns > self.columns.len() {
+						return Err(Error::ConfigMismatch(vec![ConfigMismatch {
+							column: None,
+							field: "columns",
+							stored: columns.to_string(),
+							configured: self.columns.len().to_string(),
+						}]));
+					}
+					// Keys of databases without metadata are hashed without salt.
+					if let Some(salt) = &self.salt {
+						return Err(Error::ConfigMismatch(vec![salt_mismatch(None, salt)]));
+					}
+					log::warn!(target: "parity-db", "Database metadata is missing, using configured column options");
+					if !read_only {
+						Self::write_columns_metadata(&path, None, &self.columns)?;
+					}
+					return Ok(Metadata {
+						version: CURRENT_VERSION,
+						columns: self.columns.clone(),
+						salt: None,
+					});
+				},
+				None if create => {
+					let s: Salt = self.salt.unwrap_or(rand::thread_rng().gen());
+					self.write_metadata(&path, &s)?;
+					if self.persist_options {
+						let mut options = self.clone();
+						options.salt = Some(s);
+						options.write_to(&self.path.join(OPTIONS_FILE))?;
+					}
+					return Ok(Metadata {
+						version: CURRENT_VERSION,
+						columns: self.columns.clone(),
+						salt: Some(s),
+					});
+				},
+				None => {
+					return Err(Error::InvalidConfiguration("Database does not exist. To create a new one, use open_or_create".into()));
+				},
+			},
+		};
+
+		if let Some(salt) = &self.salt {
+			if meta.salt.as_ref() != Some(salt) {
+				return Err(Error::ConfigMismatch(vec![salt_mismatch(meta.salt.as_ref(), salt)]));
 			}
-			Ok(meta)
-		} else if create {
-			let s: Salt = self.salt.unwrap_or(rand::thread_rng().gen());
-			self.write_metadata(&path, &s)?;
-			Ok(Metadata {
-				version: CURRENT_VERSION,
-				columns: self.columns.clone(),
-				salt: Some(s),
-			})
-		} else {
-			Err(Error::InvalidConfiguration("Database does not exist. To create a new one, use open_or_create".into()))
 		}
+		if meta.columns.len() != self.columns.len() {
+			return Err(Error::ConfigMismatch(vec![ConfigMismatch {
+				column: None,
+				field: "columns",
+				stored: meta.columns.len().to_string(),
+				configured: self.columns.len().to_string(),
+			}]));
+		}
+		let mut mismatch = Vec::new();
+		let mut names_added = false;
+		for (c, (meta, options)) in meta.columns.iter_mut().zip(self.columns.iter()).enumerate() {
+			// Not stored in the metadata.
+			meta.cache_size_bytes = options.cache_size_bytes;
+			meta.table_growth_bytes = options.table_growth_bytes;
+			meta.compression_treshold = options.compression_treshold;
+			meta.path = options.path.clone();
+			if meta.name.is_none() && options.name.is_some() {
+				meta.name = options.name.clone();
+				names_added = true;
+			}
+			mismatch.extend(meta.mismatch(c as ColId, options));
+		}
+		if !mismatch.is_empty() {
+			return Err(Error::ConfigMismatch(mismatch));
+		}
+		if let Some(name) = duplicate_name(&meta.columns) {
+			return Err(Error::InvalidConfiguration(format!("Duplicate column name {} in stored and configured names", name)));
+		}
+		if names_added && !read_only && meta.version == CURRENT_VERSION {
+			Self::write_columns_metadata(&path, meta.salt.as_ref(), &meta.columns)?;
+		}
+		Ok(meta)
 	}
 
 	pub fn load_metadata(path: &Path) -> Result<Option<Metadata>> {
@@ -260,12 +962,243 @@ impl Options {
 	}
 
 	pub fn is_valid(&self) -> bool {
-		for option in self.columns.iter() {
-			if !option.is_valid() {
-				return false;
+		self.validate().is_ok()
+	}
+
+	/// Check the options, including all columns. Fails with `Error::InvalidConfiguration`
+	/// naming the first invalid field and its allowed values. Databases are only opened
+	/// with valid options.
+	pub fn validate(&self) -> Result<()> {
+		let invalid = |message: String| Err(Error::InvalidConfiguration(message));
+		if self.path.as_os_str().is_empty() {
+			return invalid("path: must be set".into());
+		}
+		if self.wal_path.as_ref().is_some_and(|p| p.as_os_str().is_empty()) {
+			return invalid("wal_path: must not be empty".into());
+		}
+		let max_columns = ColId::MAX as usize + 1;
+		if self.columns.is_empty() || self.columns.len() > max_columns {
+			return invalid(format!("columns: {} columns, 1 to {} are allowed", self.columns.len(), max_columns));
+		}
+		// Trash is kept in a column of its own.
+		if self.columns.len() == max_columns && self.columns.iter().any(|c| c.trash.is_some()) {
+			return invalid(format!("columns: trash requires fewer than {} columns", max_columns));
+		}
+		for (col, options) in self.columns.iter().enumerate() {
+			if let Err(e) = options.check() {
+				return invalid(format!("column {} {}", col, e));
 			}
 		}
-		true
+		for (col, hint) in self.size_hints.iter() {
+			if *col as usize >= self.columns.len() {
+				return invalid(format!("size_hints: column {} does not exist", col));
+			}
+			if !hint.is_valid() {
+				return invalid(format!("size_hints: column {} histogram has no values, or too many", col));
+			}
+		}
+		if self.background_threads == 0 || self.background_threads > 4 {
+			return invalid(format!("background_threads: {} is out of range 1 to 4", self.background_threads));
+		}
+		if self.commit_queue_bytes == 0 {
+			return invalid("commit_queue_bytes: must be at least 1".into());
+		}
+		if self.enact_threads == 0 {
+			return invalid("enact_threads: must be at least 1".into());
+		}
+		match (self.overlay_high_water_bytes, self.overlay_low_water_bytes) {
+			(None, Some(_)) => return invalid("overlay_low_water_bytes: requires overlay_high_water_bytes".into()),
+			(Some(high), Some(low)) if low > high => {
+				return invalid(format!("overlay_low_water_bytes: {} is above overlay_high_water_bytes {}", low, high));
+			},
+			_ => {},
+		}
+		Ok(())
+	}
+
+	/// Start building options for a database at `path`, with default settings and no columns.
+	pub fn builder(path: impl Into<PathBuf>) -> OptionsBuilder {
+		let mut options = Options::with_columns(Path::new(""), 0);
+		options.path = path.into();
+		OptionsBuilder { options }
+	}
+
+	/// Directory of the write-ahead log files.
+	pub fn log_dir(&self) -> &Path {
+		self.wal_path.as_deref().unwrap_or(&self.path)
+	}
+
+	/// Directory of the index and value table files of a column.
+	pub fn column_path(&self, col: ColId) -> &Path {
+		self.columns.get(col as usize).and_then(|c| c.path.as_deref()).unwrap_or(&self.path)
+	}
+
+	/// Check that the directories of columns with their own `ColumnOptions::path` exist,
+	/// and that files can be created in them if `writable` is set.
+	pub(crate) fn check_column_paths(&self, writable: bool) -> Result<()> {
+		for (col, path) in self.columns.iter().enumerate().filter_map(|(c, o)| o.path.as_ref().map(|p| (c, p))) {
+			let invalid = |what: &str| Err(Error::InvalidConfiguration(format!("column {} path: {} {}", col, path.display(), what)));
+			match std::fs::metadata(path) {
+				Ok(m) if m.is_dir() => {},
+				Ok(_) => return invalid("is not a directory"),
+				Err(_) => return invalid("does not exist"),
+			}
+			if writable {
+				let probe = path.join(format!(".probe_{:02}", col));
+				if std::fs::File::create(&probe).and_then(|_| std::fs::remove_file(&probe)).is_err() {
+					return invalid("is not writable");
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// High and low water marks of the log overlay, if limited.
+	pub(crate) fn overlay_water_marks(&self) -> Option<(u64, u64)> {
+		self.overlay_high_water_bytes.map(|high| (high, self.overlay_low_water_bytes.unwrap_or(high / 2)))
+	}
+}
+
+/// Builder for `Options`. See `Options` for the meaning and default of each setting.
+/// `build` checks the options with `Options::validate`.
+#[derive(Clone, Debug)]
+pub struct OptionsBuilder {
+	options: Options,
+}
+
+impl OptionsBuilder {
+	/// Add a column. Columns are numbered in the order they are added.
+	pub fn column(mut self, column: ColumnOptions) -> Self {
+		self.options.columns.push(column);
+		self
+	}
+
+	/// Add `count` columns with default options.
+	pub fn default_columns(mut self, count: usize) -> Self {
+		self.options.columns.extend((0..count).map(|_| ColumnOptions::default()));
+		self
+	}
+
+	pub fn sync_wal(mut self, sync: WalSync) -> Self {
+		self.options.sync_wal = sync;
+		self
+	}
+
+	pub fn sync_data(mut self, sync: bool) -> Self {
+		self.options.sync_data = sync;
+		self
+	}
+
+	pub fn stats(mut self, stats: bool) -> Self {
+		self.options.stats = stats;
+		self
+	}
+
+	pub fn salt(mut self, salt: Salt) -> Self {
+		self.options.salt = Some(salt);
+		self
+	}
+
+	pub fn validate_on_replay(mut self, validate: bool) -> Self {
+		self.options.validate_on_replay = validate;
+		self
+	}
+
+	pub fn repair_on_replay(mut self, repair: bool) -> Self {
+		self.options.repair_on_replay = repair;
+		self
+	}
+
+	pub fn disable_wal(mut self, disable: bool) -> Self {
+		self.options.disable_wal = disable;
+		self
+	}
+
+	pub fn allow_wal_discard(mut self, allow: bool) -> Self {
+		self.options.allow_wal_discard = allow;
+		self
+	}
+
+	pub fn wal_path(mut self, path: impl Into<PathBuf>) -> Self {
+		self.options.wal_path = Some(path.into());
+		self
+	}
+
+	pub fn commit_queue_bytes(mut self, bytes: usize) -> Self {
+		self.options.commit_queue_bytes = bytes;
+		self
+	}
+
+	pub fn max_uncommitted_bytes(mut self, bytes: u64) -> Self {
+		self.options.max_uncommitted_bytes = Some(bytes);
+		self
+	}
+
+	pub fn max_background_commit_delay(mut self, delay: std::time::Duration) -> Self {
+		self.options.max_background_commit_delay = delay;
+		self
+	}
+
+	/// Set the high and low water marks of the log overlay.
+	pub fn overlay_water_marks(mut self, high: u64, low: u64) -> Self {
+		self.options.overlay_high_water_bytes = Some(high);
+		self.options.overlay_low_water_bytes = Some(low);
+		self
+	}
+
+	pub fn backlog_warning(mut self, warning: Option<BacklogWarning>) -> Self {
+		self.options.backlog_warning = warning;
+		self
+	}
+
+	pub fn max_log_size(mut self, bytes: u64) -> Self {
+		self.options.max_log_size = bytes;
+		self
+	}
+
+	pub fn log_pool_size(mut self, files: usize) -> Self {
+		self.options.log_pool_size = files;
+		self
+	}
+
+	pub fn background_threads(mut self, threads: usize) -> Self {
+		self.options.background_threads = threads;
+		self
+	}
+
+	pub fn enact_threads(mut self, threads: usize) -> Self {
+		self.options.enact_threads = threads;
+		self
+	}
+
+	pub fn mmap_value_tables(mut self, mmap: bool) -> Self {
+		self.options.mmap_value_tables = mmap;
+		self
+	}
+
+	pub fn verify_raw_keys(mut self, verify: bool) -> Self {
+		self.options.verify_raw_keys = verify;
+		self
+	}
+
+	pub fn size_hint(mut self, col: ColId, hint: SizeHint) -> Self {
+		self.options.size_hints.insert(col, hint);
+		self
+	}
+
+	pub fn metrics(mut self, metrics: Arc<dyn DbMetrics>) -> Self {
+		self.options.metrics = Some(metrics);
+		self
+	}
+
+	pub fn persist_options(mut self, persist: bool) -> Self {
+		self.options.persist_options = persist;
+		self
+	}
+
+	pub fn build(self) -> Result<Options> {
+		self.options.validate()?;
+		Ok(self.options)
 	}
 }
 
@@ -280,3 +1213,19 @@ impl Metadata {
 		result
 	}
 }
+
+fn duplicate_name(columns: &[ColumnOptions]) -> Option<&str> {
+	columns.iter().enumerate()
+		.filter_map(|(i, c)| Some((i, c.name.as_deref()?)))
+		.find(|(i, name)| columns[..*i].iter().any(|c| c.name.as_deref() == Some(*name)))
+		.map(|(_, name)| name)
+}
+
+fn salt_mismatch(stored: Option<&Salt>, configured: &Salt) -> ConfigMismatch {
+	ConfigMismatch {
+		column: None,
+		field: "salt",
+		stored: stored.map_or("none".into(), hex::encode),
+		configured: hex::encode(configured),
+	}
+}
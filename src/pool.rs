@@ -0,0 +1,94 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::mpsc::{channel, Sender};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Fixed set of threads, each running the jobs sent to it in order. Threads exit once the
+// pool is dropped and their queued jobs are done.
+pub struct ThreadPool {
+	senders: Vec<Sender<Job>>,
+	threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+	pub fn new(name: &str, threads: usize) -> std::io::Result<ThreadPool> {
+		let mut pool = ThreadPool {
+			senders: Vec::with_capacity(threads),
+			threads: Vec::with_capacity(threads),
+		};
+		for _ in 0..threads {
+			let (sender, receiver) = channel::<Job>();
+			// If spawning fails, threads that are already running are joined on drop.
+			let handle = std::thread::Builder::new()
+				.name(name.into())
+				.spawn(move || {
+					while let Ok(job) = receiver.recv() {
+						job();
+					}
+				})?;
+			pool.senders.push(sender);
+			pool.threads.push(handle);
+		}
+		Ok(pool)
+	}
+
+	pub fn threads(&self) -> usize {
+		self.senders.len()
+	}
+
+	// Run `job` on thread `thread` modulo the number of threads, after the jobs sent to it before.
+	// Jobs must catch their own panics, a panic stops the thread.
+	pub fn execute(&self, thread: usize, job: impl FnOnce() + Send + 'static) {
+		let sender = &self.senders[thread % self.senders.len()];
+		if let Err(e) = sender.send(Box::new(job)) {
+			// The thread is gone, run the job here instead.
+			(e.0)();
+		}
+	}
+}
+
+impl Drop for ThreadPool {
+	fn drop(&mut self) {
+		self.senders.clear();
+		for handle in self.threads.drain(..) {
+			let _ = handle.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::ThreadPool;
+
+	#[test]
+	fn jobs_run_in_order_per_thread() {
+		let pool = ThreadPool::new("test", 2).unwrap();
+		let (sender, receiver) = std::sync::mpsc::channel();
+		for i in 0..100 {
+			let sender = sender.clone();
+			pool.execute(i % 2, move || sender.send((i % 2, i)).unwrap());
+		}
+		drop(sender);
+		let mut last = [None, None];
+		for (thread, i) in receiver {
+			assert!(last[thread].map_or(true, |l| l < i));
+			last[thread] = Some(i);
+		}
+		assert_eq!(last, [Some(98), Some(99)]);
+	}
+}
@@ -19,7 +19,7 @@
 use std::sync::atomic::{AtomicU64, AtomicU32, AtomicI64, Ordering};
 use std::mem::MaybeUninit;
 use std::io::{Read, Write, Cursor};
-use crate::{error::Result, column::ColId, table::SIZE_TIERS};
+use crate::{error::Result, table::SIZE_TIERS};
 
 // store up to value of size HISTOGRAM_BUCKETS * 2 ^ HISTOGRAM_BUCKET_BITS,
 // that is 32ko
@@ -162,7 +162,7 @@ impl ColumnStats {
 		}
 	}
 
-	fn write_stats(&self, writer: &mut impl std::io::Write, col: ColId) -> Result<()> {
+	fn write_stats(&self, writer: &mut impl std::io::Write, col: &str) -> Result<()> {
 		writeln!(writer, "Column {}", col)?;
 		writeln!(writer, "Total values: {}", self.total_values.load(Ordering::Relaxed))?;
 		writeln!(writer, "Total bytes: {}", self.total_bytes.load(Ordering::Relaxed))?;
@@ -212,7 +212,8 @@ impl ColumnStats {
 		Ok(())
 	}
 
-	pub fn write_summary(&self, writer: &mut impl std::io::Write, col: ColId) {
+	/// `col` is the column label, as returned by `column_label`.
+	pub fn write_summary(&self, writer: &mut impl std::io::Write, col: &str) {
 		let _ = self.write_stats(writer, col);
 	}
 
@@ -274,3 +275,253 @@ impl ColumnStats {
 		self.commits.fetch_add(1, Ordering::Relaxed);
 	}
 }
+
+/// Operation counters of a column, since the database was opened or the counters were reset.
+/// See `Db::counters`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ColumnCounters {
+	/// Queries of the column, `hits + misses`.
+	pub gets: u64,
+	/// Queries that found a value.
+	pub hits: u64,
+	/// Queries that found nothing.
+	pub misses: u64,
+	/// Inserted and removed keys in commits.
+	pub committed_keys: u64,
+	/// Values in commits and their keys, 32 bytes each.
+	pub committed_bytes: u64,
+	/// Bytes of value table entries freed by removed and replaced values.
+	pub freed_bytes: u64,
+}
+
+// Counters updated on the read and commit paths, kept in memory only.
+#[derive(Default)]
+pub struct IoCounters {
+	hits: AtomicU64,
+	misses: AtomicU64,
+	committed_keys: AtomicU64,
+	committed_bytes: AtomicU64,
+}
+
+impl IoCounters {
+	pub fn get<T>(&self, value: &Option<T>) {
+		match value {
+			Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+			None => self.misses.fetch_add(1, Ordering::Relaxed),
+		};
+	}
+
+	pub fn commit(&self, bytes: usize) {
+		self.committed_keys.fetch_add(1, Ordering::Relaxed);
+		self.committed_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+	}
+
+	pub fn snapshot(&self, freed_bytes: u64) -> ColumnCounters {
+		let hits = self.hits.load(Ordering::Relaxed);
+		let misses = self.misses.load(Ordering::Relaxed);
+		ColumnCounters {
+			gets: hits + misses,
+			hits,
+			misses,
+			committed_keys: self.committed_keys.load(Ordering::Relaxed),
+			committed_bytes: self.committed_bytes.load(Ordering::Relaxed),
+			freed_bytes,
+		}
+	}
+
+	pub fn reset(&self) {
+		self.hits.store(0, Ordering::Relaxed);
+		self.misses.store(0, Ordering::Relaxed);
+		self.committed_keys.store(0, Ordering::Relaxed);
+		self.committed_bytes.store(0, Ordering::Relaxed);
+	}
+}
+
+/// Disk space used by the database.
+#[derive(Debug, Default, Clone)]
+pub struct DiskUsage {
+	/// All files in the database directory, logs in the log directory and column files in
+	/// column directories.
+	pub total: u64,
+	/// Write-ahead log files.
+	pub logs: u64,
+	pub columns: Vec<ColumnDiskUsage>,
+}
+
+/// Disk space used by a single column.
+#[derive(Debug, Default, Clone)]
+pub struct ColumnDiskUsage {
+	/// Index files, including tables queued for reindexing.
+	pub index: u64,
+	/// Value tables that have a file on disk.
+	pub tables: Vec<TableDiskUsage>,
+}
+
+/// Disk space used by a single value table.
+#[derive(Debug, Default, Clone)]
+pub struct TableDiskUsage {
+	pub size_tier: u8,
+	pub entry_size: u16,
+	/// Bytes actually allocated on disk.
+	pub on_disk: u64,
+	/// Bytes occupied by entries, including removed ones. See `Db::free_space` for the
+	/// removed entries.
+	pub allocated: u64,
+}
+
+/// Commits waiting to be written to the tables.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PendingBytes {
+	/// Commits that are not written to the log yet.
+	pub commit_queue: u64,
+	/// Logged commits that are not enacted yet.
+	pub log_queue: u64,
+	/// Size of the log file being appended to. It is rotated once it grows past
+	/// `Options::max_log_size`. Included in `log_queue`.
+	pub appending_log: u64,
+}
+
+impl PendingBytes {
+	pub fn total(&self) -> u64 {
+		self.commit_queue + self.log_queue
+	}
+}
+
+/// Approximate heap memory held by the database.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+	/// Commits that are not written to the log yet, including the overlay that serves reads of them.
+	pub commit_queue: u64,
+	/// Logged changes that are not enacted yet.
+	pub log_overlay: u64,
+	/// Cached values of all columns.
+	pub cache: u64,
+}
+
+impl MemoryUsage {
+	pub fn total(&self) -> u64 {
+		self.commit_queue + self.log_overlay + self.cache
+	}
+}
+
+/// Commits that are not written to the log yet, by priority.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CommitQueueDepth {
+	pub normal_commits: usize,
+	pub normal_bytes: u64,
+	pub background_commits: usize,
+	pub background_bytes: u64,
+	/// Largest total size of queued commits since the database was opened.
+	pub high_water_bytes: u64,
+}
+
+impl CommitQueueDepth {
+	/// Size of all queued commits, in bytes.
+	pub fn total_bytes(&self) -> u64 {
+		self.normal_bytes + self.background_bytes
+	}
+}
+
+/// How a single value is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueMeta {
+	/// Value table that holds the value.
+	pub size_tier: u8,
+	pub entry_size: u16,
+	/// Size of the value as stored, after compression.
+	pub stored_size: u32,
+	pub compressed: bool,
+	/// Number of table entries taken by the value. More than one for multipart values.
+	pub parts: u32,
+	/// Reference count, for reference counted columns.
+	pub ref_count: Option<u32>,
+}
+
+impl ValueMeta {
+	pub fn is_multipart(&self) -> bool {
+		self.parts > 1
+	}
+}
+
+/// Space in the value tables of a column that compaction can reclaim.
+#[derive(Debug, Default, Clone)]
+pub struct FreeStats {
+	pub tables: Vec<TableFreeStats>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TableFreeStats {
+	pub size_tier: u8,
+	pub entry_size: u16,
+	/// Removed entries waiting to be reused.
+	pub free_entries: u64,
+	/// Bytes of removed entries and of file space past the last entry.
+	pub reclaimable: u64,
+}
+
+impl FreeStats {
+	pub fn reclaimable(&self) -> u64 {
+		self.tables.iter().map(|t| t.reclaimable).sum()
+	}
+}
+
+/// Result of `Db::verify`.
+#[derive(Debug, Default, Clone)]
+pub struct VerifyReport {
+	pub columns: Vec<ColumnVerifyReport>,
+}
+
+/// Consistency of the index and value tables of a single column.
+#[derive(Debug, Default, Clone)]
+pub struct ColumnVerifyReport {
+	pub col: u8,
+	/// Index entries checked, including those of tables queued for reindexing.
+	pub index_entries: u64,
+	/// Index entries that point to a removed or missing value slot.
+	pub dangling: u64,
+	/// Index entries that point to a value with a different key, or a damaged value.
+	pub mismatched: u64,
+	/// Value slots that are in use, but not referenced from the index.
+	pub orphaned: u64,
+}
+
+/// Result of `Db::repair`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairSummary {
+	/// Values added to the rebuilt index.
+	pub indexed: u64,
+	/// Values left out of the index, because their key could not be recovered.
+	pub skipped: u64,
+}
+
+impl VerifyReport {
+	/// Returns `true` if no inconsistencies were found.
+	pub fn is_ok(&self) -> bool {
+		self.columns.iter().all(|c| c.is_ok())
+	}
+}
+
+impl ColumnVerifyReport {
+	pub fn is_ok(&self) -> bool {
+		self.dangling == 0 && self.mismatched == 0 && self.orphaned == 0
+	}
+}
+
+impl ColumnDiskUsage {
+	pub fn total(&self) -> u64 {
+		self.index + self.tables.iter().map(|t| t.on_disk).sum::<u64>()
+	}
+}
+
+/// Space allocated on disk for the file. Sparse regions are not counted on unix.
+pub fn file_disk_size(metadata: &std::fs::Metadata) -> u64 {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::MetadataExt;
+		std::cmp::min(metadata.blocks() * 512, metadata.len())
+	}
+	#[cfg(not(unix))]
+	{
+		metadata.len()
+	}
+}
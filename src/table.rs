@@ -64,19 +64,26 @@ use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use std::sync::Arc;
 use parking_lot::{RwLockUpgradableReadGuard, RwLock};
 use crate::{
-	error::Result,
+	error::{Error, Result},
 	column::ColId,
-	log::{LogQuery, LogReader, LogWriter},
+	log::{LogQuery, LogRead, LogReader, LogWriter},
 	display::hex,
 	options::ColumnOptions as Options,
+	stats::{self, TableDiskUsage, TableFreeStats},
 };
 
 pub const KEY_LEN: usize = 32;
+// Index entries hold up to the first 8 bytes of the key.
+pub const MIN_KEY_LEN: usize = 8;
 pub const SIZE_TIERS: usize = 1usize << SIZE_TIERS_BITS;
 pub const SIZE_TIERS_BITS: u8 = 8;
 pub const COMPRESSED_MASK: u16 = 0x80_00;
 pub const MAX_ENTRY_SIZE: usize = 0x7ff8;
 pub const MIN_ENTRY_SIZE: usize = 32;
+pub const MULTIPART_ENTRY_SIZE: u16 = 4096;
+/// Limits of `ColumnOptions::table_growth_bytes`.
+pub const MIN_GROWTH_BYTES: u64 = 64 * 1024;
+pub const MAX_GROWTH_BYTES: u64 = 1024 * 1024 * 1024;
 const REFS_SIZE: usize = 4;
 const SIZE_SIZE: usize = 2;
 const PARTIAL_SIZE: usize = 26;
@@ -93,9 +100,8 @@ const LOCKED_REF: u32 = u32::MAX;
 pub type Key = [u8; KEY_LEN];
 pub type Value = Vec<u8>;
 
-fn partial_key(hash: &Key) -> &[u8] {
-	&hash[6..]
-}
+// Largest partial key, for 32 byte keys.
+type PartialKey = [u8; PARTIAL_SIZE];
 
 #[derive(Clone, Copy, Eq, PartialEq, Hash)]
 pub struct TableId(u16);
@@ -144,12 +150,22 @@ pub struct ValueTable {
 	pub id: TableId,
 	pub entry_size: u16,
 	file: RwLock<Option<std::fs::File>>,
+	// Read only map of the file, see `Options::mmap_value_tables`. Covers the file as it was
+	// when mapped, entries past its end are read from the file.
+	map: RwLock<Option<memmap2::Mmap>>,
+	mmap: bool,
 	path: Arc<std::path::PathBuf>,
 	capacity: AtomicU64,
 	filled: AtomicU64,
 	last_removed: AtomicU64,
 	dirty_header: AtomicBool,
 	dirty: AtomicBool,
+	// Bytes of entries freed by removed and replaced values.
+	freed_bytes: AtomicU64,
+	// Bytes of the key stored in each value, see `ColumnOptions::key_len`.
+	partial_size: usize,
+	// Entries added when the file is full, see `ColumnOptions::table_growth_bytes`.
+	growth_entries: u64,
 	multipart: bool,
 	ref_counted: bool,
 	no_compression: bool, // This legacy table can't be compressed. TODO: remove this
@@ -288,8 +304,8 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> Entry<B> {
 		self.write_slice(&rc.to_le_bytes());
 	}
 
-	fn read_partial(&mut self) -> &[u8] {
-		self.read_slice(PARTIAL_SIZE)
+	fn read_partial(&mut self, size: usize) -> &[u8] {
+		self.read_slice(size)
 	}
 
 	fn remaining_to(&self, end: usize) -> &[u8] {
@@ -323,6 +339,47 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> std::ops::IndexMut<std::ops::Range<usize>> fo
 	}
 }
 
+/// Number of bytes written to the log for a table entry, given its first two bytes.
+pub fn logged_entry_size(index: u64, head: [u8; SIZE_SIZE], entry_size: u16, no_compression: bool) -> Result<usize> {
+	if index == 0 {
+		return Ok(std::mem::size_of::<Header>());
+	}
+	let size = if head[..] == *TOMBSTONE {
+		SIZE_SIZE + INDEX_SIZE
+	} else if head[..] == *MULTIPART || head[..] == *MULTIHEAD {
+		entry_size as usize
+	} else {
+		let size = u16::from_le_bytes(head);
+		SIZE_SIZE + if no_compression { size } else { size & !COMPRESSED_MASK } as usize
+	};
+	if size > entry_size as usize {
+		return Err(Error::Corruption(format!("Bad entry size {} in slot {}", size, index)));
+	}
+	Ok(size)
+}
+
+// Value tables may only be mapped where the address space fits large files, and where files
+// can be resized while mapped.
+const MMAP_SUPPORTED: bool = cfg!(all(unix, target_pointer_width = "64"));
+
+fn map_file(file: &std::fs::File) -> Result<Option<memmap2::Mmap>> {
+	if file.metadata()?.len() == 0 {
+		return Ok(None);
+	}
+	let map = unsafe { memmap2::Mmap::map(file)? };
+	#[cfg(unix)]
+	unsafe {
+		// Values are read at random, read ahead only wastes IO.
+		libc::madvise(map.as_ptr() as _, map.len(), libc::MADV_RANDOM);
+	}
+	Ok(Some(map))
+}
+
+// Table file may not exist yet in a secondary instance.
+fn missing_file() -> Error {
+	Error::Io(std::io::ErrorKind::NotFound.into())
+}
+
 impl ValueTable {
 	pub fn open(
 		path: Arc<std::path::PathBuf>,
@@ -330,10 +387,12 @@ impl ValueTable {
 		entry_size: Option<u16>,
 		options: &Options,
 		db_version: u32,
+		mmap: bool,
 	) -> Result<ValueTable> {
+		let mmap = mmap && MMAP_SUPPORTED;
 		let (multipart, entry_size) = match entry_size {
 			Some(s) => (false, s),
-			None => (true, 4096),
+			None => (true, MULTIPART_ENTRY_SIZE),
 		};
 		assert!(entry_size >= MIN_ENTRY_SIZE as u16);
 		if db_version >= 4 {
@@ -344,12 +403,12 @@ impl ValueTable {
 		// Check for old file name format
 		filepath.push(id.legacy_file_name());
 		let mut file = if db_version == 3 && std::fs::metadata(&filepath).is_ok() {
-			Some(std::fs::OpenOptions::new().create(true).read(true).write(true).open(filepath.as_path())?)
+			Some(std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(filepath.as_path())?)
 		} else {
 			filepath.pop();
 			filepath.push(id.file_name());
 			if std::fs::metadata(&filepath).is_ok() {
-				Some(std::fs::OpenOptions::new().create(true).read(true).write(true).open(filepath.as_path())?)
+				Some(std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(filepath.as_path())?)
 			} else {
 				None
 			}
@@ -376,17 +435,26 @@ impl ValueTable {
 			}
 			log::debug!(target: "parity-db", "Opened value table {} with {} entries, entry_size={}", id, filled, entry_size);
 		}
+		let map = match &file {
+			Some(file) if mmap => map_file(file)?,
+			_ => None,
+		};
 
 		Ok(ValueTable {
 			id,
 			entry_size,
 			path,
 			file: RwLock::new(file),
+			map: RwLock::new(map),
+			mmap,
 			capacity: AtomicU64::new(capacity),
 			filled: AtomicU64::new(filled),
 			last_removed: AtomicU64::new(last_removed),
 			dirty_header: AtomicBool::new(false),
 			dirty: AtomicBool::new(false),
+			freed_bytes: AtomicU64::new(0),
+			partial_size: options.key_len - (KEY_LEN - PARTIAL_SIZE),
+			growth_entries: (options.table_growth_bytes.clamp(MIN_GROWTH_BYTES, MAX_GROWTH_BYTES) / entry_size as u64).max(1),
 			multipart,
 			ref_counted: options.ref_counted,
 			no_compression: db_version <= 3,
@@ -396,20 +464,60 @@ impl ValueTable {
 	fn create_file(&self) -> Result<std::fs::File> {
 		let mut path = std::path::PathBuf::clone(&*self.path);
 		path.push(self.id.file_name());
-		let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(path.as_path())?;
+		let file = std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path.as_path())?;
 		disable_read_ahead(&file)?;
 		log::debug!(target: "parity-db", "Created value table {}", self.id);
 		Ok(file)
 	}
 
 	pub fn value_size(&self) -> u16 {
-		self.entry_size - SIZE_SIZE as u16 - self.ref_size() as u16 - PARTIAL_SIZE as u16
+		self.entry_size - SIZE_SIZE as u16 - self.ref_size() as u16 - self.partial_size as u16
+	}
+
+	// Part of the key that is stored with the value. The rest is in the index entry.
+	fn partial_key<'a>(&self, key: &'a Key) -> &'a [u8] {
+		&key[KEY_LEN - PARTIAL_SIZE .. KEY_LEN - PARTIAL_SIZE + self.partial_size]
+	}
+
+	// Read from the map, if it covers the range.
+	fn read_mapped(&self, buf: &mut [u8], offset: u64) -> bool {
+		if !self.mmap {
+			return false;
+		}
+		match &*self.map.read() {
+			Some(map) if offset + buf.len() as u64 <= map.len() as u64 => {
+				buf.copy_from_slice(&map[offset as usize .. offset as usize + buf.len()]);
+				true
+			},
+			_ => false,
+		}
+	}
+
+	// Resize the file, and map it again if it is mapped.
+	fn set_file_len(&self, file: &std::fs::File, len: u64) -> Result<()> {
+		if !self.mmap {
+			file.set_len(len)?;
+			return Ok(());
+		}
+		let mut map = self.map.write();
+		// Reading a truncated part of the file through the map would fault.
+		*map = None;
+		file.set_len(len)?;
+		*map = map_file(file)?;
+		Ok(())
 	}
 
 	#[cfg(unix)]
 	fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
-		Ok(self.file.read().as_ref().unwrap().read_exact_at(buf, offset)?)
+		if self.read_mapped(buf, offset) {
+			return Ok(());
+		}
+		let result = match self.file.read().as_ref() {
+			Some(file) => file.read_exact_at(buf, offset).map_err(Error::from),
+			None => Err(missing_file()),
+		};
+		result.map_err(|e| self.read_error(e, offset, buf.len()))
 	}
 
 	#[cfg(unix)]
@@ -428,8 +536,14 @@ impl ValueTable {
 	#[cfg(windows)]
 	fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::windows::fs::FileExt;
-		self.file.read().as_ref().unwrap().seek_read(buf, offset)?;
-		Ok(())
+		if self.read_mapped(buf, offset) {
+			return Ok(());
+		}
+		let result = match self.file.read().as_ref() {
+			Some(file) => file.seek_read(buf, offset).map(|_| ()).map_err(Error::from),
+			None => Err(missing_file()),
+		};
+		result.map_err(|e| self.read_error(e, offset, buf.len()))
 	}
 
 	#[cfg(windows)]
@@ -446,9 +560,33 @@ impl ValueTable {
 		Ok(())
 	}
 
+	fn read_error(&self, e: Error, offset: u64, len: usize) -> Error {
+		e.with_context(|| format!(
+			"reading table {} ({}) at offset {}, {} bytes",
+			self.id,
+			self.path.join(self.id.file_name()).display(),
+			offset,
+			len,
+		))
+	}
+
 	fn grow(&self) -> Result<()> {
-		let mut capacity = self.capacity.load(Ordering::Relaxed);
-		capacity += (256 * 1024) / self.entry_size as u64;
+		let capacity = self.capacity.load(Ordering::Relaxed) + self.growth_entries;
+		self.set_capacity(capacity)
+	}
+
+	/// Grow the file to fit this many entries, if it is smaller.
+	pub fn preallocate(&self, entries: u64) -> Result<()> {
+		// Plus the header entry.
+		let capacity = entries + 1;
+		if capacity <= self.capacity.load(Ordering::Relaxed) {
+			return Ok(());
+		}
+		log::debug!(target: "parity-db", "{}: Preallocating {} entries", self.id, entries);
+		self.set_capacity(capacity)
+	}
+
+	fn set_capacity(&self, capacity: u64) -> Result<()> {
 		self.capacity.store(capacity, Ordering::Relaxed);
 		let mut file = self.file.upgradable_read();
 		if file.is_none() {
@@ -456,8 +594,7 @@ impl ValueTable {
 			*wfile = Some(self.create_file()?);
 			file = parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wfile);
 		}
-		file.as_ref().unwrap().set_len(capacity * self.entry_size as u64)?;
-		Ok(())
+		self.set_file_len(file.as_ref().unwrap(), capacity * self.entry_size as u64)
 	}
 
 	// Return ref counter, partial key and if it was compressed.
@@ -468,12 +605,12 @@ impl ValueTable {
 		mut index: u64,
 		log: &Q,
 		mut f: F,
-	) -> Result<(u32, [u8; PARTIAL_SIZE], bool)> {
+	) -> Result<(u32, PartialKey, bool)> {
 		let mut buf = FullEntry::new_uninit();
 		let mut part = 0;
 		let mut compressed = false;
 		let mut rc = 1;
-		let mut pk = [0u8; PARTIAL_SIZE];
+		let mut pk = PartialKey::default();
 		let entry_size = self.entry_size as usize;
 		loop {
 			let buf = if log.value(self.id, index, buf.as_mut()) {
@@ -509,16 +646,16 @@ impl ValueTable {
 				if self.ref_counted {
 					rc = buf.read_rc();
 				}
-				let pks = buf.read_partial();
-				pk.copy_from_slice(&pks);
-				if key.map_or(false, |k| partial_key(k) != pk) {
+				let pks = buf.read_partial(self.partial_size);
+				pk[.. self.partial_size].copy_from_slice(&pks);
+				if key.map_or(false, |k| self.partial_key(k) != &pk[.. self.partial_size]) {
 					log::debug!(
 						target: "parity-db",
 						"{}: Key mismatch at {}. Expected {:?}, got {}, size = {}",
 						self.id,
 						index,
-						key.map(|k| hex(partial_key(k))),
-						hex(&pk),
+						key.map(|k| hex(self.partial_key(k))),
+						hex(&pk[.. self.partial_size]),
 						self.entry_size,
 					);
 					return Ok((0, Default::default(), false));
@@ -545,7 +682,39 @@ impl ValueTable {
 		Ok(None)
 	}
 
-	pub fn get_with_meta(&self, index: u64, log: &impl LogQuery) -> Result<Option<(Value, u32, [u8; PARTIAL_SIZE], bool)>> {
+	/// Bytes `offset .. offset + len` of the value as stored, clamped to its size, and the
+	/// compression flag. Only the requested bytes are collected.
+	pub fn get_range(&self, key: &Key, index: u64, log: &impl LogQuery, offset: usize, len: usize) -> Result<Option<(Value, bool)>> {
+		let end = offset.saturating_add(len);
+		let mut result = Vec::new();
+		let mut pos = 0;
+		let (rc, _, compressed) = self.for_parts(Some(key), index, log, |buf| {
+			let start = offset.clamp(pos, pos + buf.len());
+			let stop = end.clamp(pos, pos + buf.len());
+			result.extend_from_slice(&buf[start - pos .. stop - pos]);
+			pos += buf.len();
+		})?;
+		if rc > 0 {
+			return Ok(Some((result, compressed)));
+		}
+		Ok(None)
+	}
+
+	/// Value with its reference count, compression flag and the number of entries it takes.
+	pub fn get_with_parts(&self, key: &Key, index: u64, log: &impl LogQuery) -> Result<Option<(Value, u32, bool, u32)>> {
+		let mut result = Vec::new();
+		let mut parts = 0;
+		let (rc, _, compressed) = self.for_parts(Some(key), index, log, |buf| {
+			parts += 1;
+			result.extend_from_slice(buf)
+		})?;
+		if rc > 0 {
+			return Ok(Some((result, rc, compressed, parts)));
+		}
+		Ok(None)
+	}
+
+	pub fn get_with_meta(&self, index: u64, log: &impl LogQuery) -> Result<Option<(Value, u32, PartialKey, bool)>> {
 		let mut result = Vec::new();
 		let (rc, pkey, compressed) = self.for_parts(None, index, log, |buf| result.extend_from_slice(buf))?;
 		if rc > 0 {
@@ -564,16 +733,17 @@ impl ValueTable {
 		Ok(None)
 	}
 
-	pub fn has_key_at(&self, index: u64, key: &Key, log: &LogWriter) -> Result<bool> {
+	pub fn has_key_at(&self, index: u64, key: &Key, log: &impl LogQuery) -> Result<bool> {
 		Ok(match self.partial_key_at(index, log)? {
-			Some(existing_key) => &existing_key[..] == partial_key(key),
+			Some(existing_key) => &existing_key[.. self.partial_size] == self.partial_key(key),
 			None => false,
 		})
 	}
 
-	pub fn partial_key_at<Q: LogQuery>(&self, index: u64, log: &Q) -> Result<Option<[u8; PARTIAL_SIZE]>> {
+	/// Stored part of the key of the value at `index`, followed by zeros for short keys.
+	pub fn partial_key_at<Q: LogQuery>(&self, index: u64, log: &Q) -> Result<Option<PartialKey>> {
 		let mut buf = PartialKeyEntry::new_uninit();
-		let mut result = [0u8; PARTIAL_SIZE];
+		let mut result = PartialKey::default();
 		let buf = if log.value(self.id, index, buf.as_mut()) {
 			&mut buf
 		} else {
@@ -590,7 +760,7 @@ impl ValueTable {
 		if self.ref_counted {
 			buf.skip_rc();
 		}
-		result[..].copy_from_slice(buf.read_partial());
+		result[.. self.partial_size].copy_from_slice(buf.read_partial(self.partial_size));
 
 		Ok(Some(result))
 	}
@@ -618,6 +788,21 @@ impl ValueTable {
 		return Ok(None);
 	}
 
+	pub fn disk_usage(&self) -> Result<Option<TableDiskUsage>> {
+		let on_disk = match self.file.read().as_ref() {
+			Some(file) => stats::file_disk_size(&file.metadata()?),
+			None => return Ok(None),
+		};
+		// First entry is the header.
+		let used = self.filled.load(Ordering::Relaxed).saturating_sub(1);
+		Ok(Some(TableDiskUsage {
+			size_tier: self.id.size_tier(),
+			entry_size: self.entry_size,
+			on_disk,
+			allocated: used * self.entry_size as u64,
+		}))
+	}
+
 	pub fn next_free(&self, log: &mut LogWriter) -> Result<u64> {
 		let filled = self.filled.load(Ordering::Relaxed);
 		let last_removed = self.last_removed.load(Ordering::Relaxed);
@@ -646,7 +831,7 @@ impl ValueTable {
 	}
 
 	fn overwrite_chain(&self, key: &Key, value: &[u8], log: &mut LogWriter, at: Option<u64>, compressed: bool) -> Result<u64> {
-		let mut remainder = value.len() + self.ref_size() + PARTIAL_SIZE;
+		let mut remainder = value.len() + self.ref_size() + self.partial_size;
 		let mut offset = 0;
 		let mut start = 0;
 		assert!(self.multipart || value.len() <= self.value_size() as usize);
@@ -697,7 +882,7 @@ impl ValueTable {
 					// first rc.
 					buf.write_rc(1u32);
 				}
-				buf.write_slice(partial_key(key));
+				buf.write_slice(self.partial_key(key));
 			}
 			let written = buf.offset() - init_offset;
 			buf.write_slice(&value[offset..offset + value_len - written]);
@@ -724,17 +909,46 @@ impl ValueTable {
 		loop {
 			match self.read_next_part(index, log)? {
 				Some(next) => {
-					self.clear_slot(index, log)?;
+					self.free_slot(index, log)?;
 					index = next;
 				}
 				None => {
-					self.clear_slot(index, log)?;
+					self.free_slot(index, log)?;
 					return Ok(());
 				}
 			}
 		}
 	}
 
+	// Clear the slot of a removed or replaced value.
+	fn free_slot(&self, index: u64, log: &mut LogWriter) -> Result<()> {
+		self.freed_bytes.fetch_add(self.entry_size as u64, Ordering::Relaxed);
+		self.clear_slot(index, log)
+	}
+
+	/// Bytes of entries freed by removed and replaced values since the table was opened
+	/// or the counter was reset.
+	pub fn freed_bytes(&self) -> u64 {
+		self.freed_bytes.load(Ordering::Relaxed)
+	}
+
+	pub fn reset_freed_bytes(&self) {
+		self.freed_bytes.store(0, Ordering::Relaxed);
+	}
+
+	/// Free all slots. Entries are left in the file and overwritten as slots are used again.
+	/// The header is logged by `complete_plan`.
+	pub fn write_clear_plan(&self) {
+		if self.filled.load(Ordering::Relaxed) <= 1 && self.last_removed.load(Ordering::Relaxed) == 0 {
+			return;
+		}
+		self.last_removed.store(0, Ordering::Relaxed);
+		self.filled.store(1, Ordering::Relaxed);
+		self.freed_bytes.store(0, Ordering::Relaxed);
+		self.dirty_header.store(true, Ordering::Relaxed);
+		log::trace!(target: "parity-db", "{}: Cleared", self.id);
+	}
+
 	fn clear_slot(&self, index: u64, log: &mut LogWriter) -> Result<()> {
 		let last_removed = self.last_removed.load(Ordering::Relaxed);
 		log::trace!(
@@ -743,15 +957,203 @@ impl ValueTable {
 			self.id,
 			index,
 		);
+		self.write_tombstone(index, last_removed, log);
+		self.last_removed.store(index, Ordering::Relaxed);
+		self.dirty_header.store(true, Ordering::Relaxed);
+		Ok(())
+	}
 
+	fn write_tombstone(&self, index: u64, next: u64, log: &mut LogWriter) {
 		let mut buf = PartialEntry::new_uninit();
 		buf.write_tombstone();
-		buf.write_next(last_removed);
-
+		buf.write_next(next);
 		log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
-		self.last_removed.store(index, Ordering::Relaxed);
+	}
+
+	pub fn filled(&self) -> u64 {
+		self.filled.load(Ordering::Relaxed)
+	}
+
+	pub fn is_multipart(&self) -> bool {
+		self.multipart
+	}
+
+	/// Slots in the removed entries list.
+	pub fn free_slots(&self, log: &LogWriter) -> Result<Vec<u64>> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		let mut next = self.last_removed.load(Ordering::Relaxed);
+		let mut slots = Vec::new();
+		while next != 0 {
+			if next >= filled || slots.len() as u64 >= filled {
+				return Err(Error::Corruption(format!("{}: Bad removed entries list", self.id)));
+			}
+			slots.push(next);
+			next = self.read_next_free(next, log)?;
+		}
+		Ok(slots)
+	}
+
+	/// Replace the removed entries list with `slots`, so that they are reused in order.
+	/// Removed slots that are left out are not reused.
+	pub fn write_free_list_plan(&self, slots: &[u64], log: &mut LogWriter) {
+		let mut next = 0;
+		for index in slots.iter().rev() {
+			self.write_tombstone(*index, next, log);
+			next = *index;
+		}
+		self.last_removed.store(next, Ordering::Relaxed);
 		self.dirty_header.store(true, Ordering::Relaxed);
-		Ok(())
+	}
+
+	/// Slots that hold the parts of the value, starting with the head.
+	pub fn chain_slots(&self, index: u64, log: &LogWriter) -> Result<Vec<u64>> {
+		let mut slots = vec![index];
+		let mut index = index;
+		while let Some(next) = self.read_next_part(index, log)? {
+			if slots.len() as u64 >= self.filled.load(Ordering::Relaxed) {
+				return Err(Error::Corruption(format!("{}: Bad value chain at {}", self.id, slots[0])));
+			}
+			slots.push(next);
+			index = next;
+		}
+		Ok(slots)
+	}
+
+	pub fn is_live_slot(&self, index: u64, log: &LogWriter) -> Result<bool> {
+		let mut buf = PartialEntry::new_uninit();
+		if !log.value(self.id, index, buf.as_mut()) {
+			self.read_at(buf.as_mut(), index * self.entry_size as u64)?;
+		}
+		Ok(!buf.is_tombstone())
+	}
+
+	/// Check that the value at `index` is stored consistently for a key that starts with the
+	/// `prefix_bits` leading bits of `prefix`. Only the bits of the key kept in both the index
+	/// and the value table can be compared.
+	/// Returns the slots of the value, or `None` if the slot is removed. Inconsistent
+	/// entries are reported with `Error::Corruption`.
+	pub fn verify_slot(&self, index: u64, prefix: &Key, prefix_bits: u8, log: &impl LogQuery) -> Result<Option<Vec<u64>>> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		let entry_size = self.entry_size as usize;
+		let shared = prefix_bits.saturating_sub(((KEY_LEN - PARTIAL_SIZE) * 8) as u8).min(8);
+		let key_mask = (0xff00u16 >> shared) as u8;
+		let mut slots = Vec::new();
+		let mut next = index;
+		loop {
+			if next == 0 || next >= filled || slots.len() as u64 >= filled {
+				return Err(Error::Corruption(format!("{}: Bad value chain at {}", self.id, index)));
+			}
+			let mut buf = FullEntry::new_uninit();
+			if !log.value(self.id, next, buf.as_mut()) {
+				self.read_at(&mut buf[0..entry_size], next * self.entry_size as u64)?;
+			}
+			buf.set_offset(0);
+			if buf.is_tombstone() {
+				if slots.is_empty() {
+					return Ok(None);
+				}
+				return Err(Error::Corruption(format!("{}: Removed part {} of value at {}", self.id, next, index)));
+			}
+			slots.push(next);
+			let (end, following) = if buf.is_multipart() || buf.is_multihead() {
+				if !self.multipart {
+					return Err(Error::Corruption(format!("{}: Unexpected split value at {}", self.id, index)));
+				}
+				buf.skip_size();
+				(entry_size, buf.read_next())
+			} else {
+				let (size, _) = buf.read_size(self.no_compression);
+				(buf.offset() + size as usize, 0)
+			};
+			if end > entry_size {
+				return Err(Error::Corruption(format!("{}: Bad value size at {}", self.id, next)));
+			}
+			if slots.len() == 1 {
+				if end < buf.offset() + self.ref_size() + self.partial_size {
+					return Err(Error::Corruption(format!("{}: Bad value size at {}", self.id, index)));
+				}
+				if self.ref_counted {
+					buf.skip_rc();
+				}
+				let pk = buf.read_partial(self.partial_size);
+				if self.partial_size > 0 && (pk[0] ^ prefix[KEY_LEN - PARTIAL_SIZE]) & key_mask != 0 {
+					return Err(Error::Corruption(format!("{}: Key mismatch at {}", self.id, index)));
+				}
+			}
+			if following == 0 {
+				return Ok(Some(slots));
+			}
+			next = following;
+		}
+	}
+
+	/// Copy the value at `index` into newly allocated slots and return the new head.
+	/// Old slots below `cut` are freed, the rest are removed without being reused.
+	pub fn write_move_plan(&self, index: u64, cut: u64, log: &mut LogWriter) -> Result<u64> {
+		let slots = self.chain_slots(index, log)?;
+		let mut targets = Vec::with_capacity(slots.len());
+		for _ in 0 .. slots.len() {
+			targets.push(self.next_free(log)?);
+		}
+		let entry_size = self.entry_size as usize;
+		for (i, from) in slots.iter().enumerate() {
+			let mut buf = FullEntry::new_uninit();
+			if !log.value(self.id, *from, buf.as_mut()) {
+				self.read_at(&mut buf[0..entry_size], from * self.entry_size as u64)?;
+			}
+			let head = buf[0..SIZE_SIZE].try_into().unwrap();
+			let len = logged_entry_size(*from, head, self.entry_size, self.no_compression)?;
+			if let Some(next) = targets.get(i + 1) {
+				buf.set_offset(SIZE_SIZE);
+				buf.write_next(*next);
+			}
+			log::trace!(target: "parity-db", "{}: Moving slot {} to {}", self.id, from, targets[i]);
+			log.insert_value(self.id, targets[i], buf[0..len].to_vec());
+		}
+		for from in slots {
+			if from < cut {
+				self.clear_slot(from, log)?;
+			} else {
+				self.write_tombstone(from, 0, log);
+			}
+		}
+		Ok(targets[0])
+	}
+
+	pub fn free_stats(&self, log: &LogWriter) -> Result<Option<TableFreeStats>> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		if filled <= 1 {
+			return Ok(None);
+		}
+		let free_entries = self.free_slots(log)?.len() as u64;
+		let unused = self.capacity.load(Ordering::Relaxed).saturating_sub(filled);
+		Ok(Some(TableFreeStats {
+			size_tier: self.id.size_tier(),
+			entry_size: self.entry_size,
+			free_entries,
+			reclaimable: (free_entries + unused) * self.entry_size as u64,
+		}))
+	}
+
+	pub fn set_filled(&self, filled: u64) {
+		self.filled.store(filled, Ordering::Relaxed);
+		self.dirty_header.store(true, Ordering::Relaxed);
+	}
+
+	/// Truncate the file to the filled slots. Returns the number of bytes removed.
+	pub fn shrink(&self) -> Result<u64> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		let capacity = self.capacity.load(Ordering::Relaxed);
+		if capacity <= filled {
+			return Ok(0);
+		}
+		if let Some(file) = self.file.read().as_ref() {
+			self.set_file_len(file, filled * self.entry_size as u64)?;
+			self.dirty.store(true, Ordering::Relaxed);
+		}
+		self.capacity.store(filled, Ordering::Relaxed);
+		log::debug!(target: "parity-db", "{}: Truncated to {} entries", self.id, filled);
+		Ok((capacity - filled) * self.entry_size as u64)
 	}
 
 	pub fn write_insert_plan(&self, key: &Key, value: &[u8], log: &mut LogWriter, compressed: bool) -> Result<u64> {
@@ -767,7 +1169,7 @@ impl ValueTable {
 		if self.multipart {
 			self.clear_chain(index, log)?;
 		} else {
-			self.clear_slot(index, log)?;
+			self.free_slot(index, log)?;
 		}
 		Ok(())
 	}
@@ -831,7 +1233,7 @@ impl ValueTable {
 		return Ok(true);
 	}
 
-	pub fn enact_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
+	pub fn enact_plan(&self, index: u64, log: &mut impl LogRead) -> Result<()> {
 		while index >= self.capacity.load(Ordering::Relaxed) {
 			self.grow()?;
 		}
@@ -862,6 +1264,14 @@ impl ValueTable {
 		Ok(())
 	}
 
+	/// Write a serialized entry directly, without going through the log.
+	pub fn write_entry(&self, index: u64, data: &[u8]) -> Result<()> {
+		while index >= self.capacity.load(Ordering::Relaxed) {
+			self.grow()?;
+		}
+		self.write_at(data, index * (self.entry_size as u64))
+	}
+
 	pub fn validate_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
 		if index == 0 {
 			let mut header = Header::default();
@@ -888,6 +1298,30 @@ impl ValueTable {
 		Ok(())
 	}
 
+	/// Read the contents of an entry update from the log, to be passed to `enact_plan` later.
+	pub fn read_plan(&self, index: u64, log: &mut LogReader) -> Result<Vec<u8>> {
+		let mut head = [0u8; SIZE_SIZE];
+		log.read(&mut head)?;
+		let size = logged_entry_size(index, head, self.entry_size, self.no_compression)?;
+		let mut buf = vec![0; size];
+		buf[0..SIZE_SIZE].copy_from_slice(&head);
+		log.read(&mut buf[SIZE_SIZE..])?;
+		Ok(buf)
+	}
+
+	/// Read an entry update from the log into the overlay, without modifying the table.
+	pub fn load_plan(&self, index: u64, log: &mut LogReader, writer: &mut LogWriter) -> Result<()> {
+		let mut head = [0u8; SIZE_SIZE];
+		log.read(&mut head)?;
+		let size = logged_entry_size(index, head, self.entry_size, self.no_compression)?;
+		let mut buf = vec![0; size];
+		buf[0..SIZE_SIZE].copy_from_slice(&head);
+		log.read(&mut buf[SIZE_SIZE..])?;
+		writer.insert_value(self.id, index, buf);
+		log::trace!(target: "parity-db", "{}: Loaded slot {}, {} bytes", self.id, index, size);
+		Ok(())
+	}
+
 	pub fn refresh_metadata(&self) -> Result<()> {
 		if self.file.read().is_none() {
 			return Ok(());
@@ -974,7 +1408,7 @@ mod test {
 
 		fn table(&self, size: Option<u16>, options: &ColumnOptions) -> ValueTable {
 			let id = TableId::new(0, 0);
-			ValueTable::open(self.0.clone(), id, size, options, CURRENT_VERSION).unwrap()
+			ValueTable::open(self.0.clone(), id, size, options, CURRENT_VERSION, false).unwrap()
 		}
 
 		fn log(&self) -> Log {
@@ -997,13 +1431,14 @@ mod test {
 		let bytes_written = log.end_record(writer.drain()).unwrap();
 		// Cycle through 2 log files
 		let _ = log.read_next(false);
-		log.flush_one(0).unwrap();
+		log.flush_one(0, true).unwrap();
 		let _ = log.read_next(false);
-		log.flush_one(0).unwrap();
+		log.flush_one(0, true).unwrap();
 		let mut reader = log.read_next(false).unwrap().unwrap();
 		loop {
 			match reader.next().unwrap() {
-				LogAction::BeginRecord | LogAction::InsertIndex { .. } | LogAction::DropTable { .. } => {
+				LogAction::BeginRecord | LogAction::InsertIndex { .. } | LogAction::DropTable { .. }
+				| LogAction::ReplaceIndex { .. } => {
 					panic!("Unexpected log entry");
 				},
 				LogAction::EndRecord => {
@@ -1061,6 +1496,34 @@ mod test {
 		assert_eq!(table.filled.load(std::sync::atomic::Ordering::Relaxed), 2);
 	}
 
+	#[test]
+	fn growth_bytes() {
+		let dir = TempDir::new("growth_bytes");
+		let log = dir.log();
+		let file_len = || std::fs::metadata(dir.0.join(TableId::new(0, 0).file_name())).unwrap().len();
+		let mut options = ColumnOptions::default();
+		options.table_growth_bytes = 1024 * 1024;
+		let table = dir.table(Some(ENTRY_SIZE), &options);
+		write_ops(&table, &log, |writer| {
+			table.write_insert_plan(&key(1), &value(19), writer, false).unwrap();
+			table.complete_plan(writer).unwrap();
+		});
+		let entries = 1024 * 1024 / ENTRY_SIZE as u64;
+		assert_eq!(file_len(), (1 + entries) * ENTRY_SIZE as u64);
+		drop(table);
+
+		// Changed on reopen, and clamped.
+		options.table_growth_bytes = 1;
+		let table = dir.table(Some(ENTRY_SIZE), &options);
+		write_ops(&table, &log, |writer| {
+			for k in 2 .. entries as u32 + 2 {
+				table.write_insert_plan(&key(k), &value(19), writer, false).unwrap();
+			}
+		});
+		let growth = super::MIN_GROWTH_BYTES / ENTRY_SIZE as u64;
+		assert_eq!(file_len(), (1 + entries + growth) * ENTRY_SIZE as u64);
+	}
+
 	#[test]
 	#[should_panic(expected = "assertion failed: entry_size <= MAX_ENTRY_SIZE as u16")]
 	fn oversized_into_fixed_panics() {
@@ -1206,6 +1669,35 @@ mod test {
 		assert_eq!(table.filled.load(std::sync::atomic::Ordering::Relaxed), 7);
 	}
 
+	#[test]
+	fn multipart_boundaries() {
+		multipart_boundaries_inner(&Default::default());
+		multipart_boundaries_inner(&rc_options());
+	}
+	fn multipart_boundaries_inner(options: &ColumnOptions) {
+		let dir = TempDir::new("multipart_boundaries");
+		let table = dir.table(None, options);
+		let log = dir.log();
+
+		// Sizes around the capacity of one, two and three parts.
+		let sizes: Vec<usize> = [4096usize, 8192, 12288].iter()
+			.flat_map(|part| (part - 48 ..= part + 8).collect::<Vec<_>>())
+			.collect();
+		let mut inserted = Vec::new();
+		for (i, size) in sizes.into_iter().enumerate() {
+			let key = key(i as u32);
+			let val = value(size);
+			let mut index = 0;
+			write_ops(&table, &log, |writer| {
+				index = table.write_insert_plan(&key, &val, writer, false).unwrap();
+			});
+			inserted.push((key, index, val));
+		}
+		for (key, index, val) in inserted {
+			assert_eq!(table.get(&key, index, log.overlays()).unwrap(), Some((val, false)));
+		}
+	}
+
 	#[test]
 	fn ref_counting() {
 		for compressed in [false, true] {
@@ -1254,4 +1746,21 @@ mod test {
 		});
 		assert_eq!(table.get(&key, 1, log.overlays()).unwrap(), None);
 	}
+
+	#[test]
+	fn read_error_context() {
+		let dir = TempDir::new("read_error_context");
+		let table = dir.table(Some(ENTRY_SIZE), &Default::default());
+		let log = dir.log();
+		write_ops(&table, &log, |writer| {
+			table.write_insert_plan(&key(1), &value(19), writer, false).unwrap();
+		});
+		let mut buf = [0u8; ENTRY_SIZE as usize];
+		let e = table.read_at(&mut buf, 1 << 30).unwrap_err();
+		assert_eq!(e.io_error().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+		let message = e.to_string();
+		assert!(message.starts_with("IO Error: reading table 00-00 ("), "{}", message);
+		assert!(message.contains("table_00_00) at offset 1073741824, 64 bytes: "), "{}", message);
+		assert!(!message.contains('\n'));
+	}
 }
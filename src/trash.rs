@@ -0,0 +1,361 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Trash for removed values.
+//!
+//! Removed values are kept in a reserved column that follows the user columns, prefixed
+//! with removal time. Each column with trash keeps a journal of the trashed keys there as
+//! well. The journal is a sequence of chunks: every commit that changes the trash appends
+//! a chunk with the changes, along with the journal head, so that it survives log replay.
+//! Once there are too many chunks, the journal is rewritten as a single chunk.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use crate::{
+	error::{Error, Result},
+	table::{Key, Value},
+	options::{ColumnOptions, TrashOptions},
+};
+
+const OP_SIZE: usize = 1 + 8 + 32 + 8;
+const OP_PUSH: u8 = 0;
+const OP_TAKE: u8 = 1;
+const HEAD_SIZE: usize = 16;
+const TIMESTAMP_SIZE: usize = 8;
+// Journal is rewritten once it has this many chunks.
+const MAX_JOURNAL_CHUNKS: u64 = 256;
+
+#[derive(Clone, Debug)]
+pub struct TrashEntry {
+	/// Removal time, in seconds since unix epoch.
+	pub removed_at: u64,
+	/// Index key of the removed value.
+	pub key: Key,
+	/// Removed value size.
+	pub size: u64,
+}
+
+/// Journal record, keyed in the reserved column by the trash column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalKey {
+	/// Range of the journal chunks.
+	Head,
+	Chunk(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Trash {
+	options: TrashOptions,
+	// Keys in removal order, with the sequence number of the entry. Keys that were taken, or
+	// trashed again since, are left in place and skipped.
+	order: VecDeque<(u64, Key)>,
+	// Entries by key, with their sequence number.
+	entries: HashMap<Key, (u64, TrashEntry)>,
+	next_seq: u64,
+	bytes: u64,
+	// Journal chunks in use, `first .. next`.
+	first_chunk: u64,
+	next_chunk: u64,
+	// Changes that are not in the journal yet.
+	ops: Vec<u8>,
+}
+
+pub fn now() -> u64 {
+	std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Options of the reserved column.
+pub fn column_options() -> ColumnOptions {
+	Default::default()
+}
+
+/// Value stored under the trash key.
+pub fn encode_value(removed_at: u64, value: &[u8]) -> Value {
+	let mut result = Vec::with_capacity(TIMESTAMP_SIZE + value.len());
+	result.extend_from_slice(&removed_at.to_le_bytes());
+	result.extend_from_slice(value);
+	result
+}
+
+/// Original value from the value stored under the trash key.
+pub fn decode_value(mut value: Value) -> Result<Value> {
+	if value.len() < TIMESTAMP_SIZE {
+		return Err(Error::Corruption("Bad trash value".into()));
+	}
+	value.drain(0 .. TIMESTAMP_SIZE);
+	Ok(value)
+}
+
+impl Trash {
+	pub fn new(options: TrashOptions) -> Trash {
+		Trash {
+			options,
+			order: Default::default(),
+			entries: Default::default(),
+			next_seq: 0,
+			bytes: 0,
+			first_chunk: 0,
+			next_chunk: 0,
+			ops: Vec::new(),
+		}
+	}
+
+	/// Load the journal. `chunk` reads a journal chunk.
+	pub fn load(options: TrashOptions, head: Option<&[u8]>, mut chunk: impl FnMut(u64) -> Result<Option<Value>>) -> Result<Trash> {
+		let mut trash = Trash::new(options);
+		let head = match head {
+			Some(head) => head,
+			None => return Ok(trash),
+		};
+		if head.len() != HEAD_SIZE {
+			return Err(Error::Corruption("Bad trash journal head".into()));
+		}
+		trash.first_chunk = u64::from_le_bytes(head[0 .. 8].try_into().unwrap());
+		trash.next_chunk = u64::from_le_bytes(head[8 .. 16].try_into().unwrap());
+		for index in trash.first_chunk .. trash.next_chunk {
+			let ops = chunk(index)?.ok_or_else(|| Error::Corruption(format!("Missing trash journal chunk {}", index)))?;
+			trash.replay(&ops)?;
+		}
+		Ok(trash)
+	}
+
+	fn replay(&mut self, ops: &[u8]) -> Result<()> {
+		if ops.len() % OP_SIZE != 0 {
+			return Err(Error::Corruption("Bad trash journal".into()));
+		}
+		for op in ops.chunks(OP_SIZE) {
+			let mut key = Key::default();
+			key.copy_from_slice(&op[9 .. 41]);
+			match op[0] {
+				OP_PUSH => self.insert(TrashEntry {
+					removed_at: u64::from_le_bytes(op[1 .. 9].try_into().unwrap()),
+					key,
+					size: u64::from_le_bytes(op[41 .. 49].try_into().unwrap()),
+				}),
+				OP_TAKE => {
+					self.remove(&key);
+				},
+				_ => return Err(Error::Corruption("Bad trash journal".into())),
+			}
+		}
+		Ok(())
+	}
+
+	fn record(&mut self, op: u8, entry: &TrashEntry) {
+		self.ops.push(op);
+		self.ops.extend_from_slice(&entry.removed_at.to_le_bytes());
+		self.ops.extend_from_slice(&entry.key);
+		self.ops.extend_from_slice(&entry.size.to_le_bytes());
+	}
+
+	/// Journal changes for the changes made since the last call.
+	pub fn journal_changes(&mut self) -> Vec<(JournalKey, Option<Value>)> {
+		let mut changes = Vec::new();
+		if self.ops.is_empty() {
+			return changes;
+		}
+		if self.next_chunk - self.first_chunk >= MAX_JOURNAL_CHUNKS {
+			// Rewrite the journal with the entries that are left.
+			self.ops.clear();
+			let entries: Vec<_> = self.iter().cloned().collect();
+			for entry in entries.iter() {
+				self.record(OP_PUSH, entry);
+			}
+			changes.extend((self.first_chunk .. self.next_chunk).map(|i| (JournalKey::Chunk(i), None)));
+			self.first_chunk = self.next_chunk;
+		}
+		changes.push((JournalKey::Chunk(self.next_chunk), Some(std::mem::take(&mut self.ops))));
+		self.next_chunk += 1;
+		let mut head = Vec::with_capacity(HEAD_SIZE);
+		head.extend_from_slice(&self.first_chunk.to_le_bytes());
+		head.extend_from_slice(&self.next_chunk.to_le_bytes());
+		changes.push((JournalKey::Head, Some(head)));
+		changes
+	}
+
+	fn insert(&mut self, entry: TrashEntry) {
+		// Value removed again replaces the older copy.
+		self.remove(&entry.key);
+		self.bytes += entry.size;
+		let seq = self.next_seq;
+		self.next_seq += 1;
+		self.order.push_back((seq, entry.key));
+		self.entries.insert(entry.key, (seq, entry));
+	}
+
+	fn remove(&mut self, key: &Key) -> Option<TrashEntry> {
+		let (_, entry) = self.entries.remove(key)?;
+		self.bytes -= entry.size;
+		// Drop skipped keys once they outnumber the entries.
+		if self.order.len() > 2 * self.entries.len() + 64 {
+			let entries = &self.entries;
+			self.order.retain(|(seq, key)| entries.get(key).is_some_and(|(s, _)| s == seq));
+		}
+		Some(entry)
+	}
+
+	// Entries in removal order.
+	fn iter(&self) -> impl Iterator<Item = &TrashEntry> {
+		self.order.iter().filter_map(move |(seq, key)| match self.entries.get(key) {
+			Some((s, entry)) if s == seq => Some(entry),
+			_ => None,
+		})
+	}
+
+	pub fn push(&mut self, entry: TrashEntry) {
+		self.record(OP_PUSH, &entry);
+		self.insert(entry);
+	}
+
+	pub fn take(&mut self, key: &Key) -> Option<TrashEntry> {
+		let entry = self.remove(key)?;
+		self.record(OP_TAKE, &entry);
+		Some(entry)
+	}
+
+	/// Entry is too old to be kept.
+	pub fn is_expired(&self, entry: &TrashEntry, now: u64) -> bool {
+		now.saturating_sub(entry.removed_at) >= self.options.max_age.as_secs()
+	}
+
+	/// Remove entries that are too old or don't fit in the size bound.
+	pub fn expire(&mut self, now: u64) -> Vec<TrashEntry> {
+		let mut expired = Vec::new();
+		while let Some(&(seq, key)) = self.order.front() {
+			match self.entries.get(&key) {
+				Some((s, entry)) if *s == seq => {
+					if !self.is_expired(entry, now) && !self.is_full() {
+						break;
+					}
+					expired.push(self.take(&key).unwrap());
+				},
+				_ => {
+					self.order.pop_front();
+				},
+			}
+		}
+		expired
+	}
+
+	/// Entries don't fit in the size bound.
+	pub fn is_full(&self) -> bool {
+		self.bytes > self.options.max_bytes
+	}
+
+	/// Keys of the entries, in no particular order.
+	pub fn keys(&self) -> impl Iterator<Item = &Key> {
+		self.entries.keys()
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn bytes(&self) -> u64 {
+		self.bytes
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{JournalKey, Trash, TrashEntry};
+	use crate::options::TrashOptions;
+
+	fn entry(removed_at: u64, k: u8, size: u64) -> TrashEntry {
+		TrashEntry { removed_at, key: [k; 32], size }
+	}
+
+	#[test]
+	fn expire_by_age_and_size() {
+		let options = TrashOptions { max_bytes: 100, max_age: std::time::Duration::from_secs(10) };
+		let mut trash = Trash::new(options);
+		trash.push(entry(0, 1, 10));
+		trash.push(entry(5, 2, 10));
+		assert_eq!(trash.expire(9).len(), 0);
+		assert_eq!(trash.expire(10).len(), 1);
+		trash.push(entry(6, 3, 95));
+		let expired = trash.expire(6);
+		assert_eq!(expired.len(), 1);
+		assert_eq!(expired[0].key, [2; 32]);
+		assert_eq!(trash.bytes(), 95);
+
+		let mut chunks = std::collections::HashMap::new();
+		let mut head = None;
+		let mut apply = |trash: &mut Trash| {
+			for (key, value) in trash.journal_changes() {
+				match (key, value) {
+					(JournalKey::Head, value) => head = value,
+					(JournalKey::Chunk(i), Some(value)) => { chunks.insert(i, value); },
+					(JournalKey::Chunk(i), None) => { chunks.remove(&i); },
+				}
+			}
+		};
+		apply(&mut trash);
+		let decoded = Trash::load(options, head.as_deref(), |i| Ok(chunks.get(&i).cloned())).unwrap();
+		assert_eq!(decoded.len(), 1);
+		assert_eq!(decoded.bytes(), 95);
+	}
+
+	#[test]
+	fn expire_in_removal_order() {
+		let options = TrashOptions { max_bytes: 1000, max_age: std::time::Duration::from_secs(10) };
+		let mut trash = Trash::new(options);
+		for k in 1 ..= 200 {
+			trash.push(entry(k as u64, k, 1));
+		}
+		// Trashed again, so it moves to the back.
+		trash.push(entry(300, 1, 1));
+		for k in 2 ..= 150 {
+			trash.take(&[k; 32]);
+		}
+		assert_eq!(trash.len(), 51);
+		let expired: Vec<u8> = trash.expire(170).iter().map(|e| e.key[0]).collect();
+		assert_eq!(expired, (151 ..= 160).collect::<Vec<u8>>());
+		assert_eq!(trash.len(), 41);
+		assert_eq!(trash.bytes(), 41);
+		let expired: Vec<u8> = trash.expire(310).iter().map(|e| e.key[0]).collect();
+		assert_eq!(expired.last(), Some(&1));
+		assert_eq!(trash.len(), 0);
+	}
+
+	#[test]
+	fn journal_is_appended_and_rewritten() {
+		let options = TrashOptions { max_bytes: 1000, max_age: std::time::Duration::from_secs(10) };
+		let mut trash = Trash::new(options);
+		assert!(trash.journal_changes().is_empty());
+		trash.push(entry(0, 1, 10));
+		let changes = trash.journal_changes();
+		assert_eq!(changes.len(), 2);
+		assert_eq!(changes[0].0, JournalKey::Chunk(0));
+		// Only new changes are written.
+		trash.push(entry(0, 2, 10));
+		let changes = trash.journal_changes();
+		assert_eq!(changes[0].0, JournalKey::Chunk(1));
+		assert_eq!(changes[0].1.as_ref().unwrap().len(), super::OP_SIZE);
+
+		for i in 2 .. super::MAX_JOURNAL_CHUNKS {
+			trash.take(&[(i % 2 + 1) as u8; 32]);
+			trash.push(entry(0, (i % 2 + 1) as u8, 10));
+			trash.journal_changes();
+		}
+		trash.push(entry(0, 3, 10));
+		let changes = trash.journal_changes();
+		let removed = changes.iter().filter(|(_, v)| v.is_none()).count();
+		assert_eq!(removed as u64, super::MAX_JOURNAL_CHUNKS);
+		let chunk = changes.iter().find(|(k, v)| matches!(k, JournalKey::Chunk(_)) && v.is_some()).unwrap();
+		assert_eq!(chunk.1.as_ref().unwrap().len(), 3 * super::OP_SIZE);
+	}
+}
@@ -0,0 +1,186 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Expiring values.
+//!
+//! Values in columns with a TTL are prefixed with their insertion time. Expired values
+//! are hidden from queries and removed in the background, a batch at a time.
+
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::{
+	error::{Error, Result},
+	table::{Key, Value},
+};
+
+const TIMESTAMP_SIZE: usize = 8;
+/// File that keeps the expiry clock, in the database directory.
+pub const CLOCK_FILE: &str = "ttl_clock";
+// System time may be ahead of the kept clock by this much on open. Larger jumps are taken
+// for clock errors and cut to this, so that values don't expire early.
+const MAX_CLOCK_JUMP: u64 = 24 * 3600;
+// The clock is kept again once it is this far ahead of the kept time.
+const CLOCK_SAVE_INTERVAL: u64 = 60;
+
+/// Seconds clock used for expiry. Follows a monotonic clock while the database is open, so
+/// that clock adjustments meanwhile don't affect expiry. The highest time seen is kept in
+/// `CLOCK_FILE`. On open the clock resumes from system time, but never before the kept time,
+/// and at most `MAX_CLOCK_JUMP` past it.
+pub struct Clock {
+	anchor: u64,
+	start: Instant,
+	// Seconds the clock was moved forward by.
+	skew: AtomicU64,
+	// Where the clock is kept, `None` if it is not.
+	path: Option<PathBuf>,
+	saved: AtomicU64,
+}
+
+impl Clock {
+	/// Resume the clock kept in `path`. The file is written by `save`.
+	pub fn open(path: Option<PathBuf>) -> Result<Clock> {
+		let system = crate::trash::now();
+		let saved = match &path {
+			Some(path) => match std::fs::read(path) {
+				// A file that was cut short by a crash is ignored.
+				Ok(data) => data.get(0 .. TIMESTAMP_SIZE).map(|b| u64::from_le_bytes(b.try_into().unwrap())),
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+				Err(e) => return Err(e.into()),
+			},
+			None => None,
+		};
+		let anchor = match saved {
+			Some(saved) if system < saved => {
+				log::warn!(target: "parity-db", "System time is {}s behind the expiry clock, using the clock", saved - system);
+				saved
+			},
+			Some(saved) if system - saved > MAX_CLOCK_JUMP => {
+				log::warn!(
+					target: "parity-db",
+					"System time is {}s ahead of the expiry clock, moving the clock by {}s",
+					system - saved,
+					MAX_CLOCK_JUMP,
+				);
+				saved + MAX_CLOCK_JUMP
+			},
+			_ => system,
+		};
+		Ok(Clock {
+			anchor,
+			start: Instant::now(),
+			skew: Default::default(),
+			path,
+			saved: AtomicU64::new(saved.unwrap_or(0)),
+		})
+	}
+
+	pub fn now(&self) -> u64 {
+		self.anchor + self.start.elapsed().as_secs() + self.skew.load(Ordering::Relaxed)
+	}
+
+	/// Keep the current time, if the clock moved on since it was last kept. Unless `force` is
+	/// set, this is only done every `CLOCK_SAVE_INTERVAL`.
+	pub fn save(&self, force: bool) -> Result<()> {
+		let path = match &self.path {
+			Some(path) => path,
+			None => return Ok(()),
+		};
+		let now = self.now();
+		let saved = self.saved.load(Ordering::Relaxed);
+		if now <= saved || (!force && now - saved < CLOCK_SAVE_INTERVAL) {
+			return Ok(());
+		}
+		std::fs::write(path, now.to_le_bytes())?;
+		self.saved.store(now, Ordering::Relaxed);
+		Ok(())
+	}
+
+	#[cfg(test)]
+	pub fn advance(&self, by: Duration) {
+		self.skew.fetch_add(by.as_secs(), Ordering::Relaxed);
+	}
+}
+
+/// Background expiry state of a column.
+#[derive(Default)]
+pub struct Expiry {
+	/// Next index chunk to scan.
+	pub next_chunk: u64,
+	/// Found expired keys, not removed yet.
+	pub pending: Vec<Key>,
+	/// Total number of removed expired values.
+	pub removed: u64,
+	/// Completion time of the last full index scan.
+	pub last_scan: Option<Instant>,
+}
+
+impl Expiry {
+	/// Check if the next scan should start.
+	pub fn due(&self, interval: Duration) -> bool {
+		self.next_chunk != 0 || self.last_scan.map_or(true, |t| t.elapsed() >= interval)
+	}
+}
+
+/// Value stored in the column.
+pub fn encode_value(inserted_at: u64, value: &[u8]) -> Value {
+	let mut result = Vec::with_capacity(TIMESTAMP_SIZE + value.len());
+	result.extend_from_slice(&inserted_at.to_le_bytes());
+	result.extend_from_slice(value);
+	result
+}
+
+/// Check if the stored value has expired. Values inserted "in the future" are not expired.
+pub fn is_expired(stored: &[u8], ttl: Duration, now: u64) -> bool {
+	stored.len() >= TIMESTAMP_SIZE && {
+		let inserted_at = u64::from_le_bytes(stored[0 .. TIMESTAMP_SIZE].try_into().unwrap());
+		now.saturating_sub(inserted_at) >= ttl.as_secs()
+	}
+}
+
+/// Original value, whether it has expired or not.
+pub fn strip_value(stored: &[u8]) -> Result<&[u8]> {
+	stored.get(TIMESTAMP_SIZE ..).ok_or_else(|| Error::Corruption("Bad TTL value".into()))
+}
+
+/// Original value, or `None` if the value has expired.
+pub fn decode_value(mut stored: Value, ttl: Duration, now: u64) -> Result<Option<Value>> {
+	if stored.len() < TIMESTAMP_SIZE {
+		return Err(Error::Corruption("Bad TTL value".into()));
+	}
+	if is_expired(&stored, ttl, now) {
+		return Ok(None);
+	}
+	stored.drain(0 .. TIMESTAMP_SIZE);
+	Ok(Some(stored))
+}
+
+#[cfg(test)]
+mod test {
+	use super::{encode_value, decode_value};
+	use std::time::Duration;
+
+	#[test]
+	fn expire() {
+		let ttl = Duration::from_secs(10);
+		let stored = encode_value(100, b"value");
+		assert_eq!(decode_value(stored.clone(), ttl, 50).unwrap(), Some(b"value".to_vec()));
+		assert_eq!(decode_value(stored.clone(), ttl, 109).unwrap(), Some(b"value".to_vec()));
+		assert_eq!(decode_value(stored, ttl, 110).unwrap(), None);
+		assert!(decode_value(vec![0; 4], ttl, 0).is_err());
+	}
+}